@@ -0,0 +1,235 @@
+//! Async versions of the [Client](crate::Client) and [Server](crate::Server) on top of tokio, enabled by the `async` feature.
+//!
+//! They speak the same protocol as the blocking client and server, so either end can be blocking or async.
+//! A transfer is cancelled by dropping its future, which is also how a timeout works:
+//!
+//! ```no_run
+//! # async fn send() -> anyhow::Result<()> {
+//! use std::time::Duration;
+//!
+//! let client = quick_file_transfer::asynchronous::Client::builder()
+//!     .target(([192, 168, 0, 2], 49152))
+//!     .file("data.bin")
+//!     .build()?;
+//! let report = tokio::time::timeout(Duration::from_secs(60), client.send()).await??;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The async client and server only support sending files (and directories) uncompressed and unencrypted,
+//! with preallocation, checksums and pre-shared keys. The handshake tells a blocking peer which features it can't use,
+//! so it continues without them, or refuses the transfer if it can't do without them (e.g. `--encrypt`).
+
+use anyhow::bail;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    auth::{random_nonce, AuthChallenge, AuthError, PreSharedKey, PROOF_LEN},
+    config::transfer::{
+        command::{ServerCommand, ServerResult},
+        handshake::{
+            Capabilities, HandshakeHello, IncompatiblePeer, NegotiatedProtocol, HANDSHAKE_MAGIC,
+        },
+    },
+    framed_stream::{DATA_FRAME_HEADER_SIZE, MAX_DATA_FRAME_SIZE},
+    util::AUTH_TIMEOUT,
+};
+
+pub mod client;
+pub mod server;
+
+pub use client::{Client, ClientBuilder};
+pub use server::{Server, ServerBuilder};
+
+/// The features the async client and server support, the rest are left out of the handshake
+const CAPABILITIES: Capabilities = Capabilities::PREALLOC
+    .with(Capabilities::CHECKSUM)
+    .with(Capabilities::RECURSIVE)
+    .with(Capabilities::PSK_AUTH);
+
+/// The hello of the async client and server, which only differs from [HandshakeHello::local] in the capabilities
+fn local_hello() -> HandshakeHello {
+    HandshakeHello {
+        capabilities: CAPABILITIES,
+        ..HandshakeHello::local()
+    }
+}
+
+/// Send a [ServerCommand], or a reply that is framed like a [ServerResult]
+async fn send_frame<T, W>(stream: &mut W, frame: &T) -> anyhow::Result<()>
+where
+    T: Serialize + std::fmt::Debug,
+    W: AsyncWrite + Unpin,
+{
+    tracing::trace!("Sending: {frame:?}");
+    let bytes = bincode::serialize(frame)?;
+    let mut buf = Vec::with_capacity(ServerCommand::HEADER_SIZE + bytes.len());
+    buf.extend_from_slice(&ServerCommand::header_from_size(bytes.len())?);
+    buf.extend_from_slice(&bytes);
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Read a [ServerCommand], or a reply that is framed like a [ServerResult].
+///
+/// Returns [None] if the peer disconnected instead of sending another frame.
+async fn read_frame<T, R>(stream: &mut R) -> anyhow::Result<Option<T>>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; ServerCommand::HEADER_SIZE];
+    match stream.read_exact(&mut header).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => bail!(e),
+    }
+    let mut buf = vec![0; ServerCommand::size_from_bytes(header)?];
+    stream.read_exact(&mut buf).await?;
+    Ok(Some(bincode::deserialize(&buf)?))
+}
+
+/// Read a reply that is framed like a [ServerResult]
+async fn read_reply<T, R>(stream: &mut R) -> anyhow::Result<T>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    match read_frame(stream).await? {
+        Some(reply) => Ok(reply),
+        None => bail!("The server disconnected before replying"),
+    }
+}
+
+/// Read the header of a data frame, returns the size of the content that follows, `0` marks the end of the content
+async fn read_data_frame_header<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<usize> {
+    let mut header = [0; DATA_FRAME_HEADER_SIZE];
+    stream.read_exact(&mut header).await?;
+    let size = u32::from_be_bytes(header) as usize;
+    if size > MAX_DATA_FRAME_SIZE {
+        bail!("Data frame of {size} B exceeds the maximum of {MAX_DATA_FRAME_SIZE} B");
+    }
+    Ok(size)
+}
+
+/// The async counterpart of the client handshake of [qft_connect_to_server](crate::send::util::qft_connect_to_server)
+async fn client_handshake(
+    socket: &mut TcpStream,
+    psk: Option<&PreSharedKey>,
+) -> anyhow::Result<NegotiatedProtocol> {
+    let mut magic_buf = [0; HANDSHAKE_MAGIC.len()];
+    socket.read_exact(&mut magic_buf).await?;
+    HandshakeHello::check_magic(magic_buf)?;
+    let mut body_buf = [0; HandshakeHello::SIZE - HANDSHAKE_MAGIC.len()];
+    socket.read_exact(&mut body_buf).await?;
+    let server_hello = HandshakeHello::from_body_bytes(body_buf);
+    tracing::trace!("Server hello: {server_hello:?}");
+
+    let local_hello = local_hello();
+    socket.write_all(&local_hello.to_bytes()).await?;
+    let negotiated = local_hello.negotiate(&server_hello)?;
+    if let ServerResult::Err(reason) = read_reply(socket).await? {
+        bail!(IncompatiblePeer::Refused(reason));
+    }
+
+    if negotiated.capabilities.contains(Capabilities::PSK_AUTH) {
+        match read_reply(socket).await? {
+            AuthChallenge::None if psk.is_some() => {
+                log::warn!("The server does not require a pre-shared key");
+            }
+            AuthChallenge::None => (),
+            AuthChallenge::Psk(nonce) => {
+                let Some(psk) = psk else {
+                    bail!(AuthError::MissingKey);
+                };
+                socket.write_all(&psk.prove(&nonce)).await?;
+                if let ServerResult::Err(reason) = read_reply(socket).await? {
+                    bail!(AuthError::Refused(reason));
+                }
+                log::debug!("Authenticated with the pre-shared key");
+            }
+        }
+    } else if psk.is_some() {
+        log::warn!("The server does not support authentication with a pre-shared key");
+    }
+    Ok(negotiated)
+}
+
+/// The async counterpart of [server_handshake](crate::util::server_handshake)
+async fn server_handshake(
+    socket: &mut TcpStream,
+    psk: Option<&PreSharedKey>,
+) -> anyhow::Result<NegotiatedProtocol> {
+    let local_hello = local_hello();
+    socket.write_all(&local_hello.to_bytes()).await?;
+    let mut magic_buf = [0; HANDSHAKE_MAGIC.len()];
+    socket.read_exact(&mut magic_buf).await?;
+    HandshakeHello::check_magic(magic_buf)?;
+    let mut body_buf = [0; HandshakeHello::SIZE - HANDSHAKE_MAGIC.len()];
+    socket.read_exact(&mut body_buf).await?;
+    let client_hello = HandshakeHello::from_body_bytes(body_buf);
+    tracing::trace!("Client hello: {client_hello:?}");
+
+    let negotiated = match local_hello.negotiate(&client_hello) {
+        Ok(negotiated) => negotiated,
+        Err(e) => {
+            send_frame(socket, &ServerResult::err(e.to_string())).await?;
+            bail!(e)
+        }
+    };
+    let supports_auth = negotiated.capabilities.contains(Capabilities::PSK_AUTH);
+    if psk.is_some() && !supports_auth {
+        send_frame(
+            socket,
+            &ServerResult::err(
+                "the server requires a pre-shared key, which this version of qft can't provide",
+            ),
+        )
+        .await?;
+        bail!(AuthError::Refused(
+            "the client doesn't support authentication with a pre-shared key".into()
+        ));
+    }
+    send_frame(socket, &ServerResult::Ok).await?;
+    if supports_auth {
+        authenticate_client(socket, psk).await?;
+    }
+    log::trace!("QFT handshake OK - {negotiated:?}");
+    Ok(negotiated)
+}
+
+/// Challenge the client to prove that it knows the pre-shared key (if any) and reply whether it did
+async fn authenticate_client(
+    socket: &mut TcpStream,
+    psk: Option<&PreSharedKey>,
+) -> anyhow::Result<()> {
+    let Some(psk) = psk else {
+        return send_frame(socket, &AuthChallenge::None).await;
+    };
+    let nonce = random_nonce()?;
+    send_frame(socket, &AuthChallenge::Psk(nonce)).await?;
+
+    let mut proof = [0; PROOF_LEN];
+    match tokio::time::timeout(AUTH_TIMEOUT, socket.read_exact(&mut proof)).await {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => bail!(AuthError::Refused(
+            format!("no answer to the challenge: {e}").into()
+        )),
+        Err(elapsed) => bail!(AuthError::Refused(
+            format!("no answer to the challenge: {elapsed}").into()
+        )),
+    }
+
+    if psk.verify(&nonce, &proof) {
+        log::debug!("Client authenticated");
+        send_frame(socket, &ServerResult::Ok).await
+    } else {
+        const REASON: &str = "wrong pre-shared key";
+        send_frame(socket, &ServerResult::err(REASON)).await?;
+        bail!(AuthError::Refused(REASON.into()))
+    }
+}
@@ -0,0 +1,403 @@
+//! The async counterpart of the [Client](crate::Client).
+
+use std::{fmt, io, net::SocketAddr, path::PathBuf, sync::Arc, time::Instant};
+
+use anyhow::bail;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{client_handshake, read_reply, send_frame};
+use crate::{
+    auth::{AuthError, PreSharedKey},
+    checksum::Checksum,
+    config::transfer::{
+        command::{ServerCommand, ServerResult},
+        handshake::{Capabilities, IncompatiblePeer, NegotiatedProtocol},
+        util::{PollAbortCondition, TcpConnectMode},
+    },
+    framed_stream::{DATA_FRAME_HEADER_SIZE, MAX_DATA_FRAME_SIZE},
+    observer::{SharedObserver, TransferObserver},
+    send::{
+        report::{FileReport, FileStatus, OnSent, TransferReport},
+        sources::{SourceFile, Sources},
+    },
+};
+
+/// A client that sends files to a server from a tokio runtime, see [Client::builder]
+#[derive(Debug, Clone)]
+pub struct Client {
+    target: SocketAddr,
+    settings: Settings,
+}
+
+/// Configures a [Client], the defaults match `qft send` except that it connects only once instead of polling
+#[derive(Debug, Clone, Default)]
+pub struct ClientBuilder {
+    target: Option<SocketAddr>,
+    settings: Settings,
+}
+
+#[derive(Clone)]
+struct Settings {
+    files: Vec<PathBuf>,
+    recursive: bool,
+    prealloc: bool,
+    connect_mode: TcpConnectMode,
+    psk: Option<PreSharedKey>,
+    on_sent: Option<Arc<OnSent>>,
+    observer: SharedObserver,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            files: vec![],
+            recursive: false,
+            prealloc: true,
+            connect_mode: TcpConnectMode::OneShot,
+            psk: None,
+            on_sent: None,
+            observer: SharedObserver::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("files", &self.files)
+            .field("recursive", &self.recursive)
+            .field("prealloc", &self.prealloc)
+            .field("connect_mode", &self.connect_mode)
+            .field("psk", &self.psk)
+            .field("observer", &self.observer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    /// Configure a client, which needs at least a [ClientBuilder::target] and a [ClientBuilder::file], e.g.
+    ///
+    /// ```no_run
+    /// # async fn send() -> anyhow::Result<()> {
+    /// let report = quick_file_transfer::asynchronous::Client::builder()
+    ///     .target(([192, 168, 0, 2], 49152))
+    ///     .file("data.bin")
+    ///     .build()?
+    ///     .send()
+    ///     .await?;
+    /// report.ensure_no_failures()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// The address of the server the files are sent to
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    /// Send the files one after the other and report what became of each of them.
+    ///
+    /// Fails if the transfer as a whole fails, files that failed on their own are reported as such,
+    /// see [TransferReport::ensure_no_failures]. Dropping the future aborts the transfer,
+    /// and the server discards the file it was receiving.
+    pub async fn send(&self) -> anyhow::Result<TransferReport> {
+        let s = &self.settings;
+        let sources = tokio::task::spawn_blocking({
+            let files = s.files.clone();
+            let recursive = s.recursive;
+            move || Sources::collect(&files, recursive)
+        })
+        .await??;
+
+        let (mut socket, negotiated) = connect(self.target, s.connect_mode, s.psk.as_ref()).await?;
+        let capabilities = negotiated.capabilities;
+        if sources.has_dirs() && !capabilities.contains(Capabilities::RECURSIVE) {
+            bail!("The remote qft does not support receiving directories");
+        }
+        let prealloc = if s.prealloc && !capabilities.contains(Capabilities::PREALLOC) {
+            log::warn!("The remote qft does not support preallocation, continuing without it");
+            false
+        } else {
+            s.prealloc
+        };
+        for dir in &sources.dirs {
+            send_frame(&mut socket, &ServerCommand::CreateDir(dir.to_owned())).await?;
+            if let ServerResult::Err(e) = read_reply(&mut socket).await? {
+                bail!("{e}");
+            }
+        }
+
+        send_frame(&mut socket, &ServerCommand::GetFreePort((None, None))).await?;
+        let free_port = socket.read_u16().await?;
+        tracing::info!("Got free port: {free_port}");
+        let child_addr = SocketAddr::new(self.target.ip(), free_port);
+
+        let mut report = TransferReport::default();
+        let file_count = sources.files.len();
+        for (idx, source) in sources.files.iter().enumerate() {
+            let SourceFile { path, name } = source;
+            let start = Instant::now();
+            let sent = SendFile {
+                addr: child_addr,
+                source,
+                remaining: file_count - idx - 1,
+                prealloc,
+                capabilities,
+                psk: s.psk.as_ref(),
+                observer: &s.observer,
+            }
+            .send()
+            .await;
+            let elapsed = start.elapsed();
+            let (size, status) = match sent {
+                Ok(size) => {
+                    s.observer.on_file_complete(name, size, elapsed);
+                    (size, FileStatus::Sent { transferred: size })
+                }
+                Err(e) => {
+                    log::error!("Failed sending {}: {e}", path.display());
+                    s.observer.on_error(Some(name), &e);
+                    let error = e.to_string();
+                    (0, FileStatus::Failed { error })
+                }
+            };
+            let file_report = FileReport::new(
+                path.display().to_string(),
+                name.clone(),
+                size,
+                elapsed,
+                status,
+            );
+            if let Some(on_sent) = &s.on_sent {
+                on_sent(&file_report);
+            }
+            report.files.push(file_report);
+        }
+
+        send_frame(&mut socket, &ServerCommand::EndOfTransfer).await?;
+        if let ServerResult::Err(e) = read_reply(&mut socket).await? {
+            bail!("{e}");
+        }
+        Ok(report)
+    }
+}
+
+/// A single file that is sent over its own connection to the child the server listens for it at
+struct SendFile<'a> {
+    addr: SocketAddr,
+    source: &'a SourceFile,
+    /// The number of files after this one
+    remaining: usize,
+    prealloc: bool,
+    capabilities: Capabilities,
+    psk: Option<&'a PreSharedKey>,
+    observer: &'a SharedObserver,
+}
+
+impl SendFile<'_> {
+    /// Send the file and return its size
+    async fn send(self) -> anyhow::Result<u64> {
+        let SourceFile { path, name } = self.source;
+        let mut file = File::open(path).await?;
+        let flen = file.metadata().await?.len();
+        self.observer.on_file_start(name, Some(flen));
+        let (mut socket, _) = connect(self.addr, TcpConnectMode::OneShot, self.psk).await?;
+
+        if self.prealloc {
+            send_frame(&mut socket, &ServerCommand::Prealloc(flen, name.to_owned())).await?;
+        }
+        let cmd = ServerCommand::ReceiveData(self.remaining as u32, name.to_owned(), None);
+        send_frame(&mut socket, &cmd).await?;
+
+        // Each frame is written at once, header included
+        let mut frame = vec![0; DATA_FRAME_HEADER_SIZE + MAX_DATA_FRAME_SIZE];
+        let mut hasher = Sha256::new();
+        let mut sent: u64 = 0;
+        loop {
+            let len = file.read(&mut frame[DATA_FRAME_HEADER_SIZE..]).await?;
+            if len == 0 {
+                break;
+            }
+            frame[..DATA_FRAME_HEADER_SIZE].copy_from_slice(&(len as u32).to_be_bytes());
+            socket
+                .write_all(&frame[..DATA_FRAME_HEADER_SIZE + len])
+                .await?;
+            let content = &frame[DATA_FRAME_HEADER_SIZE..DATA_FRAME_HEADER_SIZE + len];
+            hasher.update(content);
+            sent += len as u64;
+            self.observer.on_progress(name, sent);
+        }
+        socket.write_all(&0_u32.to_be_bytes()).await?;
+
+        if self.capabilities.contains(Capabilities::CHECKSUM) {
+            let checksum = Checksum(hasher.finalize().into());
+            send_frame(&mut socket, &ServerCommand::VerifyChecksum(checksum)).await?;
+            if let ServerResult::Err(e) = read_reply(&mut socket).await? {
+                bail!("{e}");
+            }
+        }
+        socket.shutdown().await?;
+        Ok(sent)
+    }
+}
+
+/// Connect to a QFT server and return the socket along with the protocol negotiated in the handshake
+async fn connect(
+    addr: SocketAddr,
+    connect_mode: TcpConnectMode,
+    psk: Option<&PreSharedKey>,
+) -> anyhow::Result<(TcpStream, NegotiatedProtocol)> {
+    let poll_opts = match connect_mode {
+        TcpConnectMode::OneShot => {
+            log::debug!("Attempting one shot connection to {addr}");
+            let mut socket = TcpStream::connect(addr).await?;
+            let negotiated = client_handshake(&mut socket, psk).await?;
+            return Ok((socket, negotiated));
+        }
+        TcpConnectMode::Poll(poll_opts) => poll_opts,
+    };
+    let mut attempts: u32 = 0;
+    let now = Instant::now();
+    loop {
+        log::debug!("Attempt #{attempts} to connect to {addr}");
+        match TcpStream::connect(addr).await {
+            Ok(mut socket) => match client_handshake(&mut socket, psk).await {
+                Ok(negotiated) => return Ok((socket, negotiated)),
+                // Retrying won't make the peers any more compatible or the key any more correct
+                Err(e) if e.is::<IncompatiblePeer>() || e.is::<AuthError>() => return Err(e),
+                Err(e) => log::warn!("Handshake failed: {e} ... retrying"),
+            },
+            Err(e) => {
+                log::trace!("Connection attempt failed: {e}");
+                match e.kind() {
+                    io::ErrorKind::NotFound
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted => {}
+                    _ => bail!(e),
+                }
+            }
+        }
+        match poll_opts.abort_condition {
+            PollAbortCondition::Attempts(att) => {
+                if attempts == att {
+                    bail!("Failed establishing a TCP connection after {att} attempts")
+                }
+            }
+            PollAbortCondition::Timeout(timeout_dur) => {
+                if now.elapsed() >= timeout_dur {
+                    bail!("Failed establishing a TCP connection after {timeout_dur:?}")
+                }
+            }
+        };
+        let sleep_dur = poll_opts.interval * (1 << attempts);
+        log::debug!("Retrying TCP connection in {sleep_dur:?}");
+        tokio::time::sleep(sleep_dur).await;
+        attempts += 1;
+    }
+}
+
+impl ClientBuilder {
+    /// The address of the server, e.g. `([127, 0, 0, 1], 49152)`
+    pub fn target(mut self, target: impl Into<SocketAddr>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Send this file (or directory with [ClientBuilder::recursive]), can be called any number of times
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.files.push(path.into());
+        self
+    }
+
+    /// Send these files, in addition to any that were already added
+    pub fn files<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.settings
+            .files
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Send the content of directories
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.settings.recursive = recursive;
+        self
+    }
+
+    /// Have the server preallocate each file before receiving it, enabled by default
+    pub fn prealloc(mut self, prealloc: bool) -> Self {
+        self.settings.prealloc = prealloc;
+        self
+    }
+
+    /// How to connect to the server, e.g. to poll until it's up
+    pub fn connect_mode(mut self, connect_mode: TcpConnectMode) -> Self {
+        self.settings.connect_mode = connect_mode;
+        self
+    }
+
+    /// Prove to the server that the client knows this key
+    pub fn psk(mut self, psk: PreSharedKey) -> Self {
+        self.settings.psk = Some(psk);
+        self
+    }
+
+    /// Called with the report of each file as soon as it's sent
+    pub fn on_sent<F>(mut self, on_sent: F) -> Self
+    where
+        F: Fn(&FileReport) + Send + Sync + 'static,
+    {
+        self.settings.on_sent = Some(Arc::new(on_sent));
+        self
+    }
+
+    /// Observe the sent files and their progress
+    pub fn observer(mut self, observer: impl TransferObserver + 'static) -> Self {
+        self.settings.observer = SharedObserver::new(observer);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Client> {
+        let Self { target, settings } = self;
+        let Some(target) = target else {
+            bail!("A client needs a target to send to");
+        };
+        if settings.files.is_empty() {
+            bail!("An async client needs at least one file to send");
+        }
+        Ok(Client { target, settings })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_client_needs_a_file() {
+        let err = Client::builder()
+            .target(([127, 0, 0, 1], 49152))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "An async client needs at least one file to send"
+        );
+    }
+}
@@ -0,0 +1,425 @@
+//! The async counterpart of the [Server](crate::Server).
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::bail;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+};
+
+use super::{read_data_frame_header, read_frame, send_frame, server_handshake};
+use crate::{
+    auth::{AuthError, PreSharedKey},
+    checksum::Checksum,
+    config::transfer::{
+        command::{ServerCommand, ServerResult},
+        handshake::Capabilities,
+        listen::{ListenArgs, OverwritePolicy},
+    },
+    framed_stream::MAX_DATA_FRAME_SIZE,
+    observer::{SharedObserver, TransferObserver},
+    server::{
+        partial::{create_temp, PartialFile},
+        report::{OnReceived, ReceivedFile, ServerReport},
+        util::{receive_destination, verify_received_checksum, ReceivedContent},
+    },
+};
+
+/// A server that is listening for clients from a tokio runtime, see [Server::builder]
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    args: Arc<ListenArgs>,
+}
+
+/// Configures a [Server], the defaults match `qft listen` except that an output is required
+#[derive(Debug)]
+pub struct ServerBuilder {
+    args: ListenArgs,
+}
+
+impl Server {
+    /// Listen at `0.0.0.0:49152` unless configured otherwise, e.g.
+    ///
+    /// ```no_run
+    /// # async fn listen() -> anyhow::Result<()> {
+    /// let server = quick_file_transfer::asynchronous::Server::builder()
+    ///     .port(0)
+    ///     .output_dir("received")
+    ///     .build()
+    ///     .await?;
+    /// println!("Listening at {}", server.local_addr()?);
+    /// for file in server.run().await?.files {
+    ///     println!("Received {} [{} B] in {:?}", file.name, file.size, file.duration);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            args: ListenArgs {
+                ip: Ipv4Addr::UNSPECIFIED.to_string(),
+                port: 49152,
+                output: None,
+                output_dir: None,
+                remote: false,
+                decompression: None,
+                keep_alive: false,
+                max_sessions: None,
+                overwrite: OverwritePolicy::Always,
+                psk: None,
+                encrypt: false,
+                limit_rate: None,
+                on_received: None,
+                observer: SharedObserver::default(),
+            },
+        }
+    }
+
+    /// The address the server listens at, e.g. to find the port that was picked if it listens at port `0`
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve a single client and return the files it sent, once it ends the transfer or disconnects.
+    ///
+    /// With [ServerBuilder::keep_alive] any number of clients are served at the same time and this only returns
+    /// if accepting a client fails, the received files are then only passed to [ServerBuilder::on_received].
+    /// Dropping the future stops serving, files that weren't completely received are discarded.
+    pub async fn run(self) -> anyhow::Result<ServerReport> {
+        let Self { listener, args } = self;
+        if args.keep_alive {
+            let mut sessions = JoinSet::new();
+            loop {
+                let (socket, addr) = listener.accept().await?;
+                tracing::info!("Client accepted at: {addr:?}");
+                let args = Arc::clone(&args);
+                sessions.spawn(async move {
+                    if let Err(e) = serve_client(socket, args).await {
+                        log::error!("Session with {addr} failed: {e}");
+                    }
+                });
+                // Reap the sessions that ended so they don't pile up
+                while sessions.try_join_next().is_some() {}
+            }
+        }
+
+        let mut args = Arc::unwrap_or_clone(args);
+        let received: Arc<Mutex<Vec<ReceivedFile>>> = Arc::default();
+        let on_received = args.on_received.take();
+        args.on_received = Some(OnReceived(Arc::new({
+            let received = Arc::clone(&received);
+            move |file: &ReceivedFile| {
+                received
+                    .lock()
+                    .expect("Received files lock poisoned")
+                    .push(file.clone());
+                if let Some(on_received) = &on_received {
+                    on_received.call(file);
+                }
+            }
+        })));
+        let args = Arc::new(args);
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            tracing::info!("Client accepted at: {addr:?}");
+            match serve_client(socket, Arc::clone(&args)).await {
+                // Keep waiting for a client that knows the pre-shared key
+                Err(e) if e.is::<AuthError>() => log::warn!("Dropped client at {addr}: {e}"),
+                res => break res?,
+            }
+        }
+        let files = std::mem::take(&mut *received.lock().expect("Received files lock poisoned"));
+        Ok(ServerReport { files })
+    }
+}
+
+/// Serve an accepted client on its main socket until it ends the transfer or disconnects
+async fn serve_client(mut socket: TcpStream, args: Arc<ListenArgs>) -> anyhow::Result<()> {
+    let negotiated = server_handshake(&mut socket, args.psk.as_ref()).await?;
+    tracing::debug!("Negotiated protocol: {negotiated:?}");
+    let (stop_tx, stop_rx) = watch::channel(false);
+    // Dropped along with the children if the client disconnects, which discards any incomplete files
+    let mut children = JoinSet::new();
+    while let Some(cmd) = read_frame(&mut socket).await? {
+        tracing::trace!("Received command: {cmd:?}");
+        match cmd {
+            ServerCommand::GetFreePort(_) => {
+                let listener = TcpListener::bind((args.ip.as_str(), 0)).await?;
+                let free_port = listener.local_addr()?.port();
+                tracing::trace!("Bound to free port: {free_port}");
+                socket.write_all(&free_port.to_be_bytes()).await?;
+                children.spawn(run_child(listener, Arc::clone(&args), stop_rx.clone()));
+            }
+            ServerCommand::EndOfTransfer => {
+                stop_tx.send_replace(true);
+                let mut failures = vec![];
+                while let Some(res) = children.join_next().await {
+                    match res {
+                        Ok(Ok(())) => (),
+                        Ok(Err(e)) => failures.push(e.to_string()),
+                        Err(e) => failures.push(e.to_string()),
+                    }
+                }
+                if failures.is_empty() {
+                    send_frame(&mut socket, &ServerResult::Ok).await?;
+                    return Ok(());
+                }
+                let failures = failures.join("\n");
+                send_frame(&mut socket, &ServerResult::err(failures.clone())).await?;
+                bail!(failures);
+            }
+            ServerCommand::CreateDir(dir) => {
+                let res = tokio::task::spawn_blocking({
+                    let args = Arc::clone(&args);
+                    let dir = dir.clone();
+                    move || create_dir(&args, &dir)
+                })
+                .await?;
+                let result = match res {
+                    Ok(()) => ServerResult::Ok,
+                    Err(e) => {
+                        tracing::error!("Failed creating directory {dir}: {e}");
+                        ServerResult::err(format!("Failed creating directory {dir}: {e}"))
+                    }
+                };
+                send_frame(&mut socket, &result).await?;
+            }
+            // Left out of the handshake, or only sent to the children
+            cmd => bail!("Unexpected command on the main socket: {cmd:?}"),
+        }
+    }
+    tracing::debug!("Main Client disconnected...");
+    Ok(())
+}
+
+/// Create a directory of the tree the client sends
+fn create_dir(args: &ListenArgs, dir: &str) -> anyhow::Result<()> {
+    if args.output.is_some() {
+        bail!("receiving a directory requires an output directory");
+    }
+    let path = receive_destination(args, dir, None)?;
+    tracing::debug!("Creating directory: {path:?}");
+    Ok(std::fs::create_dir_all(path)?)
+}
+
+/// Serve the clients that connect to the child `listener` one at a time, until the transfer ends
+async fn run_child(
+    listener: TcpListener,
+    args: Arc<ListenArgs>,
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut failures: Vec<String> = vec![];
+    loop {
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = stop.wait_for(|stop| *stop) => break,
+        };
+        // A failed file shouldn't prevent receiving the rest of the files
+        match handle_child_socket(socket, &args).await {
+            Ok(()) => (),
+            // Not a peer of the client, just drop it
+            Err(e) if e.is::<AuthError>() => log::warn!("Dropped peer at {addr}: {e}"),
+            Err(e) => {
+                log::error!("{e}");
+                args.observer.on_error(None, &e);
+                failures.push(e.to_string());
+            }
+        }
+    }
+    if !failures.is_empty() {
+        bail!(failures.join("\n"));
+    }
+    Ok(())
+}
+
+async fn handle_child_socket(mut socket: TcpStream, args: &Arc<ListenArgs>) -> anyhow::Result<()> {
+    let negotiated = server_handshake(&mut socket, args.psk.as_ref()).await?;
+    let verifies_checksum = negotiated.capabilities.contains(Capabilities::CHECKSUM);
+    let mut prealloc: Option<u64> = None;
+    // The content received by the latest ReceiveData, until its checksum is verified
+    let mut last_received: Option<ReceivedContent> = None;
+
+    while let Some(cmd) = read_frame(&mut socket).await? {
+        log::trace!("Received command: {cmd:?}");
+        match cmd {
+            ServerCommand::Prealloc(fsize, _) => prealloc = Some(fsize),
+            ServerCommand::ReceiveData(_f_count, _, Some(compression)) => {
+                bail!("Receiving {compression} compressed content requires the blocking server")
+            }
+            ServerCommand::ReceiveData(_f_count, fname, None) => {
+                let received = receive_content(&mut socket, args, fname, prealloc.take()).await?;
+                if verifies_checksum {
+                    last_received = Some(received);
+                } else {
+                    persist(args, received, None).await?;
+                }
+            }
+            ServerCommand::VerifyChecksum(expected) => {
+                let Some(received) = last_received.take() else {
+                    send_frame(
+                        &mut socket,
+                        &ServerResult::err("No received content to verify"),
+                    )
+                    .await?;
+                    bail!("Received checksum without receiving any content");
+                };
+                if let Err(e) = persist(args, received, Some(expected)).await {
+                    send_frame(&mut socket, &ServerResult::err(e.to_string())).await?;
+                    return Err(e);
+                }
+                send_frame(&mut socket, &ServerResult::Ok).await?;
+            }
+            // Left out of the handshake, or only sent on the main socket
+            cmd => bail!("Unexpected command on a child socket: {cmd:?}"),
+        }
+    }
+    tracing::info!("Client disconnected...");
+    Ok(())
+}
+
+/// Receive the framed content of `fname` into its temporary file
+async fn receive_content(
+    socket: &mut TcpStream,
+    args: &Arc<ListenArgs>,
+    fname: String,
+    prealloc: Option<u64>,
+) -> anyhow::Result<ReceivedContent> {
+    let start = Instant::now();
+    let dest = tokio::task::spawn_blocking({
+        let args = Arc::clone(args);
+        let fname = fname.clone();
+        move || receive_destination(&args, &fname, None)
+    })
+    .await??;
+    // Removed again if receiving fails or the future is dropped
+    let partial = PartialFile::new(dest)?;
+    let mut file = File::from_std(create_temp(partial.temp())?);
+    if let Some(fsize) = prealloc {
+        file.set_len(fsize).await?;
+    }
+    args.observer.on_file_start(&fname, prealloc);
+
+    let mut buf = vec![0; MAX_DATA_FRAME_SIZE];
+    let mut hasher = Sha256::new();
+    let mut len: u64 = 0;
+    loop {
+        let size = read_data_frame_header(socket).await?;
+        if size == 0 {
+            break;
+        }
+        socket.read_exact(&mut buf[..size]).await?;
+        file.write_all(&buf[..size]).await?;
+        hasher.update(&buf[..size]);
+        len += size as u64;
+        args.observer.on_progress(&fname, len);
+    }
+    // Trims any preallocated space the content didn't fill
+    file.set_len(len).await?;
+    file.sync_all().await?;
+
+    let duration = start.elapsed();
+    args.observer.on_file_complete(&fname, len, duration);
+    Ok(ReceivedContent {
+        name: fname,
+        file: partial,
+        len,
+        checksum: Checksum(hasher.finalize().into()),
+        duration,
+    })
+}
+
+/// Move the received content into place if it matches the `expected` checksum (if any)
+async fn persist(
+    args: &Arc<ListenArgs>,
+    received: ReceivedContent,
+    expected: Option<Checksum>,
+) -> anyhow::Result<()> {
+    let file = received.received_file();
+    tokio::task::spawn_blocking(move || match expected {
+        Some(expected) => verify_received_checksum(received, &expected),
+        None => Ok(received.file.persist()?),
+    })
+    .await??;
+    args.received(&file);
+    Ok(())
+}
+
+impl ServerBuilder {
+    /// The IP to listen at, e.g. `127.0.0.1` to only accept local clients
+    pub fn ip(mut self, ip: impl Into<IpAddr>) -> Self {
+        self.args.ip = ip.into().to_string();
+        self
+    }
+
+    /// The port to listen at, `0` picks any free port (see [Server::local_addr])
+    pub fn port(mut self, port: u16) -> Self {
+        self.args.port = port;
+        self
+    }
+
+    /// Write the received content to this file
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.output = Some(path.into());
+        self
+    }
+
+    /// Receive files into this directory, by the names they're sent as
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.args.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Keep serving clients, each in their own task, instead of returning after the first client
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.args.keep_alive = keep_alive;
+        self
+    }
+
+    /// Only accept files from clients that know this key
+    pub fn psk(mut self, psk: PreSharedKey) -> Self {
+        self.args.psk = Some(psk);
+        self
+    }
+
+    /// Called with each file as soon as it's received, from the task that received it
+    pub fn on_received<F>(mut self, on_received: F) -> Self
+    where
+        F: Fn(&ReceivedFile) + Send + Sync + 'static,
+    {
+        self.args.on_received = Some(OnReceived(Arc::new(on_received)));
+        self
+    }
+
+    /// Observe the received files
+    pub fn observer(mut self, observer: impl TransferObserver + 'static) -> Self {
+        self.args.observer = SharedObserver::new(observer);
+        self
+    }
+
+    /// Start listening
+    pub async fn build(self) -> anyhow::Result<Server> {
+        let Self { args } = self;
+        match (&args.output, &args.output_dir) {
+            (Some(_), Some(_)) => bail!("Received content is written to either an output file or an output directory, not both"),
+            (None, None) => bail!("An async server needs an output file or directory to receive to"),
+            _ => (),
+        }
+        let listener = TcpListener::bind((args.ip.as_str(), args.port)).await?;
+        Ok(Server {
+            listener,
+            args: Arc::new(args),
+        })
+    }
+}
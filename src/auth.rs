@@ -0,0 +1,158 @@
+//! Authentication of clients with a pre-shared key.
+//!
+//! After the handshake the server sends a random nonce and the client proves that it knows the key by answering
+//! with an HMAC-SHA256 over the nonce. The key itself is never sent.
+
+use std::{fmt, str::FromStr};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Size of the nonce the server challenges a client with
+pub const NONCE_LEN: usize = 32;
+
+/// Size of the proof a client answers the challenge with
+pub const PROOF_LEN: usize = 32;
+
+/// Prefixed to the nonce before it is authenticated, so that a proof can't be mistaken for any other use of the key
+const PROOF_CONTEXT: &[u8] = b"qft psk auth v1";
+
+/// Name of the environment variable that the pre-shared key can be passed in, instead of `--psk`
+pub const PSK_ENV_VAR: &str = "QFT_PSK";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A key shared by the server and its clients out of band
+#[derive(Clone, PartialEq, Eq)]
+pub struct PreSharedKey(Box<[u8]>);
+
+impl PreSharedKey {
+    /// The proof that `nonce` was answered by a peer that knows the key
+    pub fn prove(&self, nonce: &[u8; NONCE_LEN]) -> [u8; PROOF_LEN] {
+        self.mac(nonce).finalize().into_bytes().into()
+    }
+
+    /// Check (in constant time) that `proof` answers `nonce`
+    pub fn verify(&self, nonce: &[u8; NONCE_LEN], proof: &[u8; PROOF_LEN]) -> bool {
+        self.mac(nonce).verify_slice(proof).is_ok()
+    }
+
+    fn mac(&self, nonce: &[u8; NONCE_LEN]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts keys of any size");
+        mac.update(PROOF_CONTEXT);
+        mac.update(nonce);
+        mac
+    }
+}
+
+impl FromStr for PreSharedKey {
+    type Err = &'static str;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        if key.is_empty() {
+            return Err("the pre-shared key must not be empty");
+        }
+        Ok(Self(key.as_bytes().into()))
+    }
+}
+
+// Keep the key out of logs
+impl fmt::Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreSharedKey(..)")
+    }
+}
+
+/// Sent by the server after a successful handshake if both peers support [Capabilities::PSK_AUTH](crate::config::transfer::handshake::Capabilities::PSK_AUTH)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthChallenge {
+    /// The server doesn't require authentication
+    None,
+    /// The client has to answer with the [proof](PreSharedKey::prove) for the nonce
+    Psk([u8; NONCE_LEN]),
+}
+
+/// A fresh nonce from the random number generator of the OS
+pub fn random_nonce() -> anyhow::Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)
+        .map_err(|e| anyhow::anyhow!("Failed generating a nonce: {e}"))?;
+    Ok(nonce)
+}
+
+/// A random one-time key, hex encoded so that it can be passed to a remote qft through its shell
+pub fn random_key() -> anyhow::Result<String> {
+    let mut key = [0; 32];
+    getrandom::getrandom(&mut key).map_err(|e| anyhow::anyhow!("Failed generating a key: {e}"))?;
+    Ok(key.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// A client failed to authenticate with the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The server requires a pre-shared key but the client has none
+    MissingKey,
+    /// The client didn't prove that it knows the pre-shared key
+    Refused(Box<str>),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingKey => write!(
+                f,
+                "the server requires a pre-shared key, specify it with --psk or {PSK_ENV_VAR}"
+            ),
+            AuthError::Refused(reason) => write!(f, "authentication failed: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_proof_verifies_only_with_same_key_and_nonce() {
+        let key: PreSharedKey = "secret".parse().unwrap();
+        let other_key: PreSharedKey = "Secret".parse().unwrap();
+        let nonce = [7; NONCE_LEN];
+        let proof = key.prove(&nonce);
+
+        assert!(key.verify(&nonce, &proof));
+        assert!(!other_key.verify(&nonce, &proof));
+        assert!(!key.verify(&[8; NONCE_LEN], &proof));
+        assert_eq!(proof, key.prove(&nonce));
+    }
+
+    #[test]
+    fn test_empty_key_is_rejected() {
+        assert!("".parse::<PreSharedKey>().is_err());
+    }
+
+    #[test]
+    fn test_random_nonces_differ() -> TestResult {
+        assert_ne!(random_nonce()?, random_nonce()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_keys_differ() -> TestResult {
+        let key = random_key()?;
+        assert_eq!(key.len(), 64);
+        assert!(key.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(key, random_key()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_is_not_logged() {
+        let key: PreSharedKey = "secret".parse().unwrap();
+        assert!(!format!("{key:?}").contains("secret"));
+    }
+}
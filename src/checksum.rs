@@ -0,0 +1,214 @@
+//! End-to-end verification of transferred content.
+//!
+//! The client hashes the content as it is read from the source and the server hashes it as it is written
+//! to the destination, after the content has been transferred the client sends its [Checksum] for the server to verify.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// SHA-256 digest of the (uncompressed) content of a transferred file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum(pub [u8; 32]);
+
+impl Checksum {
+    pub fn of(content: &[u8]) -> Self {
+        Self(Sha256::digest(content).into())
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The hash state after hashing a prefix of some content, used to continue hashing where a previous transfer left off
+///
+/// The default is the state of an empty prefix.
+#[derive(Debug, Default, Clone)]
+pub struct PrefixHash {
+    hasher: Sha256,
+    len: u64,
+}
+
+impl PrefixHash {
+    /// Hash the first `len` bytes read from `reader`
+    pub fn of_reader<R: Read>(reader: R, len: u64) -> io::Result<Self> {
+        let mut hasher = Sha256::new();
+        let hashed = io::copy(&mut reader.take(len), &mut hasher)?;
+        if hashed != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("expected a prefix of {len} B but only {hashed} B could be read"),
+            ));
+        }
+        Ok(Self { hasher, len })
+    }
+
+    /// The length of the prefix in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The checksum of the prefix
+    pub fn checksum(&self) -> Checksum {
+        Checksum(self.hasher.clone().finalize().into())
+    }
+}
+
+/// Hashes the content as it is read from the inner reader
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Continue hashing after an already hashed prefix, the inner reader should start right after the prefix
+    pub fn resume(inner: R, prefix: PrefixHash) -> Self {
+        Self {
+            inner,
+            hasher: prefix.hasher,
+        }
+    }
+
+    /// The checksum of the content read so far
+    pub fn checksum(&self) -> Checksum {
+        Checksum(self.hasher.clone().finalize().into())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+/// Hashes the content as it is written to the inner writer
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            written: 0,
+        }
+    }
+
+    /// Continue hashing after an already hashed prefix, the inner writer should start right after the prefix
+    pub fn resume(inner: W, prefix: PrefixHash) -> Self {
+        Self {
+            inner,
+            hasher: prefix.hasher,
+            written: prefix.len,
+        }
+    }
+
+    /// The amount of bytes hashed so far (including any resumed prefix)
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The checksum of the content written so far
+    pub fn checksum(&self) -> Checksum {
+        Checksum(self.hasher.clone().finalize().into())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..bytes_written]);
+        self.written += bytes_written as u64;
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_checksum_display_is_hex() {
+        // Well known SHA-256 of the empty string
+        assert_eq!(
+            Checksum::of(b"").to_string(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_hashing_reader_and_writer_agree() -> TestResult {
+        let content = b"Hello, world!".repeat(1000);
+
+        let mut reader = HashingReader::new(content.as_slice());
+        let mut writer = HashingWriter::new(Vec::new());
+        io::copy(&mut reader, &mut writer)?;
+
+        assert_eq!(reader.checksum(), Checksum::of(&content));
+        assert_eq!(writer.checksum(), Checksum::of(&content));
+        assert_eq!(writer.get_mut().as_slice(), content.as_slice());
+        assert_eq!(writer.written(), content.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_from_prefix_hash() -> TestResult {
+        let content = b"Hello, world!".repeat(1000);
+        let (prefix, rest) = content.split_at(4321);
+
+        let prefix_hash = PrefixHash::of_reader(content.as_slice(), prefix.len() as u64)?;
+        assert_eq!(prefix_hash.len(), prefix.len() as u64);
+        assert_eq!(prefix_hash.checksum(), Checksum::of(prefix));
+
+        let mut reader = HashingReader::resume(rest, prefix_hash.clone());
+        let mut writer = HashingWriter::resume(Vec::new(), prefix_hash);
+        io::copy(&mut reader, &mut writer)?;
+
+        assert_eq!(reader.checksum(), Checksum::of(&content));
+        assert_eq!(writer.checksum(), Checksum::of(&content));
+        assert_eq!(writer.written(), content.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_hash_longer_than_content_fails() {
+        let err = PrefixHash::of_reader(b"short".as_slice(), 6).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
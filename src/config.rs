@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use stderrlog::LogLevelNum;
 use transfer::{listen::ListenArgs, send::SendArgs};
 mod util;
@@ -48,6 +48,21 @@ pub struct Config {
     )]
     pub color: clap::ColorChoice,
 
+    /// How the results of a subcommand are printed to stdout, e.g. `json` for scripts and other tools.
+    ///
+    /// Log output is unaffected, it's written to stderr either way.
+    #[arg(long, value_enum, default_value_t = misc::OutputFormat::Text, global = true)]
+    pub output_format: misc::OutputFormat,
+
+    /// Write newline-delimited JSON events of the transfers (connections, files and their progress, errors)
+    /// to an open file descriptor e.g. `3`, or to a file
+    #[arg(long, value_name = "FD|PATH", global = true)]
+    pub events: Option<crate::events::EventsTarget>,
+
+    /// Shorthand for `--output-format json`
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with("output_format"), global = true)]
+    pub json: bool,
+
     /// Generate completion scripts for the specified shell.
     /// Note: The completion script is printed to stdout
     #[arg(
@@ -81,10 +96,33 @@ impl Config {
             .quiet(cfg.quiet)
             .color(log_color_when)
             .init()?;
+        crate::progress::init(cfg.quiet);
+        if let Some(target) = &cfg.events {
+            if *target == crate::events::EventsTarget::Fd(1) {
+                if cfg.output_format() == misc::OutputFormat::Json {
+                    bail!("Events can't be written to stdout along with the JSON output, write them to another file descriptor or a file");
+                }
+                if matches!(&cfg.command, Some(Command::Listen(args)) if args.receives_to_stdout())
+                {
+                    bail!("Events can't be written to stdout along with the received content, write them to another file descriptor or a file");
+                }
+            }
+            crate::events::init(target)
+                .map_err(|e| anyhow::anyhow!("Failed opening {target} for events: {e}"))?;
+        }
 
         Ok(cfg)
     }
 
+    /// How the results of a subcommand are printed
+    pub fn output_format(&self) -> misc::OutputFormat {
+        if self.json {
+            misc::OutputFormat::Json
+        } else {
+            self.output_format
+        }
+    }
+
     /// Generate completion scripts for the specified shell.
     pub fn generate_completion_script(shell: clap_complete::Shell) {
         use clap::CommandFactory;
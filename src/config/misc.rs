@@ -25,6 +25,16 @@ impl std::fmt::Display for ColorWhen {
     }
 }
 
+/// How the results of a subcommand are printed to stdout
+#[derive(Debug, Default, ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// For humans to read
+    #[default]
+    Text,
+    /// A single JSON document
+    Json,
+}
+
 /// Used to choose IP version for any commands where that is appropriate
 #[derive(Debug, Default, ValueEnum, Clone, Copy)]
 pub enum IpVersion {
@@ -1,9 +1,10 @@
 use std::path::PathBuf;
 
 use anyhow::bail;
-use clap::{arg, ArgAction, Args};
+use clap::{ArgAction, Args};
 
 use super::Compression;
+use crate::{rate_limit::RateLimit, sync::SyncOptions};
 
 #[derive(Debug, Args)]
 #[command(flatten_help = true)]
@@ -36,7 +37,7 @@ pub struct SendSshArgs {
     pub ip_version: crate::config::misc::IpVersion,
 
     /// Port for SSH
-    #[arg(short('p'), long, default_value_t = 22)]
+    #[arg(short('P'), long, default_value_t = 22)]
     pub ssh_port: u16,
 
     /// Compression format
@@ -62,6 +63,34 @@ pub struct SendSshArgs {
     /// Use memory mapping mode
     #[arg(long, action = ArgAction::SetTrue, global(true))]
     pub mmap: bool,
+
+    /// Transfer directories recursively, recreating the directory tree under the destination
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    pub recursive: bool,
+
+    /// Preserve permissions and access/modification times of the transferred files
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    pub preserve: bool,
+
+    /// Resume files that were partially received by a previous (interrupted) transfer, and reconnect to resume if a transfer is interrupted
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub resume: bool,
+
+    /// Limit the bandwidth to this many bytes per second (after compression), with an optional K, M or G suffix e.g. `500K`
+    #[arg(long, value_name("RATE"))]
+    pub limit_rate: Option<RateLimit>,
+
+    /// Only send the files that are missing or stale on the receiving end, compared by size and modification time
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub sync: bool,
+
+    /// Delete the files and directories inside the sent directories that don't exist on the sending end
+    #[arg(long, action = ArgAction::SetTrue, requires = "sync")]
+    pub delete: bool,
+
+    /// Compare the checksums of the files instead of their modification times when syncing
+    #[arg(long, action = ArgAction::SetTrue, requires = "sync")]
+    pub checksum: bool,
 }
 
 /// The components in the target args (if present) e.g. user@hostname:/home/user/f.txt
@@ -98,4 +127,12 @@ impl SendSshArgs {
         // If destination doesn't contain '@', it must be the source that has the `<user>@<hostname>:<path>` syntax instaed
         self.destination.contains('@')
     }
+
+    /// Returns how to sync the files, if they are synced
+    pub fn sync_options(&self) -> Option<SyncOptions> {
+        self.sync.then_some(SyncOptions {
+            delete: self.delete,
+            checksum: self.checksum,
+        })
+    }
 }
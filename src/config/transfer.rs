@@ -2,4 +2,5 @@ pub mod listen;
 pub mod send;
 
 pub mod command;
+pub mod handshake;
 pub mod util;
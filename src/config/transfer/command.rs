@@ -1,7 +1,17 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumIter};
 
-use crate::config::compression::CompressionVariant;
+use crate::{
+    checksum::Checksum,
+    config::compression::CompressionVariant,
+    preserve::{FileMetadata, Timestamp},
+    sync::SyncOptions,
+};
+
+/// Separates the components of relative file names (when transferring a directory tree), regardless of platform
+pub const REMOTE_PATH_SEPARATOR: char = '/';
 
 #[derive(Debug, Default, Serialize, Deserialize, EnumIter, PartialEq, Display)]
 pub enum DestinationMode {
@@ -23,18 +33,52 @@ pub enum ServerCommand {
     ReceiveData(u32, String, Option<CompressionVariant>),
     EndOfTransfer,
     IsDestinationValid(DestinationMode, String),
+    /// Sent on a child socket after the content of a file, the server replies with a [ServerResult]
+    VerifyChecksum(Checksum),
+    /// Ask how many bytes of the named file the server already has, the server replies with the length as a big-endian [u64]
+    GetResumeOffset(String),
+    /// Resume receiving the named file after its first `offset` bytes if they have the given checksum, the server replies with a [ServerResult]
+    ResumeFrom(String, u64, Checksum),
+    /// Apply the permissions and times to the next file received on the child socket
+    SetMetadata(FileMetadata),
+    /// Create the named (relative) directory and any missing parents, the server replies with a [ServerResult]
+    CreateDir(String),
+    /// Apply the overwrite policy of the server to the named file given its modification time,
+    /// the server replies with a [ResolvedDestination] before any content is sent
+    ResolveDestination(String, Option<Timestamp>),
+    /// Prepare receiving the named file of the given length in ranges sent over other child sockets
+    /// with [ServerCommand::ReceiveRange], the server replies with a [ServerResult] once the file is preallocated
+    ReceiveRanges(String, u64),
+    /// Receive the content of the named file (that the session receives in ranges) from the given offset,
+    /// followed by [ServerCommand::VerifyChecksum] of the range. The range must end within the size of the file
+    ReceiveRange(String, u64, Option<CompressionVariant>),
+    /// Sent after [ServerCommand::ReceiveRanges] once all ranges are verified, with the checksum of the whole file.
+    /// The server verifies the assembled file against it, moves the file into place and replies with a [ServerResult]
+    FinishRanges(Checksum),
+    /// Ask for the [Signature](crate::delta::Signature) of the existing file at the destination of the named file,
+    /// the server replies with the signature as framed content (without blocks if there's no existing file)
+    /// and keeps the existing file open to rebuild the file from
+    GetSignature(String),
+    /// Receive the named file as a delta against the file of the latest [ServerCommand::GetSignature],
+    /// followed by [ServerCommand::VerifyChecksum] of the rebuilt file
+    ReceiveDelta(String, Option<CompressionVariant>),
+    /// Sent on the main socket followed by the [Manifest](crate::sync::Manifest) of the files as framed content,
+    /// the server replies with a [SyncPlan](crate::sync::SyncPlan) (or why it can't sync) as framed content
+    Sync(SyncOptions),
 }
 
 impl ServerCommand {
     /// The length of the command header that describes how long the command is (in bytes).
-    ///
-    /// # Note
-    /// TODO: Revisit this before 1.0
-    pub const HEADER_SIZE: usize = 1;
+    pub const HEADER_SIZE: usize = FRAME_HEADER_SIZE;
 
     /// Takes an array of bytes describing the header size and returns how size of the incoming command in bytes
-    pub fn size_from_bytes(raw_header: [u8; Self::HEADER_SIZE]) -> usize {
-        u8::from_be_bytes(raw_header) as usize
+    pub fn size_from_bytes(raw_header: [u8; Self::HEADER_SIZE]) -> Result<usize, OversizedFrame> {
+        frame_size_from_header(raw_header)
+    }
+
+    /// Returns the header describing a serialized command of `size` bytes
+    pub fn header_from_size(size: usize) -> Result<[u8; Self::HEADER_SIZE], OversizedFrame> {
+        frame_header_from_size(size)
     }
 }
 
@@ -45,7 +89,8 @@ pub enum ServerResult {
 }
 
 impl ServerResult {
-    pub const HEADER_SIZE: usize = 2;
+    /// The length of the result header that describes how long the result is (in bytes).
+    pub const HEADER_SIZE: usize = FRAME_HEADER_SIZE;
 
     pub fn err<S>(err_msg: S) -> Self
     where
@@ -55,8 +100,69 @@ impl ServerResult {
     }
 
     /// Takes an array of bytes describing the header size and returns the size of the incoming command in bytes
-    pub fn size_from_bytes(raw_header: [u8; Self::HEADER_SIZE]) -> usize {
-        u16::from_be_bytes(raw_header) as usize
+    pub fn size_from_bytes(raw_header: [u8; Self::HEADER_SIZE]) -> Result<usize, OversizedFrame> {
+        frame_size_from_header(raw_header)
+    }
+
+    /// Returns the header describing a serialized result of `size` bytes
+    pub fn header_from_size(size: usize) -> Result<[u8; Self::HEADER_SIZE], OversizedFrame> {
+        frame_header_from_size(size)
+    }
+}
+
+/// The reply to [ServerCommand::ResolveDestination], framed like a [ServerResult]
+#[derive(Debug, Clone, Serialize, Deserialize, EnumIter, PartialEq, Eq)]
+pub enum ResolvedDestination {
+    /// The file is received under the sent name (replacing any existing file)
+    Receive,
+    /// The existing file was kept under the given name and the file is received under the sent name
+    BackedUp(String),
+    /// The sent name is taken, the file is received under the given name instead
+    Renamed(String),
+    /// The existing file is kept, the file should not be sent
+    Skipped(Box<str>),
+}
+
+/// The size of the header that precedes every [ServerCommand] and [ServerResult] frame.
+///
+/// The header is the size of the serialized frame as a big-endian [u32].
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// The maximum size of a serialized [ServerCommand] or [ServerResult] (excluding the header).
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// A [ServerCommand] or [ServerResult] frame exceeded [MAX_FRAME_SIZE]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OversizedFrame {
+    pub size: usize,
+}
+
+impl fmt::Display for OversizedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame of {} B exceeds the maximum frame size of {MAX_FRAME_SIZE} B",
+            self.size
+        )
+    }
+}
+
+impl std::error::Error for OversizedFrame {}
+
+fn frame_size_from_header(raw_header: [u8; FRAME_HEADER_SIZE]) -> Result<usize, OversizedFrame> {
+    let size = u32::from_be_bytes(raw_header) as usize;
+    if size > MAX_FRAME_SIZE {
+        Err(OversizedFrame { size })
+    } else {
+        Ok(size)
+    }
+}
+
+fn frame_header_from_size(size: usize) -> Result<[u8; FRAME_HEADER_SIZE], OversizedFrame> {
+    if size > MAX_FRAME_SIZE {
+        Err(OversizedFrame { size })
+    } else {
+        Ok((size as u32).to_be_bytes())
     }
 }
 
@@ -82,4 +188,35 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_frame_header_roundtrip() -> TestResult {
+        for size in [0, 1, 255, 256, 300, u16::MAX as usize + 1, MAX_FRAME_SIZE] {
+            let header = ServerCommand::header_from_size(size)?;
+            assert_eq!(ServerCommand::size_from_bytes(header)?, size);
+            let header = ServerResult::header_from_size(size)?;
+            assert_eq!(ServerResult::size_from_bytes(header)?, size);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_frame_is_rejected() {
+        let size = MAX_FRAME_SIZE + 1;
+        assert_eq!(
+            ServerCommand::header_from_size(size),
+            Err(OversizedFrame { size })
+        );
+        let raw_header = (size as u32).to_be_bytes();
+        assert_eq!(
+            ServerCommand::size_from_bytes(raw_header),
+            Err(OversizedFrame { size })
+        );
+        assert_eq!(
+            ServerResult::size_from_bytes(u32::MAX.to_be_bytes()),
+            Err(OversizedFrame {
+                size: u32::MAX as usize
+            })
+        );
+    }
 }
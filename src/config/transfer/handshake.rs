@@ -0,0 +1,310 @@
+use std::{fmt, ops::BitAnd};
+
+use crate::config::compression::CompressionVariant;
+
+/// The version of the QFT wire protocol spoken by this build.
+///
+/// Bump this whenever the framing or the meaning of a [ServerCommand](super::command::ServerCommand) changes.
+pub const PROTOCOL_VERSION: u16 = 3;
+
+/// The oldest protocol version this build is able to speak.
+///
+/// v2 replaced the 1 byte command and 2 byte result headers with 4 byte headers.
+/// v3 transfers file content as [framed_stream](crate::framed_stream) data frames followed by a checksum.
+pub const MIN_SUPPORTED_PROTOCOL_VERSION: u16 = 3;
+
+/// Every versioned handshake starts with these bytes.
+///
+/// Pre-versioned (legacy) QFT peers open with a pseudo-random `u32` instead, which is how they are told apart.
+pub const HANDSHAKE_MAGIC: [u8; 4] = *b"QFT\x01";
+
+/// Bitset of the optional features a QFT peer supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const COMPRESSION_BZIP2: Self = Self(1 << 0);
+    pub const COMPRESSION_GZIP: Self = Self(1 << 1);
+    pub const COMPRESSION_LZ4: Self = Self(1 << 2);
+    pub const COMPRESSION_XZ: Self = Self(1 << 3);
+    /// Preallocation of files from their size with [ServerCommand::Prealloc](super::command::ServerCommand::Prealloc)
+    pub const PREALLOC: Self = Self(1 << 4);
+    /// Validation of the destination with [ServerCommand::IsDestinationValid](super::command::ServerCommand::IsDestinationValid)
+    pub const DESTINATION_VALIDATION: Self = Self(1 << 5);
+    /// End-to-end verification of transferred content with [ServerCommand::VerifyChecksum](super::command::ServerCommand::VerifyChecksum)
+    pub const CHECKSUM: Self = Self(1 << 6);
+    /// Resuming interrupted transfers with [ServerCommand::GetResumeOffset](super::command::ServerCommand::GetResumeOffset)
+    /// and [ServerCommand::ResumeFrom](super::command::ServerCommand::ResumeFrom)
+    pub const RESUME: Self = Self(1 << 7);
+    /// Receiving directory trees, i.e. file names that are relative paths and [ServerCommand::CreateDir](super::command::ServerCommand::CreateDir)
+    pub const RECURSIVE: Self = Self(1 << 8);
+    /// Preserving permissions and times with [ServerCommand::SetMetadata](super::command::ServerCommand::SetMetadata)
+    pub const PRESERVE: Self = Self(1 << 9);
+    /// Applying the overwrite policy of the server before sending a file with [ServerCommand::ResolveDestination](super::command::ServerCommand::ResolveDestination)
+    pub const OVERWRITE_POLICY: Self = Self(1 << 10);
+    /// Authentication with a pre-shared key, the server sends an [AuthChallenge](crate::auth::AuthChallenge) after the handshake
+    pub const PSK_AUTH: Self = Self(1 << 11);
+    /// Encrypting the connection after the handshake, see [transport](crate::transport)
+    pub const ENCRYPTION: Self = Self(1 << 12);
+    /// Receiving a file in ranges over parallel child sockets with [ServerCommand::ReceiveRanges](super::command::ServerCommand::ReceiveRanges)
+    pub const RANGES: Self = Self(1 << 13);
+    /// Receiving a file as a delta against the existing file with [ServerCommand::GetSignature](super::command::ServerCommand::GetSignature)
+    /// and [ServerCommand::ReceiveDelta](super::command::ServerCommand::ReceiveDelta)
+    pub const DELTA: Self = Self(1 << 14);
+    /// Syncing files with [ServerCommand::Sync](super::command::ServerCommand::Sync)
+    pub const SYNC: Self = Self(1 << 15);
+
+    /// All the capabilities of this build
+    pub fn local() -> Self {
+        Self::COMPRESSION_BZIP2
+            .with(Self::COMPRESSION_GZIP)
+            .with(Self::COMPRESSION_LZ4)
+            .with(Self::COMPRESSION_XZ)
+            .with(Self::PREALLOC)
+            .with(Self::DESTINATION_VALIDATION)
+            .with(Self::CHECKSUM)
+            .with(Self::RESUME)
+            .with(Self::RECURSIVE)
+            .with(Self::PRESERVE)
+            .with(Self::OVERWRITE_POLICY)
+            .with(Self::PSK_AUTH)
+            .with(Self::ENCRYPTION)
+            .with(Self::RANGES)
+            .with(Self::DELTA)
+            .with(Self::SYNC)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    #[must_use]
+    pub const fn with(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn supports_compression(self, variant: CompressionVariant) -> bool {
+        self.contains(variant.into())
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl From<CompressionVariant> for Capabilities {
+    fn from(value: CompressionVariant) -> Self {
+        match value {
+            CompressionVariant::Bzip2 => Self::COMPRESSION_BZIP2,
+            CompressionVariant::Gzip => Self::COMPRESSION_GZIP,
+            CompressionVariant::Lz4 => Self::COMPRESSION_LZ4,
+            CompressionVariant::Xz => Self::COMPRESSION_XZ,
+        }
+    }
+}
+
+/// The hello that each peer sends during the handshake, describing the protocol versions and features it supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeHello {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl HandshakeHello {
+    /// Size of the serialized hello (including the magic bytes)
+    pub const SIZE: usize = HANDSHAKE_MAGIC.len() + 2 + 2 + 4;
+
+    /// The hello describing this build
+    pub fn local() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_SUPPORTED_PROTOCOL_VERSION,
+            capabilities: Capabilities::local(),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..4].copy_from_slice(&HANDSHAKE_MAGIC);
+        bytes[4..6].copy_from_slice(&self.version.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.min_version.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.capabilities.bits().to_be_bytes());
+        bytes
+    }
+
+    /// Checks the magic bytes that start a hello.
+    ///
+    /// The magic is read and checked on its own before the rest of the hello,
+    /// as a legacy peer only ever sends 4 bytes and then waits for a reply.
+    pub fn check_magic(raw_magic: [u8; HANDSHAKE_MAGIC.len()]) -> Result<(), IncompatiblePeer> {
+        if raw_magic == HANDSHAKE_MAGIC {
+            Ok(())
+        } else {
+            Err(IncompatiblePeer::Legacy)
+        }
+    }
+
+    /// Parses the part of the hello that follows the magic bytes
+    pub fn from_body_bytes(raw_body: [u8; Self::SIZE - HANDSHAKE_MAGIC.len()]) -> Self {
+        Self {
+            version: u16::from_be_bytes([raw_body[0], raw_body[1]]),
+            min_version: u16::from_be_bytes([raw_body[2], raw_body[3]]),
+            capabilities: Capabilities::from_bits(u32::from_be_bytes([
+                raw_body[4],
+                raw_body[5],
+                raw_body[6],
+                raw_body[7],
+            ])),
+        }
+    }
+
+    /// Agree on the highest protocol version both peers speak and the features they both support.
+    pub fn negotiate(&self, peer: &Self) -> Result<NegotiatedProtocol, IncompatiblePeer> {
+        let version = self.version.min(peer.version);
+        if version < self.min_version || version < peer.min_version {
+            return Err(IncompatiblePeer::Version {
+                local: *self,
+                peer: *peer,
+            });
+        }
+        Ok(NegotiatedProtocol {
+            version,
+            capabilities: self.capabilities & peer.capabilities,
+        })
+    }
+}
+
+/// The protocol version and feature set that two QFT peers agreed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedProtocol {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+/// The peer is a QFT peer that speaks a protocol we cannot agree on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncompatiblePeer {
+    /// The peer predates protocol versioning
+    Legacy,
+    /// There's no overlap between the protocol versions the peers support
+    Version {
+        local: HandshakeHello,
+        peer: HandshakeHello,
+    },
+    /// The remote end refused the handshake
+    Refused(Box<str>),
+}
+
+impl fmt::Display for IncompatiblePeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IncompatiblePeer::Legacy => write!(
+                f,
+                "peer speaks a legacy QFT protocol (pre protocol v{MIN_SUPPORTED_PROTOCOL_VERSION}), upgrade qft on both ends to {} or newer",
+                env!("CARGO_PKG_VERSION")
+            ),
+            IncompatiblePeer::Version { local, peer } => write!(
+                f,
+                "incompatible QFT protocol versions, this qft supports v{}-v{} while the peer supports v{}-v{}, upgrade the older qft",
+                local.min_version, local.version, peer.min_version, peer.version
+            ),
+            IncompatiblePeer::Refused(reason) => write!(f, "peer refused handshake: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for IncompatiblePeer {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn test_hello_roundtrip() {
+        let hello = HandshakeHello::local();
+        let bytes = hello.to_bytes();
+        assert!(HandshakeHello::check_magic(bytes[..4].try_into().unwrap()).is_ok());
+        let parsed = HandshakeHello::from_body_bytes(bytes[4..].try_into().unwrap());
+        assert_eq!(hello, parsed);
+    }
+
+    #[test]
+    fn test_legacy_handshake_is_rejected() {
+        // Legacy peers open with a pseudo-random u32 derived from their process ID
+        let legacy = 0x8f1c_27d3_u32.to_be_bytes();
+        assert_eq!(
+            HandshakeHello::check_magic(legacy),
+            Err(IncompatiblePeer::Legacy)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_picks_lowest_common_version_and_shared_capabilities() {
+        let local = HandshakeHello {
+            version: 3,
+            min_version: 1,
+            capabilities: Capabilities::local(),
+        };
+        let peer = HandshakeHello {
+            version: 2,
+            min_version: 2,
+            capabilities: Capabilities::COMPRESSION_LZ4.with(Capabilities::PREALLOC),
+        };
+        let negotiated = local.negotiate(&peer).unwrap();
+        assert_eq!(negotiated, peer.negotiate(&local).unwrap());
+        assert_eq!(negotiated.version, 2);
+        assert!(negotiated
+            .capabilities
+            .supports_compression(CompressionVariant::Lz4));
+        assert!(!negotiated
+            .capabilities
+            .supports_compression(CompressionVariant::Xz));
+        assert!(!negotiated
+            .capabilities
+            .contains(Capabilities::DESTINATION_VALIDATION));
+    }
+
+    #[test]
+    fn test_negotiate_without_version_overlap_fails() {
+        let local = HandshakeHello {
+            version: 4,
+            min_version: 3,
+            capabilities: Capabilities::local(),
+        };
+        let peer = HandshakeHello {
+            version: 2,
+            min_version: 1,
+            capabilities: Capabilities::local(),
+        };
+        assert!(matches!(
+            local.negotiate(&peer),
+            Err(IncompatiblePeer::Version { .. })
+        ));
+        assert!(matches!(
+            peer.negotiate(&local),
+            Err(IncompatiblePeer::Version { .. })
+        ));
+    }
+
+    #[test]
+    fn test_local_capabilities_include_all_compression_variants() {
+        for variant in CompressionVariant::iter() {
+            assert!(Capabilities::local().supports_compression(variant));
+        }
+    }
+}
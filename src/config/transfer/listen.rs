@@ -1,4 +1,10 @@
-use crate::config::{compression::CompressionVariant, util::*};
+use crate::{
+    auth::PreSharedKey,
+    config::{compression::CompressionVariant, util::*},
+    observer::SharedObserver,
+    rate_limit::RateLimit,
+    server::report::{OnReceived, ReceivedFile},
+};
 
 /// Holds the Listen subcommands
 #[derive(Debug, Args, Clone)]
@@ -37,4 +43,76 @@ pub struct ListenArgs {
     /// Compression format of the received file, incremental decompression is performed as the data is received.
     #[arg(short('d'), long, global(true))]
     pub decompression: Option<CompressionVariant>,
+
+    /// Keep listening after a transfer has ended and serve any number of clients, each in their own session
+    #[arg(long, visible_alias("daemon"), action = ArgAction::SetTrue)]
+    pub keep_alive: bool,
+
+    /// Maximum number of clients to serve at the same time in keep-alive mode, further clients wait until a session ends
+    #[arg(long, requires("keep_alive"), value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_sessions: Option<u16>,
+
+    /// What to do when a received file already exists
+    #[arg(long, global(true), value_name("POLICY"), default_value_t = OverwritePolicy::Always)]
+    pub overwrite: OverwritePolicy,
+
+    /// Require clients to prove that they know this pre-shared key before accepting any files from them
+    #[arg(long, env("QFT_PSK"), hide_env_values(true), value_name("KEY"))]
+    pub psk: Option<PreSharedKey>,
+
+    /// Require clients to encrypt the connection, clients can also ask for encryption without this
+    #[arg(long, action = ArgAction::SetTrue)]
+    pub encrypt: bool,
+
+    /// Limit the bandwidth of all clients combined to this many bytes per second, with an optional K, M or G suffix e.g. `500K`
+    #[arg(long, value_name("RATE"))]
+    pub limit_rate: Option<RateLimit>,
+
+    /// Called with each received file, when the server is embedded through [Server](crate::Server)
+    #[arg(skip)]
+    pub on_received: Option<OnReceived>,
+
+    /// Observes the received files, when the server is embedded through [Server](crate::Server)
+    #[arg(skip)]
+    pub observer: SharedObserver,
+}
+
+impl ListenArgs {
+    /// The received content is written to stdout, as neither an output path nor directory was given,
+    /// and no client over ssh chooses the destination
+    pub fn receives_to_stdout(&self) -> bool {
+        self.output.is_none() && self.output_dir.is_none() && !self.remote
+    }
+
+    /// Tell the embedding program (if any) that `file` was received
+    pub fn received(&self, file: &ReceivedFile) {
+        if let Some(on_received) = &self.on_received {
+            on_received.call(file);
+        }
+    }
+}
+
+/// How the server handles received files that already exist at the destination
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Replace the existing file
+    #[default]
+    Always,
+    /// Keep the existing file and refuse the received file
+    Never,
+    /// Replace the existing file if the received file was modified more recently
+    Newer,
+    /// Keep the existing file and receive as `name (1).ext` (or the first free number)
+    Rename,
+    /// Keep the existing file as `name~` and replace it
+    Backup,
+}
+
+impl fmt::Display for OverwritePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
 }
@@ -1,6 +1,9 @@
 use std::time::Duration;
 
-use crate::{config::util::*, util::IANA_RECOMMEND_DYNAMIC_PORT_RANGE_START};
+use crate::{
+    auth::PreSharedKey, config::util::*, rate_limit::RateLimit, sync::SyncOptions,
+    util::IANA_RECOMMEND_DYNAMIC_PORT_RANGE_START,
+};
 
 #[cfg(feature = "mdns")]
 pub mod mdns;
@@ -31,6 +34,62 @@ pub struct SendArgs {
     #[arg(long, action = ArgAction::SetTrue, requires = "INPUT_FILE", global(true))]
     pub mmap: bool,
 
+    /// Transfer directories recursively, recreating the directory tree on the receiving end
+    #[arg(short, long, action = ArgAction::SetTrue, requires = "INPUT_FILE", global(true))]
+    pub recursive: bool,
+
+    /// Preserve permissions and access/modification times of the transferred files
+    #[arg(long, action = ArgAction::SetTrue, requires = "INPUT_FILE", global(true))]
+    pub preserve: bool,
+
+    /// Resume files that were partially received by a previous (interrupted) transfer, and reconnect to resume if a transfer is interrupted
+    #[arg(long, action = ArgAction::SetTrue, requires = "INPUT_FILE", global(true))]
+    pub resume: bool,
+
+    /// Send only what changed compared to the existing file at the destination (if there is one), like rsync
+    #[arg(
+        long,
+        action = ArgAction::SetTrue,
+        requires = "INPUT_FILE",
+        conflicts_with_all = ["resume", "streams"],
+        global(true)
+    )]
+    pub delta: bool,
+
+    /// Only send the files that are missing or stale on the server, compared by size and modification time
+    #[arg(long, action = ArgAction::SetTrue, requires = "INPUT_FILE", global(true))]
+    pub sync: bool,
+
+    /// Delete the files and directories inside the sent directories that don't exist on this end
+    #[arg(long, action = ArgAction::SetTrue, requires = "sync", global(true))]
+    pub delete: bool,
+
+    /// Compare the checksums of the files instead of their modification times when syncing
+    #[arg(long, action = ArgAction::SetTrue, requires = "sync", global(true))]
+    pub checksum: bool,
+
+    /// Send this many files at the same time, each over its own connection
+    #[arg(
+        short,
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        requires = "INPUT_FILE",
+        global(true)
+    )]
+    pub jobs: u16,
+
+    /// Split each file of at least 2 MiB into up to this many ranges that are sent at the same time, each over its own connection
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..),
+        requires = "INPUT_FILE",
+        conflicts_with_all = ["resume", "delta"],
+        global(true)
+    )]
+    pub streams: u16,
+
     /// Poll the server with a specified interval (ms) until a connection is established.
     #[arg(
         long("poll"),
@@ -55,6 +114,24 @@ pub struct SendArgs {
     /// Maximum attempts to establish a TCP connection to remote.
     #[arg(long, group("tcp_about_condition"))]
     pub tcp_max_attempts: Option<u32>,
+
+    /// Authenticate with this pre-shared key if the server requires one
+    #[arg(
+        long,
+        global(true),
+        env("QFT_PSK"),
+        hide_env_values(true),
+        value_name("KEY")
+    )]
+    pub psk: Option<PreSharedKey>,
+
+    /// Encrypt the connection, the identity of the server is trusted on first use and must match on later connections
+    #[arg(long, action = ArgAction::SetTrue, global(true))]
+    pub encrypt: bool,
+
+    /// Limit the bandwidth to this many bytes per second (after compression), with an optional K, M or G suffix e.g. `500K`
+    #[arg(long, global(true), value_name("RATE"))]
+    pub limit_rate: Option<RateLimit>,
 }
 
 impl SendArgs {
@@ -81,6 +158,14 @@ impl SendArgs {
             !self.no_prealloc
         }
     }
+
+    /// Returns how to sync the files, if they are synced
+    pub fn sync_options(&self) -> Option<SyncOptions> {
+        self.sync.then_some(SyncOptions {
+            delete: self.delete,
+            checksum: self.checksum,
+        })
+    }
 }
 
 #[allow(clippy::large_enum_variant)] // This lint should be revised when command-line args are fairly stabilized
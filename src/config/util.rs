@@ -5,7 +5,7 @@ pub use {
     },
     clap::{
         builder::styling::{AnsiColor, Effects, Styles},
-        command, ArgAction, Args, Parser, Subcommand, ValueEnum,
+        ArgAction, Args, Parser, Subcommand, ValueEnum,
     },
     std::{fmt, path::PathBuf},
     strum_macros::{Display, EnumIter},
@@ -0,0 +1,405 @@
+//! Delta transfer of files that the receiving end has an older version of, like rsync.
+//!
+//! The receiver sends the [Signature] of its existing file: a weak and a strong checksum of each block of the file.
+//! The sender slides a window over the new content looking for blocks with the same weak checksum, which is cheap to
+//! update as the window moves byte by byte, and confirms a match with the strong checksum. The new content is then
+//! sent as [delta operations](DeltaReader) that are either literal content or references to blocks of the existing
+//! file, from which the receiver rebuilds the new file with [apply_delta].
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum::Checksum, progress::FileProgress};
+
+const MIN_BLOCK_LEN: u64 = 2 * 1024;
+const MAX_BLOCK_LEN: u64 = 128 * 1024;
+/// Literal content is sent in operations of at most this many bytes
+const MAX_LITERAL_LEN: usize = 64 * 1024;
+
+/// The end of the delta, followed by nothing
+const OP_END: u8 = 0;
+/// Literal content, followed by its length as a big-endian [u32] and the content
+const OP_LITERAL: u8 = 1;
+/// Consecutive blocks of the existing file, followed by the index of the first block and the amount of blocks as big-endian [u32]s
+const OP_COPY: u8 = 2;
+
+/// The checksums of the blocks of a file that the receiver already has
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    /// The length of the file
+    pub len: u64,
+    pub block_len: u32,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Checksum,
+}
+
+impl Signature {
+    /// The block length for a file of `len` bytes, about its square root so that the signature of large files stays small
+    pub fn block_len_for(len: u64) -> u32 {
+        ((len as f64).sqrt() as u64).clamp(MIN_BLOCK_LEN, MAX_BLOCK_LEN) as u32
+    }
+
+    /// The signature of the `len` bytes read from `reader`, in blocks of `block_len` bytes (the last block may be shorter)
+    pub fn of_reader<R: Read>(reader: R, len: u64, block_len: u32) -> io::Result<Self> {
+        let mut reader = reader.take(len);
+        let mut block = vec![0; block_len as usize];
+        let mut blocks = vec![];
+        loop {
+            let read = read_block(&mut reader, &mut block)?;
+            if read == 0 {
+                break;
+            }
+            blocks.push(BlockSignature {
+                weak: RollingChecksum::new(&block[..read]).value(),
+                strong: Checksum::of(&block[..read]),
+            });
+        }
+        Ok(Self {
+            len,
+            block_len,
+            blocks,
+        })
+    }
+
+    /// There are no blocks to refer to, e.g. because the receiver has no existing file
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+/// Fill `buf` from `reader` until it's full or the content ends, returns how much was read
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The weak checksum of a window of content, which is updated in constant time when the window moves by a byte
+#[derive(Debug, Clone, Copy)]
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0_u32, 0_u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(u32::from(byte));
+            b = b.wrapping_add((len - i as u32).wrapping_mul(u32::from(byte)));
+        }
+        Self { a, b, len }
+    }
+
+    /// Move the window forward by a byte, dropping `outgoing` from the start and adding `incoming` at the end
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(outgoing))
+            .wrapping_add(u32::from(incoming));
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(u32::from(outgoing)))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Reads the delta operations that rebuild `content` from the file with the given [Signature]
+pub struct DeltaReader<'a> {
+    content: &'a [u8],
+    signature: &'a Signature,
+    /// The full length blocks of the signature by their weak checksum
+    blocks_by_weak: HashMap<u32, Vec<u32>>,
+    /// The start of the window that is compared with the blocks
+    pos: usize,
+    /// The start of the content that isn't covered by an operation yet
+    literal_start: usize,
+    window: Option<RollingChecksum>,
+    /// Consecutive matched blocks that aren't encoded yet, as the first block and the amount of blocks
+    pending_copy: Option<(u32, u32)>,
+    encoded: Vec<u8>,
+    encoded_pos: usize,
+    finished: bool,
+    matched: u64,
+    progress: &'a FileProgress,
+}
+
+impl<'a> DeltaReader<'a> {
+    pub fn new(content: &'a [u8], signature: &'a Signature, progress: &'a FileProgress) -> Self {
+        let block_len = u64::from(signature.block_len);
+        let mut blocks_by_weak: HashMap<u32, Vec<u32>> = HashMap::new();
+        // A shorter last block can't be matched by the full length window
+        let full_blocks = (signature.len / block_len.max(1)) as usize;
+        for (i, block) in signature.blocks.iter().take(full_blocks).enumerate() {
+            blocks_by_weak.entry(block.weak).or_default().push(i as u32);
+        }
+        Self {
+            content,
+            signature,
+            blocks_by_weak,
+            pos: 0,
+            literal_start: 0,
+            window: None,
+            pending_copy: None,
+            encoded: Vec::with_capacity(MAX_LITERAL_LEN + 5),
+            encoded_pos: 0,
+            finished: false,
+            matched: 0,
+            progress,
+        }
+    }
+
+    /// How much of the content was matched with blocks of the existing file so far
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// The block of the existing file that has the content of `window`, preferring the block after the previous match
+    fn matching_block(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let candidates = self.blocks_by_weak.get(&weak)?;
+        let strong = Checksum::of(window);
+        let next = self.pending_copy.map(|(start, count)| start + count);
+        candidates
+            .iter()
+            .copied()
+            .filter(|&i| self.signature.blocks[i as usize].strong == strong)
+            .max_by_key(|&i| Some(i) == next)
+    }
+
+    fn encode_copy(&mut self) {
+        if let Some((start, count)) = self.pending_copy.take() {
+            self.encoded.push(OP_COPY);
+            self.encoded.extend_from_slice(&start.to_be_bytes());
+            self.encoded.extend_from_slice(&count.to_be_bytes());
+        }
+    }
+
+    /// Encode the content up to `end` that isn't covered by an operation yet as literal content
+    fn encode_literal_until(&mut self, end: usize) {
+        if end == self.literal_start {
+            return;
+        }
+        self.encode_copy();
+        for literal in self.content[self.literal_start..end].chunks(MAX_LITERAL_LEN) {
+            self.encoded.push(OP_LITERAL);
+            self.encoded
+                .extend_from_slice(&(literal.len() as u32).to_be_bytes());
+            self.encoded.extend_from_slice(literal);
+        }
+        self.progress.inc((end - self.literal_start) as u64);
+        self.literal_start = end;
+    }
+
+    /// Advance through the content until there are encoded operations to read
+    fn encode_next(&mut self) {
+        let block_len = self.signature.block_len as usize;
+        while self.encoded.is_empty() && !self.finished {
+            if block_len == 0 || self.pos + block_len > self.content.len() {
+                // The rest is shorter than a block
+                self.encode_literal_until(self.content.len());
+                self.encode_copy();
+                self.encoded.push(OP_END);
+                self.finished = true;
+                break;
+            }
+            let window = &self.content[self.pos..self.pos + block_len];
+            let weak = self
+                .window
+                .get_or_insert_with(|| RollingChecksum::new(window))
+                .value();
+            if let Some(block) = self.matching_block(weak, window) {
+                self.encode_literal_until(self.pos);
+                match &mut self.pending_copy {
+                    Some((start, count)) if *start + *count == block => *count += 1,
+                    _ => {
+                        self.encode_copy();
+                        self.pending_copy = Some((block, 1));
+                    }
+                }
+                self.progress.inc(block_len as u64);
+                self.matched += block_len as u64;
+                self.pos += block_len;
+                self.literal_start = self.pos;
+                self.window = None;
+                continue;
+            }
+            if self.pos - self.literal_start >= MAX_LITERAL_LEN {
+                self.encode_literal_until(self.pos);
+            }
+            match self.content.get(self.pos + block_len) {
+                Some(&incoming) => {
+                    if let Some(window) = &mut self.window {
+                        window.roll(self.content[self.pos], incoming);
+                    }
+                }
+                None => self.window = None,
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+impl Read for DeltaReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.encoded_pos == self.encoded.len() {
+            self.encoded.clear();
+            self.encoded_pos = 0;
+            self.encode_next();
+        }
+        let read = (&self.encoded[self.encoded_pos..]).read(buf)?;
+        self.encoded_pos += read;
+        Ok(read)
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Rebuild the content from the delta operations read from `delta` and the blocks of `basis`, the file the signature was made of.
+///
+/// Returns the length of the content that was written to `writer`.
+pub fn apply_delta<R: Read, B: Read + Seek, W: Write>(
+    mut delta: R,
+    basis: &mut B,
+    block_len: u32,
+    writer: &mut W,
+) -> io::Result<u64> {
+    let mut written = 0;
+    loop {
+        let mut op = [0];
+        delta.read_exact(&mut op)?;
+        let (copied, expected) = match op[0] {
+            OP_END => return Ok(written),
+            OP_LITERAL => {
+                let len = u64::from(read_u32(&mut delta)?);
+                (io::copy(&mut (&mut delta).take(len), writer)?, len)
+            }
+            OP_COPY => {
+                let start = u64::from(read_u32(&mut delta)?);
+                let len = u64::from(read_u32(&mut delta)?) * u64::from(block_len);
+                basis.seek(SeekFrom::Start(start * u64::from(block_len)))?;
+                (io::copy(&mut (&mut *basis).take(len), writer)?, len)
+            }
+            op => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid delta operation {op}"),
+                ))
+            }
+        };
+        if copied != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("delta operation of {expected} B ended after {copied} B"),
+            ));
+        }
+        written += copied;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::SharedObserver;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    /// Rebuild `new` from `old`, returns how much was matched and the length of the delta
+    fn roundtrip(old: &[u8], new: &[u8]) -> io::Result<(u64, usize)> {
+        let signature = Signature::of_reader(old, old.len() as u64, 2048)?;
+        let progress = FileProgress::new(
+            "delta",
+            Some(new.len() as u64),
+            None,
+            &SharedObserver::default(),
+        );
+        let mut reader = DeltaReader::new(new, &signature, &progress);
+        let mut delta = vec![];
+        reader.read_to_end(&mut delta)?;
+        let matched = reader.matched();
+
+        let mut rebuilt = vec![];
+        let len = apply_delta(&delta[..], &mut Cursor::new(old), 2048, &mut rebuilt)?;
+        assert_eq!(len, new.len() as u64);
+        assert!(rebuilt == new, "Rebuilt content differs");
+        Ok((matched, delta.len()))
+    }
+
+    #[test]
+    fn test_rolling_checksum_matches_checksum_of_window() {
+        let content = pseudo_random(300, 1);
+        let mut rolling = RollingChecksum::new(&content[..100]);
+        for start in 1..=200 {
+            rolling.roll(content[start - 1], content[start + 99]);
+            assert_eq!(
+                rolling.value(),
+                RollingChecksum::new(&content[start..start + 100]).value()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_of_changed_content_refers_to_unchanged_blocks() -> io::Result<()> {
+        let old = pseudo_random(100_000, 2);
+        // Changed in the middle, with inserted and removed content that shifts the rest
+        let mut new = old.clone();
+        new[50_000..50_100].copy_from_slice(&pseudo_random(100, 3));
+        new.splice(20_000..20_000, pseudo_random(777, 4));
+        new.drain(80_000..81_000);
+        let (matched, delta_len) = roundtrip(&old, &new)?;
+        assert!(matched > 90_000, "matched {matched} B");
+        assert!(delta_len < 10_000, "delta of {delta_len} B");
+
+        let (matched, _) = roundtrip(&old, &old)?;
+        assert_eq!(matched, 100_000 / 2048 * 2048);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_without_matching_blocks_is_literal() -> io::Result<()> {
+        let (matched, _) = roundtrip(&pseudo_random(10_000, 5), &pseudo_random(200_000, 6))?;
+        assert_eq!(matched, 0);
+        let (matched, delta_len) = roundtrip(&[], b"short")?;
+        assert_eq!((matched, delta_len), (0, 1 + 4 + 5 + 1));
+        let (_, delta_len) = roundtrip(b"old", &[])?;
+        assert_eq!(delta_len, 1);
+        Ok(())
+    }
+}
@@ -13,6 +13,7 @@ use crate::{
             Bzip2Args, Compression, CompressionRange, CompressionVariant, GzipArgs, XzArgs,
         },
         evaluate_compression::EvaluateCompressionArgs,
+        misc::OutputFormat,
     },
     send::util::file_with_bufreader,
 };
@@ -28,7 +29,7 @@ use compression_result::{Awaiting, CompressionResult, Finished};
 mod print_results;
 mod test_compress;
 
-pub fn evaluate_compression(args: EvaluateCompressionArgs) -> Result<()> {
+pub fn evaluate_compression(args: EvaluateCompressionArgs, format: OutputFormat) -> Result<()> {
     let EvaluateCompressionArgs {
         input_file,
         omit,
@@ -123,9 +124,7 @@ pub fn evaluate_compression(args: EvaluateCompressionArgs) -> Result<()> {
 
     let res = multi_progress_bar(compression_awaiting, &test_contents, threads)?;
 
-    print_results::evaluate_and_printout_results(&res);
-
-    Ok(())
+    print_results::evaluate_and_printout_results(&res, test_contents_len, format)
 }
 
 fn multi_progress_bar(
@@ -5,6 +5,7 @@ use super::test_compress::{
 };
 use crate::{config::compression::Compression, util::format_data_size};
 use anyhow::Result;
+use serde::Serialize;
 
 use comfy_table::modifiers::UTF8_ROUND_CORNERS;
 use comfy_table::presets::UTF8_FULL;
@@ -30,6 +31,18 @@ pub struct CompressionResult<S: CompressionResultState> {
     state: PhantomData<S>,
 }
 
+/// A finished [CompressionResult] as it's printed with `--output-format json`
+#[derive(Debug, Serialize)]
+pub struct CompressionReport {
+    pub format: String,
+    pub level: Option<u8>,
+    pub compressed_size: usize,
+    pub compression_ratio: f64,
+    pub percentage_of_original: f64,
+    pub compression_time_ms: f64,
+    pub decompression_time_ms: f64,
+}
+
 impl CompressionResult<Awaiting> {
     pub fn new(compression: Compression) -> Self {
         Self {
@@ -130,6 +143,19 @@ impl CompressionResult<Finished> {
         summary
     }
 
+    /// The result as it's printed with `--output-format json`
+    pub fn report(&self) -> CompressionReport {
+        CompressionReport {
+            format: self.compression_format().to_owned(),
+            level: self.compression_level(),
+            compressed_size: self.compressed_size.unwrap(),
+            compression_ratio: self.compression_ratio.unwrap(),
+            percentage_of_original: self.percentage_of_original.unwrap(),
+            compression_time_ms: self.compression_time.unwrap().as_secs_f64() * 1000.,
+            decompression_time_ms: self.decompression_time.unwrap().as_secs_f64() * 1000.,
+        }
+    }
+
     fn compression_level(&self) -> Option<u8> {
         match self.compression {
             Compression::Bzip2(ref a) => Some(a.compression_level),
@@ -1,6 +1,26 @@
-use super::compression_result::{print_results_as_table, CompressionResult, Finished};
+use serde::Serialize;
 
-pub fn evaluate_and_printout_results(compression_results: &[CompressionResult<Finished>]) {
+use crate::{config::misc::OutputFormat, util::print_json};
+
+use super::compression_result::{
+    print_results_as_table, CompressionReport, CompressionResult, Finished,
+};
+
+/// The results of `evaluate-compression` with `--output-format json`
+#[derive(Debug, Serialize)]
+struct EvaluationReport {
+    input_size: usize,
+    results: Vec<CompressionReport>,
+    best_ratio: Option<CompressionReport>,
+    fastest_compression: Option<CompressionReport>,
+    fastest_decompression: Option<CompressionReport>,
+}
+
+pub fn evaluate_and_printout_results(
+    compression_results: &[CompressionResult<Finished>],
+    input_size: usize,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
     let mut fastest_compression: Option<&CompressionResult<Finished>> = None;
     let mut fastest_decompression: Option<&CompressionResult<Finished>> = None;
     let mut best_ratio: Option<&CompressionResult<Finished>> = None;
@@ -31,9 +51,21 @@ pub fn evaluate_and_printout_results(compression_results: &[CompressionResult<Fi
         debug_assert!(fastest_decompression.is_some());
     }
 
-    if let (Some(f_compr), Some(f_decompr), Some(br)) =
-        (fastest_compression, fastest_decompression, best_ratio)
-    {
-        print_results_as_table(f_compr, f_decompr, br);
+    match format {
+        OutputFormat::Text => {
+            if let (Some(f_compr), Some(f_decompr), Some(br)) =
+                (fastest_compression, fastest_decompression, best_ratio)
+            {
+                print_results_as_table(f_compr, f_decompr, br);
+            }
+        }
+        OutputFormat::Json => print_json(&EvaluationReport {
+            input_size,
+            results: compression_results.iter().map(|r| r.report()).collect(),
+            best_ratio: best_ratio.map(|r| r.report()),
+            fastest_compression: fastest_compression.map(|r| r.report()),
+            fastest_decompression: fastest_decompression.map(|r| r.report()),
+        })?,
     }
+    Ok(())
 }
@@ -0,0 +1,256 @@
+//! Newline-delimited JSON events of the transfers, for GUIs and dashboards that follow a transfer as it happens.
+//!
+//! No events are written unless [init] was given a target with `--events <FD|PATH>`.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    net::TcpStream,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use serde::Serialize;
+
+use crate::{checksum::Checksum, config::transfer::handshake::NegotiatedProtocol};
+
+/// Where the events of this process are written, if anywhere
+static EVENTS: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// Where to write the events to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventsTarget {
+    /// An open file descriptor e.g. `3`, or `1` for stdout
+    Fd(i32),
+    /// A file that is created, or truncated if it exists
+    Path(PathBuf),
+}
+
+impl FromStr for EventsTarget {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(fd) => Self::Fd(fd),
+            Err(_) => Self::Path(PathBuf::from(s)),
+        })
+    }
+}
+
+impl fmt::Display for EventsTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fd(fd) => write!(f, "file descriptor {fd}"),
+            Self::Path(path) => write!(f, "{path:?}"),
+        }
+    }
+}
+
+/// Something that happened during a transfer, on either end
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// Connected to the server at `peer`, or a client connected from `peer`
+    Connect { peer: String },
+    /// Agreed on the protocol with `peer`
+    HandshakeOk {
+        peer: String,
+        protocol_version: u16,
+        capabilities: u32,
+    },
+    /// Started sending or receiving `name`, `size` is unknown for stdin
+    FileStart { name: &'a str, size: Option<u64> },
+    /// `bytes` of `name` are transferred, at `bytes_per_sec` on average
+    Progress {
+        name: &'a str,
+        bytes: u64,
+        bytes_per_sec: u64,
+    },
+    /// All of `name` was sent or received, `checksum` is of its uncompressed content (if it's known)
+    FileDone {
+        name: &'a str,
+        bytes: u64,
+        checksum: Option<String>,
+        duration_ms: u64,
+    },
+    /// The server refused `name` because of its overwrite policy
+    FileSkipped { name: &'a str, reason: &'a str },
+    Error {
+        name: Option<&'a str>,
+        message: String,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub fn handshake_ok(peer: String, negotiated: &NegotiatedProtocol) -> Self {
+        Self::HandshakeOk {
+            peer,
+            protocol_version: negotiated.version,
+            capabilities: negotiated.capabilities.bits(),
+        }
+    }
+
+    pub fn file_done(
+        name: &'a str,
+        bytes: u64,
+        checksum: Option<Checksum>,
+        duration: std::time::Duration,
+    ) -> Self {
+        Self::FileDone {
+            name,
+            bytes,
+            checksum: checksum.map(|c| c.to_string()),
+            duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+        }
+    }
+}
+
+/// A line of the event stream
+#[derive(Serialize)]
+struct EventLine<'e, 'a> {
+    /// Milliseconds since the Unix epoch
+    time_ms: u64,
+    #[serde(flatten)]
+    event: &'e Event<'a>,
+}
+
+/// Write the events to `target`, must be called (once) before any event is emitted to take effect
+pub fn init(target: &EventsTarget) -> anyhow::Result<()> {
+    init_in(&EVENTS, target)
+}
+
+fn init_in(
+    events: &OnceLock<Mutex<Box<dyn Write + Send>>>,
+    target: &EventsTarget,
+) -> anyhow::Result<()> {
+    // Checked before opening the target, which would e.g. truncate a file that is never written to
+    if events.get().is_some() {
+        bail!("Events are already written to another target");
+    }
+    let writer: Box<dyn Write + Send> = match target {
+        EventsTarget::Fd(fd) if *fd <= 0 => {
+            bail!("File descriptor {fd} isn't writable, give one that was opened for the events (or 1 for stdout)")
+        }
+        EventsTarget::Fd(1) => Box::new(io::stdout()),
+        EventsTarget::Fd(2) => Box::new(io::stderr()),
+        #[cfg(unix)]
+        EventsTarget::Fd(fd) => {
+            use std::os::fd::FromRawFd;
+            // SAFETY: Only reads the flags of the descriptor, which fails if it isn't open
+            if unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                bail!(
+                    "File descriptor {fd} isn't open: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            // SAFETY: The descriptor is open, and handed to qft to write the events to and not used for anything else
+            Box::new(unsafe { File::from_raw_fd(*fd) })
+        }
+        #[cfg(not(unix))]
+        EventsTarget::Fd(fd) => {
+            bail!("Writing events to file descriptor {fd} is only supported on Unix")
+        }
+        EventsTarget::Path(path) => Box::new(File::create(path)?),
+    };
+    events
+        .set(Mutex::new(writer))
+        .map_err(|_| anyhow::anyhow!("Events are already written to another target"))
+}
+
+/// The address of the peer at the other end of `socket`, for events
+pub fn peer(socket: &TcpStream) -> String {
+    socket
+        .peer_addr()
+        .map_or_else(|e| format!("unknown ({e})"), |addr| addr.to_string())
+}
+
+/// Events are written somewhere
+pub fn enabled() -> bool {
+    EVENTS.get().is_some()
+}
+
+/// Write `event` as a line of JSON, if events are written anywhere
+pub fn emit(event: &Event) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+    let time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    let mut line = match serde_json::to_vec(&EventLine { time_ms, event }) {
+        Ok(line) => line,
+        Err(e) => {
+            log::debug!("Failed serializing {event:?}: {e}");
+            return;
+        }
+    };
+    line.push(b'\n');
+    let mut writer = events.lock().expect("Events lock poisoned");
+    // A reader that went away shouldn't fail the transfer
+    if let Err(e) = writer.write_all(&line).and_then(|()| writer.flush()) {
+        log::debug!("Failed writing event: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_events_target_from_str() {
+        assert_eq!("3".parse(), Ok(EventsTarget::Fd(3)));
+        assert_eq!(
+            "events.ndjson".parse(),
+            Ok(EventsTarget::Path(PathBuf::from("events.ndjson")))
+        );
+    }
+
+    #[test]
+    fn test_unusable_fd_is_refused() {
+        let events = OnceLock::new();
+        for fd in [-1, 0, i32::MAX] {
+            assert!(init_in(&events, &EventsTarget::Fd(fd)).is_err(), "{fd}");
+        }
+        assert!(events.get().is_none());
+    }
+
+    #[test]
+    fn test_second_init_is_refused() -> TestResult {
+        let d = temp_dir::TempDir::new()?;
+        let events = OnceLock::new();
+        init_in(&events, &EventsTarget::Path(d.child("first.ndjson")))?;
+
+        let second = EventsTarget::Path(d.child("second.ndjson"));
+        assert!(init_in(&events, &second).is_err());
+        assert!(!d.child("second.ndjson").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_event_as_json() -> TestResult {
+        let event = Event::file_done(
+            "f.txt",
+            7,
+            Some(Checksum::of(b"content")),
+            std::time::Duration::from_millis(12),
+        );
+        let line = serde_json::to_string(&EventLine {
+            time_ms: 1,
+            event: &event,
+        })?;
+        assert_eq!(
+            line,
+            format!(
+                r#"{{"time_ms":1,"event":"file_done","name":"f.txt","bytes":7,"checksum":"{}","duration_ms":12}}"#,
+                Checksum::of(b"content")
+            )
+        );
+        Ok(())
+    }
+}
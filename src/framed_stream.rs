@@ -0,0 +1,196 @@
+//! Framing of the (possibly compressed) file content sent over the child sockets.
+//!
+//! The content is split into frames of at most [MAX_DATA_FRAME_SIZE] bytes, each preceded by its size as a big-endian [u32].
+//! A frame of size 0 marks the end of the content, which allows the receiver to tell a complete transfer from
+//! an interrupted one, and the peers to keep talking on the socket once the content has been transferred.
+
+use std::io::{self, Read, Write};
+
+use crate::BUFFERED_RW_BUFSIZE;
+
+/// Size of the header that precedes each data frame
+pub const DATA_FRAME_HEADER_SIZE: usize = 4;
+
+/// The largest amount of content in a single data frame
+pub const MAX_DATA_FRAME_SIZE: usize = BUFFERED_RW_BUFSIZE;
+
+/// Buffers written content and writes it to the inner writer as data frames
+pub struct FramedWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> FramedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(MAX_DATA_FRAME_SIZE),
+        }
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let header = (self.buf.len() as u32).to_be_bytes();
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes any buffered content followed by the end of content marker, and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_frame()?;
+        self.inner.write_all(&0_u32.to_be_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for FramedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == MAX_DATA_FRAME_SIZE {
+            self.write_frame()?;
+        }
+        let len = buf.len().min(MAX_DATA_FRAME_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_frame()?;
+        self.inner.flush()
+    }
+}
+
+/// Reads the content of data frames from the inner reader until the end of content marker.
+pub struct FramedReader<R: Read> {
+    inner: R,
+    frame_remaining: usize,
+    finished: bool,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            frame_remaining: 0,
+            finished: false,
+        }
+    }
+
+    /// Returns true once the end of content marker has been read
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Reads (and discards) any remaining content up to and including the end of content marker.
+    ///
+    /// Returns the amount of discarded bytes, which should be zero when a decoder consumed everything the sender encoded.
+    pub fn finish(&mut self) -> io::Result<u64> {
+        io::copy(self, &mut io::sink())
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read> Read for FramedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.frame_remaining == 0 {
+            let mut header = [0; DATA_FRAME_HEADER_SIZE];
+            if let Err(e) = self.inner.read_exact(&mut header) {
+                if e.kind() == io::ErrorKind::UnexpectedEof {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before the end of the transferred content, was the transfer interrupted?",
+                    ));
+                }
+                return Err(e);
+            }
+            let frame_size = u32::from_be_bytes(header) as usize;
+            if frame_size > MAX_DATA_FRAME_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("data frame of {frame_size} B exceeds the maximum of {MAX_DATA_FRAME_SIZE} B"),
+                ));
+            }
+            if frame_size == 0 {
+                self.finished = true;
+                return Ok(0);
+            }
+            self.frame_remaining = frame_size;
+        }
+        let max_read = buf.len().min(self.frame_remaining);
+        let bytes_read = self.inner.read(&mut buf[..max_read])?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a data frame, was the transfer interrupted?",
+            ));
+        }
+        self.frame_remaining -= bytes_read;
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_framed_roundtrip_with_trailing_data() -> TestResult {
+        let content: Vec<u8> = (0..(3 * MAX_DATA_FRAME_SIZE + 17))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut writer = FramedWriter::new(Vec::new());
+        writer.write_all(&content)?;
+        let mut wire = writer.finish()?;
+        wire.extend_from_slice(b"trailer");
+
+        let mut reader = FramedReader::new(wire.as_slice());
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received)?;
+        assert!(reader.is_finished());
+        assert_eq!(received, content);
+
+        // The bytes after the end of content marker are left untouched
+        let mut trailer = Vec::new();
+        reader.get_mut().read_to_end(&mut trailer)?;
+        assert_eq!(trailer, b"trailer");
+        Ok(())
+    }
+
+    #[test]
+    fn test_framed_empty_content() -> TestResult {
+        let wire = FramedWriter::new(Vec::new()).finish()?;
+        assert_eq!(wire, 0_u32.to_be_bytes());
+
+        let mut reader = FramedReader::new(wire.as_slice());
+        assert_eq!(reader.finish()?, 0);
+        assert!(reader.is_finished());
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_stream_is_an_error() -> TestResult {
+        let mut writer = FramedWriter::new(Vec::new());
+        writer.write_all(b"some content that never gets its end marker")?;
+        writer.flush()?;
+        let wire = writer.finish()?;
+        let truncated = &wire[..wire.len() - DATA_FRAME_HEADER_SIZE - 5];
+
+        let mut reader = FramedReader::new(truncated);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!reader.is_finished());
+        Ok(())
+    }
+}
@@ -1,30 +1,39 @@
 use anyhow::bail;
+use serde::Serialize;
 
 use crate::{
-    config::get_free_port::GetFreePortArgs,
-    util::{self, get_free_port_in_range},
+    config::{get_free_port::GetFreePortArgs, misc::OutputFormat},
+    util::{self, get_free_port_in_range, print_json},
 };
 
-pub fn handle_get_free_port(args: &GetFreePortArgs) -> anyhow::Result<()> {
+/// The result of `get-free-port` with `--output-format json`
+#[derive(Debug, Serialize)]
+struct FreePort<'a> {
+    ip: &'a str,
+    port: u16,
+}
+
+pub fn handle_get_free_port(args: &GetFreePortArgs, format: OutputFormat) -> anyhow::Result<()> {
     if args.start_port.is_none() {
         log::debug!("Retrieving any free port for IP: {}", args.ip);
     }
 
-    if let Some(start_port_range) = args.start_port {
+    let port = if let Some(start_port_range) = args.start_port {
         let end_port = args.end_port.unwrap_or(u16::MAX);
         log::debug!(
             "Retrieving free port for IP: {ip}, in range: {start_port_range}:{end_port}",
             ip = args.ip
         );
-        if let Some(port) = get_free_port_in_range(&args.ip, start_port_range, end_port) {
-            println!("{port}");
-        } else {
-            bail!("Could not retrieve free port");
-        }
-    } else if let Some(port) = util::get_free_port(&args.ip) {
-        println!("{port}");
+        get_free_port_in_range(&args.ip, start_port_range, end_port)
     } else {
+        util::get_free_port(&args.ip)
+    };
+    let Some(port) = port else {
         bail!("Could not retrieve free port");
+    };
+    match format {
+        OutputFormat::Text => println!("{port}"),
+        OutputFormat::Json => print_json(&FreePort { ip: &args.ip, port })?,
     }
     Ok(())
 }
@@ -11,17 +11,40 @@
 pub const TCP_STREAM_BUFSIZE: usize = 8 * 1024;
 pub const BUFFERED_RW_BUFSIZE: usize = 32 * 1024;
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod auth;
+pub mod checksum;
 pub mod config;
+pub mod delta;
 #[cfg(feature = "evaluate-compression")]
 pub mod evaluate_compression;
+pub mod events;
+pub mod framed_stream;
 pub mod get_free_port;
 #[cfg(feature = "mdns")]
 pub mod mdns;
 pub mod mmap_reader;
+pub mod observer;
+pub mod preserve;
+pub mod progress;
+pub mod rate_limit;
 pub mod send;
 pub mod server;
 #[cfg(feature = "ssh")]
 pub mod ssh;
+pub mod sync;
+pub mod transport;
 pub mod util;
 
 pub mod run;
+
+pub use observer::{NoopObserver, TransferObserver};
+pub use send::{
+    builder::{Client, ClientBuilder},
+    report::{FileReport, FileStatus, TransferReport},
+};
+pub use server::{
+    builder::{Server, ServerBuilder},
+    report::{ReceivedFile, ServerReport},
+};
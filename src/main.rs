@@ -1,6 +1,6 @@
 use std::process::ExitCode;
 
-use quick_file_transfer::{config, run};
+use quick_file_transfer::{config, events, run};
 fn main() -> ExitCode {
     let cfg = match config::Config::init() {
         Ok(cfg) => cfg,
@@ -18,6 +18,10 @@ fn main() -> ExitCode {
     }
 
     if let Err(e) = run::run(&cfg) {
+        events::emit(&events::Event::Error {
+            name: None,
+            message: e.to_string(),
+        });
         eprintln!("qft: {e}");
         return ExitCode::FAILURE;
     }
@@ -1,7 +1,11 @@
 use anyhow::Result;
 
-use crate::config::mdns::{
-    discover::MdnsDiscoverArgs, register::MdnsRegisterArgs, resolve::MdnsResolveArgs, MdnsCommand,
+use crate::config::{
+    mdns::{
+        discover::MdnsDiscoverArgs, register::MdnsRegisterArgs, resolve::MdnsResolveArgs,
+        MdnsCommand,
+    },
+    misc::OutputFormat,
 };
 
 pub mod resolve;
@@ -10,19 +14,22 @@ mod discover;
 mod register;
 mod util;
 
-pub fn handle_mdns_command(cmd: &MdnsCommand) -> Result<()> {
+pub fn handle_mdns_command(cmd: &MdnsCommand, format: OutputFormat) -> Result<()> {
     match cmd {
         MdnsCommand::Discover(MdnsDiscoverArgs {
             timeout_ms,
             service_type,
-        }) => {
-            discover::discover_service_type(&service_type.label, service_type.protocol, *timeout_ms)
-        }
+        }) => discover::discover_service_type(
+            &service_type.label,
+            service_type.protocol,
+            *timeout_ms,
+            format,
+        ),
         MdnsCommand::Resolve(MdnsResolveArgs {
             hostname,
             timeout_ms,
             short_circuit,
-        }) => resolve::resolve_hostname_print_stdout(hostname, *timeout_ms, *short_circuit),
+        }) => resolve::resolve_hostname_print_stdout(hostname, *timeout_ms, *short_circuit, format),
         MdnsCommand::Register(MdnsRegisterArgs {
             hostname,
             instance_name,
@@ -7,14 +7,16 @@ use std::{
 };
 
 use crate::{
-    config::misc::TransportLayerProtocol,
+    config::misc::{OutputFormat, TransportLayerProtocol},
     mdns::util::{self, MdnsServiceInfo},
+    util::print_json,
 };
 
 pub fn discover_service_type(
     service_label: &str,
     service_protocol: TransportLayerProtocol,
     timeout_ms: u64,
+    format: OutputFormat,
 ) -> Result<()> {
     let stopflag = AtomicBool::new(false);
 
@@ -85,8 +87,13 @@ pub fn discover_service_type(
             .expect("Failed joining service discovery thread")
     });
 
-    for discovered_service in discovered_services {
-        println!("{discovered_service}");
+    match format {
+        OutputFormat::Text => {
+            for discovered_service in discovered_services {
+                println!("{discovered_service}");
+            }
+        }
+        OutputFormat::Json => print_json(&discovered_services)?,
     }
     Ok(())
 }
@@ -2,7 +2,7 @@ use anyhow::Result;
 use mdns_sd::ServiceDaemon;
 use std::{collections::HashSet, net::IpAddr, thread};
 
-use crate::mdns::util;
+use crate::{config::misc::OutputFormat, mdns::util, util::print_json};
 
 use super::util::{try_clean_hostname, MdnsServiceInfo};
 
@@ -10,17 +10,22 @@ pub fn resolve_hostname_print_stdout(
     hostname: &str,
     timeout_ms: u64,
     short_circuit: bool,
+    format: OutputFormat,
 ) -> Result<()> {
     log::info!("Resolving address for {hostname}");
-    if let Some(resolved_info) = resolve_mdns_hostname(
+    let resolved_info = resolve_mdns_hostname(
         &try_clean_hostname(hostname.into()),
         timeout_ms,
         short_circuit,
-    )? {
-        println!("{resolved_info}");
-    } else {
+    )?;
+    if resolved_info.is_none() {
         log::error!("Failed resolving {hostname}");
     }
+    match (format, resolved_info) {
+        (OutputFormat::Json, resolved_info) => print_json(&resolved_info)?,
+        (OutputFormat::Text, Some(resolved_info)) => println!("{resolved_info}"),
+        (OutputFormat::Text, None) => (),
+    }
 
     Ok(())
 }
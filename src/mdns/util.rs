@@ -1,9 +1,10 @@
 use mdns_sd::{DaemonStatus, ServiceDaemon, ServiceInfo};
+use serde::Serialize;
 use std::{borrow::Cow, collections::HashSet, fmt, net::IpAddr};
 
 use crate::config::misc::IpVersion;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
 pub struct MdnsServiceInfo {
     hostname: String,
     type_name: Option<String>,
@@ -0,0 +1,93 @@
+//! Observe transfers from a program that embeds qft, like the CLI does with progress bars and [events](crate::events).
+
+use std::{fmt, ops::Deref, sync::Arc, time::Duration};
+
+/// Called as files are sent or received, from the threads that transfer them.
+///
+/// Every method does nothing by default, so an observer only implements what it's interested in.
+/// `name` is the name a file is sent as, the content of stdin is named `stdin`.
+pub trait TransferObserver: Send + Sync {
+    /// Started sending or receiving `name`, `size` is unknown for stdin
+    fn on_file_start(&self, name: &str, size: Option<u64>) {
+        let _ = (name, size);
+    }
+
+    /// `transferred` bytes of `name` are sent or received so far, called each time a buffer is transferred
+    fn on_progress(&self, name: &str, transferred: u64) {
+        let _ = (name, transferred);
+    }
+
+    /// All `bytes` of `name` were sent or received, which took `duration`
+    fn on_file_complete(&self, name: &str, bytes: u64, duration: Duration) {
+        let _ = (name, bytes, duration);
+    }
+
+    /// The server refused `name` because of its overwrite policy
+    fn on_file_skipped(&self, name: &str, reason: &str) {
+        let _ = (name, reason);
+    }
+
+    /// Transferring `name` failed, or the connection failed if the error isn't about a single file
+    fn on_error(&self, name: Option<&str>, error: &anyhow::Error) {
+        let _ = (name, error);
+    }
+}
+
+/// Lets the caller keep a handle to the observer, e.g. to read what it recorded once the transfer is done
+impl<T: TransferObserver + ?Sized> TransferObserver for Arc<T> {
+    fn on_file_start(&self, name: &str, size: Option<u64>) {
+        (**self).on_file_start(name, size);
+    }
+
+    fn on_progress(&self, name: &str, transferred: u64) {
+        (**self).on_progress(name, transferred);
+    }
+
+    fn on_file_complete(&self, name: &str, bytes: u64, duration: Duration) {
+        (**self).on_file_complete(name, bytes, duration);
+    }
+
+    fn on_file_skipped(&self, name: &str, reason: &str) {
+        (**self).on_file_skipped(name, reason);
+    }
+
+    fn on_error(&self, name: Option<&str>, error: &anyhow::Error) {
+        (**self).on_error(name, error);
+    }
+}
+
+/// Observes nothing, which is what the CLI uses
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopObserver;
+
+impl TransferObserver for NoopObserver {}
+
+/// A [TransferObserver] that is shared by all the threads of a transfer
+#[derive(Clone)]
+pub struct SharedObserver(Arc<dyn TransferObserver>);
+
+impl SharedObserver {
+    pub fn new(observer: impl TransferObserver + 'static) -> Self {
+        Self(Arc::new(observer))
+    }
+}
+
+impl Default for SharedObserver {
+    fn default() -> Self {
+        Self::new(NoopObserver)
+    }
+}
+
+impl Deref for SharedObserver {
+    type Target = dyn TransferObserver;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedObserver")
+    }
+}
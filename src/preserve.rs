@@ -0,0 +1,166 @@
+//! Preserving the permissions and access/modification times of transferred files (like `scp -p`).
+//!
+//! The client reads the [FileMetadata] of each file before sending it, and the server applies it to the file once it has been received.
+
+use std::{
+    fs::{self, FileTimes},
+    io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A point in time as the duration since the unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub secs: u64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    /// Returns [None] for times before the unix epoch
+    pub fn from_system_time(time: SystemTime) -> Option<Self> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            secs: since_epoch.as_secs(),
+            nanos: since_epoch.subsec_nanos(),
+        })
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.secs, self.nanos)
+    }
+}
+
+/// The metadata of a file that is preserved in the transfer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Unix permission bits, on other platforms `0o444` for read-only files and `0o644` otherwise
+    pub mode: u32,
+    pub accessed: Option<Timestamp>,
+    pub modified: Option<Timestamp>,
+}
+
+impl FileMetadata {
+    /// Mask of the permission bits (including setuid, setgid, and sticky bits)
+    const MODE_MASK: u32 = 0o7777;
+
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        let md = fs::metadata(path)?;
+        Ok(Self {
+            mode: permission_bits(&md.permissions()),
+            accessed: md.accessed().ok().and_then(Timestamp::from_system_time),
+            modified: md.modified().ok().and_then(Timestamp::from_system_time),
+        })
+    }
+
+    /// Apply the metadata to the file at `path`
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        let mut times = FileTimes::new();
+        if let Some(accessed) = self.accessed {
+            times = times.set_accessed(accessed.to_system_time());
+        }
+        if let Some(modified) = self.modified {
+            times = times.set_modified(modified.to_system_time());
+        }
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_times(times)?;
+
+        // Set last as the permissions might not allow writing
+        let mut permissions = fs::metadata(path)?.permissions();
+        set_permission_bits(&mut permissions, self.mode & Self::MODE_MASK);
+        fs::set_permissions(path, permissions)
+    }
+}
+
+#[cfg(unix)]
+fn permission_bits(permissions: &fs::Permissions) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    permissions.mode() & FileMetadata::MODE_MASK
+}
+
+#[cfg(not(unix))]
+fn permission_bits(permissions: &fs::Permissions) -> u32 {
+    if permissions.readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn set_permission_bits(permissions: &mut fs::Permissions, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    permissions.set_mode(mode);
+}
+
+#[cfg(not(unix))]
+fn set_permission_bits(permissions: &mut fs::Permissions, mode: u32) {
+    permissions.set_readonly(mode & 0o222 == 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_apply_preserves_times() -> TestResult {
+        let d = TempDir::new()?;
+        let src = d.child("src");
+        let dest = d.child("dest");
+        fs::write(&src, "content")?;
+        fs::write(&dest, "content")?;
+        let mtime = UNIX_EPOCH + Duration::new(1_000_000_000, 123_000);
+        fs::File::options()
+            .write(true)
+            .open(&src)?
+            .set_times(FileTimes::new().set_accessed(mtime).set_modified(mtime))?;
+
+        let metadata = FileMetadata::from_path(&src)?;
+        metadata.apply(&dest)?;
+
+        assert_eq!(fs::metadata(&dest)?.modified()?, mtime);
+        assert_eq!(FileMetadata::from_path(&dest)?, metadata);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_preserves_mode() -> TestResult {
+        use std::os::unix::fs::PermissionsExt;
+        let d = TempDir::new()?;
+        let dest = d.child("dest");
+        fs::write(&dest, "#!/bin/sh")?;
+
+        let metadata = FileMetadata {
+            mode: 0o751,
+            ..Default::default()
+        };
+        metadata.apply(&dest)?;
+
+        assert_eq!(fs::metadata(&dest)?.permissions().mode() & 0o7777, 0o751);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_read_only() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("dest");
+        fs::write(&dest, "content")?;
+
+        let metadata = FileMetadata {
+            mode: 0o444,
+            ..Default::default()
+        };
+        metadata.apply(&dest)?;
+
+        assert!(fs::metadata(&dest)?.permissions().readonly());
+        Ok(())
+    }
+}
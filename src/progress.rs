@@ -0,0 +1,253 @@
+//! Progress bars of the files that are sent or received, drawn on stderr.
+//!
+//! The bars are hidden unless [init] enabled them, which it only does if stderr is a terminal and output isn't silenced with `--quiet`.
+//! The progress is also emitted as [Event::Progress] if [events](crate::events) are written, and passed to the [TransferObserver](crate::observer::TransferObserver) of the transfer.
+
+use std::{
+    io::{self, IsTerminal, Read, Write},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::{
+    events::{self, Event},
+    observer::SharedObserver,
+};
+
+/// All the bars of this process, drawn together so that concurrent transfers don't overwrite each other
+static PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Interval at which the bars are redrawn even if no content is transferred, to keep the throughput and ETA current
+const TICK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Draw progress bars on stderr if it is a terminal and `quiet` isn't set, must be called before any bar is created to take effect
+pub fn init(quiet: bool) {
+    let target = if !quiet && io::stderr().is_terminal() {
+        ProgressDrawTarget::stderr()
+    } else {
+        ProgressDrawTarget::hidden()
+    };
+    let _ = PROGRESS.set(MultiProgress::with_draw_target(target));
+}
+
+fn multi_progress() -> &'static MultiProgress {
+    PROGRESS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::hidden()))
+}
+
+fn add_bar(len: Option<u64>, style: ProgressStyle, prefix: String) -> ProgressBar {
+    let bar = ProgressBar::with_draw_target(len, ProgressDrawTarget::hidden());
+    let multi = multi_progress();
+    if multi.is_hidden() {
+        // Still tracks the progress, without drawing or ticking
+        return bar;
+    }
+    let bar = multi.add(bar);
+    bar.set_style(style);
+    bar.set_prefix(prefix);
+    bar.enable_steady_tick(TICK_INTERVAL);
+    bar
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("Invalid progress bar template")
+        .progress_chars("=> ")
+}
+
+/// The aggregated progress of all the `file_count` files, which are `total_len` bytes combined
+pub fn aggregate_bar(file_count: usize, total_len: u64) -> ProgressBar {
+    add_bar(
+        Some(total_len),
+        style("{prefix:.bold} [{bar:40.green}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}"),
+        format!("{file_count} files"),
+    )
+}
+
+/// Tracks the progress of a single file, and of all files if there is an [aggregate_bar]
+#[derive(Debug)]
+pub struct FileProgress {
+    name: Box<str>,
+    bar: ProgressBar,
+    aggregate: Option<ProgressBar>,
+    /// When the latest [Event::Progress] was emitted, they're emitted as often as the bars are redrawn
+    last_event: Mutex<Option<Instant>>,
+    observer: SharedObserver,
+}
+
+impl FileProgress {
+    /// Progress of the file `name`, a file of `len` bytes if the length is known (e.g. not when it is read from stdin),
+    /// which is passed to `observer`
+    pub fn new(
+        name: &str,
+        len: Option<u64>,
+        aggregate: Option<&ProgressBar>,
+        observer: &SharedObserver,
+    ) -> Self {
+        let style = match len {
+            Some(_) => style(
+                "{prefix} [{bar:40.cyan}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}",
+            ),
+            None => style("{prefix} {spinner} {bytes} {binary_bytes_per_sec}"),
+        };
+        Self {
+            name: name.into(),
+            bar: add_bar(len, style, name.to_owned()),
+            aggregate: aggregate.cloned(),
+            last_event: Mutex::new(None),
+            observer: observer.clone(),
+        }
+    }
+
+    /// The name of the file
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The observer of the transfer the file is part of
+    pub fn observer(&self) -> &SharedObserver {
+        &self.observer
+    }
+
+    /// The length of the file, if it's known
+    pub fn length(&self) -> Option<u64> {
+        self.bar.length()
+    }
+
+    /// (Re)start the transfer after the first `offset` bytes, which were transferred before
+    pub fn start_from(&self, offset: u64) {
+        if let Some(aggregate) = &self.aggregate {
+            aggregate.set_position(aggregate.position() - self.bar.position() + offset);
+        }
+        self.bar.set_position(offset);
+        self.bar.reset_eta();
+    }
+
+    /// Count the rest of the file as done, without transferring it (e.g. if it's skipped)
+    pub fn skip(&self) {
+        if let (Some(aggregate), Some(len)) = (&self.aggregate, self.bar.length()) {
+            aggregate.inc(len.saturating_sub(self.bar.position()));
+        }
+    }
+
+    pub fn inc(&self, len: u64) {
+        self.bar.inc(len);
+        if let Some(aggregate) = &self.aggregate {
+            aggregate.inc(len);
+        }
+        if events::enabled() {
+            self.emit_progress();
+        }
+        self.observer.on_progress(&self.name, self.bar.position());
+    }
+
+    fn emit_progress(&self) {
+        let mut last_event = self.last_event.lock().expect("Progress lock poisoned");
+        let now = Instant::now();
+        if last_event.is_some_and(|last| now.duration_since(last) < TICK_INTERVAL) {
+            return;
+        }
+        *last_event = Some(now);
+        events::emit(&Event::Progress {
+            name: &self.name,
+            bytes: self.bar.position(),
+            bytes_per_sec: self.bar.per_sec() as u64,
+        });
+    }
+
+    /// A reader that counts the bytes read from `inner` as progress
+    pub fn wrap_read<R: Read>(&self, inner: R) -> ProgressReader<'_, R> {
+        ProgressReader {
+            inner,
+            progress: self,
+        }
+    }
+
+    /// A writer that counts the bytes written to `inner` as progress
+    pub fn wrap_write<W: Write>(&self, inner: W) -> ProgressWriter<'_, W> {
+        ProgressWriter {
+            inner,
+            progress: self,
+        }
+    }
+}
+
+// The outcome of the transfer is logged instead, also if it failed
+impl Drop for FileProgress {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Counts the bytes that are read as progress
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a FileProgress,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.progress.inc(read as u64);
+        Ok(read)
+    }
+}
+
+/// Counts the bytes that are written as progress
+pub struct ProgressWriter<'a, W> {
+    inner: W,
+    progress: &'a FileProgress,
+}
+
+impl<W: Write> Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.progress.inc(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_aggregate_counts_restarted_and_skipped_files_once() {
+        let aggregate = aggregate_bar(2, 300);
+        let first = FileProgress::new(
+            "first",
+            Some(100),
+            Some(&aggregate),
+            &SharedObserver::default(),
+        );
+        first.inc(60);
+        // Interrupted, and resumed after the first 40 B the server received
+        first.start_from(40);
+        assert_eq!(aggregate.position(), 40);
+        first.inc(60);
+        let second = FileProgress::new(
+            "second",
+            Some(200),
+            Some(&aggregate),
+            &SharedObserver::default(),
+        );
+        second.inc(10);
+        second.skip();
+        assert_eq!(aggregate.position(), 300);
+    }
+
+    #[test]
+    fn test_wrapped_reader_counts_read_bytes() -> io::Result<()> {
+        let progress = FileProgress::new("stdin", None, None, &SharedObserver::default());
+        let mut read = vec![];
+        progress.wrap_read(&b"content"[..]).read_to_end(&mut read)?;
+        assert_eq!(progress.bar.position(), 7);
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! Limit the rate at which content is sent or received, with a token bucket.
+//!
+//! The limit applies to the bytes on the wire, i.e. after compression and including the framing.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long the bucket can be left to fill up, so that a connection that was idle gets to send a burst of at most this long
+const MAX_BURST: Duration = Duration::from_millis(100);
+
+/// A limit of bytes per second, e.g. parsed from `--limit-rate 10M`
+///
+/// All the clones of a limit share the same bucket, so a limit that is used by multiple connections applies to
+/// their combined rate.
+#[derive(Clone)]
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0, "A rate limit must allow some bytes");
+        Self {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(TokenBucket::new(bytes_per_sec))),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Take `len` bytes from the bucket, blocking until the bytes stay within the limit
+    pub fn throttle(&self, len: usize) {
+        let wait = self
+            .bucket
+            .lock()
+            .expect("Rate limit lock poisoned")
+            .take(len as f64, Instant::now());
+        // Sleep without holding the lock, the bytes are already taken so anyone else waits for them too
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    /// Parse a number of bytes per second with an optional `K`, `M` or `G` suffix (powers of 1024)
+    fn from_str(rate: &str) -> Result<Self, Self::Err> {
+        let rate = rate.trim();
+        let (digits, multiplier) = match rate.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&rate[..i], 1 << 10),
+            Some((i, 'm' | 'M')) => (&rate[..i], 1 << 20),
+            Some((i, 'g' | 'G')) => (&rate[..i], 1 << 30),
+            _ => (rate, 1),
+        };
+        let bytes_per_sec = digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .ok_or_else(|| {
+                format!("invalid rate {rate:?}, expected bytes per second with an optional K, M or G suffix, e.g. 500K")
+            })?;
+        if bytes_per_sec == 0 {
+            return Err("the rate limit must be greater than 0".to_owned());
+        }
+        Ok(Self::new(bytes_per_sec))
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.bytes_per_sec)
+    }
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RateLimit({} B/s)", self.bytes_per_sec)
+    }
+}
+
+/// Fills with the allowed bytes per second, up to [MAX_BURST] worth of bytes.
+///
+/// Taking more bytes than there are puts the bucket in debt, which is paid by waiting until it is filled again.
+#[derive(Debug)]
+struct TokenBucket {
+    bytes_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        let capacity = bytes_per_sec * MAX_BURST.as_secs_f64();
+        Self {
+            bytes_per_sec,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `len` bytes at `now`, returns how long to wait before the bytes are within the limit
+    fn take(&mut self, len: f64, now: Instant) -> Duration {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.capacity);
+        self.tokens -= len;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+/// Reads or writes through `inner`, keeping within the rate limit (if any)
+pub struct Throttled<T> {
+    inner: T,
+    limit: Option<RateLimit>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, limit: Option<RateLimit>) -> Self {
+        Self { inner, limit }
+    }
+
+    fn throttle(&self, len: usize) {
+        if let Some(limit) = &self.limit {
+            limit.throttle(len);
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.throttle(read);
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.throttle(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_rate_with_suffix() {
+        let rate = |s: &str| s.parse::<RateLimit>().map(|r| r.bytes_per_sec());
+        assert_eq!(rate("1500"), Ok(1500));
+        assert_eq!(rate("500K"), Ok(500 * 1024));
+        assert_eq!(rate("10m"), Ok(10 * 1024 * 1024));
+        assert_eq!(rate("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(rate("0").is_err());
+        assert!(rate("1.5M").is_err());
+        assert!(rate("M").is_err());
+        assert!(rate("10T").is_err());
+        assert!(rate("99999999999G").is_err());
+    }
+
+    #[test]
+    fn test_bucket_waits_for_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000);
+        // The initial burst
+        assert_eq!(bucket.take(100.0, start), Duration::ZERO);
+        assert_eq!(bucket.take(500.0, start), Duration::from_millis(500));
+        // Half a second later the debt is paid
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take(0.0, later), Duration::ZERO);
+        // Idling doesn't fill the bucket beyond the maximum burst
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(200.0, much_later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_throttled_writer_keeps_within_limit() -> io::Result<()> {
+        let limit: RateLimit = "100K".parse().unwrap();
+        let mut writer = Throttled::new(io::sink(), Some(limit));
+        let start = Instant::now();
+        // 10 KiB burst, the remaining 40 KiB take at least 0.4 s
+        for _ in 0..50 {
+            writer.write_all(&[0; 1024])?;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(350));
+        Ok(())
+    }
+}
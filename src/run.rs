@@ -2,19 +2,23 @@ use anyhow::bail;
 
 use crate::config::{Command, Config};
 
-pub fn run(_cfg: &Config) -> anyhow::Result<()> {
-    if let Some(ref cmd) = _cfg.command {
+pub fn run(cfg: &Config) -> anyhow::Result<()> {
+    if let Some(ref cmd) = cfg.command {
         match cmd {
-            Command::Listen(ref args) => crate::server::listen(_cfg, args),
-            Command::Send(ref cmd) => crate::send::handle_send_cmd(cmd, _cfg),
-            Command::GetFreePort(ref a) => crate::get_free_port::handle_get_free_port(a),
+            Command::Listen(ref args) => crate::server::listen(cfg, args),
+            Command::Send(ref cmd) => crate::send::handle_send_cmd(cmd, cfg),
+            Command::GetFreePort(ref a) => {
+                crate::get_free_port::handle_get_free_port(a, cfg.output_format())
+            }
 
             #[cfg(feature = "mdns")]
-            Command::Mdns(ref cmd) => crate::mdns::handle_mdns_command(&cmd.subcmd),
+            Command::Mdns(ref cmd) => {
+                crate::mdns::handle_mdns_command(&cmd.subcmd, cfg.output_format())
+            }
 
             #[cfg(feature = "evaluate-compression")]
             Command::EvaluateCompression(ref args) => {
-                crate::evaluate_compression::evaluate_compression(args.clone())
+                crate::evaluate_compression::evaluate_compression(args.clone(), cfg.output_format())
             }
 
             #[cfg(feature = "ssh")]
@@ -26,7 +30,10 @@ pub fn run(_cfg: &Config) -> anyhow::Result<()> {
                     },
                     ssh::remote_info::RemoteInfo,
                 };
-                use std::{path::PathBuf, time::Duration};
+                use std::{
+                    path::{Path, PathBuf},
+                    time::Duration,
+                };
 
                 // Determine if the operation is local to remote or remote to local
                 let is_local_to_remote = args.is_sending();
@@ -41,8 +48,8 @@ pub fn run(_cfg: &Config) -> anyhow::Result<()> {
 
                 tracing::trace!("Sources: {:?}", args.sources);
                 tracing::trace!("Destination: {}", args.destination);
-                //println!("Recursive: {}", args.recursive);
-                //println!("Preserve Times: {}", args.preserve_times);
+                tracing::trace!("Recursive: {}", args.recursive);
+                tracing::trace!("Preserve: {}", args.preserve);
                 //println!("Verbose: {}", args.verbose);
                 tracing::trace!(
                     "Operation: {}",
@@ -53,6 +60,46 @@ pub fn run(_cfg: &Config) -> anyhow::Result<()> {
                     }
                 );
 
+                let remote_info = RemoteInfo::from_args(args, &remote_uri_components);
+
+                if is_remote_to_local {
+                    let remote_sources = args
+                        .sources
+                        .iter()
+                        .map(|src| {
+                            let components = parse_scp_style_uri(src)?;
+                            if components.user != remote_uri_components.user
+                                || components.host != remote_uri_components.host
+                            {
+                                bail!("All sources must be on the same remote, got '{src}' and '{first}'", first = args.sources[0]);
+                            }
+                            Ok(components.destination)
+                        })
+                        .collect::<anyhow::Result<Vec<PathBuf>>>()?;
+                    let remote_sources: Vec<&Path> =
+                        remote_sources.iter().map(PathBuf::as_path).collect();
+
+                    return crate::ssh::pull::run_ssh_pull(
+                        cfg,
+                        &remote_info,
+                        args.ssh_private_key_path.as_deref(),
+                        args.ssh_key_dir.as_deref(),
+                        args.tcp_port,
+                        args.mmap,
+                        &remote_sources,
+                        Path::new(&args.destination),
+                        args.recursive,
+                        args.preserve,
+                        args.resume,
+                        &args.compression,
+                        args.start_port,
+                        args.end_port,
+                        args.ssh_timeout_ms,
+                        args.limit_rate.as_ref(),
+                        args.sync_options(),
+                    );
+                }
+
                 let input_files: Vec<PathBuf> = args
                     .sources
                     .clone()
@@ -60,17 +107,18 @@ pub fn run(_cfg: &Config) -> anyhow::Result<()> {
                     .map(PathBuf::from)
                     .collect();
 
-                let remote_info = RemoteInfo::from_args(args, &remote_uri_components);
-
                 crate::ssh::run_ssh(
-                    _cfg,
+                    cfg,
                     &remote_info,
                     args.ssh_private_key_path.as_deref(),
                     args.ssh_key_dir.as_deref(),
                     args.tcp_port,
                     args.mmap,
                     &input_files,
+                    args.recursive,
                     true,
+                    args.preserve,
+                    args.resume,
                     &args.compression,
                     args.start_port,
                     args.end_port,
@@ -79,6 +127,8 @@ pub fn run(_cfg: &Config) -> anyhow::Result<()> {
                         20_u8,
                         PollAbortCondition::Timeout(Duration::from_secs(10)),
                     ),
+                    args.limit_rate.as_ref(),
+                    args.sync_options(),
                 )?;
 
                 Ok(())
@@ -1,18 +1,24 @@
-use crate::config::{
-    transfer::send::{SendArgs, SendCommand, SendIpArgs},
-    Config,
-};
 #[cfg(feature = "mdns")]
 use crate::{config::transfer::send::mdns::SendMdnsArgs, mdns::resolve::resolve_mdns_hostname};
+use crate::{
+    config::{
+        transfer::send::{SendArgs, SendCommand, SendIpArgs},
+        Config,
+    },
+    observer::SharedObserver,
+};
 
 use anyhow::Result;
 use client::run_client;
 
+pub mod builder;
 pub mod client;
+pub mod report;
+pub mod sources;
 pub mod util;
 
 pub fn handle_send_cmd(send_args: &SendArgs, _cfg: &Config) -> Result<()> {
-    match send_args.subcmd {
+    let report = match send_args.subcmd {
         SendCommand::Ip(SendIpArgs {
             ref ip,
             port,
@@ -22,10 +28,22 @@ pub fn handle_send_cmd(send_args: &SendArgs, _cfg: &Config) -> Result<()> {
             port,
             send_args.mmap,
             send_args.file.as_slice(),
+            send_args.recursive,
             send_args.prealloc(),
+            send_args.preserve,
+            send_args.resume,
+            send_args.delta,
+            send_args.sync_options(),
             compression,
             send_args.tcp_connect_mode(),
             None,
+            send_args.psk.as_ref(),
+            send_args.encrypt,
+            send_args.limit_rate.as_ref(),
+            send_args.jobs,
+            send_args.streams,
+            None,
+            &SharedObserver::default(),
         )?,
         #[cfg(feature = "mdns")]
         SendCommand::Mdns(SendMdnsArgs {
@@ -35,21 +53,35 @@ pub fn handle_send_cmd(send_args: &SendArgs, _cfg: &Config) -> Result<()> {
             port,
             compression,
         }) => {
-            if let Some(resolved_info) = resolve_mdns_hostname(hostname, timeout_ms, true)? {
-                if let Some(ip) = resolved_info.get_ip(ip_version) {
-                    run_client(
-                        *ip,
-                        port,
-                        send_args.mmap,
-                        send_args.file.as_slice(),
-                        send_args.prealloc(),
-                        compression,
-                        send_args.tcp_connect_mode(),
-                        None,
-                    )?;
-                }
-            }
+            let Some(resolved_info) = resolve_mdns_hostname(hostname, timeout_ms, true)? else {
+                return Ok(());
+            };
+            let Some(ip) = resolved_info.get_ip(ip_version) else {
+                return Ok(());
+            };
+            run_client(
+                *ip,
+                port,
+                send_args.mmap,
+                send_args.file.as_slice(),
+                send_args.recursive,
+                send_args.prealloc(),
+                send_args.preserve,
+                send_args.resume,
+                send_args.delta,
+                send_args.sync_options(),
+                compression,
+                send_args.tcp_connect_mode(),
+                None,
+                send_args.psk.as_ref(),
+                send_args.encrypt,
+                send_args.limit_rate.as_ref(),
+                send_args.jobs,
+                send_args.streams,
+                None,
+                &SharedObserver::default(),
+            )?
         }
-    }
-    Ok(())
+    };
+    report.print(_cfg.output_format())
 }
@@ -0,0 +1,339 @@
+//! Embed the client in another program, without parsing any command-line arguments.
+
+use std::{fmt, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use anyhow::bail;
+
+use crate::{
+    auth::PreSharedKey,
+    config::{compression::Compression, transfer::util::TcpConnectMode},
+    observer::{SharedObserver, TransferObserver},
+    rate_limit::RateLimit,
+    send::{
+        client::run_client,
+        report::{FileReport, OnSent, TransferReport},
+    },
+    sync::SyncOptions,
+};
+
+/// A client that sends files to a server, see [Client::builder]
+#[derive(Debug, Clone)]
+pub struct Client {
+    target: SocketAddr,
+    settings: Settings,
+}
+
+/// Configures a [Client], the defaults match `qft send` except that it connects only once instead of polling
+#[derive(Debug, Clone, Default)]
+pub struct ClientBuilder {
+    target: Option<SocketAddr>,
+    settings: Settings,
+}
+
+#[derive(Clone)]
+struct Settings {
+    files: Vec<PathBuf>,
+    recursive: bool,
+    mmap: bool,
+    prealloc: bool,
+    preserve: bool,
+    resume: bool,
+    delta: bool,
+    sync: Option<SyncOptions>,
+    compression: Option<Compression>,
+    connect_mode: TcpConnectMode,
+    psk: Option<PreSharedKey>,
+    encrypt: bool,
+    limit_rate: Option<RateLimit>,
+    jobs: u16,
+    streams: u16,
+    on_sent: Option<Arc<OnSent>>,
+    observer: SharedObserver,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            files: vec![],
+            recursive: false,
+            mmap: false,
+            prealloc: true,
+            preserve: false,
+            resume: false,
+            delta: false,
+            sync: None,
+            compression: None,
+            connect_mode: TcpConnectMode::OneShot,
+            psk: None,
+            encrypt: false,
+            limit_rate: None,
+            jobs: 1,
+            streams: 1,
+            on_sent: None,
+            observer: SharedObserver::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("files", &self.files)
+            .field("recursive", &self.recursive)
+            .field("mmap", &self.mmap)
+            .field("prealloc", &self.prealloc)
+            .field("preserve", &self.preserve)
+            .field("resume", &self.resume)
+            .field("delta", &self.delta)
+            .field("sync", &self.sync)
+            .field("compression", &self.compression)
+            .field("connect_mode", &self.connect_mode)
+            .field("psk", &self.psk)
+            .field("encrypt", &self.encrypt)
+            .field("limit_rate", &self.limit_rate)
+            .field("jobs", &self.jobs)
+            .field("streams", &self.streams)
+            .field("observer", &self.observer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Client {
+    /// Configure a client, which needs at least a [ClientBuilder::target], e.g.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let report = quick_file_transfer::Client::builder()
+    ///     .target(([192, 168, 0, 2], 49152))
+    ///     .file("data.bin")
+    ///     .build()?
+    ///     .send()?;
+    /// for file in &report.files {
+    ///     println!("{}: {:?} in {:?}", file.name, file.status, file.duration());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// The address of the server the files are sent to
+    pub fn target(&self) -> SocketAddr {
+        self.target
+    }
+
+    /// Send the files (or stdin if no files were given) and report what became of each of them.
+    ///
+    /// Fails if the transfer as a whole fails, files that failed on their own are reported as such,
+    /// see [TransferReport::ensure_no_failures].
+    pub fn send(&self) -> anyhow::Result<TransferReport> {
+        let s = &self.settings;
+        run_client(
+            self.target.ip(),
+            self.target.port(),
+            s.mmap,
+            &s.files,
+            s.recursive,
+            s.prealloc && !s.files.is_empty(),
+            s.preserve,
+            s.resume,
+            s.delta,
+            s.sync,
+            s.compression,
+            s.connect_mode,
+            None,
+            s.psk.as_ref(),
+            s.encrypt,
+            s.limit_rate.as_ref(),
+            s.jobs,
+            s.streams,
+            s.on_sent.as_deref(),
+            &s.observer,
+        )
+    }
+}
+
+impl ClientBuilder {
+    /// The address of the server, e.g. `([127, 0, 0, 1], 49152)`
+    pub fn target(mut self, target: impl Into<SocketAddr>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Send this file (or directory with [ClientBuilder::recursive]), can be called any number of times
+    pub fn file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.files.push(path.into());
+        self
+    }
+
+    /// Send these files, in addition to any that were already added
+    pub fn files<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.settings
+            .files
+            .extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Send the content of directories
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.settings.recursive = recursive;
+        self
+    }
+
+    /// Read the files through memory maps
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.settings.mmap = mmap;
+        self
+    }
+
+    /// Have the server preallocate each file before receiving it, enabled by default
+    pub fn prealloc(mut self, prealloc: bool) -> Self {
+        self.settings.prealloc = prealloc;
+        self
+    }
+
+    /// Preserve the permissions and times of the files
+    pub fn preserve(mut self, preserve: bool) -> Self {
+        self.settings.preserve = preserve;
+        self
+    }
+
+    /// Resume files that were partially received before
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.settings.resume = resume;
+        self
+    }
+
+    /// Only send the changed blocks of files that exist on the server
+    pub fn delta(mut self, delta: bool) -> Self {
+        self.settings.delta = delta;
+        self
+    }
+
+    /// Only send the files that are missing or stale on the server
+    pub fn sync(mut self, sync: SyncOptions) -> Self {
+        self.settings.sync = Some(sync);
+        self
+    }
+
+    /// Compress the content as it is sent
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.settings.compression = Some(compression);
+        self
+    }
+
+    /// How to connect to the server, e.g. to poll until it's up
+    pub fn connect_mode(mut self, connect_mode: TcpConnectMode) -> Self {
+        self.settings.connect_mode = connect_mode;
+        self
+    }
+
+    /// Prove to the server that the client knows this key
+    pub fn psk(mut self, psk: PreSharedKey) -> Self {
+        self.settings.psk = Some(psk);
+        self
+    }
+
+    /// Encrypt the connection
+    pub fn encrypt(mut self, encrypt: bool) -> Self {
+        self.settings.encrypt = encrypt;
+        self
+    }
+
+    /// Limit the bandwidth (after compression)
+    pub fn limit_rate(mut self, limit_rate: RateLimit) -> Self {
+        self.settings.limit_rate = Some(limit_rate);
+        self
+    }
+
+    /// Send up to this many files at the same time
+    pub fn jobs(mut self, jobs: u16) -> Self {
+        self.settings.jobs = jobs;
+        self
+    }
+
+    /// Split large files into up to this many ranges that are sent at the same time
+    pub fn streams(mut self, streams: u16) -> Self {
+        self.settings.streams = streams;
+        self
+    }
+
+    /// Called with the report of each file in the order they were given, as soon as its outcome is known
+    pub fn on_sent<F>(mut self, on_sent: F) -> Self
+    where
+        F: Fn(&FileReport) + Send + Sync + 'static,
+    {
+        self.settings.on_sent = Some(Arc::new(on_sent));
+        self
+    }
+
+    /// Observe the sent files and their progress
+    pub fn observer(mut self, observer: impl TransferObserver + 'static) -> Self {
+        self.settings.observer = SharedObserver::new(observer);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Client> {
+        let Self { target, settings } = self;
+        let Some(target) = target else {
+            bail!("A client needs a target to send to");
+        };
+        if settings.jobs == 0 || settings.streams == 0 {
+            bail!("A client needs at least one job and one stream");
+        }
+        if settings.streams > 1 && settings.resume {
+            bail!("Files that are sent in ranges can't be resumed");
+        }
+        if settings.streams > 1 && settings.delta {
+            bail!("Files that are sent in ranges can't be sent as deltas");
+        }
+        Ok(Client { target, settings })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_client_needs_a_target() {
+        assert_eq!(
+            Client::builder().file("f").build().unwrap_err().to_string(),
+            "A client needs a target to send to"
+        );
+    }
+
+    #[test]
+    fn test_resumed_client_needs_a_single_stream() {
+        let err = Client::builder()
+            .target(([127, 0, 0, 1], 49152))
+            .resume(true)
+            .streams(2)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Files that are sent in ranges can't be resumed"
+        );
+    }
+
+    #[test]
+    fn test_delta_client_needs_a_single_stream() {
+        let err = Client::builder()
+            .target(([127, 0, 0, 1], 49152))
+            .delta(true)
+            .streams(2)
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Files that are sent in ranges can't be sent as deltas"
+        );
+    }
+}
@@ -1,29 +1,58 @@
 use std::{
-    fs::File,
-    io::{Read, Write},
-    net::{IpAddr, TcpStream},
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    net::IpAddr,
+    ops::Range,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
+use indicatif::ProgressBar;
 
 use crate::{
+    auth::PreSharedKey,
+    checksum::{Checksum, HashingReader, HashingWriter, PrefixHash},
     config::{
         self,
         compression::{Bzip2Args, Compression, GzipArgs, XzArgs},
         transfer::{
-            command::{DestinationMode, ServerCommand, ServerResult},
+            command::{DestinationMode, ResolvedDestination, ServerCommand, ServerResult},
+            handshake::Capabilities,
             util::TcpConnectMode,
         },
     },
+    delta::{DeltaReader, Signature},
+    events::{self, Event},
+    framed_stream::{FramedReader, FramedWriter},
     mmap_reader::MemoryMapWrapper,
-    send::util::{file_with_bufreader, qft_connect_to_server, send_command, tcp_bufwriter},
-    util::{format_data_size, incremental_rw, read_server_response},
-    TCP_STREAM_BUFSIZE,
+    observer::SharedObserver,
+    preserve::{FileMetadata, Timestamp},
+    progress::{self, FileProgress},
+    rate_limit::{RateLimit, Throttled},
+    send::{
+        report::{FileReport, FileStatus, OnSent, TransferReport},
+        sources::{SourceFile, Sources},
+        util::{file_with_bufreader, qft_connect_to_server, send_command, tcp_bufwriter},
+    },
+    sync::{Manifest, SyncOptions, SyncPlan},
+    transport::QftStream,
+    util::{format_data_size, incremental_rw, read_server_reply, read_server_response},
+    BUFFERED_RW_BUFSIZE, TCP_STREAM_BUFSIZE,
 };
 
+/// How many times the client reconnects to resume a single file that keeps getting interrupted
+const MAX_RESUME_RECONNECTS: u32 = 5;
+
+/// Files are only split into ranges of at least this many bytes, smaller ranges aren't worth a connection of their own
+const MIN_RANGE_LEN: u64 = 1 << 20;
+
 /// If poll is specified, poll the server with the specified interval, else exut on the first failure to establish a connection.
 #[allow(clippy::too_many_arguments)]
 pub fn run_client(
@@ -31,12 +60,70 @@ pub fn run_client(
     port: u16,
     use_mmap: bool,
     input_files: &[PathBuf],
+    recursive: bool,
     prealloc: bool,
+    preserve: bool,
+    resume: bool,
+    delta: bool,
+    sync: Option<SyncOptions>,
     compression: Option<Compression>,
     connect_mode: TcpConnectMode,
     remote_dest: Option<&Path>,
-) -> anyhow::Result<()> {
-    let mut initial_tcp_stream = qft_connect_to_server((ip, port), connect_mode)?;
+    psk: Option<&PreSharedKey>,
+    encrypt: bool,
+    limit_rate: Option<&RateLimit>,
+    jobs: u16,
+    streams: u16,
+    on_sent: Option<&OnSent>,
+    observer: &SharedObserver,
+) -> anyhow::Result<TransferReport> {
+    let mut sources = Sources::collect(input_files, recursive)?;
+    let (mut initial_tcp_stream, negotiated) =
+        qft_connect_to_server((ip, port), connect_mode, psk, encrypt, ip)?;
+    tracing::debug!("Negotiated protocol: {negotiated:?}");
+    let capabilities = negotiated.capabilities;
+
+    if let Some(compression) = compression {
+        if !capabilities.supports_compression(compression.variant()) {
+            bail!(
+                "The remote qft does not support {} compression",
+                compression.variant_as_str()
+            );
+        }
+    }
+    let prealloc = if prealloc && !capabilities.contains(Capabilities::PREALLOC) {
+        log::warn!("The remote qft does not support preallocation, continuing without it");
+        false
+    } else {
+        prealloc
+    };
+    if preserve && !capabilities.contains(Capabilities::PRESERVE) {
+        bail!("The remote qft does not support preserving permissions and times");
+    }
+    if resume && !capabilities.contains(Capabilities::RESUME) {
+        bail!("The remote qft does not support resuming transfers");
+    }
+    let delta = if delta && !capabilities.contains(Capabilities::DELTA) {
+        log::warn!("The remote qft does not support delta transfers, sending the files in full");
+        false
+    } else {
+        delta
+    };
+    // The ranges are only moved into place once each of them is verified
+    let streams = if streams > 1
+        && !capabilities.contains(Capabilities::RANGES.with(Capabilities::CHECKSUM))
+    {
+        log::warn!("The remote qft does not support receiving files in ranges, sending each file over a single connection");
+        1
+    } else {
+        streams
+    };
+    if sources.has_dirs() && !capabilities.contains(Capabilities::RECURSIVE) {
+        bail!("The remote qft does not support receiving directories");
+    }
+    if sync.is_some() && !capabilities.contains(Capabilities::SYNC) {
+        bail!("The remote qft does not support syncing");
+    }
 
     // Validate remote path before start
     if let Some(remote_dest) = remote_dest {
@@ -44,7 +131,12 @@ pub fn run_client(
         if input_files.is_empty() {
             bail!("Error: no files to send");
         }
-        let dest_mode: DestinationMode = if input_files.len() == 1 {
+        if !capabilities.contains(Capabilities::DESTINATION_VALIDATION) {
+            bail!("The remote qft does not support validating the destination path");
+        }
+        let dest_mode: DestinationMode = if sources.has_dirs() {
+            DestinationMode::RecusiveDirectory
+        } else if sources.files.len() == 1 {
             DestinationMode::SingleFile
         } else {
             DestinationMode::MultipleFiles
@@ -66,76 +158,743 @@ pub fn run_client(
         }
     }
 
-    let cmd_free_port = ServerCommand::GetFreePort((None, None));
-    send_command(&mut initial_tcp_stream, &cmd_free_port)?;
-    let mut free_port_buf: [u8; 2] = [0; 2];
-    if let Err(e) = initial_tcp_stream.read_exact(&mut free_port_buf) {
-        log::trace!("Initial tcp read of free port response failed: {e}, retrying in 100 ms...");
-        thread::sleep(Duration::from_millis(100));
-        initial_tcp_stream.read_exact(&mut free_port_buf)?;
+    let mut report = TransferReport::default();
+    if let Some(sync) = sync {
+        (report.up_to_date, report.deleted) =
+            sync_sources(&mut initial_tcp_stream, &mut sources, sync)?;
     }
-    let free_port = u16::from_be_bytes(free_port_buf);
-    tracing::info!("Got free port: {free_port}");
+
+    for dir in &sources.dirs {
+        log::debug!("Creating remote directory: {dir}");
+        send_command(
+            &mut initial_tcp_stream,
+            &ServerCommand::CreateDir(dir.to_owned()),
+        )?;
+        if let ServerResult::Err(e) = read_server_response(&mut initial_tcp_stream)? {
+            bail!(e);
+        }
+    }
+
+    let free_port = query_free_port(&mut initial_tcp_stream)?;
 
     if input_files.is_empty() {
-        let mut tcp_stream = qft_connect_to_server((ip, free_port), connect_mode)?;
+        let (mut tcp_stream, _) =
+            qft_connect_to_server((ip, free_port), connect_mode, psk, encrypt, ip)?;
         let cmd_receive_data =
             ServerCommand::ReceiveData(0, "stdin".to_string(), compression.map(|c| c.variant()));
         send_command(&mut tcp_stream, &cmd_receive_data)?;
-        let transferred_len =
-            transfer_data((ip, port), &mut tcp_stream, compression, None, use_mmap)?;
+        let progress = FileProgress::new("stdin", None, None, observer);
+        let start = Instant::now();
+        events::emit(&Event::FileStart {
+            name: "stdin",
+            size: None,
+        });
+        observer.on_file_start("stdin", None);
+        let (transferred_len, checksum) = transfer_data(
+            (ip, port),
+            &mut tcp_stream,
+            compression,
+            None,
+            use_mmap,
+            PrefixHash::default(),
+            &progress,
+            limit_rate,
+        )?;
+        tcp_stream.flush()?;
+        if capabilities.contains(Capabilities::CHECKSUM) {
+            verify_checksum(&mut tcp_stream, checksum)?;
+            log::debug!("Checksum {checksum} verified by server");
+        }
         log::info!(
-            "Sent {} [{transferred_len} B]",
+            "Sent stdin {} [{transferred_len} B]",
             format_data_size(transferred_len)
         );
+        events::emit(&Event::file_done(
+            "stdin",
+            transferred_len,
+            Some(checksum),
+            start.elapsed(),
+        ));
+        observer.on_file_complete("stdin", transferred_len, start.elapsed());
+        let file = FileReport::new(
+            "-".to_owned(),
+            "stdin".to_owned(),
+            transferred_len,
+            start.elapsed(),
+            FileStatus::Sent {
+                transferred: transferred_len,
+            },
+        );
+        if let Some(on_sent) = on_sent {
+            on_sent(&file);
+        }
+        report.files.push(file);
     } else {
-        let mut fcount = input_files.len();
-        log::info!("Sending {fcount} file(s)");
-
-        for f in input_files {
-            let mut tcp_stream = qft_connect_to_server((ip, free_port), connect_mode)?;
-
-            fcount -= 1;
-            let fname: String = f.file_name().unwrap().to_str().unwrap().to_owned();
-            if prealloc {
-                let file_size = File::open(f)?.metadata()?.len();
-                tracing::debug!(
-                    "Requesting preallocation of file of size {} [{file_size} B]",
-                    format_data_size(file_size)
+        let file_count = sources.files.len();
+        log::info!("Sending {file_count} file(s)");
+        let aggregate = if file_count > 1 {
+            let total_len = sources
+                .files
+                .iter()
+                .map(|f| fs::metadata(&f.path).map(|md| md.len()))
+                .sum::<io::Result<u64>>()?;
+            Some(progress::aggregate_bar(file_count, total_len))
+        } else {
+            None
+        };
+        let opts = FileSendOptions {
+            ip,
+            port,
+            connect_mode,
+            psk,
+            encrypt,
+            capabilities,
+            compression,
+            use_mmap,
+            prealloc,
+            preserve,
+            resume,
+            delta,
+            limit_rate,
+            aggregate: aggregate.as_ref(),
+            observer,
+        };
+        // Each job sends its files over the port of its own child on the server, and its ranges over children of their own
+        let mut free_ports = vec![free_port];
+        for _ in 1..usize::from(jobs).min(file_count) {
+            free_ports.push(query_free_port(&mut initial_tcp_stream)?);
+        }
+        let mut job_ports = Vec::with_capacity(free_ports.len());
+        for free_port in free_ports {
+            let range_ports = if streams > 1 {
+                (0..streams)
+                    .map(|_| query_free_port(&mut initial_tcp_stream))
+                    .collect::<anyhow::Result<Vec<u16>>>()?
+            } else {
+                vec![]
+            };
+            job_ports.push((free_port, range_ports));
+        }
+        log::debug!(
+            "Sending with {} job(s) of up to {streams} stream(s)",
+            job_ports.len()
+        );
+
+        let next_file = AtomicUsize::new(0);
+        let ordered_report = Mutex::new(OrderedReport::new(&sources.files, on_sent));
+        thread::scope(|scope| {
+            for (free_port, range_ports) in job_ports {
+                let (files, next_file, report, opts) =
+                    (&sources.files, &next_file, &ordered_report, &opts);
+                scope.spawn(move || loop {
+                    let i = next_file.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = files.get(i) else {
+                        break;
+                    };
+                    let flen = fs::metadata(&file.path).map(|md| md.len()).unwrap_or(0);
+                    let ranges = split_into_ranges(flen, range_ports.len());
+                    let start = Instant::now();
+                    events::emit(&Event::FileStart {
+                        name: &file.name,
+                        size: Some(flen),
+                    });
+                    opts.observer.on_file_start(&file.name, Some(flen));
+                    let outcome = if ranges.len() > 1 {
+                        send_file_in_ranges(opts, free_port, &range_ports, file, &ranges)
+                    } else {
+                        send_file(opts, free_port, file, file_count - 1 - i)
+                    };
+                    emit_outcome(opts.observer, &file.name, flen, start.elapsed(), &outcome);
+                    report.lock().expect("Report lock poisoned").complete(
+                        i,
+                        flen,
+                        start.elapsed(),
+                        outcome,
+                    );
+                });
+            }
+        });
+        report.files = ordered_report
+            .into_inner()
+            .expect("Report lock poisoned")
+            .reports;
+    }
+
+    send_command(&mut initial_tcp_stream, &ServerCommand::EndOfTransfer)?;
+    let server_result = query_server_result(&mut initial_tcp_stream);
+    // The files that failed are what the caller reports then
+    if !report.has_failures() {
+        server_result?;
+    }
+    Ok(report)
+}
+
+/// Ask the server which of the `sources` are missing or stale, and only keep those.
+///
+/// Returns the names of the files that are up to date, and of the files and directories the server deleted.
+fn sync_sources(
+    initial_tcp_stream: &mut QftStream,
+    sources: &mut Sources,
+    sync: SyncOptions,
+) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let manifest = Manifest::of(sources, sync.checksum)?;
+    send_command(initial_tcp_stream, &ServerCommand::Sync(sync))?;
+    let mut framed_writer = FramedWriter::new(tcp_bufwriter(&mut *initial_tcp_stream));
+    bincode::serialize_into(&mut framed_writer, &manifest)?;
+    framed_writer.finish()?;
+
+    let mut framed_reader = FramedReader::new(&mut *initial_tcp_stream);
+    let plan: Result<SyncPlan, String> = bincode::deserialize_from(&mut framed_reader)?;
+    framed_reader.finish()?;
+    let plan = match plan {
+        Ok(plan) => plan,
+        Err(e) => bail!(e),
+    };
+    for name in &plan.deleted {
+        log::info!("Deleted {name} on the server");
+    }
+    let file_count = sources.files.len();
+    log::info!(
+        "{} of {file_count} file(s) up to date",
+        file_count.saturating_sub(plan.send.len())
+    );
+    let send: HashSet<u32> = plan.send.into_iter().collect();
+    let mut up_to_date = vec![];
+    let mut idx = 0;
+    sources.files.retain(|file| {
+        let keep = send.contains(&idx);
+        if !keep {
+            up_to_date.push(file.name.clone());
+        }
+        idx += 1;
+        keep
+    });
+    Ok((up_to_date, plan.deleted))
+}
+
+/// Emit the [Event] of the `outcome` of sending the file `name` of `flen` bytes, which took `elapsed`, and pass it to `observer`
+fn emit_outcome(
+    observer: &SharedObserver,
+    name: &str,
+    flen: u64,
+    elapsed: Duration,
+    outcome: &anyhow::Result<FileOutcome>,
+) {
+    let event = match outcome {
+        Ok(FileOutcome::Sent { checksum, .. }) => {
+            observer.on_file_complete(name, flen, elapsed);
+            Event::file_done(name, flen, *checksum, elapsed)
+        }
+        Ok(FileOutcome::Skipped(reason)) => {
+            observer.on_file_skipped(name, reason);
+            Event::FileSkipped { name, reason }
+        }
+        Err(e) => {
+            observer.on_error(Some(name), e);
+            Event::Error {
+                name: Some(name),
+                message: e.to_string(),
+            }
+        }
+    };
+    events::emit(&event);
+}
+
+/// The settings that each file of a transfer is sent with
+#[derive(Clone, Copy)]
+struct FileSendOptions<'a> {
+    ip: IpAddr,
+    port: u16,
+    connect_mode: TcpConnectMode,
+    psk: Option<&'a PreSharedKey>,
+    encrypt: bool,
+    capabilities: Capabilities,
+    compression: Option<Compression>,
+    use_mmap: bool,
+    prealloc: bool,
+    preserve: bool,
+    resume: bool,
+    delta: bool,
+    limit_rate: Option<&'a RateLimit>,
+    aggregate: Option<&'a ProgressBar>,
+    observer: &'a SharedObserver,
+}
+
+/// What became of a file that was sent
+#[derive(Debug)]
+enum FileOutcome {
+    /// `len` bytes were sent (after compression), `checksum` is of the whole content if it's known
+    Sent {
+        len: u64,
+        checksum: Option<Checksum>,
+    },
+    /// The server refused the file because of its overwrite policy
+    Skipped(Box<str>),
+}
+
+/// Logs the outcome of each file in the order of the files, as soon as the outcome of every file before it is known.
+///
+/// Keeps the output readable when files are sent by multiple jobs.
+struct OrderedReport<'a> {
+    files: &'a [SourceFile],
+    outcomes: Vec<Option<(u64, Duration, anyhow::Result<FileOutcome>)>>,
+    /// The first file that isn't logged yet
+    next: usize,
+    /// The reports of the files that are logged
+    reports: Vec<FileReport>,
+    /// Called with the report of each file as it's logged
+    on_sent: Option<&'a OnSent>,
+}
+
+impl<'a> OrderedReport<'a> {
+    fn new(files: &'a [SourceFile], on_sent: Option<&'a OnSent>) -> Self {
+        Self {
+            files,
+            outcomes: files.iter().map(|_| None).collect(),
+            next: 0,
+            reports: Vec::with_capacity(files.len()),
+            on_sent,
+        }
+    }
+
+    /// Complete the file at `index` of `size` bytes, that took `elapsed` to send
+    fn complete(
+        &mut self,
+        index: usize,
+        size: u64,
+        elapsed: Duration,
+        outcome: anyhow::Result<FileOutcome>,
+    ) {
+        self.outcomes[index] = Some((size, elapsed, outcome));
+        while let Some((size, elapsed, outcome)) =
+            self.outcomes.get_mut(self.next).and_then(Option::take)
+        {
+            let SourceFile { path, name } = &self.files[self.next];
+            let file = path.display();
+            let status = match outcome {
+                Ok(FileOutcome::Sent { len, .. }) => {
+                    log::info!("Sent {file} {} [{len} B]", format_data_size(len));
+                    FileStatus::Sent { transferred: len }
+                }
+                Ok(FileOutcome::Skipped(reason)) => {
+                    log::warn!("Skipped {file}: {reason}");
+                    FileStatus::Skipped {
+                        reason: reason.into(),
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed sending {file}: {e}");
+                    FileStatus::Failed {
+                        error: e.to_string(),
+                    }
+                }
+            };
+            let report = FileReport::new(file.to_string(), name.clone(), size, elapsed, status);
+            if let Some(on_sent) = self.on_sent {
+                on_sent(&report);
+            }
+            self.reports.push(report);
+            self.next += 1;
+        }
+    }
+}
+
+/// Send a single file over a new connection to the child at `free_port`, `remaining` is the number of files after this one
+fn send_file(
+    opts: &FileSendOptions,
+    free_port: u16,
+    SourceFile {
+        path: f,
+        name: fname,
+    }: &SourceFile,
+    remaining: usize,
+) -> anyhow::Result<FileOutcome> {
+    let flen = fs::metadata(f)?.len();
+    let progress = FileProgress::new(fname, Some(flen), opts.aggregate, opts.observer);
+    if opts.delta && flen > 0 {
+        if let Some(outcome) = send_file_delta(opts, free_port, f, fname, &progress)? {
+            return Ok(outcome);
+        }
+    }
+    let mut reconnects = 0;
+    let (mut tcp_stream, (transferred_len, checksum)) = loop {
+        let (mut tcp_stream, _) = qft_connect_to_server(
+            (opts.ip, free_port),
+            opts.connect_mode,
+            opts.psk,
+            opts.encrypt,
+            opts.ip,
+        )?;
+
+        let resume_from = if opts.resume {
+            query_resume_prefix(&mut tcp_stream, f, fname)?
+        } else {
+            None
+        };
+        // The server only resumes content that doesn't replace an existing file the overwrite policy protects
+        if resume_from.is_none() && opts.capabilities.contains(Capabilities::OVERWRITE_POLICY) {
+            if let ResolvedDestination::Skipped(reason) =
+                resolve_destination(&mut tcp_stream, f, fname)?
+            {
+                progress.skip();
+                return Ok(FileOutcome::Skipped(reason));
+            }
+        }
+        // Preallocating would truncate the content that is resumed
+        if opts.prealloc && resume_from.is_none() {
+            tracing::debug!(
+                "Requesting preallocation of file of size {} [{flen} B]",
+                format_data_size(flen)
+            );
+            send_command(
+                &mut tcp_stream,
+                &ServerCommand::Prealloc(flen, fname.to_owned()),
+            )?;
+        }
+
+        if opts.preserve {
+            let metadata = FileMetadata::from_path(f)?;
+            send_command(&mut tcp_stream, &ServerCommand::SetMetadata(metadata))?;
+        }
+
+        log::trace!("Sending receive data command");
+        let cmd_receive_data = ServerCommand::ReceiveData(
+            remaining as u32,
+            fname.clone(),
+            opts.compression.map(|c| c.variant()),
+        );
+        send_command(&mut tcp_stream, &cmd_receive_data)?;
+
+        match transfer_data(
+            (opts.ip, opts.port),
+            &mut tcp_stream,
+            opts.compression,
+            Some(f),
+            opts.use_mmap,
+            resume_from.unwrap_or_default(),
+            &progress,
+            opts.limit_rate,
+        ) {
+            Ok(transferred) => break (tcp_stream, transferred),
+            Err(e) if opts.resume && reconnects < MAX_RESUME_RECONNECTS => {
+                reconnects += 1;
+                log::warn!(
+                    "Transfer of {file} interrupted: {e}, reconnecting to resume ({reconnects}/{MAX_RESUME_RECONNECTS})",
+                    file = f.display()
                 );
-                send_command(
-                    &mut tcp_stream,
-                    &ServerCommand::Prealloc(
-                        file_size,
-                        f.file_name().unwrap().to_string_lossy().into(),
-                    ),
-                )?;
+                thread::sleep(Duration::from_millis(100));
             }
+            Err(e) => return Err(e),
+        }
+    };
+    tcp_stream.flush()?;
 
-            log::trace!("Sending receive data command");
-            let cmd_receive_data =
-                ServerCommand::ReceiveData(fcount as u32, fname, compression.map(|c| c.variant()));
-            send_command(&mut tcp_stream, &cmd_receive_data)?;
+    if opts.capabilities.contains(Capabilities::CHECKSUM) {
+        verify_checksum(&mut tcp_stream, checksum)?;
+        log::debug!("Checksum {checksum} verified by server");
+    }
+    Ok(FileOutcome::Sent {
+        len: transferred_len,
+        checksum: Some(checksum),
+    })
+}
+
+/// Send `file` as a delta against the existing file on the server, returns [None] if the server has no file to apply a delta to
+fn send_file_delta(
+    opts: &FileSendOptions,
+    free_port: u16,
+    file: &Path,
+    fname: &str,
+    progress: &FileProgress,
+) -> anyhow::Result<Option<FileOutcome>> {
+    let (mut tcp_stream, _) = qft_connect_to_server(
+        (opts.ip, free_port),
+        opts.connect_mode,
+        opts.psk,
+        opts.encrypt,
+        opts.ip,
+    )?;
+    send_command(
+        &mut tcp_stream,
+        &ServerCommand::GetSignature(fname.to_owned()),
+    )?;
+    let mut framed_reader = FramedReader::new(&mut tcp_stream);
+    let signature: Signature = bincode::deserialize_from(&mut framed_reader)?;
+    framed_reader.finish()?;
+    if signature.is_empty() {
+        log::debug!("The server has no existing {fname} to apply a delta to, sending all of it");
+        return Ok(None);
+    }
+    if opts.capabilities.contains(Capabilities::OVERWRITE_POLICY) {
+        if let ResolvedDestination::Skipped(reason) =
+            resolve_destination(&mut tcp_stream, file, fname)?
+        {
+            progress.skip();
+            return Ok(Some(FileOutcome::Skipped(reason)));
+        }
+    }
+    if opts.preserve {
+        let metadata = FileMetadata::from_path(file)?;
+        send_command(&mut tcp_stream, &ServerCommand::SetMetadata(metadata))?;
+    }
+    send_command(
+        &mut tcp_stream,
+        &ServerCommand::ReceiveDelta(fname.to_owned(), opts.compression.map(|c| c.variant())),
+    )?;
+
+    let mmap = MemoryMapWrapper::new(file)?;
+    let content = mmap.borrow_full();
+    let mut framed_tcp_stream = FramedWriter::new(Throttled::new(
+        tcp_bufwriter(&mut tcp_stream),
+        opts.limit_rate.cloned(),
+    ));
+    let mut delta = DeltaReader::new(content, &signature, progress);
+    let len = encode_into(&mut framed_tcp_stream, &mut delta, opts.compression)?;
+    framed_tcp_stream.finish()?;
+    log::debug!(
+        "Sent {file} as a delta, {} [{matched} B] matched the existing file",
+        format_data_size(delta.matched()),
+        matched = delta.matched(),
+        file = file.display()
+    );
+
+    let checksum = if opts.capabilities.contains(Capabilities::CHECKSUM) {
+        let checksum = Checksum::of(content);
+        verify_checksum(&mut tcp_stream, checksum)?;
+        log::debug!("Checksum {checksum} verified by server");
+        Some(checksum)
+    } else {
+        None
+    };
+    Ok(Some(FileOutcome::Sent { len, checksum }))
+}
+
+/// Split a file of `flen` bytes into at most `streams` ranges of at least [MIN_RANGE_LEN] bytes
+fn split_into_ranges(flen: u64, streams: usize) -> Vec<Range<u64>> {
+    let count = (flen / MIN_RANGE_LEN).clamp(1, streams.max(1) as u64);
+    let range_len = flen.div_ceil(count);
+    (0..count)
+        .map(|i| i * range_len..((i + 1) * range_len).min(flen))
+        .collect()
+}
+
+/// Send a single file split into `ranges`, each range over a new connection to one of the children at `range_ports`.
+///
+/// The file is prepared and moved into place over a connection to the child at `free_port`, once every range is verified.
+fn send_file_in_ranges(
+    opts: &FileSendOptions,
+    free_port: u16,
+    range_ports: &[u16],
+    SourceFile {
+        path: f,
+        name: fname,
+    }: &SourceFile,
+    ranges: &[Range<u64>],
+) -> anyhow::Result<FileOutcome> {
+    let mmap = MemoryMapWrapper::new(f)?;
+    let flen = mmap.flen() as u64;
+    let progress = FileProgress::new(fname, Some(flen), opts.aggregate, opts.observer);
+    let (mut tcp_stream, _) = qft_connect_to_server(
+        (opts.ip, free_port),
+        opts.connect_mode,
+        opts.psk,
+        opts.encrypt,
+        opts.ip,
+    )?;
+    let mut range_name = fname.to_owned();
+    if opts.capabilities.contains(Capabilities::OVERWRITE_POLICY) {
+        match resolve_destination(&mut tcp_stream, f, fname)? {
+            ResolvedDestination::Skipped(reason) => {
+                progress.skip();
+                return Ok(FileOutcome::Skipped(reason));
+            }
+            ResolvedDestination::Renamed(name) => range_name = name,
+            ResolvedDestination::Receive | ResolvedDestination::BackedUp(_) => (),
+        }
+    }
+    if opts.preserve {
+        let metadata = FileMetadata::from_path(f)?;
+        send_command(&mut tcp_stream, &ServerCommand::SetMetadata(metadata))?;
+    }
+    log::debug!(
+        "Sending {file} in {} ranges",
+        ranges.len(),
+        file = f.display()
+    );
+    send_command(
+        &mut tcp_stream,
+        &ServerCommand::ReceiveRanges(fname.to_owned(), flen),
+    )?;
+    if let ServerResult::Err(e) = read_server_response(&mut tcp_stream)? {
+        bail!(e);
+    }
+
+    let sent = thread::scope(|scope| {
+        let handles: Vec<_> = ranges
+            .iter()
+            .zip(range_ports)
+            .map(|(range, &port)| {
+                let (mmap, range_name, progress) = (&mmap, &range_name, &progress);
+                scope.spawn(move || send_range(opts, port, mmap, range_name, range, progress))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Failed joining range thread"))
+            .sum::<anyhow::Result<u64>>()
+    })?;
+    // Each range is verified on its own, the server also verifies the file they were assembled into.
+    // Dropping the connection before this removes the partially received file
+    let checksum = Checksum::of(mmap.borrow_slice(0..mmap.flen())?);
+    send_command(&mut tcp_stream, &ServerCommand::FinishRanges(checksum))?;
+    if let ServerResult::Err(e) = read_server_response(&mut tcp_stream)? {
+        bail!(e);
+    }
+    Ok(FileOutcome::Sent {
+        len: sent,
+        checksum: Some(checksum),
+    })
+}
+
+/// Send the `range` of the memory mapped file `mmap` to the child at `port`, which verifies it, returns the amount of bytes sent
+fn send_range(
+    opts: &FileSendOptions,
+    port: u16,
+    mmap: &MemoryMapWrapper,
+    range_name: &str,
+    range: &Range<u64>,
+    progress: &FileProgress,
+) -> anyhow::Result<u64> {
+    let (mut tcp_stream, _) = qft_connect_to_server(
+        (opts.ip, port),
+        opts.connect_mode,
+        opts.psk,
+        opts.encrypt,
+        opts.ip,
+    )?;
+    send_command(
+        &mut tcp_stream,
+        &ServerCommand::ReceiveRange(
+            range_name.to_owned(),
+            range.start,
+            opts.compression.map(|c| c.variant()),
+        ),
+    )?;
+    let content = mmap.borrow_slice(range.start.try_into()?..range.end.try_into()?)?;
+    let mut framed_tcp_stream = FramedWriter::new(Throttled::new(
+        tcp_bufwriter(&mut tcp_stream),
+        opts.limit_rate.cloned(),
+    ));
+    // Each range is compressed on its own
+    let mut reader = HashingReader::new(progress.wrap_read(content));
+    let len = encode_into(&mut framed_tcp_stream, &mut reader, opts.compression)?;
+    framed_tcp_stream.finish()?;
+    verify_checksum(&mut tcp_stream, reader.checksum())?;
+    log::trace!("Range {range:?} of {range_name} verified by server");
+    Ok(len)
+}
+
+/// Have the server spawn a child that listens on a free port for the files, returns the port
+fn query_free_port(initial_tcp_stream: &mut QftStream) -> anyhow::Result<u16> {
+    let cmd_free_port = ServerCommand::GetFreePort((None, None));
+    send_command(initial_tcp_stream, &cmd_free_port)?;
+    let mut free_port_buf: [u8; 2] = [0; 2];
+    if let Err(e) = initial_tcp_stream.read_exact(&mut free_port_buf) {
+        log::trace!("Initial tcp read of free port response failed: {e}, retrying in 100 ms...");
+        thread::sleep(Duration::from_millis(100));
+        initial_tcp_stream.read_exact(&mut free_port_buf)?;
+    }
+    let free_port = u16::from_be_bytes(free_port_buf);
+    tracing::info!("Got free port: {free_port}");
+    Ok(free_port)
+}
 
-            let transferred_len =
-                transfer_data((ip, port), &mut tcp_stream, compression, Some(f), use_mmap)?;
-            tcp_stream.flush()?;
+/// Ask the server how much of `fname` it already received, and if that content matches the start of `file`, have the server resume from there.
+///
+/// Returns the hash of the content the server already has, or [None] if the file should be sent from the start.
+fn query_resume_prefix(
+    tcp_stream: &mut QftStream,
+    file: &Path,
+    fname: &str,
+) -> anyhow::Result<Option<PrefixHash>> {
+    send_command(
+        tcp_stream,
+        &ServerCommand::GetResumeOffset(fname.to_owned()),
+    )?;
+    let mut offset_buf: [u8; 8] = [0; 8];
+    tcp_stream.read_exact(&mut offset_buf)?;
+    let offset = u64::from_be_bytes(offset_buf);
+    let file_len = File::open(file)?.metadata()?.len();
+    if offset == 0 || offset > file_len {
+        log::debug!(
+            "Server has {offset} B of {file} [{file_len} B], sending it from the start",
+            file = file.display()
+        );
+        return Ok(None);
+    }
 
+    let prefix = PrefixHash::of_reader(file_with_bufreader(file)?, offset)?;
+    send_command(
+        tcp_stream,
+        &ServerCommand::ResumeFrom(fname.to_owned(), offset, prefix.checksum()),
+    )?;
+    match read_server_response(tcp_stream)? {
+        ServerResult::Ok => {
+            log::info!(
+                "Resuming {file} from offset {offset}",
+                file = file.display()
+            );
+            Ok(Some(prefix))
+        }
+        ServerResult::Err(e) => {
             log::info!(
-                "Sent {file} {} [{transferred_len} B]",
-                format_data_size(transferred_len),
-                file = f.display()
+                "Unable to resume {file}: {e}, sending it from the start",
+                file = file.display()
             );
+            Ok(None)
         }
     }
+}
 
-    send_command(&mut initial_tcp_stream, &ServerCommand::EndOfTransfer)?;
-    query_server_result(&mut initial_tcp_stream)?;
+/// Have the server apply its overwrite policy to `fname` before sending `file`
+fn resolve_destination(
+    tcp_stream: &mut QftStream,
+    file: &Path,
+    fname: &str,
+) -> anyhow::Result<ResolvedDestination> {
+    let modified = file
+        .metadata()?
+        .modified()
+        .ok()
+        .and_then(Timestamp::from_system_time);
+    send_command(
+        tcp_stream,
+        &ServerCommand::ResolveDestination(fname.to_owned(), modified),
+    )?;
+    let resolved = read_server_reply(tcp_stream)?;
+    match &resolved {
+        ResolvedDestination::Receive | ResolvedDestination::Skipped(_) => (),
+        ResolvedDestination::BackedUp(backup_name) => log::info!(
+            "{fname} already exists on the server, the existing file was kept as {backup_name}"
+        ),
+        ResolvedDestination::Renamed(name) => log::warn!(
+            "{fname} already exists on the server, {file} is received as {name}",
+            file = file.display()
+        ),
+    }
+    Ok(resolved)
+}
 
-    Ok(())
+/// Send the checksum of the transferred content and have the server verify it against the content it received.
+fn verify_checksum(tcp_stream: &mut QftStream, checksum: Checksum) -> anyhow::Result<()> {
+    send_command(tcp_stream, &ServerCommand::VerifyChecksum(checksum))?;
+    match read_server_response(tcp_stream)? {
+        ServerResult::Ok => Ok(()),
+        ServerResult::Err(e) => bail!(e),
+    }
 }
 
-pub fn query_server_result(initial_tcp_stream: &mut TcpStream) -> anyhow::Result<()> {
+pub fn query_server_result(initial_tcp_stream: &mut QftStream) -> anyhow::Result<()> {
     use config::transfer::command::ServerResult;
     let mut header_buf = [0; ServerResult::HEADER_SIZE];
     // Read the header to determine the size of the incoming command/data
@@ -144,7 +903,7 @@ pub fn query_server_result(initial_tcp_stream: &mut TcpStream) -> anyhow::Result
         std::thread::sleep(Duration::from_millis(100));
         initial_tcp_stream.read_exact(&mut header_buf)?;
     }
-    let inc_cmd_len = ServerResult::size_from_bytes(header_buf);
+    let inc_cmd_len = ServerResult::size_from_bytes(header_buf)?;
 
     let mut resp_buf = vec![0; inc_cmd_len];
 
@@ -161,118 +920,251 @@ pub fn query_server_result(initial_tcp_stream: &mut TcpStream) -> anyhow::Result
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn transfer_data(
     (ip, port): (IpAddr, u16),
-    tcp_stream: &mut TcpStream,
+    tcp_stream: &mut QftStream,
     compression: Option<Compression>,
     file: Option<&Path>,
     use_mmap: bool,
-) -> anyhow::Result<u64> {
+    resume_from: PrefixHash,
+    progress: &FileProgress,
+    limit_rate: Option<&RateLimit>,
+) -> anyhow::Result<(u64, Checksum)> {
     log::debug!("Sending to: {ip}:{port}");
+    let offset = resume_from.len();
+    progress.start_from(offset);
 
-    let mut buf_tcp_stream = tcp_bufwriter(tcp_stream);
+    let mut framed_tcp_stream = FramedWriter::new(Throttled::new(
+        tcp_bufwriter(tcp_stream),
+        limit_rate.cloned(),
+    ));
 
-    if use_mmap && file.is_some() {
+    if let (true, Some(file)) = (use_mmap, file) {
         log::debug!("Using mmap");
-        let mmap = MemoryMapWrapper::new(file.unwrap())?;
+        let mmap = MemoryMapWrapper::new(file)?;
         let target_read = mmap.flen();
+        let offset: usize = offset.try_into()?;
+        let content = mmap.borrow_slice(offset..target_read)?;
 
-        let transferred_bytes = match compression {
+        let (transferred_bytes, checksum) = match compression {
             None => {
+                let mut hashing_tcp_stream =
+                    HashingWriter::resume(&mut framed_tcp_stream, resume_from);
                 let mut total_written = 0;
-                let chunks = mmap.borrow_full().chunks(TCP_STREAM_BUFSIZE);
+                let chunks = content.chunks(TCP_STREAM_BUFSIZE);
                 for chunk in chunks {
                     let mut chunk_written = 0;
                     let chunk_len = chunk.len();
                     while chunk_written != chunk_len {
-                        let bytes_written = buf_tcp_stream.write(chunk)?;
+                        let bytes_written = hashing_tcp_stream.write(&chunk[chunk_written..])?;
                         if bytes_written == 0 {
                             bail!("Wrote 0 bytes to socket, server disconnected?");
                         }
                         chunk_written += bytes_written;
+                        progress.inc(bytes_written as u64);
                     }
                     total_written += chunk_written;
                 }
 
-                total_written.try_into()?
+                (total_written.try_into()?, hashing_tcp_stream.checksum())
             }
             Some(c) => match c {
                 config::compression::Compression::Bzip2(Bzip2Args { compression_level }) => {
                     let mut encoder = bzip2::read::BzEncoder::new(
-                        mmap.borrow_full(),
+                        HashingReader::resume(progress.wrap_read(content), resume_from),
                         bzip2::Compression::new(compression_level.into()),
                     );
-                    incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut buf_tcp_stream, &mut encoder)?
+                    let len = incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(
+                        &mut framed_tcp_stream,
+                        &mut encoder,
+                    )?;
+                    (len, encoder.get_ref().checksum())
                 }
                 config::compression::Compression::Lz4 => {
-                    let mut lz4_writer = lz4_flex::frame::FrameEncoder::new(&mut buf_tcp_stream);
-                    let mut total_read = 0;
+                    let mut lz4_writer = HashingWriter::resume(
+                        lz4_flex::frame::FrameEncoder::new(&mut framed_tcp_stream),
+                        resume_from,
+                    );
+                    let mut total_read = offset;
                     while total_read < target_read {
                         let remaining = target_read - total_read;
                         let chunk_size = remaining.min(TCP_STREAM_BUFSIZE);
                         let chunk = mmap.borrow_slice(total_read..total_read + chunk_size)?;
                         let written_bytes = lz4_writer.write(chunk)?;
                         total_read += written_bytes;
+                        progress.inc(written_bytes as u64);
                     }
-                    lz4_writer.flush()?; // Needed to ensure the entire content is written
-                    total_read as u64
+                    let checksum = lz4_writer.checksum();
+                    // Needed to ensure the entire content is written
+                    lz4_writer.get_mut().try_finish()?;
+                    ((total_read - offset) as u64, checksum)
                 }
                 config::compression::Compression::Gzip(GzipArgs { compression_level }) => {
                     let mut encoder = flate2::read::GzEncoder::new(
-                        mmap.borrow_full(),
+                        HashingReader::resume(progress.wrap_read(content), resume_from),
                         flate2::Compression::new(compression_level.into()),
                     );
-                    incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut buf_tcp_stream, &mut encoder)?
+                    let len = incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(
+                        &mut framed_tcp_stream,
+                        &mut encoder,
+                    )?;
+                    (len, encoder.get_ref().checksum())
                 }
                 config::compression::Compression::Xz(XzArgs { compression_level }) => {
-                    let mut compressor =
-                        xz2::read::XzEncoder::new(mmap.borrow_full(), compression_level.into());
-                    incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(
-                        &mut buf_tcp_stream,
+                    let mut compressor = xz2::read::XzEncoder::new(
+                        HashingReader::resume(progress.wrap_read(content), resume_from),
+                        compression_level.into(),
+                    );
+                    let len = incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(
+                        &mut framed_tcp_stream,
                         &mut compressor,
-                    )?
+                    )?;
+                    (len, compressor.get_ref().checksum())
                 }
             },
         };
-        return Ok(transferred_bytes);
+        framed_tcp_stream.finish()?;
+        return Ok((transferred_bytes, checksum));
     }
 
-    // On-stack dynamic dispatch
-    let mut bufreader = file_with_bufreader(file.unwrap())?;
     if let Some(compression) = compression {
         log::debug!("Compression mode: {compression}");
     };
+    let (transferred_bytes, checksum) = match file {
+        Some(file) => {
+            let mut file_reader = file_with_bufreader(file)?;
+            file_reader.seek(SeekFrom::Start(offset))?;
+            let mut bufreader = HashingReader::resume(progress.wrap_read(file_reader), resume_from);
+            let len = encode_into(&mut framed_tcp_stream, &mut bufreader, compression)?;
+            (len, bufreader.checksum())
+        }
+        None => {
+            let stdin = BufReader::with_capacity(BUFFERED_RW_BUFSIZE, io::stdin().lock());
+            let mut bufreader = HashingReader::new(progress.wrap_read(stdin));
+            let len = encode_into(&mut framed_tcp_stream, &mut bufreader, compression)?;
+            (len, bufreader.checksum())
+        }
+    };
+    framed_tcp_stream.finish()?;
+
+    Ok((transferred_bytes, checksum))
+}
 
+/// Compress the content of `reader` into `writer`, returns the amount of bytes written
+fn encode_into<W: Write, R: Read>(
+    writer: &mut W,
+    reader: &mut R,
+    compression: Option<Compression>,
+) -> anyhow::Result<u64> {
+    // On-stack dynamic dispatch
     let transferred_bytes = match compression {
         Some(compression) => match compression {
             config::compression::Compression::Bzip2(Bzip2Args { compression_level }) => {
                 let mut encoder = bzip2::read::BzEncoder::new(
-                    bufreader,
+                    reader,
                     bzip2::Compression::new(compression_level.into()),
                 );
-                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut buf_tcp_stream, &mut encoder)?
+                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(writer, &mut encoder)?
             }
             config::compression::Compression::Lz4 => {
-                let mut lz4_writer = lz4_flex::frame::FrameEncoder::new(&mut buf_tcp_stream);
-                let len: u64 =
-                    incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut lz4_writer, &mut bufreader)?;
-                lz4_writer.flush()?; // Needed to ensure the entire content is written
+                let mut lz4_writer = lz4_flex::frame::FrameEncoder::new(writer);
+                let len: u64 = incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut lz4_writer, reader)?;
+                lz4_writer.try_finish()?; // Needed to ensure the entire content is written
                 len
             }
             config::compression::Compression::Gzip(GzipArgs { compression_level }) => {
                 let mut encoder = flate2::read::GzEncoder::new(
-                    bufreader,
+                    reader,
                     flate2::Compression::new(compression_level.into()),
                 );
-                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut buf_tcp_stream, &mut encoder)?
+                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(writer, &mut encoder)?
             }
             config::compression::Compression::Xz(XzArgs { compression_level }) => {
-                let mut compressor = xz2::read::XzEncoder::new(bufreader, compression_level.into());
-                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut buf_tcp_stream, &mut compressor)?
+                let mut compressor = xz2::read::XzEncoder::new(reader, compression_level.into());
+                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(writer, &mut compressor)?
             }
         },
-        None => incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut buf_tcp_stream, &mut bufreader)?,
+        None => incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(writer, reader)?,
     };
-
     Ok(transferred_bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_report_keeps_file_order() {
+        let files: Vec<SourceFile> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| SourceFile {
+                path: PathBuf::from(name),
+                name: name.to_owned(),
+            })
+            .collect();
+        let mut report = OrderedReport::new(&files, None);
+
+        report.complete(2, 3, Duration::ZERO, Err(anyhow::anyhow!("c failed")));
+        // Not logged before the outcome of the first file is known
+        assert_eq!(report.next, 0);
+        report.complete(0, 1, Duration::ZERO, Err(anyhow::anyhow!("a failed")));
+        assert_eq!(report.next, 1);
+        report.complete(
+            1,
+            2,
+            Duration::ZERO,
+            Ok(FileOutcome::Sent {
+                len: 7,
+                checksum: None,
+            }),
+        );
+        assert_eq!(report.next, 3);
+
+        let statuses: Vec<(&str, u64, &FileStatus)> = report
+            .reports
+            .iter()
+            .map(|file| (file.path.as_str(), file.size, &file.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (
+                    "a",
+                    1,
+                    &FileStatus::Failed {
+                        error: "a failed".to_owned()
+                    }
+                ),
+                ("b", 2, &FileStatus::Sent { transferred: 7 }),
+                (
+                    "c",
+                    3,
+                    &FileStatus::Failed {
+                        error: "c failed".to_owned()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_into_ranges_of_minimum_length() {
+        // Too small to split
+        assert_eq!(split_into_ranges(0, 4), vec![(0..0)]);
+        assert_eq!(
+            split_into_ranges(MIN_RANGE_LEN * 2 - 1, 4),
+            vec![(0..MIN_RANGE_LEN * 2 - 1)]
+        );
+        assert_eq!(split_into_ranges(MIN_RANGE_LEN * 5, 0).len(), 1);
+        // No more ranges than streams, the last range is shorter if the length doesn't divide evenly
+        let flen = MIN_RANGE_LEN * 10 + 1;
+        let ranges = split_into_ranges(flen, 3);
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(ranges[2].end, flen);
+        assert!(ranges.windows(2).all(|w| w[0].end == w[1].start));
+        assert_eq!(split_into_ranges(MIN_RANGE_LEN * 3, 16).len(), 3);
+    }
+}
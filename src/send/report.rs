@@ -0,0 +1,155 @@
+//! What became of each file of a transfer, printed as JSON with `--output-format json`.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use serde::Serialize;
+
+use crate::{config::misc::OutputFormat, util::print_json};
+
+/// The outcome of a transfer
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct TransferReport {
+    /// The files that were sent or attempted, in the order they were given
+    pub files: Vec<FileReport>,
+    /// The names of the files that were already up to date on the server (with `--sync`)
+    pub up_to_date: Vec<String>,
+    /// The names of the files and directories the server deleted (with `--sync --delete`)
+    pub deleted: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileReport {
+    /// The local path of the file, `-` for stdin
+    pub path: String,
+    /// The name the file is received as
+    pub name: String,
+    /// The size of the file before compression
+    pub size: u64,
+    pub duration_ms: u64,
+    #[serde(flatten)]
+    pub status: FileStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum FileStatus {
+    /// `transferred` bytes were sent (after compression)
+    Sent {
+        transferred: u64,
+    },
+    /// The server refused the file because of its overwrite policy
+    Skipped {
+        reason: String,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Called with the report of each file as soon as its outcome (and that of every file before it) is known
+pub type OnSent = dyn Fn(&FileReport) + Send + Sync;
+
+impl FileReport {
+    pub fn new(
+        path: String,
+        name: String,
+        size: u64,
+        duration: Duration,
+        status: FileStatus,
+    ) -> Self {
+        Self {
+            path,
+            name,
+            size,
+            duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+            status,
+        }
+    }
+
+    /// How long it took to send the file
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+}
+
+impl TransferReport {
+    pub fn has_failures(&self) -> bool {
+        self.files
+            .iter()
+            .any(|file| matches!(file.status, FileStatus::Failed { .. }))
+    }
+
+    /// Print the report as JSON if that's the `format`, and fail listing the files that failed, if any did.
+    ///
+    /// In the text format each file is already logged as it's sent.
+    pub fn print(&self, format: OutputFormat) -> anyhow::Result<()> {
+        if format == OutputFormat::Json {
+            print_json(self)?;
+        }
+        self.ensure_no_failures()
+    }
+
+    /// Fails listing the files that failed, if any did
+    pub fn ensure_no_failures(&self) -> anyhow::Result<()> {
+        let failures: Vec<String> = self
+            .files
+            .iter()
+            .filter_map(|file| match file.status {
+                FileStatus::Failed { ref error } => Some(format!("{}: {error}", file.path)),
+                _ => None,
+            })
+            .collect();
+        if !failures.is_empty() {
+            bail!(
+                "{} file(s) failed:\n{}",
+                failures.len(),
+                failures.join("\n")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_report_as_json() -> TestResult {
+        let report = TransferReport {
+            files: vec![
+                FileReport::new(
+                    "dir/a.txt".to_owned(),
+                    "a.txt".to_owned(),
+                    10,
+                    Duration::from_millis(5),
+                    FileStatus::Sent { transferred: 8 },
+                ),
+                FileReport::new(
+                    "dir/b.txt".to_owned(),
+                    "b.txt".to_owned(),
+                    3,
+                    Duration::ZERO,
+                    FileStatus::Failed {
+                        error: "Connection reset".to_owned(),
+                    },
+                ),
+            ],
+            up_to_date: vec!["c.txt".to_owned()],
+            deleted: vec![],
+        };
+
+        assert_eq!(
+            serde_json::to_string(&report)?,
+            r#"{"files":[{"path":"dir/a.txt","name":"a.txt","size":10,"duration_ms":5,"status":"sent","transferred":8},{"path":"dir/b.txt","name":"b.txt","size":3,"duration_ms":0,"status":"failed","error":"Connection reset"}],"up_to_date":["c.txt"],"deleted":[]}"#
+        );
+        assert_eq!(
+            report.ensure_no_failures().unwrap_err().to_string(),
+            "1 file(s) failed:\ndir/b.txt: Connection reset"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! Expanding the sources given on the command line to the files (and directories) that are transferred.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+use crate::config::transfer::command::REMOTE_PATH_SEPARATOR;
+
+/// A file to transfer along with the (relative) name it is received as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// The name the file is received as, components are separated by [REMOTE_PATH_SEPARATOR]
+    pub name: String,
+}
+
+/// The files and directories to transfer
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Sources {
+    pub files: Vec<SourceFile>,
+    /// Directories to create (relative to the destination), parents always precede their children
+    pub dirs: Vec<String>,
+}
+
+impl Sources {
+    /// Collect the files to transfer from the given sources.
+    ///
+    /// Directories are only allowed if `recursive` is set, in which case the entire tree is collected with names
+    /// relative to the parent of the directory (i.e. the directory itself is recreated on the receiving end).
+    pub fn collect(sources: &[PathBuf], recursive: bool) -> anyhow::Result<Self> {
+        let mut collected = Self::default();
+        for source in sources {
+            if source.is_dir() {
+                if !recursive {
+                    bail!(
+                        "{source:?} is a directory, transferring directories requires --recursive"
+                    );
+                }
+                collected.walk_dir(source, &file_name(source)?)?;
+            } else {
+                collected.files.push(SourceFile {
+                    path: source.to_path_buf(),
+                    name: file_name(source)?,
+                });
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Returns true if any of the sources were directories
+    pub fn has_dirs(&self) -> bool {
+        !self.dirs.is_empty()
+    }
+
+    fn walk_dir(&mut self, dir: &Path, name: &str) -> anyhow::Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .with_context(|| format!("Failed reading directory {dir:?}"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        // Deterministic order makes the transfer (and its logs) reproducible
+        entries.sort_by_key(|e| e.file_name());
+        self.dirs.push(name.to_owned());
+
+        for entry in entries {
+            let path = entry.path();
+            let entry_name = format!("{name}{REMOTE_PATH_SEPARATOR}{}", file_name(&path)?);
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.walk_dir(&path, &entry_name)?;
+            } else if file_type.is_symlink() && path.is_dir() {
+                // Following symlinked directories risks walking in circles
+                log::warn!("Skipping symlink to directory {path:?}");
+            } else {
+                self.files.push(SourceFile {
+                    path,
+                    name: entry_name,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> anyhow::Result<String> {
+    let Some(name) = path.file_name() else {
+        bail!("{path:?} has no file name");
+    };
+    match name.to_str() {
+        Some(name) => Ok(name.to_owned()),
+        None => bail!("{path:?} is not valid UTF-8"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_collect_files() -> TestResult {
+        let d = TempDir::new()?;
+        let f1 = d.child("f1.txt");
+        let f2 = d.child("f2.txt");
+        fs::write(&f1, "1")?;
+        fs::write(&f2, "2")?;
+
+        let sources = Sources::collect(&[f1.clone(), f2.clone()], false)?;
+        assert!(!sources.has_dirs());
+        assert_eq!(
+            sources.files,
+            vec![
+                SourceFile {
+                    path: f1,
+                    name: "f1.txt".to_owned()
+                },
+                SourceFile {
+                    path: f2,
+                    name: "f2.txt".to_owned()
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_dir_requires_recursive() -> TestResult {
+        let d = TempDir::new()?;
+        let err = Sources::collect(&[d.path().to_path_buf()], false).unwrap_err();
+        assert!(err.to_string().contains("requires --recursive"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_recursive() -> TestResult {
+        let d = TempDir::new()?;
+        let root = d.child("root");
+        fs::create_dir_all(root.join("a/b"))?;
+        fs::create_dir(root.join("empty"))?;
+        fs::write(root.join("top.txt"), "top")?;
+        fs::write(root.join("a/b/nested.txt"), "nested")?;
+
+        let sources = Sources::collect(std::slice::from_ref(&root), true)?;
+        assert_eq!(
+            sources.dirs,
+            vec!["root", "root/a", "root/a/b", "root/empty"]
+        );
+        let names: Vec<&str> = sources.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["root/a/b/nested.txt", "root/top.txt"]);
+        assert_eq!(sources.files[0].path, root.join("a/b/nested.txt"));
+        Ok(())
+    }
+}
@@ -2,16 +2,22 @@ use anyhow::{bail, Result};
 use std::{
     fs::{self, File},
     io::{self, BufReader, BufWriter, Read, Write},
-    net::{TcpStream, ToSocketAddrs},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
     path::Path,
 };
 
 use crate::{
+    auth::{AuthChallenge, AuthError, PreSharedKey},
     config::transfer::{
-        command::ServerCommand,
+        command::{ServerCommand, ServerResult},
+        handshake::{
+            Capabilities, HandshakeHello, IncompatiblePeer, NegotiatedProtocol, HANDSHAKE_MAGIC,
+        },
         util::{PollAbortCondition, TcpConnectMode},
     },
-    util::tiny_rnd::rnd_u32,
+    events::{self, Event},
+    transport::{QftStream, TransportError},
+    util::{read_server_reply, read_server_response},
     BUFFERED_RW_BUFSIZE,
 };
 
@@ -21,17 +27,15 @@ pub fn file_with_bufreader(path: &Path) -> Result<BufReader<File>> {
     Ok(reader)
 }
 
-pub fn tcp_bufwriter(socket: &TcpStream) -> BufWriter<&TcpStream> {
+pub fn tcp_bufwriter(socket: &mut QftStream) -> BufWriter<&mut QftStream> {
     BufWriter::with_capacity(BUFFERED_RW_BUFSIZE, socket)
 }
 
 /// Send a [ServerCommand] to the server
-pub fn send_command(stream: &mut TcpStream, command: &ServerCommand) -> anyhow::Result<()> {
+pub fn send_command(stream: &mut QftStream, command: &ServerCommand) -> anyhow::Result<()> {
     tracing::trace!("Sending command: {command:?}");
     let command_bytes = bincode::serialize(command)?;
-    debug_assert!(command_bytes.len() <= u8::MAX as usize);
-    let size = command_bytes.len() as u8;
-    let header = size.to_be_bytes();
+    let header = ServerCommand::header_from_size(command_bytes.len())?;
 
     // Send the header followed by the command
     stream.write_all(&header)?;
@@ -39,32 +43,110 @@ pub fn send_command(stream: &mut TcpStream, command: &ServerCommand) -> anyhow::
     Ok(())
 }
 
-/// Perform the simple QFT handshake from the client end.
+/// Perform the QFT handshake from the client end.
 ///
-/// The handshake is simply to ensure why are talking to a QFT server
-fn qft_client_handshake(socket: &mut TcpStream) -> anyhow::Result<()> {
-    let mut handshake_buf: [u8; 4] = [0; 4];
-    socket.read_exact(&mut handshake_buf)?;
-    let recv_handshake: u32 = u32::from_be_bytes(handshake_buf);
-    let return_handshake: u32 = rnd_u32(recv_handshake as u64);
-    socket.write_all(&return_handshake.to_be_bytes())?;
-    Ok(())
+/// The handshake ensures we are talking to a QFT server and agrees on a protocol version and the features both ends support.
+/// The connection is then encrypted if either end asks for it, and authenticated if the server requires a pre-shared key.
+fn qft_client_handshake(
+    socket: &mut QftStream,
+    psk: Option<&PreSharedKey>,
+    encrypt: bool,
+    known_host: IpAddr,
+) -> anyhow::Result<NegotiatedProtocol> {
+    let mut magic_buf = [0; HANDSHAKE_MAGIC.len()];
+    socket.read_exact(&mut magic_buf)?;
+    HandshakeHello::check_magic(magic_buf)?;
+    let mut body_buf = [0; HandshakeHello::SIZE - HANDSHAKE_MAGIC.len()];
+    socket.read_exact(&mut body_buf)?;
+    let server_hello = HandshakeHello::from_body_bytes(body_buf);
+    tracing::trace!("Server hello: {server_hello:?}");
+
+    let local_hello = HandshakeHello::local();
+    let client_hello = local_hello.to_bytes();
+    socket.write_all(&client_hello)?;
+    let negotiated = local_hello.negotiate(&server_hello)?;
+
+    if let ServerResult::Err(reason) = read_server_response(socket)? {
+        bail!(IncompatiblePeer::Refused(reason));
+    }
+    if negotiated.capabilities.contains(Capabilities::ENCRYPTION) {
+        let request = [u8::from(encrypt)];
+        socket.write_all(&request)?;
+        let mut encrypted = [0];
+        socket.read_exact(&mut encrypted)?;
+        if encrypted[0] != 0 {
+            let handshake = [
+                &magic_buf[..],
+                &body_buf,
+                &client_hello,
+                &request,
+                &encrypted,
+            ]
+            .concat();
+            socket.encrypt_as_client(&handshake, known_host)?;
+        } else if encrypt {
+            // A server that supports encryption always encrypts when asked to, so don't continue in plaintext
+            bail!(TransportError::Unsupported);
+        }
+    } else if encrypt {
+        bail!(TransportError::Unsupported);
+    }
+    if negotiated.capabilities.contains(Capabilities::PSK_AUTH) {
+        authenticate(socket, psk)?;
+    } else if psk.is_some() {
+        log::warn!("The server does not support authentication with a pre-shared key");
+    }
+    Ok(negotiated)
+}
+
+/// Answer the [AuthChallenge] of the server with the proof that we know the pre-shared key
+fn authenticate(socket: &mut QftStream, psk: Option<&PreSharedKey>) -> anyhow::Result<()> {
+    let nonce = match read_server_reply::<AuthChallenge>(socket)? {
+        AuthChallenge::None => {
+            if psk.is_some() {
+                log::warn!("The server does not require a pre-shared key");
+            }
+            return Ok(());
+        }
+        AuthChallenge::Psk(nonce) => nonce,
+    };
+    let Some(psk) = psk else {
+        bail!(AuthError::MissingKey);
+    };
+    socket.write_all(&psk.prove(&nonce))?;
+    match read_server_response(socket)? {
+        ServerResult::Ok => {
+            log::debug!("Authenticated with the pre-shared key");
+            Ok(())
+        }
+        ServerResult::Err(reason) => bail!(AuthError::Refused(reason)),
+    }
 }
 
-/// Connect to a QFT server
+/// Connect to a QFT server and return the socket along with the protocol negotiated in the handshake.
+///
+/// If the connection is encrypted, the identity of the server is pinned for `known_host`, the host the client was pointed at.
+/// The children of the server (on whatever port) present the identity of that server, so the pin holds for them too.
 pub fn qft_connect_to_server<A>(
     socket_addr: A,
     connect_mode: TcpConnectMode,
-) -> anyhow::Result<TcpStream>
+    psk: Option<&PreSharedKey>,
+    encrypt: bool,
+    known_host: IpAddr,
+) -> anyhow::Result<(QftStream, NegotiatedProtocol)>
 where
     A: ToSocketAddrs + std::fmt::Debug,
 {
     match connect_mode {
         TcpConnectMode::OneShot => {
             log::debug!("Attempting one shot connection to {socket_addr:?}");
-            let mut socket = TcpStream::connect(socket_addr)?;
-            qft_client_handshake(&mut socket)?;
-            Ok(socket)
+            let socket = TcpStream::connect(socket_addr)?;
+            let peer = events::peer(&socket);
+            events::emit(&Event::Connect { peer: peer.clone() });
+            let mut socket = QftStream::plain(socket);
+            let negotiated = qft_client_handshake(&mut socket, psk, encrypt, known_host)?;
+            events::emit(&Event::handshake_ok(peer, &negotiated));
+            Ok((socket, negotiated))
         }
         TcpConnectMode::Poll(poll_opts) => {
             let mut attempts: u32 = 0;
@@ -72,11 +154,24 @@ where
             loop {
                 log::debug!("Attempt #{attempts} to connect to {socket_addr:?}");
                 match TcpStream::connect(&socket_addr) {
-                    Ok(mut socket) => {
-                        if let Err(e) = qft_client_handshake(&mut socket) {
-                            log::warn!("Handshake failed: {e} ... retrying");
-                        } else {
-                            break Ok(socket);
+                    Ok(socket) => {
+                        let peer = events::peer(&socket);
+                        events::emit(&Event::Connect { peer: peer.clone() });
+                        let mut socket = QftStream::plain(socket);
+                        match qft_client_handshake(&mut socket, psk, encrypt, known_host) {
+                            Ok(negotiated) => {
+                                events::emit(&Event::handshake_ok(peer, &negotiated));
+                                break Ok((socket, negotiated));
+                            }
+                            // Retrying won't make the peers any more compatible, the key any more correct or the server more trusted
+                            Err(e)
+                                if e.is::<IncompatiblePeer>()
+                                    || e.is::<AuthError>()
+                                    || e.is::<TransportError>() =>
+                            {
+                                break Err(e)
+                            }
+                            Err(e) => log::warn!("Handshake failed: {e} ... retrying"),
                         }
                     }
                     Err(e) => {
@@ -113,3 +208,36 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::util::send_result;
+    use std::net::TcpListener;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_encrypting_client_refuses_plaintext_reply() -> TestResult {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let addr = listener.local_addr()?;
+        // A server that negotiates encryption but answers the client's request to encrypt with 0
+        let server = std::thread::spawn(move || -> anyhow::Result<u8> {
+            let (socket, _) = listener.accept()?;
+            let mut socket = QftStream::plain(socket);
+            socket.write_all(&HandshakeHello::local().to_bytes())?;
+            socket.read_exact(&mut [0; HandshakeHello::SIZE])?;
+            send_result(&mut socket, &ServerResult::Ok)?;
+            let mut asks = [0];
+            socket.read_exact(&mut asks)?;
+            socket.write_all(&[0])?;
+            Ok(asks[0])
+        });
+
+        let err = qft_connect_to_server(addr, TcpConnectMode::OneShot, None, true, addr.ip())
+            .unwrap_err();
+
+        assert!(err.is::<TransportError>(), "{err:?}");
+        assert_eq!(server.join().unwrap()?, 1, "The client asked to encrypt");
+        Ok(())
+    }
+}
@@ -1,32 +1,52 @@
 use crate::{
+    auth::AuthError,
     config::{
+        misc::OutputFormat,
         transfer::{
-            command::{ServerCommand, ServerResult},
+            command::{DestinationMode, ServerCommand, ServerResult},
             listen::ListenArgs,
         },
         Config,
     },
+    events::{self, Event},
+    framed_stream::{FramedReader, FramedWriter},
+    sync::Manifest,
     util::{read_server_cmd, server_handshake},
 };
 use anyhow::{bail, Result};
 use std::{
-    net::{IpAddr, TcpListener},
+    fs, io,
+    net::{IpAddr, TcpListener, TcpStream},
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    thread::JoinHandle,
+    time::Duration,
 };
 
-mod path;
+pub(crate) mod path;
 use path::validate_remote_path;
 
 pub mod util;
-use util::{join_all_threads, send_result, spawn_child_on_new_port};
+use util::{join_all_threads, receive_destination, send_result, spawn_child_on_new_port};
 
 pub mod child;
+use child::RangedFiles;
 
-pub fn listen(_cfg: &Config, listen_args: &ListenArgs) -> Result<()> {
+pub mod overwrite;
+
+pub mod partial;
+
+pub mod sync;
+
+pub mod report;
+use report::ReceivedFiles;
+
+pub mod builder;
+
+pub fn listen(cfg: &Config, listen_args: &ListenArgs) -> Result<()> {
     let ListenArgs {
         ip,
         port,
@@ -34,83 +54,230 @@ pub fn listen(_cfg: &Config, listen_args: &ListenArgs) -> Result<()> {
         decompression: _,
         output_dir: _,
         remote: _,
+        keep_alive,
+        max_sessions,
+        overwrite: _,
+        psk: _,
+        encrypt: _,
+        limit_rate: _,
+        on_received: _,
+        observer: _,
     } = listen_args;
 
-    let stop_flag: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let format = cfg.output_format();
+    if format == OutputFormat::Json && listen_args.receives_to_stdout() {
+        bail!("The received content is written to stdout, which leaves no room for a JSON report, give an output path or directory");
+    }
     let ip: IpAddr = ip.parse()?;
     let initial_listener = TcpListener::bind((ip, *port))?;
-    run_server(&initial_listener, listen_args, &stop_flag)
+    if *keep_alive {
+        return run_server_keep_alive(&initial_listener, listen_args, *max_sessions, format);
+    }
+    let mut args = listen_args.clone();
+    let received = ReceivedFiles::collect(&mut args);
+    let stop_flag: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    let res = run_server(&initial_listener, &args, &stop_flag);
+    // Also lists what was received before the transfer failed
+    received.take_report().print(format)?;
+    res
+}
+
+/// Keep accepting clients and serve each of them in their own session (thread with its own stop flag).
+///
+/// Never returns unless accepting a client fails, a failed session is logged and doesn't affect the other sessions.
+/// The [ServerReport](report::ServerReport) of each session is printed in the `format` once it ends.
+fn run_server_keep_alive(
+    initial_listener: &TcpListener,
+    args: &ListenArgs,
+    max_sessions: Option<u16>,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    log::info!(
+        "Listening for clients at {}{}",
+        initial_listener.local_addr()?,
+        max_sessions.map_or(String::new(), |max| format!(
+            ", serving up to {max} at a time"
+        ))
+    );
+    let mut sessions: Vec<JoinHandle<()>> = vec![];
+    for session_id in 1_u64.. {
+        if let Some(max_sessions) = max_sessions {
+            while sessions.len() >= max_sessions.into() {
+                std::thread::sleep(Duration::from_millis(10));
+                sessions.retain(|s| !s.is_finished());
+            }
+        }
+        let (socket, addr) = initial_listener.accept()?;
+        sessions.retain(|s| !s.is_finished());
+        tracing::info!("Session #{session_id}: client accepted at: {addr:?}");
+        let handle = std::thread::Builder::new()
+            .name(format!("Session#{session_id}"))
+            .spawn({
+                let mut args = args.clone();
+                move || {
+                    let received = ReceivedFiles::collect(&mut args);
+                    let stop_flag: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+                    match serve_client(socket, &args, &stop_flag) {
+                        Ok(()) => log::info!("Session #{session_id} ended"),
+                        Err(e) => log::error!("Session #{session_id} failed: {e}"),
+                    }
+                    if let Err(e) = received.take_report().print(format) {
+                        log::error!("Failed printing the report of session #{session_id}: {e}");
+                    }
+                }
+            })?;
+        sessions.push(handle);
+    }
+    unreachable!("Ran out of session IDs")
 }
 
 fn run_server(
     initial_listener: &TcpListener,
     args: &ListenArgs,
     stop_flag: &Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    loop {
+        match initial_listener.accept() {
+            Ok((socket, addr)) => {
+                tracing::info!("Client accepted at: {addr:?}");
+                match serve_client(socket, args, stop_flag) {
+                    // Keep waiting for a client that knows the pre-shared key
+                    Err(e) if e.is::<AuthError>() => log::warn!("Dropped client at {addr}: {e}"),
+                    res => return res,
+                }
+            }
+            Err(e) => bail!(e),
+        }
+    }
+}
+
+/// Serve an accepted client on its main socket until it ends the transfer or disconnects
+pub(crate) fn serve_client(
+    socket: TcpStream,
+    args: &ListenArgs,
+    stop_flag: &Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     let mut thread_handles = vec![];
-    match initial_listener.accept() {
-        Ok((mut socket, addr)) => {
-            tracing::info!("Client accepted at: {addr:?}");
-            server_handshake(&mut socket)?;
-            let mut root_dest: Option<PathBuf> = None; // Used as root destination if invoked through ssh/scp mode
-            let mut cmd_buf: [u8; 256] = [0; 256];
-            loop {
-                if let Some(cmd) = read_server_cmd(&mut socket, &mut cmd_buf)? {
-                    tracing::trace!("Received command: {cmd:?}");
-                    match cmd {
-                        ServerCommand::GetFreePort(_) => {
-                            let child_thread_handle = spawn_child_on_new_port(
-                                &mut socket,
-                                args,
-                                &Arc::clone(stop_flag),
-                                &cmd,
-                                root_dest.clone(),
-                            )?;
-                            thread_handles.push(child_thread_handle);
+    let peer = events::peer(&socket);
+    events::emit(&Event::Connect { peer: peer.clone() });
+    let (mut socket, negotiated) = server_handshake(socket, args.psk.as_ref(), args.encrypt)?;
+    events::emit(&Event::handshake_ok(peer, &negotiated));
+    tracing::debug!("Negotiated protocol: {negotiated:?}");
+    let mut root_dest: Option<PathBuf> = None; // Used as root destination if invoked through ssh/scp mode
+    let ranged_files = RangedFiles::default(); // Shared by the children of this session
+    let mut cmd_buf: Vec<u8> = Vec::with_capacity(256);
+    loop {
+        if let Some(cmd) = read_server_cmd(&mut socket, &mut cmd_buf)? {
+            tracing::trace!("Received command: {cmd:?}");
+            match cmd {
+                ServerCommand::GetFreePort(_) => {
+                    let child_thread_handle = spawn_child_on_new_port(
+                        &mut socket,
+                        args,
+                        &Arc::clone(stop_flag),
+                        &cmd,
+                        root_dest.clone(),
+                        &ranged_files,
+                    )?;
+                    thread_handles.push(child_thread_handle);
+                }
+                ServerCommand::EndOfTransfer => {
+                    tracing::trace!("Received command: {cmd:?}, stopping all threads...");
+                    stop_flag.store(true, Ordering::Relaxed);
+                    match join_all_threads(thread_handles) {
+                        Ok(_) => {
+                            send_result(&mut socket, &ServerResult::Ok)?;
+                            return Ok(());
                         }
-                        ServerCommand::EndOfTransfer => {
-                            tracing::trace!("Received command: {cmd:?}, stopping all threads...");
-                            stop_flag.store(true, Ordering::Relaxed);
-                            match join_all_threads(thread_handles) {
-                                Ok(_) => {
-                                    send_result(&mut socket, &ServerResult::Ok)?;
-                                    return Ok(());
-                                }
-                                Err(th_errs) => {
-                                    let err_res = ServerResult::err(th_errs.clone());
-                                    send_result(&mut socket, &err_res)?;
-                                    bail!(th_errs);
-                                }
-                            }
+                        Err(th_errs) => {
+                            let err_res = ServerResult::err(th_errs.clone());
+                            send_result(&mut socket, &err_res)?;
+                            bail!(th_errs);
+                        }
+                    }
+                }
+                ServerCommand::IsDestinationValid(mode, dest) => {
+                    let dest = PathBuf::from(dest);
+                    tracing::info!("Checking validity of remote path: {dest:?}");
+                    // Only a client that authenticated over SSH gets to write outside of the output directory
+                    let validated = if args.remote {
+                        validate_remote_path(&mode, &dest)
+                    } else {
+                        Err(anyhow::anyhow!(
+                            "choosing the destination is only allowed when listening in ssh mode"
+                        ))
+                    };
+                    match validated.and_then(|remote_dest| prepare_root_dest(&mode, remote_dest)) {
+                        Ok(remote_dest) => {
+                            send_result(&mut socket, &ServerResult::Ok)?;
+                            root_dest = Some(remote_dest);
                         }
-                        ServerCommand::IsDestinationValid(mode, dest) => {
-                            let dest = PathBuf::from(dest);
-                            tracing::info!("Checking validity of remote path: {dest:?}");
-                            match validate_remote_path(&mode, &dest) {
-                                Ok(remote_dest) => {
-                                    send_result(&mut socket, &ServerResult::Ok)?;
-                                    root_dest = Some(remote_dest);
-                                }
-                                Err(e) => {
-                                    tracing::error!("Invalid remote path: {e}");
-                                    send_result(
-                                        &mut socket,
-                                        &ServerResult::Err(e.to_string().into()),
-                                    )?;
-                                }
-                            }
+                        Err(e) => {
+                            tracing::error!("Invalid remote path: {e}");
+                            send_result(&mut socket, &ServerResult::Err(e.to_string().into()))?;
                         }
-                        // For child threads
-                        ServerCommand::Prealloc(_, _) => todo!(),
-                        ServerCommand::ReceiveData(_, _, _) => todo!(),
                     }
-                } else {
-                    tracing::debug!("Main Client disconnected...");
-                    break;
+                }
+                ServerCommand::CreateDir(dir) => {
+                    let res = if root_dest.is_none() && args.output.is_some() {
+                        Err(anyhow::anyhow!(
+                            "receiving a directory requires an output directory"
+                        ))
+                    } else {
+                        receive_destination(args, &dir, root_dest.as_deref())
+                    }
+                    .and_then(|dir| {
+                        tracing::debug!("Creating directory: {dir:?}");
+                        Ok(fs::create_dir_all(dir)?)
+                    });
+                    match res {
+                        Ok(_) => send_result(&mut socket, &ServerResult::Ok)?,
+                        Err(e) => {
+                            tracing::error!("Failed creating directory {dir}: {e}");
+                            send_result(
+                                &mut socket,
+                                &ServerResult::err(format!("Failed creating directory {dir}: {e}")),
+                            )?;
+                        }
+                    }
+                }
+                ServerCommand::Sync(opts) => {
+                    let mut framed_reader = FramedReader::new(&mut socket);
+                    let manifest: Manifest = bincode::deserialize_from(&mut framed_reader)?;
+                    framed_reader.finish()?;
+                    tracing::debug!("Syncing {} file(s) with {opts:?}", manifest.files.len());
+                    let plan = sync::plan_sync(args, root_dest.as_deref(), &manifest, opts)
+                        .map_err(|e| {
+                            tracing::error!("Failed syncing: {e}");
+                            e.to_string()
+                        });
+                    let mut framed_writer = FramedWriter::new(io::BufWriter::new(&mut socket));
+                    bincode::serialize_into(&mut framed_writer, &plan)?;
+                    framed_writer.finish()?;
+                }
+                // Only sent to child threads, a client that sends them here is misbehaving
+                cmd => {
+                    let reason = format!("unexpected command on the main socket: {cmd:?}");
+                    send_result(&mut socket, &ServerResult::err(reason.clone()))?;
+                    stop_flag.store(true, Ordering::Relaxed);
+                    bail!(reason);
                 }
             }
+        } else {
+            tracing::debug!("Main Client disconnected...");
+            // Stop the child threads, files that weren't completely received are removed
+            stop_flag.store(true, Ordering::Relaxed);
+            break;
         }
-        Err(e) => bail!(e),
     }
     Ok(())
 }
+
+/// Create the root destination of a recursive transfer if it doesn't exist yet, so that received directories end up inside it
+fn prepare_root_dest(mode: &DestinationMode, root_dest: PathBuf) -> Result<PathBuf> {
+    if *mode == DestinationMode::RecusiveDirectory && !root_dest.exists() {
+        tracing::debug!("Creating root destination: {root_dest:?}");
+        fs::create_dir(&root_dest)?;
+    }
+    Ok(root_dest)
+}
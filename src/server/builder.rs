@@ -0,0 +1,196 @@
+//! Embed the server in another program, without parsing any command-line arguments.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::bail;
+
+use crate::{
+    auth::PreSharedKey,
+    config::{
+        compression::CompressionVariant,
+        misc::OutputFormat,
+        transfer::listen::{ListenArgs, OverwritePolicy},
+    },
+    observer::{SharedObserver, TransferObserver},
+    rate_limit::RateLimit,
+    server::{
+        report::{OnReceived, ReceivedFile, ReceivedFiles, ServerReport},
+        run_server, run_server_keep_alive,
+    },
+};
+
+/// A server that is listening for clients, see [Server::builder]
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    args: ListenArgs,
+}
+
+/// Configures a [Server], the defaults match `qft listen`
+#[derive(Debug)]
+pub struct ServerBuilder {
+    args: ListenArgs,
+}
+
+impl Server {
+    /// Listen at `0.0.0.0:49152` and write received content to stdout unless configured otherwise, e.g.
+    ///
+    /// ```no_run
+    /// # fn main() -> anyhow::Result<()> {
+    /// let server = quick_file_transfer::Server::builder()
+    ///     .port(0)
+    ///     .output_dir("received")
+    ///     .build()?;
+    /// println!("Listening at {}", server.local_addr()?);
+    /// for file in server.run()?.files {
+    ///     println!("Received {} [{} B] in {:?}", file.name, file.size, file.duration);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            args: ListenArgs {
+                ip: Ipv4Addr::UNSPECIFIED.to_string(),
+                port: 49152,
+                output: None,
+                output_dir: None,
+                remote: false,
+                decompression: None,
+                keep_alive: false,
+                max_sessions: None,
+                overwrite: OverwritePolicy::default(),
+                psk: None,
+                encrypt: false,
+                limit_rate: None,
+                on_received: None,
+                observer: SharedObserver::default(),
+            },
+        }
+    }
+
+    /// The address the server listens at, e.g. to find the port that was picked if it listens at port `0`
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve a single client and return the files it sent, once it ends the transfer or disconnects.
+    ///
+    /// With [ServerBuilder::keep_alive] any number of clients are served and this only returns if accepting a client fails,
+    /// the received files are then only passed to [ServerBuilder::on_received].
+    pub fn run(self) -> anyhow::Result<ServerReport> {
+        let Self { listener, mut args } = self;
+        if args.keep_alive {
+            let max_sessions = args.max_sessions;
+            run_server_keep_alive(&listener, &args, max_sessions, OutputFormat::Text)?;
+            return Ok(ServerReport::default());
+        }
+        let received = ReceivedFiles::collect(&mut args);
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        run_server(&listener, &args, &stop_flag)?;
+        Ok(received.take_report())
+    }
+}
+
+impl ServerBuilder {
+    /// The IP to listen at, e.g. `127.0.0.1` to only accept local clients
+    pub fn ip(mut self, ip: impl Into<IpAddr>) -> Self {
+        self.args.ip = ip.into().to_string();
+        self
+    }
+
+    /// The port to listen at, `0` picks any free port (see [Server::local_addr])
+    pub fn port(mut self, port: u16) -> Self {
+        self.args.port = port;
+        self
+    }
+
+    /// Write the received content to this file
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.output = Some(path.into());
+        self
+    }
+
+    /// Receive files into this directory, by the names they're sent as
+    pub fn output_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.args.output_dir = Some(dir.into());
+        self
+    }
+
+    /// Decompress the received content, for clients that compress without negotiating it
+    pub fn decompression(mut self, decompression: CompressionVariant) -> Self {
+        self.args.decompression = Some(decompression);
+        self
+    }
+
+    /// Keep serving clients, each in their own session, instead of returning after the first client
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.args.keep_alive = keep_alive;
+        self
+    }
+
+    /// Serve up to this many clients at the same time in keep-alive mode
+    pub fn max_sessions(mut self, max_sessions: u16) -> Self {
+        self.args.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// What to do when a received file already exists
+    pub fn overwrite(mut self, policy: OverwritePolicy) -> Self {
+        self.args.overwrite = policy;
+        self
+    }
+
+    /// Only accept files from clients that know this key
+    pub fn psk(mut self, psk: PreSharedKey) -> Self {
+        self.args.psk = Some(psk);
+        self
+    }
+
+    /// Require clients to encrypt the connection
+    pub fn encrypt(mut self, encrypt: bool) -> Self {
+        self.args.encrypt = encrypt;
+        self
+    }
+
+    /// Limit the bandwidth of all clients combined
+    pub fn limit_rate(mut self, limit_rate: RateLimit) -> Self {
+        self.args.limit_rate = Some(limit_rate);
+        self
+    }
+
+    /// Called with each file as soon as it's received, from the thread that received it
+    pub fn on_received<F>(mut self, on_received: F) -> Self
+    where
+        F: Fn(&ReceivedFile) + Send + Sync + 'static,
+    {
+        self.args.on_received = Some(OnReceived(Arc::new(on_received)));
+        self
+    }
+
+    /// Observe the received files
+    pub fn observer(mut self, observer: impl TransferObserver + 'static) -> Self {
+        self.args.observer = SharedObserver::new(observer);
+        self
+    }
+
+    /// Start listening
+    pub fn build(self) -> anyhow::Result<Server> {
+        let Self { args } = self;
+        if args.output.is_some() && args.output_dir.is_some() {
+            bail!("Received content is written to either an output file or an output directory, not both");
+        }
+        match args.max_sessions {
+            Some(_) if !args.keep_alive => bail!("Limiting the sessions requires keep-alive"),
+            Some(0) => bail!("At least one session must be allowed"),
+            _ => (),
+        }
+        let listener = TcpListener::bind((args.ip.as_str(), args.port))?;
+        Ok(Server { listener, args })
+    }
+}
@@ -1,21 +1,45 @@
 use std::{
+    collections::HashMap,
     fs,
-    io::{self},
+    io::{self, Write},
     net::{TcpListener, TcpStream},
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 
 use crate::{
-    config::transfer::{command::ServerCommand, listen::ListenArgs},
-    server::util::handle_receive_data,
-    util::{create_file_with_len, read_server_cmd, server_handshake},
+    auth::AuthError,
+    checksum::{Checksum, PrefixHash},
+    config::transfer::{
+        command::{ResolvedDestination, ServerCommand, ServerResult},
+        handshake::Capabilities,
+        listen::{ListenArgs, OverwritePolicy},
+    },
+    delta::Signature,
+    events::{self, Event},
+    framed_stream::{FramedReader, FramedWriter},
+    preserve::{FileMetadata, Timestamp},
+    progress::FileProgress,
+    server::{
+        overwrite::{resolve_destination, Destination},
+        partial::{create_temp, resumable_content, temp_path, AlreadyReceiving, PartialFile},
+        path::RejectedName,
+        report::ReceivedFile,
+        util::{
+            handle_receive_data, handle_receive_delta, handle_receive_range, handle_receive_stdout,
+            receive_destination, receives_to_stdout, send_reply, send_result,
+            verify_received_checksum, InterruptedTransfer, ReceivedContent,
+        },
+    },
+    transport::QftStream,
+    util::{read_server_cmd, server_handshake},
+    BUFFERED_RW_BUFSIZE,
 };
 
 pub fn run_child(
@@ -23,11 +47,25 @@ pub fn run_child(
     cfg: &ListenArgs,
     stop_flag: &Arc<AtomicBool>,
     root_dest: Option<&Path>,
+    ranged_files: &RangedFiles,
 ) -> anyhow::Result<()> {
+    let mut failures: Vec<String> = vec![];
     for client in listener.incoming() {
         match client {
-            Ok(mut socket) => {
-                handle_child_socket(cfg, &mut socket, root_dest)?;
+            Ok(socket) => {
+                let peer_addr = socket.peer_addr();
+                // A failed file shouldn't prevent receiving the rest of the files
+                match handle_child_socket(cfg, socket, root_dest, ranged_files) {
+                    Ok(()) => (),
+                    // Not a peer of the client, just drop it
+                    Err(e) if e.is::<AuthError>() => {
+                        log::warn!("Dropped peer at {peer_addr:?}: {e}");
+                    }
+                    Err(e) => {
+                        log::error!("{e}");
+                        failures.push(e.to_string());
+                    }
+                }
             }
             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                 tracing::trace!("Would block - yielding thread");
@@ -42,27 +80,55 @@ pub fn run_child(
             break;
         }
     }
+    if !failures.is_empty() {
+        bail!(failures.join("\n"));
+    }
     Ok(())
 }
 
 pub fn handle_child_socket(
     cfg: &ListenArgs,
-    socket: &mut TcpStream,
+    socket: TcpStream,
     root_dest: Option<&Path>,
+    ranged_files: &RangedFiles,
 ) -> anyhow::Result<()> {
     socket
         .set_nonblocking(false)
         .expect("Failed putting socket into blocking state");
     tracing::trace!("{socket:?}");
     tracing::trace!("Got client at {}", socket.local_addr()?);
-    server_handshake(socket)?;
-    let mut cmd_buf: [u8; 256] = [0; 256];
+    let peer = events::peer(&socket);
+    events::emit(&Event::Connect { peer: peer.clone() });
+    let (mut socket, negotiated) = server_handshake(socket, cfg.psk.as_ref(), cfg.encrypt)?;
+    events::emit(&Event::handshake_ok(peer, &negotiated));
+    let mut cmd_buf: Vec<u8> = Vec::with_capacity(256);
+    let mut state = ChildSocketState {
+        verifies_checksum: negotiated.capabilities.contains(Capabilities::CHECKSUM),
+        ranged_files: ranged_files.clone(),
+        ..Default::default()
+    };
 
     loop {
         tracing::info!("Ready to receive command");
-        if let Some(cmd) = read_server_cmd(socket, &mut cmd_buf)? {
+        if let Some(cmd) = read_server_cmd(&mut socket, &mut cmd_buf)? {
             log::trace!("Received command: {cmd:?}");
-            handle_child_cmd(cmd, cfg, socket, root_dest)?;
+            if let Err(e) = handle_child_cmd(cmd, cfg, &mut socket, root_dest, &mut state) {
+                events::emit(&Event::Error {
+                    name: None,
+                    message: e.to_string(),
+                });
+                cfg.observer.on_error(None, &e);
+                match e.downcast::<InterruptedTransfer>() {
+                    // The client reconnects to resume the transfer
+                    Ok(interrupted) if state.resumable => {
+                        log::warn!("{interrupted}, waiting for the client to resume");
+                        interrupted.file.keep();
+                        break;
+                    }
+                    Ok(interrupted) => return Err(interrupted.into()),
+                    Err(e) => return Err(e),
+                }
+            }
         } else {
             tracing::info!("Client disconnected...");
             break;
@@ -71,45 +137,1008 @@ pub fn handle_child_socket(
     Ok(())
 }
 
+/// State that is kept between the commands received on a child socket
+#[derive(Debug, Default)]
+pub struct ChildSocketState {
+    /// The client verifies the checksum of each file, which is only moved into place once verified
+    pub verifies_checksum: bool,
+    /// The content received by the latest [ServerCommand::ReceiveData], until its checksum is verified
+    pub last_received: Option<ReceivedContent>,
+    /// What the latest [ServerCommand::ReceiveData] to stdout or [ServerCommand::ReceiveRange] wrote
+    /// and the checksum of the written content, until it is verified
+    pub last_written: Option<(String, Checksum)>,
+    /// The file name and hash of the already received prefix that the next received content resumes
+    pub resume_from: Option<(String, PrefixHash)>,
+    /// The client asked where to resume, so it will reconnect if the transfer is interrupted
+    pub resumable: bool,
+    /// The size of the next received file, if the client preallocated it
+    pub expected_len: Option<u64>,
+    /// Permissions and times to apply to the next received file
+    pub metadata: Option<FileMetadata>,
+    /// The sent name and the destination of the file that is being received, once the overwrite policy is applied
+    pub destination: Option<(String, PartialFile)>,
+    /// Why the file that is being received is refused (its name was rejected or the existing file is kept),
+    /// reported to the client when verifying the content
+    pub refused: Option<anyhow::Error>,
+    /// The sent name, existing file and block length of the latest [ServerCommand::GetSignature], to apply the delta to
+    pub delta_basis: Option<(String, fs::File, u32)>,
+    /// The size of the file that is received in ranges, and when receiving it started
+    pub ranges_started: Option<(u64, Instant)>,
+    /// The files that the session of this child socket receives in ranges
+    pub ranged_files: RangedFiles,
+}
+
+/// The temporary files that a session receives in ranges, and their size.
+///
+/// Shared by the child sockets of the session, which only receive a range into a file that the session started receiving
+/// in ranges with [ServerCommand::ReceiveRanges], and only within its size.
+#[derive(Debug, Default, Clone)]
+pub struct RangedFiles(Arc<Mutex<HashMap<PathBuf, u64>>>);
+
+impl RangedFiles {
+    fn files(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, u64>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start(&self, temp: &Path, size: u64) {
+        self.files().insert(temp.to_path_buf(), size);
+    }
+
+    fn finish(&self, temp: &Path) {
+        self.files().remove(temp);
+    }
+
+    /// The size of the file that is received in ranges into `temp`, if the session receives it in ranges
+    fn size_of(&self, temp: &Path) -> Option<u64> {
+        self.files().get(temp).copied()
+    }
+}
+
+impl ChildSocketState {
+    /// Keep the reason a file is refused until the client verifies the content, which fails with the reason
+    fn refuse(&mut self, reason: anyhow::Error) {
+        log::warn!("{reason}");
+        self.refused = Some(reason);
+    }
+
+    /// Apply the overwrite policy to the destination of `fname`, a file that was modified at `modified` (if known)
+    fn resolve(
+        &mut self,
+        cfg: &ListenArgs,
+        fname: &str,
+        root_dest: Option<&Path>,
+        modified: Option<Timestamp>,
+    ) -> anyhow::Result<ResolvedDestination> {
+        let dest = receive_destination(cfg, fname, root_dest)?;
+        let Destination {
+            path,
+            backup,
+            resolved,
+        } = resolve_destination(cfg.overwrite, fname, dest, modified)?;
+        match &resolved {
+            ResolvedDestination::Skipped(reason) => self.refuse(anyhow!("{reason}")),
+            ResolvedDestination::Renamed(name) => {
+                log::info!("{fname} already exists, receiving it as {name}");
+            }
+            ResolvedDestination::Receive | ResolvedDestination::BackedUp(_) => (),
+        }
+        // Released first, so that resolving the same file again doesn't count as another transfer receiving it
+        self.destination = None;
+        if let Some(path) = path {
+            match PartialFile::new(path) {
+                Ok(file) => self.destination = Some((fname.to_owned(), file.with_backup(backup))),
+                Err(e) => self.refuse(e.into()),
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Apply the metadata to the received file, which is moved into place once it is verified (if the client verifies it)
+    fn complete(&mut self, cfg: &ListenArgs, received: ReceivedContent) -> anyhow::Result<()> {
+        // Applied before the file is moved into place, which keeps the permissions and times
+        if let Some(metadata) = self.metadata.take() {
+            if let Err(e) = metadata.apply(received.file.temp()) {
+                log::warn!(
+                    "Failed preserving permissions and times of {:?}: {e}",
+                    received.file.dest()
+                );
+            }
+        }
+        if self.verifies_checksum {
+            self.last_received = Some(received);
+        } else {
+            let file = received.received_file();
+            received.file.persist()?;
+            cfg.received(&file);
+        }
+        Ok(())
+    }
+
+    /// Apply the overwrite policy to the destination of `fname` unless it was already applied to `fname` (or the file is refused)
+    fn resolve_once(
+        &mut self,
+        cfg: &ListenArgs,
+        fname: &str,
+        root_dest: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        let resolved =
+            matches!(&self.destination, Some((resolved_fname, _)) if resolved_fname == fname);
+        if !resolved && self.refused.is_none() {
+            let modified = self.metadata.and_then(|md| md.modified);
+            self.resolve(cfg, fname, root_dest, modified)?;
+        }
+        Ok(())
+    }
+}
+
+/// The partially received content of `dest` to resume, if resuming it doesn't replace an existing file that
+/// the overwrite policy protects (the policy is only applied to files that are sent from the start)
+fn resumable(cfg: &ListenArgs, dest: &Path) -> Option<PathBuf> {
+    if cfg.overwrite != OverwritePolicy::Always && dest.exists() {
+        log::debug!(
+            "Not resuming {dest:?}, it exists and the overwrite policy is {}",
+            cfg.overwrite
+        );
+        return None;
+    }
+    resumable_content(dest)
+}
+
 pub fn handle_child_cmd(
     cmd: ServerCommand,
     cfg: &ListenArgs,
-    socket: &mut TcpStream,
+    socket: &mut QftStream,
     root_dest: Option<&Path>,
+    state: &mut ChildSocketState,
 ) -> anyhow::Result<()> {
     match cmd {
+        // There's nothing to preallocate when writing to stdout
+        ServerCommand::Prealloc(fsize, _) if receives_to_stdout(cfg, root_dest) => {
+            state.expected_len = Some(fsize);
+        }
         ServerCommand::Prealloc(fsize, fname) => {
-            if let Some(root_dest) = root_dest {
-                if root_dest.is_dir() {
-                    let dest = root_dest.join(fname);
-                    create_file_with_len(&dest, fsize)?;
-                } else {
-                    create_file_with_len(root_dest, fsize)?;
+            match state.resolve_once(cfg, &fname, root_dest) {
+                Err(e) if e.is::<RejectedName>() => {
+                    state.refuse(e);
+                    return Ok(());
                 }
-            } else if let Some(out_dir) = cfg.output_dir.as_deref() {
-                if !out_dir.is_dir() && out_dir.exists() {
-                    bail!("Output directory path {out_dir:?} is invalid - has to point at a directory or non-existent path")
-                }
-                if !out_dir.exists() {
-                    fs::create_dir(out_dir)?;
-                }
-                let out_file = out_dir.join(fname);
-                log::trace!("Preallocating for path: {out_dir:?}");
-                create_file_with_len(&out_file, fsize)?;
-            } else if let Some(out_file) = cfg.output.as_deref() {
-                create_file_with_len(out_file, fsize)?;
+                resolved => resolved?,
+            };
+            if let Some((_, file)) = &state.destination {
+                log::trace!("Preallocating for path: {:?}", file.temp());
+                create_temp(file.temp())?.set_len(fsize)?;
+            }
+            state.expected_len = Some(fsize);
+        }
+        ServerCommand::ReceiveData(_f_count, fname, decompr)
+            if receives_to_stdout(cfg, root_dest) =>
+        {
+            log::debug!("Writing {fname:?} to stdout");
+            // Permissions and times only apply to files
+            state.metadata = None;
+            let progress =
+                FileProgress::new(&fname, state.expected_len.take(), None, &cfg.observer);
+            let (written, checksum) =
+                handle_receive_stdout(socket, decompr, &progress, cfg.limit_rate.as_ref())?;
+            // Content on stdout can't be taken back, it's received whether it's verified or not
+            cfg.received(&written);
+            if state.verifies_checksum {
+                state.last_written = Some(("content written to stdout".to_owned(), checksum));
             }
         }
         ServerCommand::ReceiveData(_f_count, fname, decompr) => {
             log::debug!("Received file list: {fname:?}");
-            handle_receive_data(cfg, socket, fname, decompr, root_dest)?;
+            let resume_from = state
+                .resume_from
+                .take()
+                .filter(|(resume_fname, _)| *resume_fname == fname)
+                .map(|(_, prefix)| prefix);
+            // Resumed content is appended to the temporary file, which only replaces an existing file if that's always allowed
+            let file = if resume_from.is_some() {
+                receive_destination(cfg, &fname, root_dest)
+                    .and_then(|dest| Ok(Some(PartialFile::new(dest)?)))
+            } else {
+                state
+                    .resolve_once(cfg, &fname, root_dest)
+                    .map(|()| state.destination.take().map(|(_, file)| file))
+            };
+            let file = match file {
+                Err(e) if e.is::<RejectedName>() || e.is::<AlreadyReceiving>() => {
+                    state.refuse(e);
+                    None
+                }
+                file => file?,
+            };
+            let Some(file) = file else {
+                // Discard the content so that the client gets to verify it and is told why it was refused
+                let mut framed_reader = FramedReader::new(&mut *socket);
+                io::copy(&mut framed_reader, &mut io::sink())?;
+                framed_reader.finish()?;
+                return Ok(());
+            };
+            let progress =
+                FileProgress::new(&fname, state.expected_len.take(), None, &cfg.observer);
+            let received = handle_receive_data(
+                socket,
+                file,
+                decompr,
+                resume_from,
+                &progress,
+                cfg.limit_rate.as_ref(),
+            )?;
+            state.complete(cfg, received)?;
+        }
+        ServerCommand::SetMetadata(metadata) => {
+            log::trace!("Preserving metadata: {metadata:?}");
+            state.metadata = Some(metadata);
         }
-        // TODO: Constrict these to only the main thread.
-        ServerCommand::GetFreePort(_) => todo!(),
-        ServerCommand::EndOfTransfer => {
-            unreachable!("Child thread received end of transfer command")
+        ServerCommand::VerifyChecksum(expected) => {
+            if let Some(refused) = state.refused.take() {
+                send_result(socket, &ServerResult::err(refused.to_string()))?;
+                return Err(refused);
+            }
+            if let Some((written, checksum)) = state.last_written.take() {
+                if checksum != expected {
+                    let e = anyhow!("Checksum mismatch for the {written}: expected {expected}, written content has checksum {checksum}");
+                    send_result(socket, &ServerResult::err(e.to_string()))?;
+                    return Err(e);
+                }
+                log::debug!("Checksum verified: {expected}");
+                send_result(socket, &ServerResult::Ok)?;
+                return Ok(());
+            }
+            let Some(received) = state.last_received.take() else {
+                send_result(socket, &ServerResult::err("No received content to verify"))?;
+                bail!("Received checksum without receiving any content");
+            };
+            let file = received.received_file();
+            if let Err(e) = verify_received_checksum(received, &expected) {
+                send_result(socket, &ServerResult::err(e.to_string()))?;
+                return Err(e);
+            }
+            cfg.received(&file);
+            send_result(socket, &ServerResult::Ok)?;
+        }
+        // Content written to stdout can't be resumed
+        ServerCommand::GetResumeOffset(_) if receives_to_stdout(cfg, root_dest) => {
+            socket.write_all(&0_u64.to_be_bytes())?;
+            socket.flush()?;
+        }
+        ServerCommand::GetResumeOffset(fname) => {
+            state.resumable = true;
+            let dest = match receive_destination(cfg, &fname, root_dest) {
+                Err(e) if e.is::<RejectedName>() => {
+                    // Nothing to resume, the refusal is reported once the content is verified
+                    socket.write_all(&0_u64.to_be_bytes())?;
+                    socket.flush()?;
+                    state.refuse(e);
+                    return Ok(());
+                }
+                dest => dest?,
+            };
+            let offset = resumable(cfg, &dest)
+                .and_then(|partial| fs::metadata(partial).ok())
+                .map_or(0, |md| md.len());
+            log::debug!("Resume offset of {dest:?}: {offset}");
+            socket.write_all(&offset.to_be_bytes())?;
+            socket.flush()?;
+        }
+        ServerCommand::ResumeFrom(fname, offset, expected) => {
+            let dest = match receive_destination(cfg, &fname, root_dest) {
+                Err(e) if e.is::<RejectedName>() => {
+                    send_result(socket, &ServerResult::err(e.to_string()))?;
+                    state.refuse(e);
+                    return Ok(());
+                }
+                dest => dest?,
+            };
+            let Some(partial) = resumable(cfg, &dest) else {
+                send_result(
+                    socket,
+                    &ServerResult::err(format!("There's nothing to resume for {dest:?}")),
+                )?;
+                return Ok(());
+            };
+            let prefix = fs::File::open(&partial)
+                .and_then(|f| PrefixHash::of_reader(io::BufReader::new(f), offset));
+            match prefix {
+                Ok(prefix) if prefix.checksum() == expected => {
+                    state.resume_from = Some((fname, prefix));
+                    send_result(socket, &ServerResult::Ok)?;
+                }
+                Ok(_) => send_result(
+                    socket,
+                    &ServerResult::err(format!(
+                        "The first {offset} B of {dest:?} differ from the sent file"
+                    )),
+                )?,
+                Err(e) => send_result(
+                    socket,
+                    &ServerResult::err(format!(
+                        "Failed reading the first {offset} B of {dest:?}: {e}"
+                    )),
+                )?,
+            }
+        }
+        // Nothing on stdout is overwritten
+        ServerCommand::ResolveDestination(_, _) if receives_to_stdout(cfg, root_dest) => {
+            send_reply(socket, &ResolvedDestination::Receive)?;
+        }
+        ServerCommand::ResolveDestination(fname, modified) => {
+            let resolved = match state.resolve(cfg, &fname, root_dest, modified) {
+                Err(e) if e.is::<RejectedName>() => {
+                    // The client is told why once it verifies the content
+                    state.refuse(e);
+                    ResolvedDestination::Receive
+                }
+                resolved => resolved?,
+            };
+            send_reply(socket, &resolved)?;
+        }
+        ServerCommand::ReceiveRanges(_, _) if receives_to_stdout(cfg, root_dest) => {
+            send_result(
+                socket,
+                &ServerResult::err("Receiving to stdout, a file can't be received in ranges"),
+            )?;
+        }
+        ServerCommand::ReceiveRanges(fname, fsize) => {
+            match state.resolve_once(cfg, &fname, root_dest) {
+                Err(e) if e.is::<RejectedName>() => state.refuse(e),
+                resolved => resolved?,
+            };
+            if let Some(refused) = state.refused.take() {
+                send_result(socket, &ServerResult::err(refused.to_string()))?;
+                return Err(refused);
+            }
+            if let Some((_, file)) = &state.destination {
+                log::debug!("Receiving {fname:?} in ranges into {:?}", file.temp());
+                create_temp(file.temp())?.set_len(fsize)?;
+                state.ranged_files.start(file.temp(), fsize);
+            }
+            state.ranges_started = Some((fsize, Instant::now()));
+            send_result(socket, &ServerResult::Ok)?;
+        }
+        ServerCommand::ReceiveRange(fname, _, _) if receives_to_stdout(cfg, root_dest) => {
+            bail!("Receiving to stdout, {fname:?} can't be received in ranges")
+        }
+        ServerCommand::ReceiveRange(fname, offset, decompr) => {
+            let dest = receive_destination(cfg, &fname, root_dest)?;
+            let temp = temp_path(&dest);
+            let Some(size) = state.ranged_files.size_of(&temp) else {
+                bail!("{fname:?} isn't being received in ranges by this transfer")
+            };
+            if offset > size {
+                bail!("The range at offset {offset} is past the end of {fname:?} ({size} B)")
+            }
+            let progress =
+                FileProgress::new(&format!("{fname} @{offset}"), None, None, &cfg.observer);
+            let checksum = handle_receive_range(
+                socket,
+                &temp,
+                offset,
+                size - offset,
+                decompr,
+                &progress,
+                cfg.limit_rate.as_ref(),
+            )?;
+            if state.verifies_checksum {
+                state.last_written =
+                    Some((format!("range at offset {offset} of {dest:?}"), checksum));
+            }
+        }
+        ServerCommand::FinishRanges(expected) => {
+            let Some((fname, file)) = state.destination.take() else {
+                send_result(socket, &ServerResult::err("No file is received in ranges"))?;
+                bail!("Received finish ranges without receiving a file in ranges");
+            };
+            state.ranged_files.finish(file.temp());
+            let (size, started) = state
+                .ranges_started
+                .take()
+                .map_or((0, None), |(size, started)| (size, Some(started)));
+            // Each range was verified, this catches ranges that overlap or are missing
+            let checksum = fs::File::open(file.temp())
+                .and_then(|f| PrefixHash::of_reader(io::BufReader::new(f), size))
+                .map(|content| content.checksum());
+            match checksum {
+                Ok(checksum) if checksum == expected => (),
+                Ok(checksum) => {
+                    let e = anyhow!(
+                        "Checksum mismatch for {:?}: expected {expected}, received content has checksum {checksum}",
+                        file.dest()
+                    );
+                    send_result(socket, &ServerResult::err(e.to_string()))?;
+                    return Err(e);
+                }
+                Err(e) => {
+                    let e = anyhow!("Failed verifying {:?}: {e}", file.dest());
+                    send_result(socket, &ServerResult::err(e.to_string()))?;
+                    return Err(e);
+                }
+            }
+            // Applied before the file is moved into place, which keeps the permissions and times
+            if let Some(metadata) = state.metadata.take() {
+                if let Err(e) = metadata.apply(file.temp()) {
+                    log::warn!(
+                        "Failed preserving permissions and times of {:?}: {e}",
+                        file.dest()
+                    );
+                }
+            }
+            let dest = file.dest().to_path_buf();
+            if let Err(e) = file.persist() {
+                let e = anyhow!("Failed moving the received file into place at {dest:?}: {e}");
+                send_result(socket, &ServerResult::err(e.to_string()))?;
+                return Err(e);
+            }
+            log::info!("Received {dest:?} in ranges");
+            cfg.received(&ReceivedFile {
+                name: fname,
+                path: Some(dest),
+                size,
+                duration: started.map_or(Duration::ZERO, |started| started.elapsed()),
+            });
+            send_result(socket, &ServerResult::Ok)?;
+        }
+        ServerCommand::GetSignature(fname) => {
+            let existing = if receives_to_stdout(cfg, root_dest) {
+                None
+            } else {
+                match receive_destination(cfg, &fname, root_dest) {
+                    // The client is told why once it verifies the content
+                    Err(e) if e.is::<RejectedName>() => None,
+                    dest => fs::File::open(dest?).ok(),
+                }
+            };
+            let signature = match &existing {
+                Some(file) => {
+                    let len = file.metadata()?.len();
+                    Signature::of_reader(
+                        io::BufReader::with_capacity(BUFFERED_RW_BUFSIZE, file),
+                        len,
+                        Signature::block_len_for(len),
+                    )?
+                }
+                None => Signature::default(),
+            };
+            log::debug!(
+                "Signature of {fname:?}: {} blocks of {} B",
+                signature.blocks.len(),
+                signature.block_len
+            );
+            state.delta_basis = existing.map(|existing| (fname, existing, signature.block_len));
+            let mut framed_writer = FramedWriter::new(io::BufWriter::new(&mut *socket));
+            bincode::serialize_into(&mut framed_writer, &signature)?;
+            framed_writer.finish()?;
+        }
+        ServerCommand::ReceiveDelta(fname, _) if receives_to_stdout(cfg, root_dest) => {
+            bail!("Receiving to stdout, {fname:?} can't be received as a delta")
+        }
+        ServerCommand::ReceiveDelta(fname, decompr) => {
+            let Some((_, mut basis, block_len)) = state
+                .delta_basis
+                .take()
+                .filter(|(basis_fname, _, _)| *basis_fname == fname)
+            else {
+                bail!("Received a delta of {fname:?} without an existing file to apply it to");
+            };
+            let file = match state.resolve_once(cfg, &fname, root_dest) {
+                Err(e) if e.is::<RejectedName>() => {
+                    state.refuse(e);
+                    None
+                }
+                resolved => {
+                    resolved?;
+                    state.destination.take().map(|(_, file)| file)
+                }
+            };
+            let Some(file) = file else {
+                // Discard the delta so that the client gets to verify it and is told why it was refused
+                let mut framed_reader = FramedReader::new(&mut *socket);
+                io::copy(&mut framed_reader, &mut io::sink())?;
+                framed_reader.finish()?;
+                return Ok(());
+            };
+            let progress = FileProgress::new(&fname, None, None, &cfg.observer);
+            let received = handle_receive_delta(
+                socket,
+                file,
+                &mut basis,
+                block_len,
+                decompr,
+                &progress,
+                cfg.limit_rate.as_ref(),
+            )?;
+            state.complete(cfg, received)?;
+        }
+        // Only sent to the main thread, a client that sends them here is misbehaving
+        cmd => {
+            let reason = format!("unexpected command on a child socket: {cmd:?}");
+            send_result(socket, &ServerResult::err(reason.clone()))?;
+            bail!(reason);
         }
-        ServerCommand::IsDestinationValid(_, _) => todo!(),
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::transfer::{listen::OverwritePolicy, util::TcpConnectMode},
+        framed_stream::FramedWriter,
+        observer::SharedObserver,
+        send::util::qft_connect_to_server,
+        util::read_server_response,
+    };
+    use pretty_assertions::assert_eq;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    fn socket_pair() -> io::Result<(QftStream, QftStream)> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+        Ok((QftStream::plain(client), QftStream::plain(server)))
+    }
+
+    fn listen_args(output_dir: &Path, overwrite: OverwritePolicy) -> ListenArgs {
+        ListenArgs {
+            ip: "127.0.0.1".to_owned(),
+            port: 0,
+            output: None,
+            output_dir: Some(output_dir.to_path_buf()),
+            remote: false,
+            decompression: None,
+            keep_alive: false,
+            max_sessions: None,
+            overwrite,
+            psk: None,
+            encrypt: false,
+            limit_rate: None,
+            on_received: None,
+            observer: SharedObserver::default(),
+        }
+    }
+
+    #[test]
+    fn test_traversing_name_is_rejected_when_verifying() -> TestResult {
+        let d = TempDir::new()?;
+        let output_dir = d.child("output");
+        fs::create_dir(&output_dir)?;
+        let cfg = listen_args(&output_dir, OverwritePolicy::Always);
+        let (mut client, mut server) = socket_pair()?;
+        let mut state = ChildSocketState::default();
+        let fname = "../escaped.txt".to_owned();
+
+        handle_child_cmd(
+            ServerCommand::Prealloc(7, fname.clone()),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        let mut framed_writer = FramedWriter::new(&mut client);
+        framed_writer.write_all(b"content")?;
+        framed_writer.finish()?;
+        handle_child_cmd(
+            ServerCommand::ReceiveData(0, fname, None),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        let verified = handle_child_cmd(
+            ServerCommand::VerifyChecksum(Checksum::of(b"content")),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        );
+
+        assert!(verified.unwrap_err().is::<RejectedName>());
+        match read_server_response(&mut client)? {
+            ServerResult::Err(e) => assert!(e.contains("'..' components are not allowed"), "{e}"),
+            ServerResult::Ok => panic!("Expected the name to be rejected"),
+        }
+        assert!(!d.child("escaped.txt").exists());
+        assert_eq!(fs::read_dir(&output_dir)?.count(), 0);
+        Ok(())
+    }
+
+    /// Clients that don't resolve the destination first are refused when verifying the content
+    #[test]
+    fn test_never_overwrite_is_refused_when_verifying() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Never);
+        fs::write(d.child("f.txt"), "existing")?;
+        let (mut client, mut server) = socket_pair()?;
+        let mut state = ChildSocketState::default();
+
+        handle_child_cmd(
+            ServerCommand::Prealloc(7, "f.txt".to_owned()),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        let mut framed_writer = FramedWriter::new(&mut client);
+        framed_writer.write_all(b"content")?;
+        framed_writer.finish()?;
+        handle_child_cmd(
+            ServerCommand::ReceiveData(0, "f.txt".to_owned(), None),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        let verified = handle_child_cmd(
+            ServerCommand::VerifyChecksum(Checksum::of(b"content")),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        );
+
+        assert!(verified.is_err());
+        match read_server_response(&mut client)? {
+            ServerResult::Err(e) => assert!(e.contains("doesn't overwrite"), "{e}"),
+            ServerResult::Ok => panic!("Expected the file to be refused"),
+        }
+        assert_eq!(fs::read_to_string(d.child("f.txt"))?, "existing");
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_is_moved_into_place_once_verified() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Always);
+        let dest = d.child("f.txt");
+        let (mut client, mut server) = socket_pair()?;
+        let mut state = ChildSocketState {
+            verifies_checksum: true,
+            ..Default::default()
+        };
+
+        handle_child_cmd(
+            ServerCommand::Prealloc(7, "f.txt".to_owned()),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        assert!(!dest.exists());
+        let mut framed_writer = FramedWriter::new(&mut client);
+        framed_writer.write_all(b"content")?;
+        framed_writer.finish()?;
+        handle_child_cmd(
+            ServerCommand::ReceiveData(0, "f.txt".to_owned(), None),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        assert!(!dest.exists());
+        handle_child_cmd(
+            ServerCommand::VerifyChecksum(Checksum::of(b"content")),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+
+        assert_eq!(read_server_response(&mut client)?, ServerResult::Ok);
+        assert_eq!(fs::read_to_string(&dest)?, "content");
+        assert_eq!(fs::read_dir(d.path())?.count(), 1, "No temporary file left");
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_session_receiving_same_file_is_refused() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Always);
+        let dest = d.child("f.txt");
+        let (mut client, mut server) = socket_pair()?;
+        let (mut other_client, mut other_server) = socket_pair()?;
+        let mut state = ChildSocketState {
+            verifies_checksum: true,
+            ..Default::default()
+        };
+        let mut other_state = ChildSocketState {
+            verifies_checksum: true,
+            ..Default::default()
+        };
+
+        handle_child_cmd(
+            ServerCommand::Prealloc(7, "f.txt".to_owned()),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        // Another session starts receiving the same file before the first one is done
+        handle_child_cmd(
+            ServerCommand::Prealloc(5, "f.txt".to_owned()),
+            &cfg,
+            &mut other_server,
+            None,
+            &mut other_state,
+        )?;
+        let mut framed_writer = FramedWriter::new(&mut other_client);
+        framed_writer.write_all(b"other")?;
+        framed_writer.finish()?;
+        handle_child_cmd(
+            ServerCommand::ReceiveData(0, "f.txt".to_owned(), None),
+            &cfg,
+            &mut other_server,
+            None,
+            &mut other_state,
+        )?;
+        let verified = handle_child_cmd(
+            ServerCommand::VerifyChecksum(Checksum::of(b"other")),
+            &cfg,
+            &mut other_server,
+            None,
+            &mut other_state,
+        );
+        assert!(verified.unwrap_err().is::<AlreadyReceiving>());
+        match read_server_response(&mut other_client)? {
+            ServerResult::Err(e) => assert!(e.contains("already being received"), "{e}"),
+            ServerResult::Ok => panic!("Expected the second session to be refused"),
+        }
+        assert!(
+            temp_path(&dest).exists(),
+            "The first session's file is kept"
+        );
+
+        let mut framed_writer = FramedWriter::new(&mut client);
+        framed_writer.write_all(b"content")?;
+        framed_writer.finish()?;
+        handle_child_cmd(
+            ServerCommand::ReceiveData(0, "f.txt".to_owned(), None),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        handle_child_cmd(
+            ServerCommand::VerifyChecksum(Checksum::of(b"content")),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        assert_eq!(read_server_response(&mut client)?, ServerResult::Ok);
+        assert_eq!(fs::read_to_string(&dest)?, "content");
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_transfer_leaves_no_file() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Always);
+        let (mut client, mut server) = socket_pair()?;
+        let mut state = ChildSocketState::default();
+
+        handle_child_cmd(
+            ServerCommand::Prealloc(0x10000, "f.txt".to_owned()),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        let mut framed_writer = FramedWriter::new(&mut client);
+        framed_writer.write_all(&[1; 0x1000])?;
+        framed_writer.flush()?;
+        drop(framed_writer);
+        // Disconnect without ending the content
+        drop(client);
+        let interrupted = handle_child_cmd(
+            ServerCommand::ReceiveData(0, "f.txt".to_owned(), None),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )
+        .unwrap_err();
+        assert!(interrupted.is::<InterruptedTransfer>());
+        drop(interrupted);
+
+        assert_eq!(fs::read_dir(d.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_child_port_requires_psk() -> TestResult {
+        let d = TempDir::new()?;
+        let mut cfg = listen_args(d.path(), OverwritePolicy::Always);
+        cfg.psk = Some("s3cret".parse()?);
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let child = std::thread::spawn({
+            let stop_flag = Arc::clone(&stop_flag);
+            move || run_child(&listener, &cfg, &stop_flag, None, &RangedFiles::default())
+        });
+
+        let wrong_key = "wrong".parse()?;
+        let refused = qft_connect_to_server(
+            addr,
+            TcpConnectMode::OneShot,
+            Some(&wrong_key),
+            false,
+            addr.ip(),
+        )
+        .expect_err("a wrong key should be refused");
+        assert_eq!(
+            refused.downcast_ref::<AuthError>(),
+            Some(&AuthError::Refused("wrong pre-shared key".into()))
+        );
+        let refused = qft_connect_to_server(addr, TcpConnectMode::OneShot, None, false, addr.ip())
+            .expect_err("a missing key should be refused");
+        assert_eq!(
+            refused.downcast_ref::<AuthError>(),
+            Some(&AuthError::MissingKey)
+        );
+
+        let key = "s3cret".parse()?;
+        let (socket, _) =
+            qft_connect_to_server(addr, TcpConnectMode::OneShot, Some(&key), false, addr.ip())?;
+        drop(socket);
+        stop_flag.store(true, Ordering::Relaxed);
+        // Dropped peers don't count as failed transfers
+        child.join().expect("Failed joining child thread")?;
+        Ok(())
+    }
+
+    /// A complete file is never moved to the temporary file to resume it, where a failed transfer would remove it
+    #[test]
+    fn test_complete_file_is_not_resumed() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Always);
+        fs::write(d.child("f.txt"), "abc")?;
+        let (mut client, mut server) = socket_pair()?;
+        let mut state = ChildSocketState::default();
+
+        handle_child_cmd(
+            ServerCommand::ResumeFrom("f.txt".to_owned(), 3, Checksum::of(b"abc")),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        match read_server_response(&mut client)? {
+            ServerResult::Err(e) => assert!(e.contains("nothing to resume"), "{e}"),
+            ServerResult::Ok => panic!("Expected the complete file not to be resumed"),
+        }
+        // The client disconnects before sending anything
+        drop(state);
+
+        assert_eq!(fs::read_to_string(d.child("f.txt"))?, "abc");
+        assert_eq!(fs::read_dir(d.path())?.count(), 1, "No temporary file");
+        Ok(())
+    }
+
+    #[test]
+    fn test_main_socket_command_is_refused() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Always);
+        let (mut client, mut server) = socket_pair()?;
+
+        let err = handle_child_cmd(
+            ServerCommand::CreateDir("dir".to_owned()),
+            &cfg,
+            &mut server,
+            None,
+            &mut ChildSocketState::default(),
+        )
+        .unwrap_err();
+
+        assert!(err.to_string().contains("unexpected command"), "{err}");
+        match read_server_response(&mut client)? {
+            ServerResult::Err(reason) => assert!(reason.contains("unexpected command"), "{reason}"),
+            ServerResult::Ok => panic!("Expected the command to be refused"),
+        }
+        assert!(!d.path().join("dir").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_outside_of_file_received_in_ranges_is_refused() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Always);
+        let (mut client, mut server) = socket_pair()?;
+        let (mut range_client, mut range_server) = socket_pair()?;
+        let mut state = ChildSocketState::default();
+        let ranged_files = state.ranged_files.clone();
+        let mut receive_range = |offset: u64, content: &[u8]| -> anyhow::Result<()> {
+            // Another child socket of the same session
+            let mut range_state = ChildSocketState {
+                ranged_files: ranged_files.clone(),
+                ..Default::default()
+            };
+            let mut framed_writer = FramedWriter::new(&mut range_client);
+            framed_writer.write_all(content)?;
+            framed_writer.finish()?;
+            handle_child_cmd(
+                ServerCommand::ReceiveRange("f.txt".to_owned(), offset, None),
+                &cfg,
+                &mut range_server,
+                None,
+                &mut range_state,
+            )
+        };
+
+        let err = receive_range(0, b"content").unwrap_err();
+        assert!(
+            err.to_string().contains("isn't being received in ranges"),
+            "{err}"
+        );
+        assert!(!temp_path(&d.child("f.txt")).exists());
+
+        handle_child_cmd(
+            ServerCommand::ReceiveRanges("f.txt".to_owned(), 7),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        assert_eq!(read_server_response(&mut client)?, ServerResult::Ok);
+        let err = receive_range(8, b"").unwrap_err();
+        assert!(err.to_string().contains("past the end"), "{err}");
+        let err = receive_range(4, b"content").unwrap_err();
+        assert!(format!("{err:#}").contains("past the end"), "{err:#}");
+        assert_eq!(fs::metadata(temp_path(&d.child("f.txt")))?.len(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranges_assembled_into_wrong_content_are_refused() -> TestResult {
+        let d = TempDir::new()?;
+        let cfg = listen_args(d.path(), OverwritePolicy::Always);
+        let (mut client, mut server) = socket_pair()?;
+        let dest = d.child("f.txt");
+        let mut state = ChildSocketState::default();
+        let receive_in_ranges =
+            |state: &mut ChildSocketState, content: &str| -> anyhow::Result<()> {
+                let file = PartialFile::new(dest.clone())?;
+                fs::write(file.temp(), content)?;
+                state.destination = Some(("f.txt".to_owned(), file));
+                state.ranges_started = Some((7, Instant::now()));
+                Ok(())
+            };
+
+        receive_in_ranges(&mut state, "contend")?;
+        let err = handle_child_cmd(
+            ServerCommand::FinishRanges(Checksum::of(b"content")),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{err}");
+        assert!(matches!(
+            read_server_response(&mut client)?,
+            ServerResult::Err(_)
+        ));
+        assert!(!dest.exists());
+        assert!(!temp_path(&dest).exists());
+
+        receive_in_ranges(&mut state, "content")?;
+        handle_child_cmd(
+            ServerCommand::FinishRanges(Checksum::of(b"content")),
+            &cfg,
+            &mut server,
+            None,
+            &mut state,
+        )?;
+        assert!(matches!(
+            read_server_response(&mut client)?,
+            ServerResult::Ok
+        ));
+        assert_eq!(fs::read_to_string(&dest)?, "content");
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Applying the [OverwritePolicy] to received files whose destination already exists.
+
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::transfer::{
+        command::{ResolvedDestination, REMOTE_PATH_SEPARATOR},
+        listen::OverwritePolicy,
+    },
+    preserve::Timestamp,
+};
+
+/// Highest number tried when picking a free `name (N).ext` for [OverwritePolicy::Rename]
+const MAX_RENAME_ATTEMPTS: u32 = 10_000;
+
+/// A destination after applying the overwrite policy
+#[derive(Debug, PartialEq, Eq)]
+pub struct Destination {
+    /// Where the content is written, [None] if the existing file is kept and the content refused
+    pub path: Option<PathBuf>,
+    /// Where the existing file is moved once the received file replaces it
+    pub backup: Option<PathBuf>,
+    pub resolved: ResolvedDestination,
+}
+
+/// Apply `policy` to the destination `dest` of the file sent as `fname`, which was modified at `modified` (if known).
+///
+/// With [OverwritePolicy::Backup] the existing file is only moved to its backup name once the received file replaces it,
+/// so a failed transfer leaves it in place.
+pub fn resolve_destination(
+    policy: OverwritePolicy,
+    fname: &str,
+    dest: PathBuf,
+    modified: Option<Timestamp>,
+) -> anyhow::Result<Destination> {
+    let Ok(existing) = fs::metadata(&dest) else {
+        return Ok(Destination {
+            path: Some(dest),
+            backup: None,
+            resolved: ResolvedDestination::Receive,
+        });
+    };
+    let keep = |reason: String| Destination {
+        path: None,
+        backup: None,
+        resolved: ResolvedDestination::Skipped(reason.into()),
+    };
+    match policy {
+        OverwritePolicy::Always => Ok(Destination {
+            path: Some(dest),
+            backup: None,
+            resolved: ResolvedDestination::Receive,
+        }),
+        OverwritePolicy::Never => Ok(keep(format!(
+            "{fname} already exists and the server doesn't overwrite files"
+        ))),
+        OverwritePolicy::Newer => {
+            let existing_modified = existing
+                .modified()
+                .ok()
+                .and_then(Timestamp::from_system_time);
+            match (modified, existing_modified) {
+                (Some(modified), Some(existing_modified)) if modified > existing_modified => {
+                    Ok(Destination {
+                        path: Some(dest),
+                        backup: None,
+                        resolved: ResolvedDestination::Receive,
+                    })
+                }
+                (Some(_), Some(_)) => Ok(keep(format!(
+                    "{fname} already exists and is not older than the sent file"
+                ))),
+                _ => Ok(keep(format!(
+                    "{fname} already exists and the modification times can't be compared"
+                ))),
+            }
+        }
+        OverwritePolicy::Rename => {
+            let renamed = free_numbered_path(&dest)?;
+            let renamed_name = with_file_name(fname, &renamed);
+            Ok(Destination {
+                path: Some(renamed),
+                backup: None,
+                resolved: ResolvedDestination::Renamed(renamed_name),
+            })
+        }
+        OverwritePolicy::Backup => {
+            let backup = backup_path(&dest);
+            let backup_name = with_file_name(fname, &backup);
+            Ok(Destination {
+                path: Some(dest),
+                backup: Some(backup),
+                resolved: ResolvedDestination::BackedUp(backup_name),
+            })
+        }
+    }
+}
+
+/// `name~` next to `path`, replacing any previous backup
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup_name = path.file_name().unwrap_or_default().to_os_string();
+    backup_name.push("~");
+    path.with_file_name(backup_name)
+}
+
+/// The first of `name (1).ext`, `name (2).ext`, ... that doesn't exist next to `path`
+fn free_numbered_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let stem = path.file_stem().unwrap_or_default();
+    for n in 1..=MAX_RENAME_ATTEMPTS {
+        let mut name = OsString::from(stem);
+        name.push(format!(" ({n})"));
+        if let Some(ext) = path.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+        let candidate = path.with_file_name(name);
+        if fs::symlink_metadata(&candidate).is_err() {
+            return Ok(candidate);
+        }
+    }
+    anyhow::bail!("No free name for {path:?} after {MAX_RENAME_ATTEMPTS} attempts")
+}
+
+/// Replace the last component of the sent name `fname` with the file name of `path`
+fn with_file_name(fname: &str, path: &Path) -> String {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    match fname.rsplit_once(REMOTE_PATH_SEPARATOR) {
+        Some((parent, _)) => format!("{parent}{REMOTE_PATH_SEPARATOR}{file_name}"),
+        None => file_name.into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, SystemTime};
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    fn set_modified(path: &Path, time: SystemTime) -> TestResult {
+        fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(time)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_destination_is_received_with_any_policy() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        for policy in [
+            OverwritePolicy::Always,
+            OverwritePolicy::Never,
+            OverwritePolicy::Newer,
+            OverwritePolicy::Rename,
+            OverwritePolicy::Backup,
+        ] {
+            assert_eq!(
+                resolve_destination(policy, "f.txt", dest.clone(), None)?,
+                Destination {
+                    path: Some(dest.clone()),
+                    backup: None,
+                    resolved: ResolvedDestination::Receive
+                }
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_never_keeps_existing() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        fs::write(&dest, "existing")?;
+
+        let resolved = resolve_destination(OverwritePolicy::Never, "f.txt", dest, None)?;
+        assert_eq!(resolved.path, None);
+        assert!(matches!(resolved.resolved, ResolvedDestination::Skipped(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_newer_compares_modification_times() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        fs::write(&dest, "existing")?;
+        let existing_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        set_modified(&dest, existing_time)?;
+
+        let older = Timestamp::from_system_time(existing_time - Duration::from_secs(1));
+        let newer = Timestamp::from_system_time(existing_time + Duration::from_secs(1));
+        let resolve =
+            |modified| resolve_destination(OverwritePolicy::Newer, "f.txt", dest.clone(), modified);
+        assert_eq!(resolve(newer)?.path, Some(dest.clone()));
+        assert_eq!(resolve(older)?.path, None);
+        assert_eq!(
+            resolve(Timestamp::from_system_time(existing_time))?.path,
+            None
+        );
+        assert_eq!(resolve(None)?.path, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_picks_first_free_number() -> TestResult {
+        let d = TempDir::new()?;
+        let dir = d.child("dir");
+        fs::create_dir(&dir)?;
+        let dest = dir.join("f.tar.gz");
+        fs::write(&dest, "existing")?;
+        fs::write(dir.join("f.tar (1).gz"), "existing")?;
+
+        assert_eq!(
+            resolve_destination(OverwritePolicy::Rename, "dir/f.tar.gz", dest, None)?,
+            Destination {
+                path: Some(dir.join("f.tar (2).gz")),
+                backup: None,
+                resolved: ResolvedDestination::Renamed("dir/f.tar (2).gz".to_owned())
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_keeps_existing_until_replaced() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        fs::write(&dest, "existing")?;
+
+        assert_eq!(
+            resolve_destination(OverwritePolicy::Backup, "f.txt", dest.clone(), None)?,
+            Destination {
+                path: Some(dest.clone()),
+                backup: Some(d.child("f.txt~")),
+                resolved: ResolvedDestination::BackedUp("f.txt~".to_owned())
+            }
+        );
+        assert_eq!(fs::read_to_string(&dest)?, "existing");
+        assert!(!d.child("f.txt~").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! Receiving into a hidden temporary file next to the destination, which is only renamed into place once the content is complete.
+//!
+//! This way a transfer that fails midway never leaves a partial (or preallocated and zero-filled) file under the final name.
+
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::checksum::Checksum;
+
+/// Suffix of the temporary file that content is received into
+pub const PARTIAL_SUFFIX: &str = ".qft-part";
+
+/// The longest file name most file systems allow (in bytes)
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// The hidden temporary file that the content for `dest` is received into, e.g. `dir/.file.txt.qft-part` for `dir/file.txt`
+///
+/// If that name would be too long, the file name is replaced by its checksum, which keeps the name the same for resuming.
+pub fn temp_path(dest: &Path) -> PathBuf {
+    let file_name = dest.file_name().unwrap_or_default();
+    let mut name = OsString::from(".");
+    if 1 + file_name.len() + PARTIAL_SUFFIX.len() <= MAX_FILE_NAME_LEN {
+        name.push(file_name);
+    } else {
+        name.push(Checksum::of(file_name.as_encoded_bytes()).to_string());
+    }
+    name.push(PARTIAL_SUFFIX);
+    dest.with_file_name(name)
+}
+
+/// The partially received content of `dest` to resume, i.e. its temporary file if there is one.
+///
+/// A file under the final name is complete, so it's never resumed (and thereby overwritten).
+pub fn resumable_content(dest: &Path) -> Option<PathBuf> {
+    Some(temp_path(dest)).filter(|temp| temp.is_file())
+}
+
+/// Create the temporary file `temp` anew, replacing whatever was left under its name.
+///
+/// It's created with `create_new`, which fails instead of following a symlink that was planted under the temporary name
+/// to have the received content written elsewhere.
+pub fn create_temp(temp: &Path) -> io::Result<fs::File> {
+    match fs::remove_file(temp) {
+        Ok(()) => log::debug!("Replacing leftover {temp:?}"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(temp)
+}
+
+/// Open the existing temporary file `temp` for writing, refusing a symlink under its name
+pub fn open_temp(temp: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    // Checked by the open itself, so the name can't be swapped for a symlink in between
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+    #[cfg(not(unix))]
+    if fs::symlink_metadata(temp)?.is_symlink() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{temp:?} is a symlink, refusing to write through it"),
+        ));
+    }
+    options.open(temp)
+}
+
+/// The temporary files that are being received into, by any session of this process
+static RECEIVING: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// The destination is already being received by another transfer, which would be corrupted by receiving it again concurrently
+#[derive(Debug)]
+pub struct AlreadyReceiving(pub PathBuf);
+
+impl fmt::Display for AlreadyReceiving {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is already being received by another transfer",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for AlreadyReceiving {}
+
+/// A file that is being received into its temporary file.
+///
+/// Only one [PartialFile] for a destination exists at a time, so that concurrent sessions never write to (or replace)
+/// the temporary file of each other.
+///
+/// The temporary file is removed when dropped, unless it was [persisted](PartialFile::persist) or [kept](PartialFile::keep).
+#[derive(Debug)]
+pub struct PartialFile {
+    dest: PathBuf,
+    temp: PathBuf,
+    /// Where the existing file at `dest` is moved when this file is persisted
+    backup: Option<PathBuf>,
+    keep: bool,
+}
+
+impl PartialFile {
+    /// Claim the temporary file of `dest`, fails if another [PartialFile] for it is still alive
+    pub fn new(dest: PathBuf) -> Result<Self, AlreadyReceiving> {
+        let temp = temp_path(&dest);
+        if !RECEIVING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(temp.clone())
+        {
+            return Err(AlreadyReceiving(dest));
+        }
+        Ok(Self {
+            temp,
+            dest,
+            backup: None,
+            keep: false,
+        })
+    }
+
+    /// Move the existing file at the destination to `backup` when this file is persisted (and not before)
+    pub fn with_backup(mut self, backup: Option<PathBuf>) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    /// The final path of the file
+    pub fn dest(&self) -> &Path {
+        &self.dest
+    }
+
+    /// The temporary file the content is written to
+    pub fn temp(&self) -> &Path {
+        &self.temp
+    }
+
+    /// Move the (synced) temporary file into place, after backing up the existing file if requested
+    pub fn persist(mut self) -> io::Result<()> {
+        if let Some(backup) = &self.backup {
+            match fs::rename(&self.dest, backup) {
+                Ok(()) => log::info!("Backed up {:?} to {backup:?}", self.dest),
+                // Nothing left to back up
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("Failed backing up {:?} to {backup:?}: {e}", self.dest),
+                    ))
+                }
+            }
+        }
+        fs::rename(&self.temp, &self.dest)?;
+        self.keep = true;
+        log::trace!("Moved {:?} into place at {:?}", self.temp, self.dest);
+        sync_parent_dir(&self.dest);
+        Ok(())
+    }
+
+    /// Keep the temporary file, e.g. so that an interrupted transfer can be resumed
+    pub fn keep(mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            match fs::remove_file(&self.temp) {
+                Ok(()) => log::debug!("Removed incomplete {:?}", self.temp),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => log::warn!("Failed removing incomplete {:?}: {e}", self.temp),
+            }
+        }
+        // Only released once the temporary file is gone, so that it's never removed from under the next session
+        RECEIVING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.temp);
+    }
+}
+
+/// Make the rename durable, only possible (and needed) on unix
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) {
+    let Some(parent) = path.parent() else {
+        return;
+    };
+    let parent = if parent.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        parent
+    };
+    if let Err(e) = fs::File::open(parent).and_then(|dir| dir.sync_all()) {
+        log::debug!("Failed syncing directory {parent:?}: {e}");
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_temp_path_is_hidden_next_to_dest() {
+        assert_eq!(
+            temp_path(Path::new("dir/f.txt")),
+            PathBuf::from("dir/.f.txt.qft-part")
+        );
+        let long_name = format!("{}.txt", "f".repeat(251));
+        let temp = temp_path(Path::new(&long_name));
+        assert!(temp.as_os_str().len() <= MAX_FILE_NAME_LEN);
+        assert_eq!(temp, temp_path(Path::new(&long_name)));
+    }
+
+    #[test]
+    fn test_persist_moves_into_place() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        let file = PartialFile::new(dest.clone())?;
+        fs::write(file.temp(), "content")?;
+        let temp = file.temp().to_path_buf();
+
+        file.persist()?;
+        assert_eq!(fs::read_to_string(&dest)?, "content");
+        assert!(!temp.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_is_made_when_persisted() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        let backup = d.child("f.txt~");
+        fs::write(&dest, "existing")?;
+
+        let file = PartialFile::new(dest.clone())?.with_backup(Some(backup.clone()));
+        fs::write(file.temp(), "incomplete")?;
+        drop(file);
+        assert_eq!(fs::read_to_string(&dest)?, "existing");
+        assert!(!backup.exists());
+
+        let file = PartialFile::new(dest.clone())?.with_backup(Some(backup.clone()));
+        fs::write(file.temp(), "content")?;
+        file.persist()?;
+        assert_eq!(fs::read_to_string(&dest)?, "content");
+        assert_eq!(fs::read_to_string(&backup)?, "existing");
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_partial_file_is_removed() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        let file = PartialFile::new(dest.clone())?;
+        fs::write(file.temp(), "incomplete")?;
+        let temp = file.temp().to_path_buf();
+
+        drop(file);
+        assert!(!temp.exists());
+        assert!(!dest.exists());
+
+        let file = PartialFile::new(dest.clone())?;
+        fs::write(file.temp(), "incomplete")?;
+        file.keep();
+        assert_eq!(resumable_content(&dest), Some(temp));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_at_temp_path_is_not_followed() -> TestResult {
+        let d = TempDir::new()?;
+        let outside = d.child("outside.txt");
+        fs::write(&outside, "outside")?;
+        let temp = temp_path(&d.child("f.txt"));
+        std::os::unix::fs::symlink(&outside, &temp)?;
+
+        assert!(open_temp(&temp).is_err());
+        let mut file = create_temp(&temp)?;
+        io::Write::write_all(&mut file, b"received")?;
+        assert_eq!(fs::read_to_string(&outside)?, "outside");
+        assert!(!fs::symlink_metadata(&temp)?.is_symlink());
+        assert_eq!(fs::read_to_string(&temp)?, "received");
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_receive_of_same_dest_is_refused() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        let first = PartialFile::new(dest.clone())?;
+        let mut first_temp = create_temp(first.temp())?;
+        io::Write::write_all(&mut first_temp, b"first")?;
+
+        // Another session must neither replace the temporary file nor move it into place
+        let second = std::thread::spawn({
+            let dest = dest.clone();
+            move || PartialFile::new(dest).map(drop)
+        });
+        let err = second.join().unwrap().unwrap_err();
+        assert_eq!(err.0, dest);
+        io::Write::write_all(&mut first_temp, b" session")?;
+        first.persist()?;
+        assert_eq!(fs::read_to_string(&dest)?, "first session");
+
+        // Released once the first session is done
+        PartialFile::new(dest)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_file_is_not_resumable() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        fs::write(&dest, "complete")?;
+
+        assert_eq!(resumable_content(&dest), None);
+        Ok(())
+    }
+}
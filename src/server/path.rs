@@ -1,11 +1,11 @@
 use std::{
-    env,
-    path::{Path, PathBuf},
+    env, fmt, fs,
+    path::{Component, Path, PathBuf},
 };
 
 use anyhow::bail;
 
-use crate::config::transfer::command::DestinationMode;
+use crate::config::transfer::command::{DestinationMode, REMOTE_PATH_SEPARATOR};
 
 /// Resolves a path that might start with a '~' or is the empty string
 pub fn resolve_scp_path(remote_path: &Path) -> anyhow::Result<PathBuf> {
@@ -99,6 +99,83 @@ pub fn validate_remote_path(mode: &DestinationMode, remote_path: &Path) -> anyho
     }
 }
 
+/// A name received from a client was rejected as it could end up outside of the output directory
+#[derive(Debug)]
+pub struct RejectedName {
+    pub name: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for RejectedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rejected received name {:?}: {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for RejectedName {}
+
+/// Convert a name received from a client to a relative path, rejecting names that could escape the output directory.
+///
+/// Components are separated by [REMOTE_PATH_SEPARATOR], empty and `.` components are ignored,
+/// while absolute names and `..` components are rejected.
+pub fn sanitize_received_name(name: &str) -> Result<PathBuf, RejectedName> {
+    let reject = |reason| RejectedName {
+        name: name.to_owned(),
+        reason,
+    };
+    if name.starts_with(REMOTE_PATH_SEPARATOR) || Path::new(name).has_root() {
+        return Err(reject("absolute paths are not allowed"));
+    }
+    let mut relative_path = PathBuf::new();
+    // Components are checked after splitting as the local separator (e.g. '\\' on windows) could hide a '..'
+    for part in name.split(REMOTE_PATH_SEPARATOR) {
+        for component in Path::new(part).components() {
+            match component {
+                Component::Normal(c) => relative_path.push(c),
+                Component::CurDir => (),
+                Component::ParentDir => return Err(reject("'..' components are not allowed")),
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(reject("absolute paths are not allowed"))
+                }
+            }
+        }
+    }
+    if relative_path.as_os_str().is_empty() {
+        return Err(reject("the name is empty"));
+    }
+    Ok(relative_path)
+}
+
+/// Check that `relative_path` doesn't resolve to a location outside of `root` through symbolic links.
+///
+/// Every already existing prefix of the path is resolved, a dangling symlink is rejected as writing to it would create its target.
+pub fn ensure_within_root(root: &Path, relative_path: &Path) -> anyhow::Result<()> {
+    let root = fs::canonicalize(root)?;
+    let mut path = root.clone();
+    for component in relative_path.components() {
+        path.push(component);
+        let Ok(md) = path.symlink_metadata() else {
+            // Nothing below a missing path exists either
+            break;
+        };
+        if !md.is_symlink() {
+            continue;
+        }
+        match fs::canonicalize(&path) {
+            Ok(resolved) if resolved.starts_with(&root) => (),
+            _ => {
+                return Err(RejectedName {
+                    name: relative_path.to_string_lossy().into_owned(),
+                    reason:
+                        "it resolves to a path outside of the output directory through a symlink",
+                }
+                .into())
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -315,4 +392,71 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_sanitize_received_name() -> TestResult {
+        assert_eq!(sanitize_received_name("f.txt")?, PathBuf::from("f.txt"));
+        assert_eq!(
+            sanitize_received_name("dir/sub/f.txt")?,
+            ["dir", "sub", "f.txt"].iter().collect::<PathBuf>()
+        );
+        // Empty and '.' components are harmless
+        assert_eq!(
+            sanitize_received_name("./dir//f.txt")?,
+            ["dir", "f.txt"].iter().collect::<PathBuf>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanitize_received_name_rejects_escapes() {
+        for name in [
+            "../f.txt",
+            "../../etc/cron.d/x",
+            "dir/../../f.txt",
+            "dir/..",
+            "/etc/passwd",
+            "",
+            "./",
+        ] {
+            assert!(
+                sanitize_received_name(name).is_err(),
+                "{name:?} should be rejected"
+            );
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn test_sanitize_received_name_rejects_windows_escapes() {
+        for name in ["..\\f.txt", "dir\\..\\..\\f.txt", "C:\\f.txt", "\\f.txt"] {
+            assert!(
+                sanitize_received_name(name).is_err(),
+                "{name:?} should be rejected"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ensure_within_root_symlinks() -> TestResult {
+        use std::os::unix::fs::symlink;
+        let d = TempDir::new()?;
+        let root = d.child("root");
+        let outside = d.child("outside");
+        fs::create_dir_all(root.join("inside"))?;
+        fs::create_dir(&outside)?;
+        symlink(&outside, root.join("escape"))?;
+        symlink(root.join("inside"), root.join("alias"))?;
+        symlink(outside.join("missing.txt"), root.join("dangling"))?;
+
+        ensure_within_root(&root, Path::new("inside/f.txt"))?;
+        ensure_within_root(&root, Path::new("alias/f.txt"))?;
+        ensure_within_root(&root, Path::new("new_dir/f.txt"))?;
+        for name in ["escape", "escape/f.txt", "dangling"] {
+            let err = ensure_within_root(&root, Path::new(name)).unwrap_err();
+            assert!(err.is::<RejectedName>(), "{name:?}: {err}");
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,100 @@
+//! What the server received, for programs that embed the server through [Server](crate::Server),
+//! and printed as JSON by `qft listen` with `--output-format json`.
+
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Serialize, Serializer};
+
+use crate::{
+    config::{misc::OutputFormat, transfer::listen::ListenArgs},
+    util::print_json,
+};
+
+/// A file (or content written to stdout) that was received and moved into place
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReceivedFile {
+    /// The name the client sent the file as
+    pub name: String,
+    /// Where the file was written to, [None] if it was written to stdout
+    pub path: Option<PathBuf>,
+    /// The size of the file after decompression
+    pub size: u64,
+    /// How long it took to receive the file
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+}
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// The files received by a server that served a single client
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ServerReport {
+    /// The received files, in the order they were moved into place
+    pub files: Vec<ReceivedFile>,
+}
+
+impl ServerReport {
+    /// Print the report as JSON if that's the `format`, in the text format each file is already logged as it's received
+    pub fn print(&self, format: OutputFormat) -> anyhow::Result<()> {
+        if format == OutputFormat::Json {
+            print_json(self)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the files that a server receives, for the [ServerReport] of a client
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ReceivedFiles(Arc<Mutex<Vec<ReceivedFile>>>);
+
+impl ReceivedFiles {
+    /// Collect the files received with `args`, which are still passed to the [OnReceived] that `args` already has
+    pub(crate) fn collect(args: &mut ListenArgs) -> Self {
+        let received = Self::default();
+        let on_received = args.on_received.take();
+        args.on_received = Some(OnReceived(Arc::new({
+            let received = received.clone();
+            move |file: &ReceivedFile| {
+                received.files().push(file.clone());
+                if let Some(on_received) = &on_received {
+                    on_received.call(file);
+                }
+            }
+        })));
+        received
+    }
+
+    /// The report of the files collected so far, which are no longer collected
+    pub(crate) fn take_report(&self) -> ServerReport {
+        ServerReport {
+            files: std::mem::take(&mut *self.files()),
+        }
+    }
+
+    fn files(&self) -> std::sync::MutexGuard<'_, Vec<ReceivedFile>> {
+        self.0.lock().expect("Received files lock poisoned")
+    }
+}
+
+/// Called with each file as soon as it's received, from the thread that received it
+#[derive(Clone)]
+pub struct OnReceived(pub Arc<dyn Fn(&ReceivedFile) + Send + Sync>);
+
+impl OnReceived {
+    pub fn call(&self, file: &ReceivedFile) {
+        (self.0)(file)
+    }
+}
+
+impl fmt::Debug for OnReceived {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnReceived")
+    }
+}
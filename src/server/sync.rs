@@ -0,0 +1,66 @@
+//! Planning which files of a client's [Manifest] are sent, and deleting the files that aren't in it.
+
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    config::transfer::{
+        command::REMOTE_PATH_SEPARATOR,
+        listen::{ListenArgs, OverwritePolicy},
+    },
+    server::util::{receive_destination, receives_to_stdout},
+    sync::{prune_dir, Manifest, SyncOptions, SyncPlan},
+};
+
+/// Find the files of `manifest` that are missing or stale at their destinations, and delete the files in the
+/// sent directories that aren't in the manifest if `opts` says so.
+pub fn plan_sync(
+    cfg: &ListenArgs,
+    root_dest: Option<&Path>,
+    manifest: &Manifest,
+    opts: SyncOptions,
+) -> anyhow::Result<SyncPlan> {
+    if receives_to_stdout(cfg, root_dest) {
+        bail!("Receiving to stdout, there are no files to sync with");
+    }
+    let mut plan = SyncPlan::default();
+    for (i, entry) in manifest.files.iter().enumerate() {
+        // A rejected name is sent anyway, the client is told why once it is refused
+        let up_to_date = match receive_destination(cfg, &entry.name, root_dest) {
+            Ok(dest) => entry
+                .is_up_to_date(&dest)
+                .with_context(|| format!("Failed comparing {:?} with {dest:?}", entry.name))?,
+            Err(_) => false,
+        };
+        if !up_to_date {
+            plan.send.push(i as u32);
+        }
+    }
+    log::info!(
+        "{} of {} file(s) are missing or stale",
+        plan.send.len(),
+        manifest.files.len()
+    );
+
+    if opts.delete {
+        // Deleting a file is overwriting it with nothing
+        if cfg.overwrite != OverwritePolicy::Always {
+            bail!(
+                "The server doesn't delete files unless it overwrites them (--overwrite {})",
+                OverwritePolicy::Always
+            );
+        }
+        for dir in &manifest.dirs {
+            let dest = receive_destination(cfg, dir, root_dest)?;
+            let removed = prune_dir(&dest, &manifest.children_of(dir))
+                .with_context(|| format!("Failed deleting files in {dest:?}"))?;
+            for name in removed {
+                log::info!("Deleted {:?}", dest.join(&name));
+                plan.deleted
+                    .push(format!("{dir}{REMOTE_PATH_SEPARATOR}{name}"));
+            }
+        }
+    }
+    Ok(plan)
+}
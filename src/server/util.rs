@@ -1,30 +1,47 @@
 use std::{
+    fmt,
     fs::{self, File},
-    io::{self, BufReader, BufWriter, StdoutLock, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, StdoutLock, Write},
+    net::TcpListener,
     path::{Path, PathBuf},
     sync::{atomic::AtomicBool, Arc},
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
+use anyhow::Context;
 use flate2::read::GzDecoder;
 use lz4_flex::frame::FrameDecoder;
+use serde::Serialize;
 
 use crate::{
+    checksum::{Checksum, HashingWriter, PrefixHash},
     config::{
         compression::CompressionVariant,
         transfer::{
-            command::{ServerCommand, ServerResult},
+            command::{ServerCommand, ServerResult, REMOTE_PATH_SEPARATOR},
             listen::ListenArgs,
         },
     },
-    server::child::run_child,
+    delta::apply_delta,
+    events::{self, Event},
+    framed_stream::FramedReader,
+    progress::FileProgress,
+    rate_limit::{RateLimit, Throttled},
+    server::{
+        child::{run_child, RangedFiles},
+        partial::{create_temp, open_temp, PartialFile},
+        path::{ensure_within_root, sanitize_received_name},
+        report::ReceivedFile,
+    },
+    transport::QftStream,
     util::{bind_listen_to_free_port_in_range, format_data_size, incremental_rw},
     BUFFERED_RW_BUFSIZE, TCP_STREAM_BUFSIZE,
 };
 
+/// Write to the temporary file at `path`, which is [created anew](create_temp)
 pub fn file_with_bufwriter(path: &Path) -> anyhow::Result<BufWriter<File>> {
-    let f = match fs::File::create(path) {
+    let f = match create_temp(path) {
         Ok(f) => f,
         Err(e) => {
             if e.kind() == io::ErrorKind::PermissionDenied {
@@ -75,26 +92,56 @@ pub fn stdout_bufwriter() -> BufWriter<StdoutLock<'static>> {
     BufWriter::with_capacity(BUFFERED_RW_BUFSIZE, stdout)
 }
 
-pub fn handle_receive_data(
+/// A file that content was received into, the file is moved into place once its checksum is verified
+#[derive(Debug)]
+pub struct ReceivedContent {
+    /// The name the client sent the content as
+    pub name: String,
+    pub file: PartialFile,
+    pub len: u64,
+    pub checksum: Checksum,
+    /// How long it took to receive the content
+    pub duration: Duration,
+}
+
+impl ReceivedContent {
+    /// The received file, once it's moved into place
+    pub fn received_file(&self) -> ReceivedFile {
+        ReceivedFile {
+            name: self.name.clone(),
+            path: Some(self.file.dest().to_path_buf()),
+            size: self.len,
+            duration: self.duration,
+        }
+    }
+}
+
+/// Received content is written to stdout, as neither an output path nor a destination was given
+pub fn receives_to_stdout(listen_args: &ListenArgs, root_dest: Option<&Path>) -> bool {
+    listen_args.output.is_none() && listen_args.output_dir.is_none() && root_dest.is_none()
+}
+
+/// Resolve the path that content received under `fname` is written to
+///
+/// `fname` can be a relative path (when receiving a directory tree), in which case any missing parent directories are created.
+/// Names that could resolve to a path outside of the output directory are rejected with a [RejectedName](super::path::RejectedName) error.
+pub fn receive_destination(
     listen_args: &ListenArgs,
-    tcp_socket: &mut TcpStream,
-    fname: String,
-    decompression: Option<CompressionVariant>,
+    fname: &str,
     root_dest: Option<&Path>,
-) -> anyhow::Result<u64> {
-    let mut bufwriter = match (
+) -> anyhow::Result<PathBuf> {
+    let relative_path = sanitize_received_name(fname)?;
+    let out_path: PathBuf = match (
         listen_args.output.as_deref(),
         listen_args.output_dir.as_deref(),
         root_dest,
     ) {
         (_, _, Some(root_dest)) => {
-            if root_dest.is_file() {
-                tracing::info!("Initiation bufwriter targeting {root_dest:?}");
-                file_with_bufwriter(root_dest)?
+            if root_dest.is_dir() {
+                ensure_within_root(root_dest, &relative_path)?;
+                root_dest.join(relative_path)
             } else {
-                let full_path = root_dest.join(fname);
-                tracing::info!("Initiation bufwriter targeting {full_path:?}");
-                file_with_bufwriter(&full_path)?
+                root_dest.to_path_buf()
             }
         }
         (None, Some(d), _) => {
@@ -104,57 +151,383 @@ pub fn handle_receive_data(
             if !d.exists() {
                 fs::create_dir(d)?;
             }
-            let new_fpath = d.join(fname);
-            file_with_bufwriter(&new_fpath)?
+            ensure_within_root(d, &relative_path)?;
+            d.join(relative_path)
         }
-        (Some(f), None, _) => file_with_bufwriter(f)?,
+        (Some(f), None, _) => f.to_path_buf(),
         (None, None, _) => {
-            unreachable!()
+            anyhow::bail!("Receiving to stdout, {fname:?} has no destination path")
         }
         (Some(_), Some(_), _) => {
             unreachable!("Specifying both an output name and an output directory is invalid")
         }
     };
+    if fname.contains(REMOTE_PATH_SEPARATOR) {
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    Ok(out_path)
+}
 
-    let mut buf_tcp_reader = BufReader::with_capacity(BUFFERED_RW_BUFSIZE, tcp_socket);
+/// Open an existing file for writing right after its first `offset` bytes, discarding anything after them
+pub fn resumed_file_with_bufwriter(path: &Path, offset: u64) -> anyhow::Result<BufWriter<File>> {
+    let mut f = open_temp(path)?;
+    f.set_len(offset)?;
+    f.seek(SeekFrom::Start(offset))?;
+    Ok(BufWriter::with_capacity(BUFFERED_RW_BUFSIZE, f))
+}
 
-    let len = match decompression {
-        Some(compr) => match compr {
-            CompressionVariant::Bzip2 => {
-                let mut tcp_decoder = bzip2::read::BzDecoder::new(buf_tcp_reader);
-                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut bufwriter, &mut tcp_decoder)?
-            }
-            CompressionVariant::Gzip => {
-                let mut tcp_decoder = GzDecoder::new(buf_tcp_reader);
-                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut bufwriter, &mut tcp_decoder)?
-            }
-            CompressionVariant::Lz4 => {
-                let mut tcp_decoder = FrameDecoder::new(buf_tcp_reader);
-                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut bufwriter, &mut tcp_decoder)?
-            }
-            CompressionVariant::Xz => {
-                let mut tcp_decoder = xz2::read::XzDecoder::new(buf_tcp_reader);
-                incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut bufwriter, &mut tcp_decoder)?
+/// The transfer of a file was interrupted after part of it was written to disk
+///
+/// The partially received file is removed when the error is dropped, unless it is [kept](PartialFile::keep) to be resumed.
+#[derive(Debug)]
+pub struct InterruptedTransfer {
+    pub file: PartialFile,
+    /// The amount of bytes of the file that are on disk (including any resumed prefix)
+    pub received: u64,
+    pub source: anyhow::Error,
+}
+
+impl fmt::Display for InterruptedTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Transfer to {path:?} interrupted after {received} B: {source}",
+            path = self.file.dest(),
+            received = self.received,
+            source = self.source
+        )
+    }
+}
+
+impl std::error::Error for InterruptedTransfer {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Receive content into the temporary file of `file`, which is synced to disk once all the content is received.
+///
+/// If `resume_from` is [Some] the content is appended after the already received prefix in the temporary file.
+/// If the transfer is interrupted, the temporary file is truncated to the content that was received,
+/// so that it can be resumed later, and an [InterruptedTransfer] error is returned.
+pub fn handle_receive_data(
+    tcp_socket: &mut QftStream,
+    file: PartialFile,
+    decompression: Option<CompressionVariant>,
+    resume_from: Option<PrefixHash>,
+    progress: &FileProgress,
+    limit_rate: Option<&RateLimit>,
+) -> anyhow::Result<ReceivedContent> {
+    let out_path = file.temp();
+    tracing::info!("Initiation bufwriter targeting {out_path:?}");
+    let start = Instant::now();
+    events::emit(&Event::FileStart {
+        name: progress.name(),
+        size: progress.length(),
+    });
+    progress
+        .observer()
+        .on_file_start(progress.name(), progress.length());
+    let mut bufwriter = match resume_from {
+        Some(prefix) => {
+            log::info!("Resuming {out_path:?} from offset {}", prefix.len());
+            progress.start_from(prefix.len());
+            HashingWriter::resume(resumed_file_with_bufwriter(out_path, prefix.len())?, prefix)
+        }
+        None => HashingWriter::new(file_with_bufwriter(out_path)?),
+    };
+
+    // The framed reader never reads past the end of the content, so the socket can be used for commands afterwards
+    let mut framed_tcp_reader =
+        FramedReader::new(Throttled::new(&mut *tcp_socket, limit_rate.cloned()));
+    let buf_tcp_reader = BufReader::with_capacity(BUFFERED_RW_BUFSIZE, &mut framed_tcp_reader);
+
+    let decoded = decode_received(
+        &mut progress.wrap_write(&mut bufwriter),
+        buf_tcp_reader,
+        decompression,
+    );
+    let decoded = decoded.and_then(|len| {
+        bufwriter.flush()?;
+        bufwriter.get_mut().get_ref().sync_all()?;
+        Ok(len)
+    });
+    let len = match decoded {
+        Ok(len) => len,
+        Err(source) => {
+            let _ = bufwriter.flush();
+            let received = bufwriter.written();
+            bufwriter.get_mut().get_ref().set_len(received)?;
+            return Err(InterruptedTransfer {
+                file,
+                received,
+                source,
             }
-        },
-        None => incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(&mut bufwriter, &mut buf_tcp_reader)?,
+            .into());
+        }
     };
+    let trailing_bytes = framed_tcp_reader.finish()?;
+    if trailing_bytes != 0 {
+        log::warn!("Discarded {trailing_bytes} B trailing the decoded content");
+    }
     if len < 1023 {
         log::info!("Received: {len} B");
     } else {
         log::info!("Received: {} [{len} B]", format_data_size(len));
     }
+    let checksum = bufwriter.checksum();
+    events::emit(&Event::file_done(
+        progress.name(),
+        len,
+        Some(checksum),
+        start.elapsed(),
+    ));
+    progress
+        .observer()
+        .on_file_complete(progress.name(), len, start.elapsed());
+
+    Ok(ReceivedContent {
+        name: progress.name().to_owned(),
+        file,
+        len,
+        checksum,
+        duration: start.elapsed(),
+    })
+}
 
-    Ok(len)
+/// Receive content and write it to stdout as it is decoded, returns the written content (without a path) and its checksum.
+///
+/// Unlike a file, content that was written to stdout can't be taken back, so a failed transfer can't be resumed.
+pub fn handle_receive_stdout(
+    tcp_socket: &mut QftStream,
+    decompression: Option<CompressionVariant>,
+    progress: &FileProgress,
+    limit_rate: Option<&RateLimit>,
+) -> anyhow::Result<(ReceivedFile, Checksum)> {
+    tracing::info!("Initiation bufwriter targeting stdout");
+    let start = Instant::now();
+    events::emit(&Event::FileStart {
+        name: progress.name(),
+        size: progress.length(),
+    });
+    progress
+        .observer()
+        .on_file_start(progress.name(), progress.length());
+    let mut bufwriter = HashingWriter::new(stdout_bufwriter());
+    let mut framed_tcp_reader =
+        FramedReader::new(Throttled::new(&mut *tcp_socket, limit_rate.cloned()));
+    let buf_tcp_reader = BufReader::with_capacity(BUFFERED_RW_BUFSIZE, &mut framed_tcp_reader);
+    let len = decode_received(
+        &mut progress.wrap_write(&mut bufwriter),
+        buf_tcp_reader,
+        decompression,
+    )?;
+    bufwriter.flush()?;
+    let trailing_bytes = framed_tcp_reader.finish()?;
+    if trailing_bytes != 0 {
+        log::warn!("Discarded {trailing_bytes} B trailing the decoded content");
+    }
+    log::info!("Wrote {} [{len} B] to stdout", format_data_size(len));
+    let checksum = bufwriter.checksum();
+    events::emit(&Event::file_done(
+        progress.name(),
+        len,
+        Some(checksum),
+        start.elapsed(),
+    ));
+    progress
+        .observer()
+        .on_file_complete(progress.name(), len, start.elapsed());
+    let written = ReceivedFile {
+        name: progress.name().to_owned(),
+        path: None,
+        size: len,
+        duration: start.elapsed(),
+    };
+    Ok((written, checksum))
+}
+
+/// Receive a range of a file into the preallocated temporary file at `path` from `offset`,
+/// returns the checksum of the written range.
+///
+/// The rest of the file is written by other child sockets, so nothing is truncated and a failed range isn't resumed.
+pub fn handle_receive_range(
+    tcp_socket: &mut QftStream,
+    path: &Path,
+    offset: u64,
+    max_len: u64,
+    decompression: Option<CompressionVariant>,
+    progress: &FileProgress,
+    limit_rate: Option<&RateLimit>,
+) -> anyhow::Result<Checksum> {
+    let mut f = open_temp(path)
+        .with_context(|| format!("No preallocated file at {path:?} to receive a range into"))?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut bufwriter = HashingWriter::new(BoundedWriter {
+        inner: BufWriter::with_capacity(BUFFERED_RW_BUFSIZE, f),
+        remaining: max_len,
+    });
+    let mut framed_tcp_reader =
+        FramedReader::new(Throttled::new(&mut *tcp_socket, limit_rate.cloned()));
+    let buf_tcp_reader = BufReader::with_capacity(BUFFERED_RW_BUFSIZE, &mut framed_tcp_reader);
+    let len = decode_received(
+        &mut progress.wrap_write(&mut bufwriter),
+        buf_tcp_reader,
+        decompression,
+    )?;
+    bufwriter.flush()?;
+    bufwriter.get_mut().inner.get_ref().sync_data()?;
+    let trailing_bytes = framed_tcp_reader.finish()?;
+    if trailing_bytes != 0 {
+        log::warn!("Discarded {trailing_bytes} B trailing the decoded content");
+    }
+    log::info!(
+        "Received {} [{len} B] at offset {offset} of {path:?}",
+        format_data_size(len)
+    );
+    Ok(bufwriter.checksum())
+}
+
+/// Refuses writing more than `remaining` bytes, so that a range can't extend past the end of the file it's received into
+struct BoundedWriter<W: Write> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> Write for BoundedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The range extends past the end of the file",
+            ));
+        }
+        let written = self.inner.write(buf)?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decode the received content into `writer`, returns the amount of decoded bytes
+fn decode_received<W: Write, R: BufRead>(
+    writer: &mut W,
+    reader: R,
+    decompression: Option<CompressionVariant>,
+) -> anyhow::Result<u64> {
+    incremental_rw::<TCP_STREAM_BUFSIZE, _, _>(writer, &mut decompressed(reader, decompression))
+}
+
+/// Reads the decompressed content of `reader`
+fn decompressed<'a, R: BufRead + 'a>(
+    reader: R,
+    decompression: Option<CompressionVariant>,
+) -> Box<dyn Read + 'a> {
+    match decompression {
+        Some(CompressionVariant::Bzip2) => Box::new(bzip2::read::BzDecoder::new(reader)),
+        Some(CompressionVariant::Gzip) => Box::new(GzDecoder::new(reader)),
+        Some(CompressionVariant::Lz4) => Box::new(FrameDecoder::new(reader)),
+        Some(CompressionVariant::Xz) => Box::new(xz2::read::XzDecoder::new(reader)),
+        None => Box::new(reader),
+    }
+}
+
+/// Receive a file as a delta against `basis`, the existing file that the client got the signature of with blocks of `block_len` bytes.
+///
+/// The file is rebuilt into the temporary file of `file`, which is synced to disk once the delta is applied.
+pub fn handle_receive_delta(
+    tcp_socket: &mut QftStream,
+    file: PartialFile,
+    basis: &mut File,
+    block_len: u32,
+    decompression: Option<CompressionVariant>,
+    progress: &FileProgress,
+    limit_rate: Option<&RateLimit>,
+) -> anyhow::Result<ReceivedContent> {
+    let start = Instant::now();
+    events::emit(&Event::FileStart {
+        name: progress.name(),
+        size: progress.length(),
+    });
+    progress
+        .observer()
+        .on_file_start(progress.name(), progress.length());
+    let mut bufwriter = HashingWriter::new(file_with_bufwriter(file.temp())?);
+    let mut framed_tcp_reader =
+        FramedReader::new(Throttled::new(&mut *tcp_socket, limit_rate.cloned()));
+    let buf_tcp_reader = BufReader::with_capacity(BUFFERED_RW_BUFSIZE, &mut framed_tcp_reader);
+    let len = apply_delta(
+        decompressed(buf_tcp_reader, decompression),
+        &mut BufReader::with_capacity(BUFFERED_RW_BUFSIZE, basis),
+        block_len,
+        &mut progress.wrap_write(&mut bufwriter),
+    )?;
+    bufwriter.flush()?;
+    bufwriter.get_mut().get_ref().sync_all()?;
+    let trailing_bytes = framed_tcp_reader.finish()?;
+    if trailing_bytes != 0 {
+        log::debug!("Discarded {trailing_bytes} B trailing the delta");
+    }
+    log::info!("Rebuilt {} [{len} B] from a delta", format_data_size(len));
+    let checksum = bufwriter.checksum();
+    events::emit(&Event::file_done(
+        progress.name(),
+        len,
+        Some(checksum),
+        start.elapsed(),
+    ));
+    progress
+        .observer()
+        .on_file_complete(progress.name(), len, start.elapsed());
+    Ok(ReceivedContent {
+        name: progress.name().to_owned(),
+        file,
+        len,
+        checksum,
+        duration: start.elapsed(),
+    })
+}
+
+/// Verify the checksum the client computed for the content it sent against the checksum of the content that was received.
+///
+/// If it matches the received file is moved into place, on mismatch the received file is removed and an error is returned.
+pub fn verify_received_checksum(
+    received: ReceivedContent,
+    expected: &Checksum,
+) -> anyhow::Result<()> {
+    if received.checksum != *expected {
+        anyhow::bail!(
+            "Checksum mismatch for {path:?}: expected {expected}, received content has checksum {actual}",
+            path = received.file.dest(),
+            actual = received.checksum
+        )
+    }
+    log::debug!("Checksum verified: {expected}");
+    let dest = received.file.dest().to_path_buf();
+    received
+        .file
+        .persist()
+        .with_context(|| format!("Failed moving the received file into place at {dest:?}"))
 }
 
 /// Send a [ServerResult] to the client
-pub fn send_result(stream: &mut TcpStream, result: &ServerResult) -> anyhow::Result<()> {
+pub fn send_result(stream: &mut QftStream, result: &ServerResult) -> anyhow::Result<()> {
+    send_reply(stream, result)
+}
+
+/// Send a reply that is framed like a [ServerResult]
+pub fn send_reply<T: Serialize + fmt::Debug>(
+    stream: &mut QftStream,
+    result: &T,
+) -> anyhow::Result<()> {
     tracing::trace!("Sending result: {result:?}");
     let result_bytes = bincode::serialize(result)?;
-    debug_assert!(result_bytes.len() <= u8::MAX as usize);
-    let size = result_bytes.len() as u16;
-    let header = size.to_be_bytes();
+    let header = ServerResult::header_from_size(result_bytes.len())?;
 
     // Send the header followed by the command
     stream.write_all(&header)?;
@@ -188,11 +561,12 @@ pub fn join_all_threads(handles: Vec<JoinHandle<anyhow::Result<()>>>) -> Result<
 }
 
 pub fn spawn_child_on_new_port(
-    socket: &mut TcpStream,
+    socket: &mut QftStream,
     cfg: &ListenArgs,
     stop_flag: &Arc<AtomicBool>,
     server_cmd_get_free_port: &ServerCommand,
     root_dest: Option<PathBuf>,
+    ranged_files: &RangedFiles,
 ) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
     let (start_port_range, end_port_range) = match server_cmd_get_free_port {
         ServerCommand::GetFreePort((start_port_range, end_port_range)) => {
@@ -225,6 +599,7 @@ pub fn spawn_child_on_new_port(
         .spawn({
             let cfg = cfg.clone();
             let local_stop_flag = Arc::clone(stop_flag);
+            let ranged_files = ranged_files.clone();
             move || {
                 thread_listener.set_nonblocking(true)?;
                 run_child(
@@ -232,9 +607,73 @@ pub fn spawn_child_on_new_port(
                     &cfg,
                     &local_stop_flag,
                     root_dest.as_deref(),
+                    &ranged_files,
                 )
             }
         })
         .expect("Failed spawning thread");
     Ok(handle)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_verify_received_checksum_ok() -> TestResult {
+        let d = TempDir::new()?;
+        let path = d.child("received");
+        let file = PartialFile::new(path.clone())?;
+        fs::write(file.temp(), b"content")?;
+        let received = ReceivedContent {
+            name: "received".to_owned(),
+            file,
+            len: 7,
+            checksum: Checksum::of(b"content"),
+            duration: Duration::ZERO,
+        };
+
+        verify_received_checksum(received, &Checksum::of(b"content"))?;
+        assert_eq!(fs::read(&path)?, b"content");
+        Ok(())
+    }
+
+    #[test]
+    fn test_resumed_file_with_bufwriter_discards_content_after_offset() -> TestResult {
+        let d = TempDir::new()?;
+        let path = d.child("partial");
+        fs::write(&path, b"content received so far, followed by garbage")?;
+
+        let mut writer = resumed_file_with_bufwriter(&path, 24)?;
+        writer.write_all(b" and the rest")?;
+        writer.flush()?;
+        drop(writer);
+
+        assert_eq!(fs::read(&path)?, b"content received so far, and the rest");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_received_checksum_mismatch_removes_file() -> TestResult {
+        let d = TempDir::new()?;
+        let path = d.child("received");
+        let file = PartialFile::new(path.clone())?;
+        let temp = file.temp().to_path_buf();
+        fs::write(&temp, b"cont")?;
+        let received = ReceivedContent {
+            name: "received".to_owned(),
+            file,
+            len: 4,
+            checksum: Checksum::of(b"cont"),
+            duration: Duration::ZERO,
+        };
+
+        let err = verify_received_checksum(received, &Checksum::of(b"content")).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"));
+        assert!(!temp.exists());
+        assert!(!path.exists());
+        Ok(())
+    }
+}
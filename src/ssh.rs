@@ -1,5 +1,12 @@
 use crate::{
-    config::{compression::Compression, transfer::util::TcpConnectMode, Config},
+    config::{
+        compression::Compression,
+        transfer::{handshake::IncompatiblePeer, util::TcpConnectMode},
+        Config,
+    },
+    observer::SharedObserver,
+    rate_limit::RateLimit,
+    sync::SyncOptions,
     util::verbosity_to_args,
 };
 use anyhow::Result;
@@ -15,6 +22,7 @@ use std::{
 #[cfg(feature = "mdns")]
 mod mdns_util;
 pub mod private_key;
+pub mod pull;
 mod remote_cmd;
 pub mod remote_find_free_port;
 pub mod remote_info;
@@ -36,12 +44,17 @@ pub fn run_ssh(
     tcp_port: Option<u16>,
     use_mmap: bool,
     input_files: &[PathBuf],
+    recursive: bool,
     prealloc: bool,
+    preserve: bool,
+    resume: bool,
     compression: &Option<Compression>,
     start_port: u16,
     end_port: u16,
     ssh_timeout_ms: u64,
     tcp_connect_mode: TcpConnectMode,
+    limit_rate: Option<&RateLimit>,
+    sync: Option<SyncOptions>,
 ) -> Result<()> {
     log::debug!(
         "Connecting to {remote_ip} as {user} with a timeout of {ssh_timeout_ms} ms",
@@ -101,11 +114,24 @@ pub fn run_ssh(
                 tcp_port,
                 use_mmap,
                 input_files,
+                recursive,
                 prealloc,
+                preserve,
+                resume,
+                false,
+                sync,
                 *compression,
                 tcp_connect_mode,
                 Some(remote.dest()),
+                None,
+                false,
+                limit_rate,
+                1,
+                1,
+                None,
+                &SharedObserver::default(),
             )
+            .and_then(|report| report.print(cfg.output_format()))
         });
         tracing::trace!("Joining client thread");
         let client_res = client_h.join().expect("Failed joining client thread");
@@ -129,5 +155,15 @@ pub fn run_ssh(
         String::from_utf8_lossy(&server_output?)
     );
 
-    client_result
+    client_result.map_err(|e| {
+        if e.is::<IncompatiblePeer>() {
+            anyhow::anyhow!(
+                "{e}\nHint: `qft ssh` runs whichever qft is first in the PATH of {user}@{remote_ip}",
+                user = remote.user(),
+                remote_ip = remote.ip()
+            )
+        } else {
+            e
+        }
+    })
 }
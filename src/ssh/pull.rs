@@ -0,0 +1,328 @@
+//! Pulling files from a remote to the local host.
+//!
+//! The roles of the push mode are reversed: the local qft listens for a connection and receives,
+//! while the remote runs a qft that sends back to the local host.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    net::{IpAddr, TcpListener, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    auth::{random_key, AuthError, PreSharedKey, PSK_ENV_VAR},
+    config::{
+        compression::Compression,
+        transfer::{
+            command::DestinationMode,
+            listen::{ListenArgs, OverwritePolicy},
+        },
+        Config,
+    },
+    observer::SharedObserver,
+    rate_limit::RateLimit,
+    server::{
+        path::{resolve_scp_path, validate_remote_path},
+        report::ReceivedFiles,
+        serve_client,
+    },
+    sync::SyncOptions,
+    util::{bind_listen_to_free_port_in_range, verbosity_to_args},
+};
+
+use super::{
+    remote_cmd::{self, RemoteSendOptions},
+    remote_info::RemoteInfo,
+    remote_session::RemoteSshSession,
+};
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_ssh_pull(
+    cfg: &Config,
+    remote: &RemoteInfo,
+    private_key: Option<&Path>,
+    private_key_dir: Option<&Path>,
+    tcp_port: Option<u16>,
+    use_mmap: bool,
+    remote_sources: &[&Path],
+    local_dest: &Path,
+    recursive: bool,
+    preserve: bool,
+    resume: bool,
+    compression: &Option<Compression>,
+    start_port: u16,
+    end_port: u16,
+    ssh_timeout_ms: u64,
+    limit_rate: Option<&RateLimit>,
+    sync: Option<SyncOptions>,
+) -> Result<()> {
+    let dest_mode = if recursive {
+        DestinationMode::RecusiveDirectory
+    } else if remote_sources.len() == 1 {
+        DestinationMode::SingleFile
+    } else {
+        DestinationMode::MultipleFiles
+    };
+    let (output, output_dir) = local_output(&dest_mode, local_dest)?;
+
+    log::debug!(
+        "Connecting to {remote_ip} as {user} with a timeout of {ssh_timeout_ms} ms",
+        remote_ip = remote.ip(),
+        user = remote.user(),
+    );
+    let mut session = RemoteSshSession::new(
+        remote.user(),
+        (remote.ip(), remote.ssh_port()),
+        Some(Duration::from_millis(ssh_timeout_ms)),
+        private_key,
+        private_key_dir,
+    )?;
+
+    let local_ip = local_ip_towards(remote.ip(), remote.ssh_port())?;
+    let listener = match tcp_port {
+        Some(tp) => TcpListener::bind((local_ip, tp))?,
+        None => {
+            match bind_listen_to_free_port_in_range(&local_ip.to_string(), start_port, end_port) {
+                Some(listener) => listener,
+                None => bail!("Unable to find a free local port in range {start_port}-{end_port}"),
+            }
+        }
+    };
+    let tcp_port = listener.local_addr()?.port();
+    tracing::debug!("Listening for the remote qft at {local_ip}:{tcp_port}");
+    // Only the qft started over ssh knows the key, anyone else who reaches the port first is refused
+    let key = random_key()?;
+    let psk: PreSharedKey = key.parse().map_err(|e| anyhow::anyhow!("{e}"))?;
+    let mut listen_args = ListenArgs {
+        ip: local_ip.to_string(),
+        port: tcp_port,
+        output,
+        output_dir,
+        remote: false,
+        decompression: None,
+        keep_alive: false,
+        max_sessions: None,
+        overwrite: OverwritePolicy::default(),
+        psk: Some(psk),
+        encrypt: false,
+        limit_rate: None,
+        on_received: None,
+        observer: SharedObserver::default(),
+    };
+    let received = ReceivedFiles::collect(&mut listen_args);
+
+    let remote_cmd = remote_cmd::remote_qft_send_command_str(
+        local_ip,
+        tcp_port,
+        verbosity_to_args(cfg),
+        remote_sources,
+        RemoteSendOptions {
+            use_mmap,
+            recursive,
+            preserve,
+            resume,
+            compression,
+            limit_rate,
+            sync,
+        },
+    );
+    tracing::info!("Sending remote qft command '{remote_cmd}'");
+    // Passed in the environment (and left out of the logs) to keep it off the command line of the remote qft
+    let remote_cmd = format!("{PSK_ENV_VAR}={key} {remote_cmd}");
+
+    let remote_done_flag = AtomicBool::new(false);
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let (remote_result, server_result) = std::thread::scope(|scope| {
+        let remote_h = scope.spawn(|| {
+            let res = session.run_cmd_to_end(&remote_cmd);
+            remote_done_flag.store(true, Ordering::Relaxed);
+            res
+        });
+        let server_result =
+            serve_remote_client(&listener, &remote_done_flag, &listen_args, &stop_flag);
+        tracing::trace!("Joining remote command thread");
+        let remote_result = remote_h
+            .join()
+            .expect("Failed joining remote command thread");
+        (remote_result, server_result)
+    });
+    session.close();
+
+    // Also lists what was received before the transfer failed
+    received.take_report().print(cfg.output_format())?;
+    let (remote_output, remote_exit_status) = remote_result?;
+    let remote_output = String::from_utf8_lossy(&remote_output);
+    log::trace!("remote qft output: {remote_output}");
+    if remote_exit_status != 0 {
+        bail!("The remote qft failed with exit status {remote_exit_status}:\n{remote_output}");
+    }
+    server_result
+}
+
+/// Validate `local_dest` for `mode` and return the output file or output directory to receive into
+fn local_output(
+    mode: &DestinationMode,
+    local_dest: &Path,
+) -> Result<(Option<PathBuf>, Option<PathBuf>)> {
+    let local_dest = std::path::absolute(resolve_scp_path(local_dest)?)?;
+    let local_dest = validate_remote_path(mode, &local_dest)?;
+    match mode {
+        DestinationMode::SingleFile if !local_dest.is_dir() => Ok((Some(local_dest), None)),
+        DestinationMode::RecusiveDirectory if !local_dest.exists() => {
+            fs::create_dir(&local_dest)?;
+            Ok((None, Some(local_dest)))
+        }
+        _ => Ok((None, Some(local_dest))),
+    }
+}
+
+/// The local IP that the remote can reach us at, i.e. the address of the interface that routes to the remote.
+fn local_ip_towards(remote_ip: IpAddr, remote_port: u16) -> Result<IpAddr> {
+    let unspecified: IpAddr = if remote_ip.is_ipv4() {
+        [0, 0, 0, 0].into()
+    } else {
+        [0_u16; 8].into()
+    };
+    // Connecting a UDP socket doesn't send anything, it only resolves the route
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.connect((remote_ip, remote_port))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Serve the remote qft, dropping clients that fail to authenticate with the one-time key it was given
+fn serve_remote_client(
+    listener: &TcpListener,
+    remote_done_flag: &AtomicBool,
+    listen_args: &ListenArgs,
+    stop_flag: &Arc<AtomicBool>,
+) -> Result<()> {
+    loop {
+        let socket = accept_remote_client(listener, remote_done_flag)?;
+        let addr = socket.peer_addr()?;
+        match serve_client(socket, listen_args, stop_flag) {
+            Err(e) if e.is::<AuthError>() => {
+                log::warn!("Dropped client at {addr} that isn't the remote qft: {e}");
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Wait for the remote qft to connect, fails if the remote command finishes without connecting.
+fn accept_remote_client(
+    listener: &TcpListener,
+    remote_done_flag: &AtomicBool,
+) -> Result<TcpStream> {
+    listener.set_nonblocking(true)?;
+    loop {
+        match listener.accept() {
+            Ok((socket, addr)) => {
+                tracing::info!("Remote qft connected from: {addr:?}");
+                socket.set_nonblocking(false)?;
+                return Ok(socket);
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                if remote_done_flag.load(Ordering::Relaxed) {
+                    bail!("The remote qft exited without connecting");
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => bail!(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::transfer::{command::ServerCommand, util::TcpConnectMode},
+        send::util::{qft_connect_to_server, send_command},
+        util::read_server_response,
+    };
+    use pretty_assertions::assert_eq;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_local_ip_towards_loopback() -> TestResult {
+        assert_eq!(
+            local_ip_towards([127, 0, 0, 1].into(), 22)?,
+            IpAddr::from([127, 0, 0, 1])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_output() -> TestResult {
+        let d = TempDir::new()?;
+        let file = d.child("file.txt");
+        let new_dir = d.child("new_dir");
+
+        assert_eq!(
+            local_output(&DestinationMode::SingleFile, &file)?,
+            (Some(file), None)
+        );
+        assert_eq!(
+            local_output(&DestinationMode::SingleFile, d.path())?,
+            (None, Some(d.path().to_path_buf()))
+        );
+        assert_eq!(
+            local_output(&DestinationMode::RecusiveDirectory, &new_dir)?,
+            (None, Some(new_dir.clone()))
+        );
+        assert!(new_dir.is_dir());
+        assert!(local_output(&DestinationMode::MultipleFiles, &d.child("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_the_client_with_the_key_is_served() -> TestResult {
+        let d = TempDir::new()?;
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let addr = listener.local_addr()?;
+        let key: PreSharedKey = random_key()?.parse()?;
+        let listen_args = ListenArgs {
+            ip: "127.0.0.1".to_owned(),
+            port: addr.port(),
+            output: None,
+            output_dir: Some(d.path().to_path_buf()),
+            remote: false,
+            decompression: None,
+            keep_alive: false,
+            max_sessions: None,
+            overwrite: OverwritePolicy::default(),
+            psk: Some(key.clone()),
+            encrypt: false,
+            limit_rate: None,
+            on_received: None,
+            observer: SharedObserver::default(),
+        };
+        let server = std::thread::spawn(move || {
+            serve_remote_client(
+                &listener,
+                &AtomicBool::new(false),
+                &listen_args,
+                &Arc::new(AtomicBool::new(false)),
+            )
+        });
+
+        let intruder = qft_connect_to_server(addr, TcpConnectMode::OneShot, None, false, addr.ip());
+        assert!(intruder.unwrap_err().is::<AuthError>());
+        let (mut remote_qft, _) =
+            qft_connect_to_server(addr, TcpConnectMode::OneShot, Some(&key), false, addr.ip())?;
+        send_command(&mut remote_qft, &ServerCommand::EndOfTransfer)?;
+        read_server_response(&mut remote_qft)?;
+
+        server.join().expect("Failed joining server")?;
+        Ok(())
+    }
+}
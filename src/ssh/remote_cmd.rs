@@ -1,3 +1,7 @@
+use std::{fmt::Write, net::IpAddr, path::Path};
+
+use crate::{config::compression::Compression, rate_limit::RateLimit, sync::SyncOptions};
+
 // Takes the args and produces a string of the command that should be executed on the remote
 // to match the given SendSshArgs
 pub(super) fn remote_qft_command_str(tcp_port: u16, verbosity: &str) -> String {
@@ -7,3 +11,125 @@ pub(super) fn remote_qft_command_str(tcp_port: u16, verbosity: &str) -> String {
     cmd.push_str(tcp_port.to_string().as_str());
     cmd
 }
+
+/// Options for the qft that sends from the remote when pulling files
+#[derive(Debug, Clone, Copy)]
+pub(super) struct RemoteSendOptions<'a> {
+    pub use_mmap: bool,
+    pub recursive: bool,
+    pub preserve: bool,
+    pub resume: bool,
+    pub compression: &'a Option<Compression>,
+    pub limit_rate: Option<&'a RateLimit>,
+    pub sync: Option<SyncOptions>,
+}
+
+// Produces a string of the command that sends the remote sources back to the local qft listening at `ip`:`tcp_port`
+pub(super) fn remote_qft_send_command_str(
+    ip: IpAddr,
+    tcp_port: u16,
+    verbosity: &str,
+    sources: &[&Path],
+    opts: RemoteSendOptions,
+) -> String {
+    let mut cmd = format!("qft send ip {ip} --port {tcp_port} {verbosity}");
+    for src in sources {
+        cmd.push_str(" --file ");
+        cmd.push_str(&shell_quote_path(src));
+    }
+    for (enabled, flag) in [
+        (opts.use_mmap, " --mmap"),
+        (opts.recursive, " --recursive"),
+        (opts.preserve, " --preserve"),
+        (opts.resume, " --resume"),
+    ] {
+        if enabled {
+            cmd.push_str(flag);
+        }
+    }
+    if let Some(sync) = opts.sync {
+        cmd.push_str(" --sync");
+        for (enabled, flag) in [(sync.delete, " --delete"), (sync.checksum, " --checksum")] {
+            if enabled {
+                cmd.push_str(flag);
+            }
+        }
+    }
+    if let Some(limit_rate) = opts.limit_rate {
+        write!(cmd, " --limit-rate {limit_rate}").expect("Writing to a String cannot fail");
+    }
+    if let Some(compression) = opts.compression {
+        cmd.push(' ');
+        cmd.push_str(compression.variant_as_str());
+        match compression {
+            Compression::Bzip2(args) => write!(cmd, " {}", args.compression_level),
+            Compression::Gzip(args) => write!(cmd, " {}", args.compression_level),
+            Compression::Xz(args) => write!(cmd, " {}", args.compression_level),
+            Compression::Lz4 => Ok(()),
+        }
+        .expect("Writing to a String cannot fail");
+    }
+    // The remote logs go to stderr, include them in the output of the command
+    cmd.push_str(" 2>&1");
+    cmd
+}
+
+/// Quote a path for a POSIX shell, a leading `~/` is left unquoted so that the remote shell expands it
+fn shell_quote_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let (home, rest) = match path.strip_prefix("~/") {
+        Some(rest) => ("~/", rest),
+        None if path == "~" => ("~", ""),
+        None => ("", path.as_ref()),
+    };
+    if rest.is_empty() {
+        return home.to_owned();
+    }
+    format!("{home}'{}'", rest.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::compression::GzipArgs;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_shell_quote_path() {
+        assert_eq!(
+            shell_quote_path(Path::new("/var/log/a b")),
+            "'/var/log/a b'"
+        );
+        assert_eq!(shell_quote_path(Path::new("it's")), r"'it'\''s'");
+        assert_eq!(shell_quote_path(Path::new("~/logs")), "~/'logs'");
+        assert_eq!(shell_quote_path(Path::new("~")), "~");
+    }
+
+    #[test]
+    fn test_remote_qft_send_command_str() {
+        let cmd = remote_qft_send_command_str(
+            "192.168.0.2".parse().unwrap(),
+            49153,
+            "-v",
+            &[Path::new("/var/log/syslog"), Path::new("~/logs")],
+            RemoteSendOptions {
+                use_mmap: false,
+                recursive: true,
+                preserve: true,
+                resume: false,
+                compression: &Some(Compression::Gzip(GzipArgs {
+                    compression_level: 9,
+                })),
+                limit_rate: Some(&RateLimit::new(512 * 1024)),
+                sync: Some(SyncOptions {
+                    delete: true,
+                    checksum: false,
+                }),
+            },
+        );
+        assert_eq!(
+            cmd,
+            "qft send ip 192.168.0.2 --port 49153 -v --file '/var/log/syslog' --file ~/'logs' --recursive --preserve --sync --delete --limit-rate 524288 gzip 9 2>&1"
+        );
+    }
+}
@@ -78,6 +78,16 @@ impl RemoteSshSession {
         Ok(res)
     }
 
+    /// Runs the remote command through SSH until it exits, and returns its output along with its exit status
+    pub fn run_cmd_to_end(&mut self, cmd: &str) -> SshResult<(Vec<u8>, u32)> {
+        let mut executed = self.open_run_exec(cmd)?;
+        let res = executed.results()?;
+        // The exit status is received along with the end of the output
+        let exit_status = executed.exit_status()?;
+        log::trace!("Remote command exit status: {exit_status}");
+        Ok((res, exit_status))
+    }
+
     /// If a command has been executed, consumes it and returns its result.
     ///
     /// Blocks until the server has closed the connection
@@ -0,0 +1,222 @@
+//! Syncing files, i.e. only sending the files that are missing or stale on the receiving end.
+//!
+//! Before sending, the client sends a [Manifest] of the files it would send, and the server replies with a [SyncPlan]
+//! of the files that are missing or stale. An existing file is stale if its size differs or if the sent file was
+//! modified after it (so that files that were received without preserving their times aren't sent again), or if its
+//! checksum differs when the manifest has checksums.
+
+use std::{collections::HashSet, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::{Checksum, PrefixHash},
+    config::transfer::command::REMOTE_PATH_SEPARATOR,
+    preserve::Timestamp,
+    send::{sources::Sources, util::file_with_bufreader},
+    server::partial::PARTIAL_SUFFIX,
+    BUFFERED_RW_BUFSIZE,
+};
+
+/// How files are synced
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncOptions {
+    /// Remove the files and directories inside the sent directories that aren't in the manifest
+    pub delete: bool,
+    /// Compare the checksums of the content instead of the modification times
+    pub checksum: bool,
+}
+
+/// The files a client would send
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+    /// The sent directories, parents precede their children
+    pub dirs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The name the file is received as
+    pub name: String,
+    pub len: u64,
+    pub modified: Option<Timestamp>,
+    pub checksum: Option<Checksum>,
+}
+
+/// The reply to a [Manifest]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncPlan {
+    /// The indices of the files in the manifest that are missing or stale
+    pub send: Vec<u32>,
+    /// The names of the files and directories that were deleted
+    pub deleted: Vec<String>,
+}
+
+impl Manifest {
+    /// The manifest of `sources`, with the checksums of the files if `checksums` is set
+    pub fn of(sources: &Sources, checksums: bool) -> anyhow::Result<Self> {
+        let files = sources
+            .files
+            .iter()
+            .map(|file| {
+                let md = fs::metadata(&file.path)?;
+                let checksum = if checksums {
+                    let prefix = PrefixHash::of_reader(file_with_bufreader(&file.path)?, md.len())?;
+                    Some(prefix.checksum())
+                } else {
+                    None
+                };
+                Ok(ManifestEntry {
+                    name: file.name.clone(),
+                    len: md.len(),
+                    modified: md.modified().ok().and_then(Timestamp::from_system_time),
+                    checksum,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            files,
+            dirs: sources.dirs.clone(),
+        })
+    }
+
+    /// The names of the files and directories of the manifest that are directly inside `dir`
+    pub fn children_of(&self, dir: &str) -> HashSet<&str> {
+        self.files
+            .iter()
+            .map(|file| file.name.as_str())
+            .chain(self.dirs.iter().map(String::as_str))
+            .filter_map(|name| name.rsplit_once(REMOTE_PATH_SEPARATOR))
+            .filter(|(parent, _)| *parent == dir)
+            .map(|(_, child)| child)
+            .collect()
+    }
+}
+
+impl ManifestEntry {
+    /// The existing file at `dest` doesn't have to be sent again
+    pub fn is_up_to_date(&self, dest: &Path) -> io::Result<bool> {
+        let existing = match fs::metadata(dest) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if !existing.is_file() || existing.len() != self.len {
+            return Ok(false);
+        }
+        if let Some(checksum) = self.checksum {
+            let existing = io::BufReader::with_capacity(BUFFERED_RW_BUFSIZE, fs::File::open(dest)?);
+            let existing_checksum = PrefixHash::of_reader(existing, self.len)?.checksum();
+            return Ok(existing_checksum == checksum);
+        }
+        let existing_modified = existing
+            .modified()
+            .ok()
+            .and_then(Timestamp::from_system_time);
+        Ok(
+            matches!((self.modified, existing_modified), (Some(modified), Some(existing_modified)) if modified <= existing_modified),
+        )
+    }
+}
+
+/// Remove the entries of `dir` whose names aren't in `keep`, returns the names of the removed entries.
+///
+/// Partially received files are kept, so that they can still be resumed.
+pub fn prune_dir(dir: &Path, keep: &HashSet<&str>) -> io::Result<Vec<String>> {
+    let mut entries = match fs::read_dir(dir) {
+        Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+        // Nothing was received into it yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    entries.sort_by_key(|e| e.file_name());
+    let mut removed = vec![];
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if keep.contains(name.as_str()) || name.ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+        removed.push(name);
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, SystemTime};
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    fn entry(name: &str, content: &str, modified: SystemTime) -> ManifestEntry {
+        ManifestEntry {
+            name: name.to_owned(),
+            len: content.len() as u64,
+            modified: Timestamp::from_system_time(modified),
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn test_stale_files_are_not_up_to_date() -> TestResult {
+        let d = TempDir::new()?;
+        let dest = d.child("f.txt");
+        let now = SystemTime::now();
+        assert!(!entry("f.txt", "content", now).is_up_to_date(&dest)?);
+
+        fs::write(&dest, "content")?;
+        let received = fs::metadata(&dest)?.modified()?;
+        assert!(entry("f.txt", "content", received).is_up_to_date(&dest)?);
+        // Modified before it was received
+        assert!(
+            entry("f.txt", "content", received - Duration::from_secs(60)).is_up_to_date(&dest)?
+        );
+        // Modified after it was received
+        assert!(
+            !entry("f.txt", "content", received + Duration::from_secs(60)).is_up_to_date(&dest)?
+        );
+        assert!(!entry("f.txt", "changed content", received).is_up_to_date(&dest)?);
+
+        let mut with_checksum = entry("f.txt", "CONTENT", received + Duration::from_secs(60));
+        with_checksum.checksum = Some(Checksum::of(b"content"));
+        assert!(with_checksum.is_up_to_date(&dest)?);
+        with_checksum.checksum = Some(Checksum::of(b"CONTENT"));
+        assert!(!with_checksum.is_up_to_date(&dest)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_keeps_manifest_and_partial_files() -> TestResult {
+        let d = TempDir::new()?;
+        let dir = d.child("dir");
+        fs::create_dir_all(dir.join("kept_dir"))?;
+        fs::create_dir_all(dir.join("removed_dir/sub"))?;
+        fs::write(dir.join("removed_dir/sub/f.txt"), "")?;
+        fs::write(dir.join("kept.txt"), "")?;
+        fs::write(dir.join("removed.txt"), "")?;
+        fs::write(dir.join(".resumable.txt.qft-part"), "")?;
+        let manifest = Manifest {
+            files: vec![entry("dir/kept.txt", "", SystemTime::now())],
+            dirs: vec!["dir".to_owned(), "dir/kept_dir".to_owned()],
+        };
+
+        let keep = manifest.children_of("dir");
+        assert_eq!(keep, HashSet::from(["kept.txt", "kept_dir"]));
+        let removed = prune_dir(&dir, &keep)?;
+
+        assert_eq!(removed, ["removed.txt", "removed_dir"]);
+        let mut left: Vec<String> = fs::read_dir(&dir)?
+            .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+            .collect::<io::Result<_>>()?;
+        left.sort();
+        assert_eq!(left, [".resumable.txt.qft-part", "kept.txt", "kept_dir"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+//! The stream QFT peers talk over, plain TCP or an (opt-in) encrypted channel on top of it.
+//!
+//! The encrypted channel is set up right after the [handshake](crate::config::transfer::handshake) by an X25519 key exchange
+//! that the server signs, along with the handshake before it, with its long-term [identity](identity::ServerIdentity), which clients pin on first use.
+//! Both directions are then encrypted with ChaCha20-Poly1305 using keys derived with HKDF-SHA256 from the shared secret.
+//!
+//! Everything after the key exchange is sent as records: a 4 byte big endian length followed by that many bytes of ciphertext
+//! (including the authentication tag). The nonce of each record is its sequence number, so records can't be dropped,
+//! reordered or replayed without failing authentication.
+//!
+//! This is not TLS, and the protocol has not had an independent security review. Changes to it (the transcript, the key
+//! derivation or the record layer) need one before the transport is relied on for more than passive eavesdroppers.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    hkdf::{Salt, HKDF_SHA256},
+    rand::SystemRandom,
+    signature::{self, KeyPair, ED25519},
+};
+use sha2::{Digest, Sha256};
+
+pub mod identity;
+use identity::{KnownHosts, ServerIdentity};
+
+/// Size of an X25519 public key
+const KEY_SHARE_LEN: usize = 32;
+/// Size of an Ed25519 public key
+const IDENTITY_LEN: usize = 32;
+/// Size of an Ed25519 signature
+const SIGNATURE_LEN: usize = 64;
+/// Size of the message the server answers the key share of the client with: its key share, identity and signature
+const SERVER_KEY_EXCHANGE_LEN: usize = KEY_SHARE_LEN + IDENTITY_LEN + SIGNATURE_LEN;
+
+/// Prefixed to the transcript of the key exchange, so that the signature can't be mistaken for any other use of the identity
+const TRANSCRIPT_CONTEXT: &[u8] = b"qft encrypted transport v1";
+const CLIENT_TO_SERVER_INFO: &[u8] = b"qft client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"qft server to client";
+
+/// Size of the length that precedes each record
+const RECORD_HEADER_LEN: usize = 4;
+/// The most plaintext that is sealed into a single record
+const MAX_RECORD_PLAINTEXT: usize = 16 * 1024;
+/// Size of the authentication tag of ChaCha20-Poly1305
+const TAG_LEN: usize = 16;
+
+/// A connection to a QFT peer, which is encrypted if either peer asked for it during the handshake
+pub struct QftStream {
+    tcp: TcpStream,
+    channel: Option<Box<EncryptedChannel>>,
+}
+
+impl QftStream {
+    /// An unencrypted stream, as every stream is until the handshake is done
+    pub fn plain(tcp: TcpStream) -> Self {
+        Self { tcp, channel: None }
+    }
+
+    /// The underlying TCP stream, reading or writing it directly bypasses the encryption
+    pub fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.channel.is_some()
+    }
+
+    /// Exchange keys with the server and verify the identity it presents against the identity pinned for `known_host`.
+    ///
+    /// `handshake` is everything the peers exchanged before the key exchange, in the order it was sent.
+    /// The server signs it along with the key exchange, so it can't be tampered with to e.g. turn off encryption.
+    pub fn encrypt_as_client(
+        &mut self,
+        handshake: &[u8],
+        known_host: IpAddr,
+    ) -> anyhow::Result<()> {
+        self.encrypt_with_known_hosts(&KnownHosts::open()?, handshake, &known_host.to_string())
+    }
+
+    /// Exchange keys with the client and prove the identity of this server by signing the exchange and the `handshake` before it
+    pub fn encrypt_as_server(&mut self, handshake: &[u8]) -> anyhow::Result<()> {
+        self.encrypt_with(&*ServerIdentity::load_or_create()?, handshake)
+    }
+
+    fn encrypt_with_known_hosts(
+        &mut self,
+        known_hosts: &KnownHosts,
+        handshake: &[u8],
+        host: &str,
+    ) -> anyhow::Result<()> {
+        let rng = SystemRandom::new();
+        let private_key = EphemeralPrivateKey::generate(&X25519, &rng)
+            .map_err(|_| anyhow::anyhow!("Failed generating a key share"))?;
+        let client_share = private_key
+            .compute_public_key()
+            .map_err(|_| anyhow::anyhow!("Failed computing the key share"))?;
+        self.tcp.write_all(client_share.as_ref())?;
+
+        let mut server_msg = [0; SERVER_KEY_EXCHANGE_LEN];
+        self.tcp
+            .read_exact(&mut server_msg)
+            .context("Failed reading the key exchange of the server")?;
+        let (server_share, rest) = server_msg.split_at(KEY_SHARE_LEN);
+        let (server_identity, server_signature) = rest.split_at(IDENTITY_LEN);
+
+        let transcript = transcript(
+            handshake,
+            client_share.as_ref(),
+            server_share,
+            server_identity,
+        );
+        signature::UnparsedPublicKey::new(&ED25519, server_identity)
+            .verify(&transcript, server_signature)
+            .map_err(|_| anyhow::anyhow!("The server failed to prove its identity"))?;
+        known_hosts.verify_or_pin(host, server_identity)?;
+
+        let (client_to_server, server_to_client) =
+            derive_keys(private_key, server_share, &transcript)?;
+        self.channel = Some(Box::new(EncryptedChannel::new(
+            client_to_server,
+            server_to_client,
+        )));
+        log::debug!("Encrypted the connection to {host}");
+        Ok(())
+    }
+
+    fn encrypt_with(&mut self, identity: &ServerIdentity, handshake: &[u8]) -> anyhow::Result<()> {
+        let mut client_share = [0; KEY_SHARE_LEN];
+        self.tcp
+            .read_exact(&mut client_share)
+            .context("Failed reading the key share of the client")?;
+
+        let rng = SystemRandom::new();
+        let private_key = EphemeralPrivateKey::generate(&X25519, &rng)
+            .map_err(|_| anyhow::anyhow!("Failed generating a key share"))?;
+        let server_share = private_key
+            .compute_public_key()
+            .map_err(|_| anyhow::anyhow!("Failed computing the key share"))?;
+        let server_identity = identity.key_pair().public_key().as_ref();
+        let transcript = transcript(
+            handshake,
+            &client_share,
+            server_share.as_ref(),
+            server_identity,
+        );
+        let server_signature = identity.key_pair().sign(&transcript);
+
+        let mut server_msg = Vec::with_capacity(SERVER_KEY_EXCHANGE_LEN);
+        server_msg.extend_from_slice(server_share.as_ref());
+        server_msg.extend_from_slice(server_identity);
+        server_msg.extend_from_slice(server_signature.as_ref());
+        self.tcp.write_all(&server_msg)?;
+
+        let (client_to_server, server_to_client) =
+            derive_keys(private_key, &client_share, &transcript)?;
+        self.channel = Some(Box::new(EncryptedChannel::new(
+            server_to_client,
+            client_to_server,
+        )));
+        log::debug!("Encrypted the connection to {:?}", self.tcp.peer_addr());
+        Ok(())
+    }
+}
+
+impl fmt::Debug for QftStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QftStream")
+            .field("tcp", &self.tcp)
+            .field("encrypted", &self.is_encrypted())
+            .finish()
+    }
+}
+
+impl Read for QftStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.channel.as_mut() {
+            Some(channel) => channel.read(&mut self.tcp, buf),
+            None => self.tcp.read(buf),
+        }
+    }
+}
+
+impl Write for QftStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.channel.as_mut() {
+            Some(channel) => channel.write(&mut self.tcp, buf),
+            None => self.tcp.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+/// The server failed to set up an encrypted connection that can be trusted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportError {
+    /// The server predates encrypted transport
+    Unsupported,
+    /// The server presented a different identity than the one pinned for its host
+    UntrustedServer {
+        host: String,
+        pinned: String,
+        presented: String,
+        known_hosts: PathBuf,
+    },
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Unsupported => f.write_str(
+                "the server does not support encrypted transport, upgrade qft on the server",
+            ),
+            TransportError::UntrustedServer {
+                host,
+                pinned,
+                presented,
+                known_hosts,
+            } => write!(
+                f,
+                "the identity of {host} changed since it was first trusted: expected {pinned} but it presented {presented}. \
+                If the identity of the server was replaced on purpose, remove the entry of {host} from {known_hosts:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/// The hash of the handshake and everything exchanged while agreeing on the keys, which the server signs
+fn transcript(
+    handshake: &[u8],
+    client_share: &[u8],
+    server_share: &[u8],
+    server_identity: &[u8],
+) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(TRANSCRIPT_CONTEXT)
+        .chain_update((handshake.len() as u64).to_be_bytes())
+        .chain_update(handshake)
+        .chain_update(client_share)
+        .chain_update(server_share)
+        .chain_update(server_identity)
+        .finalize()
+        .into()
+}
+
+/// Agree on the shared secret and derive the keys of each direction from it, returns (client to server, server to client)
+fn derive_keys(
+    private_key: EphemeralPrivateKey,
+    peer_share: &[u8],
+    transcript: &[u8; 32],
+) -> anyhow::Result<(LessSafeKey, LessSafeKey)> {
+    let salt = Salt::new(HKDF_SHA256, transcript);
+    let prk = agreement::agree_ephemeral(
+        private_key,
+        &UnparsedPublicKey::new(&X25519, peer_share),
+        |shared_secret| salt.extract(shared_secret),
+    )
+    .map_err(|_| anyhow::anyhow!("Key agreement failed, the peer sent an invalid key share"))?;
+    let key = |info: &[u8]| -> anyhow::Result<LessSafeKey> {
+        let info = [info];
+        let okm = prk
+            .expand(&info, &CHACHA20_POLY1305)
+            .map_err(|_| anyhow::anyhow!("Failed deriving a key"))?;
+        Ok(LessSafeKey::new(UnboundKey::from(okm)))
+    };
+    Ok((key(CLIENT_TO_SERVER_INFO)?, key(SERVER_TO_CLIENT_INFO)?))
+}
+
+/// The keys and record state of an encrypted connection
+struct EncryptedChannel {
+    seal_key: LessSafeKey,
+    seal_seq: u64,
+    open_key: LessSafeKey,
+    open_seq: u64,
+    /// Decrypted content of the latest record that wasn't read yet
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+}
+
+impl EncryptedChannel {
+    fn new(seal_key: LessSafeKey, open_key: LessSafeKey) -> Self {
+        Self {
+            seal_key,
+            seal_seq: 0,
+            open_key,
+            open_seq: 0,
+            plaintext: Vec::with_capacity(MAX_RECORD_PLAINTEXT + TAG_LEN),
+            plaintext_pos: 0,
+        }
+    }
+
+    /// Seal (up to a record of) `buf` into a record and write it
+    fn write(&mut self, tcp: &mut TcpStream, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_RECORD_PLAINTEXT);
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + len + TAG_LEN);
+        record.extend_from_slice(&((len + TAG_LEN) as u32).to_be_bytes());
+        record.extend_from_slice(&buf[..len]);
+        let nonce = next_nonce(&mut self.seal_seq)?;
+        let mut sealed = record.split_off(RECORD_HEADER_LEN);
+        self.seal_key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+            .map_err(|_| io::Error::other("failed encrypting a record"))?;
+        record.append(&mut sealed);
+        tcp.write_all(&record)?;
+        Ok(len)
+    }
+
+    /// Read from the decrypted content of the current record, reading and opening the next record when it's all read
+    fn read(&mut self, tcp: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        if self.plaintext_pos == self.plaintext.len() && !self.read_record(tcp)? {
+            return Ok(0);
+        }
+        let available = &self.plaintext[self.plaintext_pos..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.plaintext_pos += len;
+        Ok(len)
+    }
+
+    /// Read and open the next record, returns false if the peer closed the connection before it
+    fn read_record(&mut self, tcp: &mut TcpStream) -> io::Result<bool> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        let mut filled = 0;
+        while filled < RECORD_HEADER_LEN {
+            match tcp.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        let sealed_len = u32::from_be_bytes(header) as usize;
+        if !(TAG_LEN + 1..=MAX_RECORD_PLAINTEXT + TAG_LEN).contains(&sealed_len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid record length: {sealed_len}"),
+            ));
+        }
+        self.plaintext.resize(sealed_len, 0);
+        tcp.read_exact(&mut self.plaintext)?;
+        let nonce = next_nonce(&mut self.open_seq)?;
+        let len = self
+            .open_key
+            .open_in_place(nonce, Aad::empty(), &mut self.plaintext)
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "record failed authentication")
+            })?
+            .len();
+        self.plaintext.truncate(len);
+        self.plaintext_pos = 0;
+        Ok(true)
+    }
+}
+
+/// The nonce of the record with sequence number `seq`, which is then incremented
+fn next_nonce(seq: &mut u64) -> io::Result<Nonce> {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - 8..].copy_from_slice(&seq.to_be_bytes());
+    *seq = seq
+        .checked_add(1)
+        .ok_or_else(|| io::Error::other("ran out of record sequence numbers"))?;
+    Ok(Nonce::assume_unique_for_key(nonce))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    /// Stands in for the hellos and encryption request and reply that precede the key exchange
+    const HANDSHAKE: &[u8] = b"hellos, encrypt, encrypted";
+    /// The address the client was pointed at
+    const HOST: &str = "127.0.0.1";
+
+    fn stream_pair() -> io::Result<(QftStream, QftStream)> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+        Ok((QftStream::plain(client), QftStream::plain(server)))
+    }
+
+    /// Encrypt a pair of streams with the identity and known hosts stored in `config_dir`
+    fn encrypted_pair(config_dir: &TempDir) -> TestResult<(QftStream, QftStream)> {
+        let (mut client, mut server) = stream_pair()?;
+        let identity = ServerIdentity::load_or_create_in(config_dir.path())?;
+        let server_thread = std::thread::spawn(move || -> anyhow::Result<QftStream> {
+            server.encrypt_with(&identity, HANDSHAKE)?;
+            Ok(server)
+        });
+        client.encrypt_with_known_hosts(
+            &KnownHosts::open_in(config_dir.path()),
+            HANDSHAKE,
+            HOST,
+        )?;
+        let server = server_thread.join().expect("Failed joining server")?;
+        Ok((client, server))
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip_larger_than_a_record() -> TestResult {
+        let config_dir = TempDir::new()?;
+        let (mut client, mut server) = encrypted_pair(&config_dir)?;
+        assert!(client.is_encrypted() && server.is_encrypted());
+
+        let content: Vec<u8> = (0..3 * MAX_RECORD_PLAINTEXT + 7)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let sent = content.clone();
+        let client_thread = std::thread::spawn(move || -> io::Result<QftStream> {
+            client.write_all(&sent)?;
+            Ok(client)
+        });
+        let mut received = vec![0; content.len()];
+        server.read_exact(&mut received)?;
+        assert!(received == content);
+
+        let mut client = client_thread.join().expect("Failed joining client")?;
+        server.write_all(b"reply")?;
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply)?;
+        assert_eq!(&reply, b"reply");
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered_record_fails_authentication() -> TestResult {
+        let config_dir = TempDir::new()?;
+        let (client, mut server) = encrypted_pair(&config_dir)?;
+        // Write a record with the right length but garbage content directly to the TCP stream
+        let mut record = ((5 + TAG_LEN) as u32).to_be_bytes().to_vec();
+        record.extend_from_slice(&[0xAB; 5 + TAG_LEN]);
+        client.tcp().write_all(&record)?;
+
+        let err = server.read_exact(&mut [0; 5]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_closed_connection_reads_as_eof() -> TestResult {
+        let config_dir = TempDir::new()?;
+        let (client, mut server) = encrypted_pair(&config_dir)?;
+        drop(client);
+        assert_eq!(server.read(&mut [0; 8])?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_changed_server_identity_is_refused() -> TestResult {
+        let config_dir = TempDir::new()?;
+        let client_dir = TempDir::new()?;
+        let (mut client, mut server) = stream_pair()?;
+        let identity = ServerIdentity::load_or_create_in(config_dir.path())?;
+        let server_thread = std::thread::spawn(move || server.encrypt_with(&identity, HANDSHAKE));
+        client.encrypt_with_known_hosts(
+            &KnownHosts::open_in(client_dir.path()),
+            HANDSHAKE,
+            HOST,
+        )?;
+        server_thread.join().expect("Failed joining server")?;
+
+        // Same host, but the server now has another identity
+        let other_dir = TempDir::new()?;
+        let (mut client, mut server) = stream_pair()?;
+        let other_identity = ServerIdentity::load_or_create_in(other_dir.path())?;
+        let server_thread =
+            std::thread::spawn(move || server.encrypt_with(&other_identity, HANDSHAKE));
+        let err = client
+            .encrypt_with_known_hosts(&KnownHosts::open_in(client_dir.path()), HANDSHAKE, HOST)
+            .unwrap_err();
+        let _ = server_thread.join();
+        assert!(matches!(
+            err.downcast_ref::<TransportError>(),
+            Some(TransportError::UntrustedServer { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered_handshake_is_refused() -> TestResult {
+        let config_dir = TempDir::new()?;
+        let (mut client, mut server) = stream_pair()?;
+        let identity = ServerIdentity::load_or_create_in(config_dir.path())?;
+        let server_thread =
+            std::thread::spawn(move || server.encrypt_with(&identity, b"hellos, don't encrypt"));
+        let err = client
+            .encrypt_with_known_hosts(&KnownHosts::open_in(config_dir.path()), HANDSHAKE, HOST)
+            .unwrap_err();
+        let _ = server_thread.join();
+        assert!(
+            err.to_string().contains("failed to prove its identity"),
+            "{err}"
+        );
+        assert!(!client.is_encrypted());
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! The long-term identity of a server, and the server identities that a client trusted on first use.
+//!
+//! Both are stored in the `qft` directory under the config directory of the user
+//! (`$XDG_CONFIG_HOME`, `~/.config` or `%APPDATA%`).
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+use super::TransportError;
+use crate::checksum::Checksum;
+
+/// File in the config directory that holds the PKCS#8 encoded key pair of the server
+const IDENTITY_FILE_NAME: &str = "identity.pk8";
+/// File in the config directory with a line of `<ip> <fingerprint>` for each server that was trusted
+const KNOWN_HOSTS_FILE_NAME: &str = "known_hosts";
+
+/// The identity of this server, loaded once and shared by all connections
+static SERVER_IDENTITY: Mutex<Option<Arc<ServerIdentity>>> = Mutex::new(None);
+
+/// The `qft` directory under the config directory of the user
+pub fn config_dir() -> anyhow::Result<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("APPDATA").map(PathBuf::from)
+            } else {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+            }
+        })
+        .context("Unable to determine the config directory, set XDG_CONFIG_HOME")?;
+    Ok(base.join("qft"))
+}
+
+/// The fingerprint of a (public) identity, as it is shown to users and pinned by clients
+pub fn fingerprint(identity: &[u8]) -> String {
+    Checksum::of(identity).to_string()
+}
+
+/// The key pair a server signs the key exchange with, proving to clients that it is the same server as before
+pub struct ServerIdentity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ServerIdentity {
+    /// The identity in the config directory, which is generated the first time it's needed
+    pub fn load_or_create() -> anyhow::Result<Arc<Self>> {
+        let mut cached = SERVER_IDENTITY
+            .lock()
+            .expect("Server identity lock poisoned");
+        if let Some(identity) = cached.as_ref() {
+            return Ok(Arc::clone(identity));
+        }
+        let identity = Arc::new(Self::load_or_create_in(&config_dir()?)?);
+        log::info!("Server identity: {}", identity.fingerprint());
+        *cached = Some(Arc::clone(&identity));
+        Ok(identity)
+    }
+
+    /// The identity in `dir`, which is generated if there is none
+    pub fn load_or_create_in(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(IDENTITY_FILE_NAME);
+        let pkcs8 = match fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::generate(&path)?,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed reading server identity {path:?}"))
+            }
+        };
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| anyhow::anyhow!("Invalid server identity {path:?}: {e}"))?;
+        Ok(Self { key_pair })
+    }
+
+    /// Generate a new identity and store it at `path` (readable only by the user)
+    fn generate(path: &Path) -> anyhow::Result<Vec<u8>> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("Failed generating a server identity"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed creating {dir:?}"))?;
+        }
+        let mut options = fs::File::options();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(path) {
+            Ok(mut file) => {
+                file.write_all(pkcs8.as_ref())?;
+                file.sync_all()?;
+                log::info!("Generated a new server identity at {path:?}");
+                Ok(pkcs8.as_ref().to_vec())
+            }
+            // Another qft generated it first
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(fs::read(path)?),
+            Err(e) => Err(e).with_context(|| format!("Failed storing server identity {path:?}")),
+        }
+    }
+
+    pub fn key_pair(&self) -> &Ed25519KeyPair {
+        &self.key_pair
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.key_pair.public_key().as_ref())
+    }
+}
+
+/// The identities of the servers a client trusted, each pinned the first time the client connected to that host.
+///
+/// Hosts are the IP the client was pointed at, regardless of the port: the ports of a server's children are random,
+/// as is the port of a server started by `qft ssh`, which would otherwise be trusted on first use every time.
+/// Servers on the same machine share the identity of the user that runs them, a server run by another user
+/// on the same machine is refused as a changed identity.
+pub struct KnownHosts {
+    path: PathBuf,
+}
+
+impl KnownHosts {
+    /// The known hosts in the config directory
+    pub fn open() -> anyhow::Result<Self> {
+        Ok(Self::open_in(&config_dir()?))
+    }
+
+    /// The known hosts in `dir`
+    pub fn open_in(dir: &Path) -> Self {
+        Self {
+            path: dir.join(KNOWN_HOSTS_FILE_NAME),
+        }
+    }
+
+    /// The fingerprint pinned for `host`, if any
+    pub fn pinned(&self, host: &str) -> anyhow::Result<Option<String>> {
+        let known_hosts = match fs::read_to_string(&self.path) {
+            Ok(known_hosts) => known_hosts,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed reading {:?}", self.path)),
+        };
+        Ok(known_hosts
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .filter_map(|line| line.split_once(' '))
+            .find(|(known_host, _)| *known_host == host)
+            .map(|(_, fingerprint)| fingerprint.trim().to_owned()))
+    }
+
+    /// Check that `identity` is the identity pinned for `host`, or pin it if `host` wasn't seen before
+    pub fn verify_or_pin(&self, host: &str, identity: &[u8]) -> anyhow::Result<()> {
+        let presented = fingerprint(identity);
+        match self.pinned(host)? {
+            Some(pinned) if pinned == presented => {
+                log::trace!("Identity of {host} matches the pinned identity {pinned}");
+                Ok(())
+            }
+            Some(pinned) => Err(TransportError::UntrustedServer {
+                host: host.to_owned(),
+                pinned,
+                presented,
+                known_hosts: self.path.clone(),
+            }
+            .into()),
+            None => {
+                if let Some(dir) = self.path.parent() {
+                    fs::create_dir_all(dir).with_context(|| format!("Failed creating {dir:?}"))?;
+                }
+                let mut file = fs::File::options()
+                    .append(true)
+                    .create(true)
+                    .open(&self.path)
+                    .with_context(|| format!("Failed opening {:?}", self.path))?;
+                writeln!(file, "{host} {presented}")?;
+                log::info!(
+                    "Trusting {host} on first use, its identity {presented} is pinned in {:?}",
+                    self.path
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use temp_dir::TempDir;
+    use testresult::TestResult;
+
+    #[test]
+    fn test_identity_is_generated_once() -> TestResult {
+        let d = TempDir::new()?;
+        let identity = ServerIdentity::load_or_create_in(d.path())?;
+        let loaded = ServerIdentity::load_or_create_in(d.path())?;
+        assert_eq!(identity.fingerprint(), loaded.fingerprint());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(d.child(IDENTITY_FILE_NAME))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_known_hosts_pin_on_first_use() -> TestResult {
+        let d = TempDir::new()?;
+        let known_hosts = KnownHosts::open_in(d.path());
+        assert_eq!(known_hosts.pinned("10.0.0.1")?, None);
+
+        known_hosts.verify_or_pin("10.0.0.1", b"identity")?;
+        known_hosts.verify_or_pin("10.0.0.2", b"other identity")?;
+        assert_eq!(
+            known_hosts.pinned("10.0.0.1")?,
+            Some(fingerprint(b"identity"))
+        );
+        known_hosts.verify_or_pin("10.0.0.1", b"identity")?;
+
+        let err = known_hosts
+            .verify_or_pin("10.0.0.1", b"other identity")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("changed since it was first trusted"));
+        Ok(())
+    }
+}
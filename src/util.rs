@@ -1,12 +1,18 @@
+use crate::auth::{random_nonce, AuthChallenge, AuthError, PreSharedKey, PROOF_LEN};
 use crate::config::transfer::command::{ServerCommand, ServerResult};
+use crate::config::transfer::handshake::{
+    Capabilities, HandshakeHello, NegotiatedProtocol, HANDSHAKE_MAGIC,
+};
 use crate::config::Config;
+use crate::server::util::{send_reply, send_result};
+use crate::transport::QftStream;
 use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Serialize};
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::path::Path;
 use std::time::Duration;
 use std::{fmt, fs, io};
-use tiny_rnd::rnd_u32;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Address<'cfg> {
@@ -83,12 +89,9 @@ where
         }
         total_read += bytes_read;
 
-        let written_bytes = stream_writer.write(&buf[..bytes_read])?;
-        log::trace!("wrote {written_bytes}");
-        debug_assert_eq!(
-            bytes_read, written_bytes,
-            "Mismatch between bytes read/written, read={bytes_read}, written={written_bytes}"
-        );
+        // The writer may take less than all of it, e.g. at the end of a data frame
+        stream_writer.write_all(&buf[..bytes_read])?;
+        log::trace!("wrote {bytes_read}");
     }
     Ok(total_read as u64)
 }
@@ -172,35 +175,138 @@ pub fn verbosity_to_args(cfg: &Config) -> &str {
     }
 }
 
-/// Do the basic handshake from the serverside to ensure we're talking to a QFT client
-pub fn server_handshake(socket: &mut TcpStream) -> anyhow::Result<()> {
-    let handshake_u32 = rnd_u32(std::process::id() as u64);
-    let expect_handshake = rnd_u32(handshake_u32 as u64);
+/// Print `value` to stdout as a single line of JSON, for `--output-format json`
+pub fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, value)?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+/// How long the server waits for a client to answer the [AuthChallenge]
+pub(crate) const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Do the handshake from the serverside to ensure we're talking to a QFT client that speaks a compatible protocol.
+///
+/// The server sends its [HandshakeHello], reads the hello of the client and replies with a [ServerResult]
+/// that tells the client whether the server accepted it.
+///
+/// The connection is then encrypted if the client asks for it or `encrypt` is set, and if `psk` is given
+/// the client has to prove that it knows the key, before any [ServerCommand] is accepted.
+pub fn server_handshake(
+    socket: TcpStream,
+    psk: Option<&PreSharedKey>,
+    encrypt: bool,
+) -> anyhow::Result<(QftStream, NegotiatedProtocol)> {
+    let mut socket = QftStream::plain(socket);
+    let local_hello = HandshakeHello::local();
 
-    if let Err(e) = socket.write_all(&handshake_u32.to_be_bytes()) {
+    if let Err(e) = socket.write_all(&local_hello.to_bytes()) {
         log::warn!("{}: {e}, retrying in 100 ms ...", e.kind());
         std::thread::sleep(Duration::from_millis(100));
-        socket.write_all(&handshake_u32.to_be_bytes())?
+        socket.write_all(&local_hello.to_bytes())?
     }
-    let mut handshake_buf: [u8; 4] = [0; 4];
-    if let Err(e) = socket.read_exact(&mut handshake_buf) {
+    let mut magic_buf = [0; HANDSHAKE_MAGIC.len()];
+    if let Err(e) = socket.read_exact(&mut magic_buf) {
         log::warn!("{}: {e}, retrying in 100 ms ...", e.kind());
         std::thread::sleep(Duration::from_millis(100));
-        socket.read_exact(&mut handshake_buf)?;
+        socket.read_exact(&mut magic_buf)?;
     }
-    let handshake: u32 = u32::from_be_bytes(handshake_buf);
+    HandshakeHello::check_magic(magic_buf)?;
+    let mut body_buf = [0; HandshakeHello::SIZE - HANDSHAKE_MAGIC.len()];
+    socket.read_exact(&mut body_buf)?;
+    let client_hello = HandshakeHello::from_body_bytes(body_buf);
+    tracing::trace!("Client hello: {client_hello:?}");
+    let hellos = [&local_hello.to_bytes()[..], &magic_buf, &body_buf].concat();
 
-    if handshake != expect_handshake {
-        bail!("Received unexpected handshake: {handshake}")
-    } else {
-        log::trace!("QFT handshake OK");
+    let negotiated = match local_hello.negotiate(&client_hello) {
+        Ok(negotiated) => negotiated,
+        Err(e) => {
+            send_result(&mut socket, &ServerResult::err(e.to_string()))?;
+            bail!(e)
+        }
+    };
+    let supports_encryption = negotiated.capabilities.contains(Capabilities::ENCRYPTION);
+    if encrypt && !supports_encryption {
+        send_result(
+            &mut socket,
+            &ServerResult::err(
+                "the server requires an encrypted transport, which this version of qft can't provide",
+            ),
+        )?;
+        bail!("Refused a client that doesn't support encrypted transport");
+    }
+    let supports_auth = negotiated.capabilities.contains(Capabilities::PSK_AUTH);
+    if psk.is_some() && !supports_auth {
+        send_result(
+            &mut socket,
+            &ServerResult::err(
+                "the server requires a pre-shared key, which this version of qft can't provide",
+            ),
+        )?;
+        bail!(AuthError::Refused(
+            "the client doesn't support authentication with a pre-shared key".into()
+        ));
+    }
+    send_result(&mut socket, &ServerResult::Ok)?;
+    if supports_encryption {
+        agree_on_encryption(&mut socket, encrypt, &hellos)?;
+    }
+    if supports_auth {
+        authenticate_client(&mut socket, psk)?;
+    }
+    log::trace!("QFT handshake OK - {negotiated:?}");
+    Ok((socket, negotiated))
+}
+
+/// Read whether the client asks for encryption, tell it whether the connection is encrypted and if so, encrypt it.
+///
+/// `hellos` are the raw hellos of the server and the client, which are signed along with the encryption request and reply.
+fn agree_on_encryption(socket: &mut QftStream, encrypt: bool, hellos: &[u8]) -> anyhow::Result<()> {
+    let mut client_asks = [0];
+    socket.read_exact(&mut client_asks)?;
+    let encrypt = encrypt || client_asks[0] != 0;
+    let reply = [u8::from(encrypt)];
+    socket.write_all(&reply)?;
+    if encrypt {
+        socket.encrypt_as_server(&[hellos, &client_asks, &reply].concat())?;
     }
     Ok(())
 }
 
+/// Challenge the client to prove that it knows the pre-shared key (if any) and reply whether it did
+fn authenticate_client(socket: &mut QftStream, psk: Option<&PreSharedKey>) -> anyhow::Result<()> {
+    let Some(psk) = psk else {
+        return send_reply(socket, &AuthChallenge::None);
+    };
+    let nonce = random_nonce()?;
+    send_reply(socket, &AuthChallenge::Psk(nonce))?;
+
+    let mut proof = [0; PROOF_LEN];
+    let prev_timeout = socket.tcp().read_timeout()?;
+    socket.tcp().set_read_timeout(Some(AUTH_TIMEOUT))?;
+    let answered = socket.read_exact(&mut proof);
+    socket.tcp().set_read_timeout(prev_timeout)?;
+    if let Err(e) = answered {
+        bail!(AuthError::Refused(
+            format!("no answer to the challenge: {e}").into()
+        ));
+    }
+
+    if psk.verify(&nonce, &proof) {
+        log::debug!("Client authenticated");
+        send_result(socket, &ServerResult::Ok)
+    } else {
+        const REASON: &str = "wrong pre-shared key";
+        send_result(socket, &ServerResult::err(REASON))?;
+        bail!(AuthError::Refused(REASON.into()))
+    }
+}
+
+/// Read a [ServerCommand] from the socket, `cmd_buf` is resized to fit the incoming command and can be reused between calls.
 pub fn read_server_cmd(
-    socket: &mut TcpStream,
-    cmd_buf: &mut [u8],
+    socket: &mut QftStream,
+    cmd_buf: &mut Vec<u8>,
 ) -> anyhow::Result<Option<ServerCommand>> {
     let mut header_buf = [0; ServerCommand::HEADER_SIZE];
     // Read the header to determine the size of the incoming command/data
@@ -215,8 +321,8 @@ pub fn read_server_cmd(
             socket.read_exact(&mut header_buf)?;
         }
     }
-    let inc_cmd_len = ServerCommand::size_from_bytes(header_buf);
-    debug_assert!(inc_cmd_len <= cmd_buf.len());
+    let inc_cmd_len = ServerCommand::size_from_bytes(header_buf)?;
+    cmd_buf.resize(inc_cmd_len, 0);
 
     // Read the actual command/data based on the size
     if let Err(e) = socket.read_exact(&mut cmd_buf[..inc_cmd_len]) {
@@ -228,22 +334,22 @@ pub fn read_server_cmd(
     Ok(Some(command))
 }
 
-fn read_server_response_header(socket: &mut TcpStream) -> anyhow::Result<usize> {
+fn read_server_response_header(socket: &mut QftStream) -> anyhow::Result<usize> {
     let mut header_buf = [0; ServerResult::HEADER_SIZE];
     // Read the header to determine the size of the incoming command/data
     if let Err(e) = socket.read_exact(&mut header_buf) {
         bail!("{e}");
     }
-    Ok(ServerResult::size_from_bytes(header_buf))
+    Ok(ServerResult::size_from_bytes(header_buf)?)
 }
 
-/// Provide your own buffer to allow for buffer reuse
+/// Provide your own buffer to allow for buffer reuse, `resp_buf` is resized to fit the incoming result.
 pub fn read_server_response_with_buf(
-    socket: &mut TcpStream,
-    resp_buf: &mut [u8],
+    socket: &mut QftStream,
+    resp_buf: &mut Vec<u8>,
 ) -> anyhow::Result<ServerResult> {
     let inc_resp_len = read_server_response_header(socket)?;
-    debug_assert!(inc_resp_len <= resp_buf.len());
+    resp_buf.resize(inc_resp_len, 0);
 
     // Read the actual command/data based on the size
     if let Err(e) = socket.read_exact(&mut resp_buf[..inc_resp_len]) {
@@ -253,7 +359,12 @@ pub fn read_server_response_with_buf(
     Ok(resp)
 }
 
-pub fn read_server_response(socket: &mut TcpStream) -> anyhow::Result<ServerResult> {
+pub fn read_server_response(socket: &mut QftStream) -> anyhow::Result<ServerResult> {
+    read_server_reply(socket)
+}
+
+/// Read a reply that is framed like a [ServerResult], e.g. a [ResolvedDestination](crate::config::transfer::command::ResolvedDestination)
+pub fn read_server_reply<T: DeserializeOwned>(socket: &mut QftStream) -> anyhow::Result<T> {
     let inc_resp_len = read_server_response_header(socket)?;
 
     // Candidate for unsafe uninitialized read
@@ -263,37 +374,73 @@ pub fn read_server_response(socket: &mut TcpStream) -> anyhow::Result<ServerResu
     if let Err(e) = socket.read_exact(&mut resp_buf) {
         anyhow::bail!("Error reading command into buffer: {e}");
     }
-    let resp: ServerResult = bincode::deserialize(&resp_buf)?;
+    let resp: T = bincode::deserialize(&resp_buf)?;
     Ok(resp)
 }
 
-/// This is for generating pseudo-random number for application client/server hand shake.
-///
-/// It is adapted from: <https://docs.rs/rand_xoshiro/latest/src/rand_xoshiro/splitmix64.rs.html>
-/// ... and gutted
-///
-/// There's no strong requirement for this random number other than being fast, and lets not add the rand crate as a dependency just for this...
-pub(crate) mod tiny_rnd {
-
-    /// Get a "random" number from a seed (one shot).
-    ///
-    /// # Note
-    ///
-    /// Adapted (gutted) from: <https://docs.rs/rand_xoshiro/latest/src/rand_xoshiro/splitmix64.rs.html>
-    ///
-    /// Stateless gutted splitmix64 random number generator.
-    ///
-    /// The gutted splitmix algorithm is NOT suitable for cryptographic purposes, but is
-    /// very fast and has a 64 bit state.
-    pub fn rnd_u32(seed: u64) -> u32 {
-        const PHI: u64 = 0x9e3779b97f4a7c15;
-        let mut z = seed.wrapping_add(PHI);
-        // David Stafford's
-        // (http://zimbry.blogspot.com/2011/09/better-bit-mixing-improving-on.html)
-        // "Mix4" variant of the 64-bit finalizer in Austin Appleby's
-        // MurmurHash3 algorithm.
-        z = (z ^ (z >> 33)).wrapping_mul(0x62A9D9ED799705F5);
-        z = (z ^ (z >> 28)).wrapping_mul(0xCB24D0A5C88C35B3);
-        (z >> 32) as u32
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::transfer::command::{OversizedFrame, MAX_FRAME_SIZE},
+        send::util::send_command,
+    };
+    use pretty_assertions::assert_eq;
+    use testresult::TestResult;
+
+    fn connected_pair() -> io::Result<(QftStream, QftStream)> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+        Ok((QftStream::plain(client), QftStream::plain(server)))
+    }
+
+    #[test]
+    fn test_command_with_long_path_roundtrip() -> TestResult {
+        let (mut client, mut server) = connected_pair()?;
+        let long_path = "/build/artifacts/".to_owned() + &"nested_dir/".repeat(40) + "f.bin";
+        assert!(long_path.len() > 400);
+
+        send_command(
+            &mut client,
+            &ServerCommand::ReceiveData(1, long_path.clone(), None),
+        )?;
+        send_command(&mut client, &ServerCommand::EndOfTransfer)?;
+
+        let mut cmd_buf = Vec::new();
+        match read_server_cmd(&mut server, &mut cmd_buf)? {
+            Some(ServerCommand::ReceiveData(1, path, None)) => assert_eq!(path, long_path),
+            other => panic!("Unexpected command: {other:?}"),
+        }
+        assert!(matches!(
+            read_server_cmd(&mut server, &mut cmd_buf)?,
+            Some(ServerCommand::EndOfTransfer)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_oversized_command_fails() -> TestResult {
+        let (mut client, _server) = connected_pair()?;
+        let huge_path = "a".repeat(MAX_FRAME_SIZE + 1);
+        let err = send_command(&mut client, &ServerCommand::Prealloc(0, huge_path)).unwrap_err();
+        assert!(err.is::<OversizedFrame>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_oversized_command_fails() -> TestResult {
+        let (mut client, mut server) = connected_pair()?;
+        let oversized_header = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        client.write_all(&oversized_header)?;
+
+        let err = read_server_cmd(&mut server, &mut Vec::new()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<OversizedFrame>(),
+            Some(&OversizedFrame {
+                size: MAX_FRAME_SIZE + 1
+            })
+        );
+        Ok(())
     }
 }
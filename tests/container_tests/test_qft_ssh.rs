@@ -168,3 +168,93 @@ pub fn test_ssh_transfer_no_tcp_port_specified_compression_gzip() -> TestResult
 
     Ok(())
 }
+
+#[test]
+#[ignore = "Needs to be run with container test (just d-test)"]
+pub fn test_ssh_transfer_recursive() -> TestResult {
+    let dir = TempDir::new()?;
+    let src_dir = dir.child("src_dir");
+    fs::create_dir_all(src_dir.join("nested"))?;
+    fs::write(src_dir.join("what.txt"), LOREM_IPSUM_WHAT)?;
+    fs::write(src_dir.join("nested/where.txt"), LOREM_IPSUM_WHERE)?;
+    let file1_to_receive: String = CONTAINER_HOME_DOWNLOAD_DIR.to_owned() + "/src_dir/what.txt";
+    let file2_to_receive: String =
+        CONTAINER_HOME_DOWNLOAD_DIR.to_owned() + "/src_dir/nested/where.txt";
+
+    let _test_container = TestContainer::setup("/usr/sbin/sshd -D -p 54320", true);
+
+    let mut cmd = Command::cargo_bin(BIN_NAME).unwrap();
+    let args = [
+        "ssh",
+        "-r",
+        src_dir.path().to_str().unwrap(),
+        &format!("{CONTAINER_USER}@{CONTAINER_IP}:{CONTAINER_HOME_DOWNLOAD_DIR}"),
+        "--ssh-port",
+        CONTAINER_SSH_PORT,
+        "-vv",
+        "--start-port",
+        CONTAINER_DYNAMIC_PORTS_START,
+        "--end-port",
+        CONTAINER_DYNAMIC_PORTS_END,
+    ];
+    cmd.args(args);
+    let StdoutStderr { stdout, stderr } = process_output_to_stdio_if_success(cmd.output()?)?;
+
+    eprint_docker_logs()?;
+    eprint_cmd_args_stderr_stdout_formatted(&args, &stdout, &stderr);
+
+    assert_no_errors_or_warn_container_specific(&stderr)?;
+
+    let f1 = assert_file_exists_in_container(&file1_to_receive)?;
+    let f2 = assert_file_exists_in_container(&file2_to_receive)?;
+    pretty_assert_str_eq!(fs::read_to_string(f1)?, LOREM_IPSUM_WHAT);
+    pretty_assert_str_eq!(fs::read_to_string(f2)?, LOREM_IPSUM_WHERE);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "Needs to be run with container test (just d-test)"]
+pub fn test_ssh_pull_transfer() -> TestResult {
+    let dir = TempDir::new()?;
+    let fname = "pull-L,sL.txt";
+    let file_to_pull: String = CONTAINER_HOME_DOWNLOAD_DIR.to_owned() + "/" + fname;
+
+    let _test_container = TestContainer::setup("/usr/sbin/sshd -D -p 54320", true);
+
+    // Push a file to the container first, then pull it back
+    let file_to_push = dir.child(fname);
+    fs::write(&file_to_push, LOREM_IPSUM_WHAT)?;
+    let mut push_cmd = Command::cargo_bin(BIN_NAME).unwrap();
+    push_cmd.args([
+        "ssh",
+        file_to_push.path().to_str().unwrap(),
+        &format!("{CONTAINER_USER}@{CONTAINER_IP}:{file_to_pull}"),
+        "--ssh-port",
+        CONTAINER_SSH_PORT,
+        "--tcp-port",
+        CONTAINER_TCP_PORT,
+    ]);
+    process_output_to_stdio_if_success(push_cmd.output()?)?;
+
+    let pulled_file = dir.child("pulled.txt");
+    let mut cmd = Command::cargo_bin(BIN_NAME).unwrap();
+    let args = [
+        "ssh",
+        &format!("{CONTAINER_USER}@{CONTAINER_IP}:{file_to_pull}"),
+        pulled_file.path().to_str().unwrap(),
+        "--ssh-port",
+        CONTAINER_SSH_PORT,
+        "-vv",
+    ];
+    cmd.args(args);
+    let StdoutStderr { stdout, stderr } = process_output_to_stdio_if_success(cmd.output()?)?;
+
+    eprint_docker_logs()?;
+    eprint_cmd_args_stderr_stdout_formatted(&args, &stdout, &stderr);
+
+    assert_no_errors_or_warn_container_specific(&stderr)?;
+    pretty_assert_str_eq!(fs::read_to_string(pulled_file)?, LOREM_IPSUM_WHAT);
+
+    Ok(())
+}
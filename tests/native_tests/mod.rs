@@ -1,6 +1,19 @@
+#[cfg(feature = "async")]
+mod test_qft_async;
+mod test_qft_auth;
 mod test_qft_basics;
+mod test_qft_encrypt;
 #[cfg(feature = "evaluate-compression")]
 mod test_qft_evaluate_compression;
+mod test_qft_events;
+mod test_qft_handshake;
+mod test_qft_keep_alive;
+mod test_qft_library;
 #[cfg(feature = "mdns")]
 mod test_qft_mdns;
+mod test_qft_output_format;
+mod test_qft_overwrite;
+mod test_qft_rate_limit;
+mod test_qft_resume;
+mod test_qft_stdio;
 mod test_qft_transfer;
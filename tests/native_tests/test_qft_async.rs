@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use quick_file_transfer::{asynchronous, auth::PreSharedKey, Client, FileStatus, Server};
+
+use crate::util::*;
+
+#[tokio::test]
+pub async fn test_async_client_sends_to_blocking_server() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    fs::write(&file, LOREM_IPSUM_0x80000_BYTES)?;
+    let output_dir = dir.child("output");
+
+    let server = Server::builder()
+        .ip([127, 0, 0, 1])
+        .port(0)
+        .output_dir(output_dir.path())
+        .build()?;
+    let addr = server.local_addr()?;
+    let server_task = tokio::task::spawn_blocking(move || server.run());
+
+    let report = asynchronous::Client::builder()
+        .target(addr)
+        .file(file.path())
+        .build()?
+        .send()
+        .await?;
+    let server_report = server_task.await??;
+
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(
+        report.files[0].status,
+        FileStatus::Sent {
+            transferred: LOREM_IPSUM_0x80000_BYTES.len() as u64
+        }
+    );
+    assert_eq!(server_report.files.len(), 1);
+    assert_eq!(
+        fs::read_to_string(output_dir.child("f.txt"))?,
+        LOREM_IPSUM_0x80000_BYTES
+    );
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_blocking_client_sends_to_async_server() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_a = dir.child("a.txt");
+    let file_b = dir.child("b.txt");
+    fs::write(&file_a, LOREM_IPSUM_WHAT)?;
+    fs::write(&file_b, LOREM_IPSUM_0x80000_BYTES)?;
+    let output_dir = dir.child("output");
+
+    let server = asynchronous::Server::builder()
+        .ip([127, 0, 0, 1])
+        .port(0)
+        .output_dir(output_dir.path())
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    let server_task = tokio::spawn(server.run());
+
+    // Compression and preservation aren't negotiated with the async server, the rest of the defaults are
+    let client = Client::builder()
+        .target(addr)
+        .files([file_a.path(), file_b.path()])
+        .build()?;
+    let report = tokio::task::spawn_blocking(move || client.send()).await??;
+    let server_report = server_task.await??;
+
+    report.ensure_no_failures()?;
+    let received: Vec<_> = server_report
+        .files
+        .iter()
+        .map(|f| (f.name.as_str(), f.size))
+        .collect();
+    assert_eq!(
+        received,
+        [
+            ("a.txt", LOREM_IPSUM_WHAT.len() as u64),
+            ("b.txt", LOREM_IPSUM_0x80000_BYTES.len() as u64)
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(output_dir.child("a.txt"))?,
+        LOREM_IPSUM_WHAT
+    );
+    assert_eq!(
+        fs::read_to_string(output_dir.child("b.txt"))?,
+        LOREM_IPSUM_0x80000_BYTES
+    );
+    assert_eq!(
+        fs::read_dir(&output_dir)?.count(),
+        2,
+        "No temporary files left"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_async_client_sends_directory_to_async_server_with_psk() -> TestResult {
+    let dir = TempDir::new()?;
+    let tree = dir.child("tree");
+    fs::create_dir_all(tree.child("sub"))?;
+    fs::write(tree.child("a.txt"), LOREM_IPSUM_WHAT)?;
+    fs::write(tree.child("sub/b.txt"), LOREM_IPSUM_0x80000_BYTES)?;
+    let output_dir = dir.child("output");
+    let psk: PreSharedKey = "secret".parse()?;
+
+    let server = asynchronous::Server::builder()
+        .ip([127, 0, 0, 1])
+        .port(0)
+        .output_dir(output_dir.path())
+        .psk(psk.clone())
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    let server_task = tokio::spawn(server.run());
+
+    let report = asynchronous::Client::builder()
+        .target(addr)
+        .file(tree.path())
+        .recursive(true)
+        .psk(psk)
+        .build()?
+        .send()
+        .await?;
+    let server_report = server_task.await??;
+
+    report.ensure_no_failures()?;
+    assert_eq!(server_report.files.len(), 2);
+    assert_eq!(
+        fs::read_to_string(output_dir.child("tree/a.txt"))?,
+        LOREM_IPSUM_WHAT
+    );
+    assert_eq!(
+        fs::read_to_string(output_dir.child("tree/sub/b.txt"))?,
+        LOREM_IPSUM_0x80000_BYTES
+    );
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_async_server_refuses_client_without_psk() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    fs::write(&file, LOREM_IPSUM_WHAT)?;
+
+    let server = asynchronous::Server::builder()
+        .ip([127, 0, 0, 1])
+        .port(0)
+        .output_dir(dir.child("output").path())
+        .psk("secret".parse()?)
+        .build()
+        .await?;
+    let addr = server.local_addr()?;
+    let server_task = tokio::spawn(server.run());
+
+    let err = asynchronous::Client::builder()
+        .target(addr)
+        .file(file.path())
+        .build()?
+        .send()
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("requires a pre-shared key"),
+        "{err}"
+    );
+    // Still waiting for a client that knows the key
+    assert!(!server_task.is_finished());
+    server_task.abort();
+    Ok(())
+}
+
+#[tokio::test]
+pub async fn test_async_client_times_out_on_silent_server() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    fs::write(&file, LOREM_IPSUM_WHAT)?;
+    // Accepts the connection but never sends its hello
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0)).await?;
+    let addr = listener.local_addr()?;
+    let _accepting = tokio::spawn(async move { listener.accept().await });
+
+    let client = asynchronous::Client::builder()
+        .target(addr)
+        .file(file.path())
+        .build()?;
+    let timed_out = tokio::time::timeout(Duration::from_millis(200), client.send()).await;
+
+    assert!(timed_out.is_err());
+    Ok(())
+}
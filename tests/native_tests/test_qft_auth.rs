@@ -0,0 +1,98 @@
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+fn spawn_server_with_psk(
+    port: &PortGuard,
+    output_dir: &Path,
+    psk: &str,
+) -> TestResult<JoinHandle<Result<Output>>> {
+    Ok(spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "-vv".to_owned(),
+            "--output-dir".to_owned(),
+            output_dir.to_string_lossy().into_owned(),
+            format!("--psk={psk}"),
+        ],
+    )?)
+}
+
+#[test]
+pub fn test_transfer_with_psk() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    fs::write(&file, "content")?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+    let port = get_free_port(IP).unwrap();
+
+    let server_thread = spawn_server_with_psk(&port, output_dir.path(), "s3cret")?;
+    let client_thread = spawn_client_thread(
+        file.path(),
+        ["ip", IP, "--port", port.as_str(), "-vv", "--psk", "s3cret"],
+    )?;
+    let (server_output, client_output) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread),
+        ClientHandle(client_thread),
+    )?;
+
+    assert!(!server_output.failed(), "{}", server_output.stderr());
+    assert!(!client_output.failed(), "{}", client_output.stderr());
+    assert_no_errors_or_warn(client_output.stderr())?;
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, "content");
+    Ok(())
+}
+
+/// Clients with a wrong or missing key are dropped, and the server keeps waiting for a client with the right key
+#[test]
+pub fn test_unauthenticated_clients_are_dropped() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    fs::write(&file, "content")?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+    let port = get_free_port(IP).unwrap();
+
+    let server_thread = spawn_server_with_psk(&port, output_dir.path(), "s3cret")?;
+
+    let wrong_key_client = spawn_client_thread(
+        file.path(),
+        ["ip", IP, "--port", port.as_str(), "-vv", "--psk", "wrong"],
+    )?;
+    let ProcessOutput { status, stderr, .. } = join_thread_and_get_output(wrong_key_client)?;
+    assert!(!status.success());
+    match_count(
+        false,
+        &stderr,
+        "authentication failed: wrong pre-shared key",
+        1,
+    )?;
+    assert_eq!(regex_matches(false, &stderr, "retrying"), 0, "{stderr}");
+
+    let missing_key_client =
+        spawn_client_thread(file.path(), ["ip", IP, "--port", port.as_str(), "-vv"])?;
+    let ProcessOutput { status, stderr, .. } = join_thread_and_get_output(missing_key_client)?;
+    assert!(!status.success());
+    match_count(false, &stderr, "the server requires a pre-shared key", 1)?;
+    assert!(!output_dir.join("f.txt").exists());
+
+    let client_thread = spawn_client_thread(
+        file.path(),
+        ["ip", IP, "--port", port.as_str(), "-vv", "--psk", "s3cret"],
+    )?;
+    let (server_output, client_output) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread),
+        ClientHandle(client_thread),
+    )?;
+
+    assert!(!server_output.failed(), "{}", server_output.stderr());
+    assert!(!client_output.failed(), "{}", client_output.stderr());
+    match_count(false, server_output.stderr(), "Dropped client", 2)?;
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, "content");
+    Ok(())
+}
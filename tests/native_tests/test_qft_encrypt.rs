@@ -0,0 +1,147 @@
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+/// A qft command that keeps its identity and known hosts in `config_dir`
+fn qft_cmd(config_dir: &Path) -> TestResult<Command> {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.env("XDG_CONFIG_HOME", config_dir);
+    cmd.timeout(Duration::from_secs(10));
+    Ok(cmd)
+}
+
+/// Send `file` from a client with its config in `client_config` to a server at `port` with its config in `server_config`
+fn transfer(
+    port: &str,
+    file: &Path,
+    output_dir: &Path,
+    (server_config, server_args): (&Path, &[&str]),
+    (client_config, client_args): (&Path, &[&str]),
+) -> TestResult<(ServerOutput, ClientOutput)> {
+    let mut server_cmd = qft_cmd(server_config)?;
+    server_cmd
+        .args(["listen", "--ip", IP, "--port", port, "-vv"])
+        .arg("--output-dir")
+        .arg(output_dir)
+        .args(server_args);
+    let mut client_cmd = qft_cmd(client_config)?;
+    client_cmd
+        .args(["send", "ip", IP, "--port", port, "-vv", "--file"])
+        .arg(file)
+        .args(client_args);
+
+    let server_thread = spawn_cmd_thread("qft server", server_cmd, None)?;
+    let client_thread = spawn_cmd_thread("qft client", client_cmd, None)?;
+    Ok(join_server_and_client_get_outputs(
+        ServerHandle(server_thread),
+        ClientHandle(client_thread),
+    )?)
+}
+
+#[test]
+pub fn test_encrypted_transfer_with_every_compression_and_mmap() -> TestResult {
+    let dir = TempDir::new()?;
+    let server_config = dir.child("server_config");
+    let client_config = dir.child("client_config");
+    let file = dir.child("f.txt");
+    fs::write(&file, LOREM_IPSUM_0x80000_BYTES)?;
+    // The identity is pinned for the address of the server
+    let port = get_free_port(IP).unwrap();
+
+    for (i, compression) in [None, Some("bzip2"), Some("gzip"), Some("lz4"), Some("xz")]
+        .into_iter()
+        .enumerate()
+    {
+        for mmap in [false, true] {
+            let output_dir = dir.child(format!("output_{i}_{mmap}"));
+            fs::create_dir(&output_dir)?;
+            let mut client_args = vec!["--encrypt"];
+            client_args.extend(mmap.then_some("--mmap"));
+            client_args.extend(compression);
+
+            let (server_output, client_output) = transfer(
+                port.as_str(),
+                file.path(),
+                output_dir.path(),
+                (server_config.path(), &[]),
+                (client_config.path(), &client_args),
+            )?;
+            assert!(!server_output.failed(), "{}", server_output.stderr());
+            assert!(!client_output.failed(), "{}", client_output.stderr());
+            match_count(false, server_output.stderr(), "Encrypted the connection", 2)?;
+            // The server is only trusted on first use, and is then known
+            let first_use = usize::from(i == 0 && !mmap);
+            match_count(false, client_output.stderr(), "on first use", first_use)?;
+            pretty_assert_str_eq!(
+                fs::read_to_string(output_dir.join("f.txt"))?,
+                LOREM_IPSUM_0x80000_BYTES
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_server_requiring_encryption_encrypts_any_client() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    fs::write(&file, "content")?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+
+    let (server_output, client_output) = transfer(
+        get_free_port(IP).unwrap().as_str(),
+        file.path(),
+        output_dir.path(),
+        (dir.child("server_config").path(), &["--encrypt"]),
+        (dir.child("client_config").path(), &[]),
+    )?;
+
+    assert!(!server_output.failed(), "{}", server_output.stderr());
+    assert!(!client_output.failed(), "{}", client_output.stderr());
+    match_count(false, client_output.stderr(), "Encrypted the connection", 2)?;
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, "content");
+    Ok(())
+}
+
+#[test]
+pub fn test_changed_server_identity_is_refused() -> TestResult {
+    let dir = TempDir::new()?;
+    let client_config = dir.child("client_config");
+    let file = dir.child("f.txt");
+    fs::write(&file, "content")?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+    let port = get_free_port(IP).unwrap();
+
+    let (server_output, client_output) = transfer(
+        port.as_str(),
+        file.path(),
+        output_dir.path(),
+        (dir.child("server_config").path(), &[]),
+        (client_config.path(), &["--encrypt"]),
+    )?;
+    assert!(!server_output.failed(), "{}", server_output.stderr());
+    assert!(!client_output.failed(), "{}", client_output.stderr());
+    fs::remove_file(output_dir.join("f.txt"))?;
+
+    // Another server (identity) at the same address
+    let (_, client_output) = transfer(
+        port.as_str(),
+        file.path(),
+        output_dir.path(),
+        (dir.child("other_server_config").path(), &[]),
+        (client_config.path(), &["--encrypt"]),
+    )?;
+    assert!(client_output.failed());
+    match_count(
+        false,
+        client_output.stderr(),
+        format!("identity of {IP} changed since it was first trusted"),
+        1,
+    )?;
+    // Refused right away instead of retrying the handshake
+    match_count(false, client_output.stderr(), "Handshake failed", 0)?;
+    assert!(!output_dir.join("f.txt").exists());
+    Ok(())
+}
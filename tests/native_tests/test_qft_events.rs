@@ -0,0 +1,129 @@
+use serde_json::Value;
+
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+fn read_events(path: &Path) -> TestResult<Vec<Value>> {
+    let events = fs::read_to_string(path)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    Ok(events)
+}
+
+fn events_named<'a>(events: &'a [Value], name: &str) -> Vec<&'a Value> {
+    events.iter().filter(|e| e["event"] == name).collect()
+}
+
+#[test]
+pub fn test_events_of_sent_and_received_file() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    let content = LOREM_IPSUM_0x80000_BYTES.repeat(10);
+    fs::write(&file, &content)?;
+    let output_dir = dir.child("output");
+    let client_events = dir.child("client.ndjson");
+    let server_events = dir.child("server.ndjson");
+
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "--output-dir".to_owned(),
+            output_dir.to_string_lossy().into_owned(),
+            "--events".to_owned(),
+            server_events.to_string_lossy().into_owned(),
+        ],
+    )?;
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["send", "ip", IP, "--port", port.as_str(), "--events"])
+        .arg(client_events.path())
+        .arg("--file")
+        .arg(file.path());
+    process_output_to_stdio_if_success(cmd.output()?)?;
+    join_thread_and_get_output_if_success(server_thread)?;
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, content);
+
+    let client_events = read_events(client_events.path())?;
+    let server_events = read_events(server_events.path())?;
+    for events in [&client_events, &server_events] {
+        assert!(events.iter().all(|e| e["time_ms"].as_u64().is_some()));
+        assert!(!events_named(events, "connect").is_empty());
+        let handshakes = events_named(events, "handshake_ok");
+        assert!(!handshakes.is_empty());
+        assert!(handshakes
+            .iter()
+            .all(|e| e["protocol_version"].as_u64().is_some()));
+        assert!(events_named(events, "error").is_empty(), "{events:?}");
+
+        let starts = events_named(events, "file_start");
+        assert_eq!(starts.len(), 1);
+        assert_eq!(starts[0]["name"], "f.txt");
+        let progress = events_named(events, "progress");
+        assert!(!progress.is_empty());
+        assert!(progress.iter().all(|e| e["name"] == "f.txt"));
+        let done = events_named(events, "file_done");
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0]["bytes"], content.len());
+    }
+    // Both ends agree on the content
+    let client_done = events_named(&client_events, "file_done")[0];
+    let server_done = events_named(&server_events, "file_done")[0];
+    assert!(client_done["checksum"].is_string());
+    assert_eq!(client_done["checksum"], server_done["checksum"]);
+    assert_eq!(
+        events_named(&client_events, "file_start")[0]["size"],
+        content.len()
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_events_of_failed_transfer() -> TestResult {
+    let dir = TempDir::new()?;
+    let events_file = dir.child("events.ndjson");
+    let port = get_free_port(IP).unwrap();
+
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    // Nothing is listening
+    cmd.args([
+        "send",
+        "--one-shot",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+        "--events",
+    ])
+    .arg(events_file.path())
+    .args(["--file", "LICENSE"]);
+    cmd.assert().failure();
+
+    let events = read_events(events_file.path())?;
+    let errors = events_named(&events, "error");
+    assert_eq!(errors.len(), 1, "{events:?}");
+    assert!(errors[0]["message"].is_string());
+    Ok(())
+}
+
+#[test]
+pub fn test_events_on_stdout_are_refused_with_other_output() -> TestResult {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["get-free-port", IP, "--json", "--events", "1"]);
+    let output = cmd.output()?;
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+
+    // The received content is written to stdout
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["listen", "--ip", IP, "--port", "0", "--events", "1"]);
+    let output = cmd.output()?;
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    Ok(())
+}
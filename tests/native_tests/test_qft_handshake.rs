@@ -0,0 +1,89 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+/// A client talking to a server from before protocol versioning should fail with a clear message instead of retrying
+#[test]
+pub fn test_send_to_legacy_server_fails_cleanly() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child("f1.txt");
+    fs::write(&file_to_transfer, "contents")?;
+
+    let port = get_free_port(IP).unwrap();
+    let legacy_listener = TcpListener::bind((IP, port.as_str().parse::<u16>()?))?;
+    let legacy_server = std::thread::spawn(move || -> Result<()> {
+        let (mut socket, _) = legacy_listener.accept()?;
+        // Legacy servers open with a pseudo-random u32 and expect a u32 back
+        socket.write_all(&0x8f1c_27d3_u32.to_be_bytes())?;
+        let mut buf = [0; 4];
+        let _ = socket.read_exact(&mut buf);
+        Ok(())
+    });
+
+    let client_thread = spawn_client_thread(
+        file_to_transfer.path(),
+        ["ip", IP, "--port", port.as_str(), "-vv"],
+    );
+    let ProcessOutput {
+        status,
+        stdout: _,
+        stderr,
+    } = join_thread_and_get_output(client_thread?)?;
+    legacy_server
+        .join()
+        .expect("Failed joining legacy server")?;
+
+    assert!(!status.success());
+    assert!(
+        regex_matches(false, &stderr, "legacy QFT protocol") >= 1,
+        "{stderr}"
+    );
+    assert_eq!(regex_matches(false, &stderr, "retrying"), 0);
+    Ok(())
+}
+
+/// A server should refuse a client from before protocol versioning with a clear message
+#[test]
+pub fn test_legacy_client_is_refused_by_server() -> TestResult {
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_thread_qft(
+        "qft server",
+        ["listen", "--ip", IP, "--port", port.as_str(), "-vv"],
+        None,
+    );
+
+    let port_num: u16 = port.as_str().parse()?;
+    let mut attempts = 0;
+    let mut socket = loop {
+        match TcpStream::connect((IP, port_num)) {
+            Err(e) if attempts < 50 => {
+                eprintln!("{e}, retrying...");
+                attempts += 1;
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            res => break res?,
+        }
+    };
+    // Legacy clients read a u32 and answer with a u32 derived from it
+    let mut buf = [0; 4];
+    socket.read_exact(&mut buf)?;
+    socket.write_all(&0x1234_5678_u32.to_be_bytes())?;
+
+    let ProcessOutput {
+        status,
+        stdout: _,
+        stderr,
+    } = join_thread_and_get_output(server_thread?)?;
+
+    assert!(!status.success());
+    assert!(
+        regex_matches(false, &stderr, "legacy QFT protocol") >= 1,
+        "{stderr}"
+    );
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::process::{Child, Stdio};
+
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+/// Kills the listening qft when dropped, so that it doesn't outlive a failed test
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_keep_alive_server(
+    port: &str,
+    output_dir: &Path,
+    extra_args: &[&str],
+) -> TestResult<KillOnDrop> {
+    let mut cmd = std::process::Command::cargo_bin(BIN_NAME)?;
+    cmd.args([
+        "listen",
+        "--ip",
+        IP,
+        "--port",
+        port,
+        "--keep-alive",
+        "--output-dir",
+    ])
+    .arg(output_dir)
+    .args(extra_args)
+    .stdout(Stdio::null())
+    .stderr(Stdio::null());
+    Ok(KillOnDrop(cmd.spawn()?))
+}
+
+fn send_file(port: &str, file: &Path) -> TestResult<StdoutStderr> {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["send", "ip", IP, "--port", port, "-v", "--file"])
+        .arg(file);
+    Ok(process_output_to_stdio_if_success(cmd.output()?)?)
+}
+
+#[test]
+pub fn test_keep_alive_serves_sequential_and_concurrent_clients() -> TestResult {
+    let dir = TempDir::new()?;
+    let output_dir = dir.child("output");
+    fs::create_dir(&output_dir)?;
+    let files: Vec<ChildPath> = (0..4)
+        .map(|i| {
+            let f = dir.child(format!("f{i}.txt"));
+            fs::write(&f, format!("contents of file #{i}"))?;
+            Ok(f)
+        })
+        .collect::<TestResult<_>>()?;
+
+    let port = get_free_port(IP).unwrap();
+    let _server =
+        spawn_keep_alive_server(port.as_str(), output_dir.path(), &["--max-sessions", "2"])?;
+
+    // One after the other, the first transfer ending doesn't stop the server
+    for f in &files[..2] {
+        let StdoutStderr { stderr, .. } = send_file(port.as_str(), f.path())?;
+        assert_no_errors_or_warn_with_ignore(&stderr, r"retrying in")?;
+    }
+    // At the same time
+    let handles: Vec<_> = files[2..]
+        .iter()
+        .map(|f| {
+            let port = port.as_str().to_owned();
+            let f = f.path().to_path_buf();
+            std::thread::spawn(move || send_file(&port, &f).map(|out| out.stderr))
+        })
+        .collect();
+    for h in handles {
+        let stderr = h.join().expect("Client thread panicked")?;
+        assert_no_errors_or_warn_with_ignore(&stderr, r"retrying in")?;
+    }
+
+    for (i, _) in files.iter().enumerate() {
+        pretty_assert_str_eq!(
+            format!("contents of file #{i}"),
+            fs::read_to_string(output_dir.join(format!("f{i}.txt")))?
+        );
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_max_sessions_requires_keep_alive() -> TestResult {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["listen", "--max-sessions", "2"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--keep-alive"));
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use quick_file_transfer::{Client, FileStatus, Server, TransferObserver};
+
+use crate::util::*;
+
+#[test]
+pub fn test_library_client_sends_to_library_server() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_a = dir.child("a.txt");
+    let file_b = dir.child("b.txt");
+    fs::write(&file_a, LOREM_IPSUM_WHAT)?;
+    fs::write(&file_b, LOREM_IPSUM_0x80000_BYTES)?;
+    let output_dir = dir.child("output");
+    fs::create_dir(&output_dir)?;
+
+    let received_names = Arc::new(Mutex::new(vec![]));
+    let server = Server::builder()
+        .ip([127, 0, 0, 1])
+        .port(0)
+        .output_dir(output_dir.path())
+        .on_received({
+            let received_names = Arc::clone(&received_names);
+            move |file| received_names.lock().unwrap().push(file.name.clone())
+        })
+        .build()?;
+    let addr = server.local_addr()?;
+    let server_thread = std::thread::spawn(move || server.run());
+
+    let sent_names = Arc::new(Mutex::new(vec![]));
+    let report = Client::builder()
+        .target(addr)
+        .files([file_a.path(), file_b.path()])
+        .on_sent({
+            let sent_names = Arc::clone(&sent_names);
+            move |file| sent_names.lock().unwrap().push(file.name.clone())
+        })
+        .build()?
+        .send()?;
+    let server_report = server_thread.join().expect("Server thread panicked")?;
+
+    assert_eq!(report.files.len(), 2);
+    assert_eq!(report.files[0].name, "a.txt");
+    assert_eq!(report.files[0].size, LOREM_IPSUM_WHAT.len() as u64);
+    assert_eq!(report.files[1].name, "b.txt");
+    assert_eq!(report.files[1].size, LOREM_IPSUM_0x80000_BYTES.len() as u64);
+    assert!(report
+        .files
+        .iter()
+        .all(|f| matches!(f.status, FileStatus::Sent { .. })));
+    assert_eq!(*sent_names.lock().unwrap(), ["a.txt", "b.txt"]);
+
+    let mut received: Vec<_> = server_report
+        .files
+        .iter()
+        .map(|f| (f.name.as_str(), f.path.clone(), f.size))
+        .collect();
+    received.sort();
+    assert_eq!(
+        received,
+        [
+            (
+                "a.txt",
+                Some(output_dir.join("a.txt")),
+                LOREM_IPSUM_WHAT.len() as u64
+            ),
+            (
+                "b.txt",
+                Some(output_dir.join("b.txt")),
+                LOREM_IPSUM_0x80000_BYTES.len() as u64
+            ),
+        ]
+    );
+    received_names.lock().unwrap().sort();
+    assert_eq!(*received_names.lock().unwrap(), ["a.txt", "b.txt"]);
+    assert_eq!(
+        fs::read_to_string(output_dir.join("a.txt"))?,
+        LOREM_IPSUM_WHAT
+    );
+    assert_eq!(
+        fs::read_to_string(output_dir.join("b.txt"))?,
+        LOREM_IPSUM_0x80000_BYTES
+    );
+    Ok(())
+}
+
+/// Records what it observes, as lines of text
+#[derive(Default)]
+struct RecordingObserver {
+    observed: Mutex<Vec<String>>,
+    progress: Mutex<u64>,
+}
+
+impl TransferObserver for RecordingObserver {
+    fn on_file_start(&self, name: &str, size: Option<u64>) {
+        self.observed
+            .lock()
+            .unwrap()
+            .push(format!("start {name} {size:?}"));
+    }
+
+    fn on_progress(&self, _name: &str, transferred: u64) {
+        *self.progress.lock().unwrap() = transferred;
+    }
+
+    fn on_file_complete(&self, name: &str, bytes: u64, _duration: Duration) {
+        self.observed
+            .lock()
+            .unwrap()
+            .push(format!("complete {name} {bytes}"));
+    }
+
+    fn on_error(&self, name: Option<&str>, error: &anyhow::Error) {
+        self.observed
+            .lock()
+            .unwrap()
+            .push(format!("error {name:?} {error}"));
+    }
+}
+
+#[test]
+pub fn test_library_observers_follow_the_transfer() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    let content = LOREM_IPSUM_0x80000_BYTES.repeat(4);
+    fs::write(&file, &content)?;
+    let len = content.len() as u64;
+    let output_dir = dir.child("output");
+    fs::create_dir(&output_dir)?;
+
+    let server_observer = Arc::new(RecordingObserver::default());
+    let server = Server::builder()
+        .ip([127, 0, 0, 1])
+        .port(0)
+        .output_dir(output_dir.path())
+        .observer(Arc::clone(&server_observer))
+        .build()?;
+    let addr = server.local_addr()?;
+    let server_thread = std::thread::spawn(move || server.run());
+
+    let client_observer = Arc::new(RecordingObserver::default());
+    let report = Client::builder()
+        .target(addr)
+        .file(file.path())
+        .observer(Arc::clone(&client_observer))
+        .build()?
+        .send()?;
+    server_thread.join().expect("Server thread panicked")?;
+    report.ensure_no_failures()?;
+
+    for observer in [&client_observer, &server_observer] {
+        assert_eq!(
+            *observer.observed.lock().unwrap(),
+            [
+                format!("start f.txt Some({len})"),
+                format!("complete f.txt {len}")
+            ]
+        );
+        assert_eq!(*observer.progress.lock().unwrap(), len);
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use serde_json::Value;
+
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+#[test]
+pub fn test_get_free_port_json() -> TestResult {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["get-free-port", IP, "--json"]);
+    let StdoutStderr { stdout, .. } = process_output_to_stdio_if_success(cmd.output()?)?;
+
+    let free_port: Value = serde_json::from_str(&stdout)?;
+    assert_eq!(free_port["ip"], IP);
+    assert!(free_port["port"].as_u64().is_some_and(|port| port > 0));
+    Ok(())
+}
+
+#[test]
+pub fn test_send_reports_each_file_as_json() -> TestResult {
+    let dir = TempDir::new()?;
+    let output_dir = dir.child("output");
+    fs::create_dir(&output_dir)?;
+    fs::write(dir.child("sent.txt"), LOREM_IPSUM_0x80000_BYTES)?;
+    fs::write(dir.child("skipped.txt"), "new")?;
+    fs::write(output_dir.join("skipped.txt"), "existing")?;
+
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "--output-dir".to_owned(),
+            output_dir.to_string_lossy().into_owned(),
+            "--overwrite".to_owned(),
+            "never".to_owned(),
+        ],
+    )?;
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args([
+        "--output-format",
+        "json",
+        "send",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+    ])
+    .arg("--file")
+    .arg(dir.child("sent.txt").path())
+    .arg("--file")
+    .arg(dir.child("skipped.txt").path());
+    let StdoutStderr { stdout, .. } = process_output_to_stdio_if_success(cmd.output()?)?;
+    join_thread_and_get_output_if_success(server_thread)?;
+
+    let report: Value = serde_json::from_str(&stdout)?;
+    let files = report["files"].as_array().expect("files is an array");
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0]["name"], "sent.txt");
+    assert_eq!(files[0]["status"], "sent");
+    assert_eq!(files[0]["size"], LOREM_IPSUM_0x80000_BYTES.len());
+    assert_eq!(files[0]["transferred"], LOREM_IPSUM_0x80000_BYTES.len());
+    assert_eq!(files[1]["name"], "skipped.txt");
+    assert_eq!(files[1]["status"], "skipped");
+    assert!(files[1]["reason"].as_str().is_some());
+    Ok(())
+}
+
+#[test]
+pub fn test_listen_reports_received_files_as_json() -> TestResult {
+    let dir = TempDir::new()?;
+    let output_dir = dir.child("output");
+    fs::create_dir(&output_dir)?;
+    fs::write(dir.child("received.txt"), LOREM_IPSUM_0x80000_BYTES)?;
+
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--output-format".to_owned(),
+            "json".to_owned(),
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "--output-dir".to_owned(),
+            output_dir.to_string_lossy().into_owned(),
+        ],
+    )?;
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["send", "ip", IP, "--port", port.as_str()])
+        .arg("--file")
+        .arg(dir.child("received.txt").path());
+    process_output_to_stdio_if_success(cmd.output()?)?;
+    let StdoutStderr { stdout, .. } = join_thread_and_get_output_if_success(server_thread)?;
+
+    let report: Value = serde_json::from_str(&stdout)?;
+    let files = report["files"].as_array().expect("files is an array");
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0]["name"], "received.txt");
+    assert_eq!(files[0]["size"], LOREM_IPSUM_0x80000_BYTES.len());
+    assert_eq!(
+        files[0]["path"].as_str().map(PathBuf::from),
+        Some(output_dir.join("received.txt"))
+    );
+    assert!(files[0]["duration_ms"].as_u64().is_some());
+    Ok(())
+}
+
+#[test]
+pub fn test_listen_to_stdout_refuses_json() -> TestResult {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["listen", "--ip", IP, "--port", "0", "--json"]);
+    let output = cmd.output()?;
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    Ok(())
+}
+
+#[cfg(feature = "evaluate-compression")]
+#[test]
+pub fn test_evaluate_compression_json() -> TestResult {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args([
+        "evaluate-compression",
+        "--json",
+        "--input-file",
+        "LICENSE",
+        "--omit",
+        "bzip2",
+        "xz",
+    ]);
+    let StdoutStderr { stdout, .. } = process_output_to_stdio_if_success(cmd.output()?)?;
+
+    let evaluation: Value = serde_json::from_str(&stdout)?;
+    assert_eq!(
+        evaluation["input_size"].as_u64(),
+        Some(fs::metadata("LICENSE")?.len())
+    );
+    // Lz4 and the 9 levels of gzip
+    let results = evaluation["results"]
+        .as_array()
+        .expect("results is an array");
+    assert_eq!(results.len(), 10);
+    assert!(results
+        .iter()
+        .all(|r| r["compressed_size"].as_u64().is_some_and(|size| size > 0)));
+    assert_eq!(evaluation["best_ratio"]["format"], "Gzip");
+    Ok(())
+}
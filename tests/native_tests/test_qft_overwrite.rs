@@ -0,0 +1,143 @@
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+/// Send `file` to a server receiving into `output_dir` with the given overwrite policy, returns the stderr of the client
+fn send_with_overwrite_policy(file: &Path, output_dir: &Path, policy: &str) -> TestResult<String> {
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "-vv".to_owned(),
+            "--output-dir".to_owned(),
+            output_dir.to_string_lossy().into_owned(),
+            format!("--overwrite={policy}"),
+        ],
+    )?;
+    let client_thread = spawn_client_thread(file, ["ip", IP, "--port", port.as_str(), "-vv"])?;
+    let (server_output, client_output) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread),
+        ClientHandle(client_thread),
+    )?;
+    assert!(!server_output.failed(), "{}", server_output.stderr());
+    assert!(!client_output.failed(), "{}", client_output.stderr());
+    Ok(client_output.stderr().to_owned())
+}
+
+/// The file to send and an output directory that already has a file with the same name
+fn setup(dir: &TempDir) -> TestResult<(ChildPath, ChildPath)> {
+    let file = dir.child("f.txt");
+    fs::write(&file, "new content")?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+    fs::write(output_dir.join("f.txt"), "existing content")?;
+    Ok((file, output_dir))
+}
+
+#[test]
+pub fn test_overwrite_always() -> TestResult {
+    let dir = TempDir::new()?;
+    let (file, output_dir) = setup(&dir)?;
+
+    send_with_overwrite_policy(file.path(), output_dir.path(), "always")?;
+
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, "new content");
+    Ok(())
+}
+
+#[test]
+pub fn test_overwrite_never_skips_existing() -> TestResult {
+    let dir = TempDir::new()?;
+    let (file, output_dir) = setup(&dir)?;
+
+    let client_stderr = send_with_overwrite_policy(file.path(), output_dir.path(), "never")?;
+
+    match_count(
+        false,
+        &client_stderr,
+        "Skipped .*f.txt: f.txt already exists",
+        1,
+    )?;
+    pretty_assert_str_eq!(
+        fs::read_to_string(output_dir.join("f.txt"))?,
+        "existing content"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_overwrite_newer_skips_older_file() -> TestResult {
+    let dir = TempDir::new()?;
+    let (file, output_dir) = setup(&dir)?;
+    let an_hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+    fs::File::options()
+        .write(true)
+        .open(&file)?
+        .set_modified(an_hour_ago)?;
+
+    let client_stderr = send_with_overwrite_policy(file.path(), output_dir.path(), "newer")?;
+    match_count(
+        false,
+        &client_stderr,
+        "Skipped .*not older than the sent file",
+        1,
+    )?;
+    pretty_assert_str_eq!(
+        fs::read_to_string(output_dir.join("f.txt"))?,
+        "existing content"
+    );
+
+    // Now the sent file is the most recently modified
+    fs::File::options()
+        .write(true)
+        .open(output_dir.join("f.txt"))?
+        .set_modified(an_hour_ago - Duration::from_secs(3600))?;
+    send_with_overwrite_policy(file.path(), output_dir.path(), "newer")?;
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, "new content");
+    Ok(())
+}
+
+#[test]
+pub fn test_overwrite_rename_keeps_existing() -> TestResult {
+    let dir = TempDir::new()?;
+    let (file, output_dir) = setup(&dir)?;
+
+    let client_stderr = send_with_overwrite_policy(file.path(), output_dir.path(), "rename")?;
+    match_count(false, &client_stderr, r"is received as f \(1\).txt", 1)?;
+    let client_stderr = send_with_overwrite_policy(file.path(), output_dir.path(), "rename")?;
+    match_count(false, &client_stderr, r"is received as f \(2\).txt", 1)?;
+
+    pretty_assert_str_eq!(
+        fs::read_to_string(output_dir.join("f.txt"))?,
+        "existing content"
+    );
+    pretty_assert_str_eq!(
+        fs::read_to_string(output_dir.join("f (1).txt"))?,
+        "new content"
+    );
+    pretty_assert_str_eq!(
+        fs::read_to_string(output_dir.join("f (2).txt"))?,
+        "new content"
+    );
+    Ok(())
+}
+
+#[test]
+pub fn test_overwrite_backup_keeps_copy() -> TestResult {
+    let dir = TempDir::new()?;
+    let (file, output_dir) = setup(&dir)?;
+
+    let client_stderr = send_with_overwrite_policy(file.path(), output_dir.path(), "backup")?;
+
+    match_count(false, &client_stderr, "existing file was kept as f.txt~", 1)?;
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, "new content");
+    pretty_assert_str_eq!(
+        fs::read_to_string(output_dir.join("f.txt~"))?,
+        "existing content"
+    );
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use crate::util::*;
+use std::time::Instant;
+
+pub const IP: &str = "127.0.0.1";
+
+/// Send 512 KiB with the given server and client args, returns how long the transfer took
+fn timed_transfer(server_args: &[&str], client_args: &[&str]) -> TestResult<Duration> {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    fs::write(&file, LOREM_IPSUM_0x80000_BYTES)?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+    let port = get_free_port(IP).unwrap();
+
+    let server_cmd_args: Vec<String> = ["--ip", IP, "--port", port.as_str(), "-vv", "--output-dir"]
+        .into_iter()
+        .chain(output_dir.to_str())
+        .chain(server_args.iter().copied())
+        .map(str::to_owned)
+        .collect();
+    let server_thread = spawn_server_thread(None, server_cmd_args)?;
+    let start = Instant::now();
+    let client_cmd_args: Vec<String> = ["ip", IP, "--port", port.as_str(), "-vv"]
+        .into_iter()
+        .chain(client_args.iter().copied())
+        .map(str::to_owned)
+        .collect();
+    let client_thread = spawn_client_thread(file.path(), client_cmd_args)?;
+    let (server_output, client_output) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread),
+        ClientHandle(client_thread),
+    )?;
+    let elapsed = start.elapsed();
+
+    assert!(!server_output.failed(), "{}", server_output.stderr());
+    assert!(!client_output.failed(), "{}", client_output.stderr());
+    pretty_assert_str_eq!(
+        fs::read_to_string(output_dir.join("f.txt"))?,
+        LOREM_IPSUM_0x80000_BYTES
+    );
+    Ok(elapsed)
+}
+
+#[test]
+pub fn test_send_with_limit_rate() -> TestResult {
+    // 512 KiB at 1 MiB/s takes at least 0.4 s after the initial burst
+    let elapsed = timed_transfer(&[], &["--limit-rate", "1M"])?;
+    assert!(elapsed >= Duration::from_millis(400), "{elapsed:?}");
+    Ok(())
+}
+
+#[test]
+pub fn test_listen_with_limit_rate() -> TestResult {
+    let elapsed = timed_transfer(&["--limit-rate", "1M"], &[])?;
+    assert!(elapsed >= Duration::from_millis(400), "{elapsed:?}");
+    Ok(())
+}
+
+#[test]
+pub fn test_invalid_limit_rate_is_rejected() -> TestResult {
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["send", "ip", IP, "--limit-rate", "1.5M"]);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "expected bytes per second with an optional K, M or G suffix",
+    ));
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+/// Send `file_to_transfer` with `--resume` and the given extra client arguments, then return the outputs
+fn resume_transfer(
+    file_to_transfer: &Path,
+    file_to_receive: &Path,
+    extra_client_args: &[&str],
+) -> TestResult<(ServerOutput, ClientOutput)> {
+    let port = get_free_port(IP).unwrap();
+    let mut cmd_client = Command::cargo_bin(BIN_NAME)?;
+    cmd_client.args([
+        "send",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+        "-vv",
+        "--file",
+        file_to_transfer.to_str().unwrap(),
+        "--resume",
+    ]);
+    cmd_client.args(extra_client_args);
+    let client_thread = spawn_cmd_thread(
+        "Client thread",
+        cmd_client,
+        Some(Duration::from_millis(200)),
+    );
+    let server_thread = spawn_server_thread(
+        Some(file_to_receive),
+        ["--ip", IP, "--port", port.as_str(), "-vv"],
+    );
+
+    let (server_out, client_out) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread?),
+        ClientHandle(client_thread?),
+    )?;
+    if server_out.failed() || client_out.failed() {
+        server_out.display_diagnostics();
+        client_out.display_diagnostics();
+    }
+    if cfg!(target_os = "linux") {
+        assert_no_errors_or_warn(server_out.stderr())?;
+        assert_no_errors_or_warn(client_out.stderr())?;
+    } else {
+        let ignore_retrying_warn = r"retrying in";
+        assert_no_errors_or_warn_with_ignore(server_out.stderr(), ignore_retrying_warn)?;
+        assert_no_errors_or_warn_with_ignore(client_out.stderr(), ignore_retrying_warn)?;
+    }
+    Ok((server_out, client_out))
+}
+
+#[test]
+pub fn test_resume_partially_received_file() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child("f1.txt");
+    let file_to_receive = dir.child("f2.txt");
+
+    const TRANSFERED_CONTENTS: &str = LOREM_IPSUM_0x80000_BYTES;
+    fs::write(&file_to_transfer, TRANSFERED_CONTENTS)?;
+    // What a previous interrupted transfer left behind
+    fs::write(
+        dir.child(".f2.txt.qft-part"),
+        &TRANSFERED_CONTENTS[..0x12345],
+    )?;
+
+    let (server_out, client_out) =
+        resume_transfer(file_to_transfer.path(), file_to_receive.path(), &["gzip"])?;
+
+    match_count(
+        false,
+        client_out.stderr(),
+        r"Resuming .*f1\.txt from offset 74565",
+        1,
+    )?;
+    match_count(false, client_out.stderr(), "verified by server", 1)?;
+    assert!(
+        regex_matches(
+            false,
+            server_out.stderr(),
+            r"Resuming .*f2\.txt.* from offset 74565"
+        ) >= 1
+    );
+    pretty_assert_str_eq!(TRANSFERED_CONTENTS, fs::read_to_string(file_to_receive)?);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_resume_mmap_with_different_prefix_sends_from_start() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child("f1.txt");
+    let file_to_receive = dir.child("f2.txt");
+
+    const TRANSFERED_CONTENTS: &str = LOREM_IPSUM_0x80000_BYTES;
+    fs::write(&file_to_transfer, TRANSFERED_CONTENTS)?;
+    fs::write(
+        dir.child(".f2.txt.qft-part"),
+        "Not the start of the transferred contents",
+    )?;
+
+    let (_, client_out) =
+        resume_transfer(file_to_transfer.path(), file_to_receive.path(), &["--mmap"])?;
+
+    match_count(false, client_out.stderr(), "Unable to resume", 1)?;
+    match_count(false, client_out.stderr(), "verified by server", 1)?;
+    pretty_assert_str_eq!(TRANSFERED_CONTENTS, fs::read_to_string(file_to_receive)?);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_resume_nothing_received_sends_from_start() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child("f1.txt");
+    let file_to_receive = dir.child("f2.txt");
+
+    const TRANSFERED_CONTENTS: &str = "contents";
+    fs::write(&file_to_transfer, TRANSFERED_CONTENTS)?;
+
+    let (_, client_out) = resume_transfer(file_to_transfer.path(), file_to_receive.path(), &[])?;
+
+    match_count(false, client_out.stderr(), "Resuming", 0)?;
+    pretty_assert_str_eq!(TRANSFERED_CONTENTS, fs::read_to_string(file_to_receive)?);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_resume_never_overwrites_existing_file() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child("f.txt");
+    fs::write(&file_to_transfer, "abcXYZ")?;
+    let output_dir = dir.child("out");
+    fs::create_dir(&output_dir)?;
+    // A complete file that happens to be a prefix of the sent file
+    fs::write(output_dir.child("f.txt"), "abc")?;
+
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "-vv".to_owned(),
+            "--output-dir".to_owned(),
+            output_dir.to_string_lossy().into_owned(),
+            "--overwrite=never".to_owned(),
+        ],
+    )?;
+    let client_thread = spawn_client_thread(
+        file_to_transfer.path(),
+        ["ip", IP, "--port", port.as_str(), "-vv", "--resume"],
+    )?;
+    let (server_out, client_out) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread),
+        ClientHandle(client_thread),
+    )?;
+
+    assert!(!server_out.failed(), "{}", server_out.stderr());
+    assert!(!client_out.failed(), "{}", client_out.stderr());
+    match_count(false, client_out.stderr(), "Resuming", 0)?;
+    match_count(false, client_out.stderr(), "doesn't overwrite files", 1)?;
+    pretty_assert_str_eq!(fs::read_to_string(output_dir.child("f.txt"))?, "abc");
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use crate::util::*;
+
+pub const IP: &str = "127.0.0.1";
+
+/// Pipe `stdin` into a client and return the outputs of the client and of a server that writes to stdout
+fn pipe_through(
+    stdin: &'static str,
+    client_args: &[&str],
+) -> TestResult<(ServerOutput, ClientOutput)> {
+    let port = get_free_port(IP).unwrap();
+    let mut server_cmd = Command::cargo_bin(BIN_NAME)?;
+    server_cmd
+        .args(["listen", "--ip", IP, "--port", port.as_str(), "-vv"])
+        .timeout(Duration::from_secs(10));
+    let mut client_cmd = Command::cargo_bin(BIN_NAME)?;
+    client_cmd
+        .args(["send", "ip", IP, "--port", port.as_str(), "-vv"])
+        .args(client_args)
+        .write_stdin(stdin)
+        .timeout(Duration::from_secs(10));
+
+    let server_thread = spawn_cmd_thread("qft server", server_cmd, None)?;
+    let client_thread = spawn_cmd_thread("qft client", client_cmd, None)?;
+    Ok(join_server_and_client_get_outputs(
+        ServerHandle(server_thread),
+        ClientHandle(client_thread),
+    )?)
+}
+
+#[test]
+pub fn test_stdin_to_stdout_with_every_compression() -> TestResult {
+    for compression in [None, Some("bzip2"), Some("gzip"), Some("lz4"), Some("xz")] {
+        let client_args: Vec<&str> = compression.into_iter().collect();
+        let (server_output, client_output) = pipe_through(LOREM_IPSUM_0x80000_BYTES, &client_args)?;
+
+        assert!(!server_output.failed(), "{}", server_output.stderr());
+        assert!(!client_output.failed(), "{}", client_output.stderr());
+        assert_no_errors_or_warn(client_output.stderr())?;
+        assert_no_errors_or_warn(server_output.stderr())?;
+        match_count(false, client_output.stderr(), "verified by server", 1)?;
+        // Nothing but the content is written to stdout
+        pretty_assert_str_eq!(server_output.stdout(), LOREM_IPSUM_0x80000_BYTES);
+        assert!(client_output.stdout().is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_empty_stdin() -> TestResult {
+    let (server_output, client_output) = pipe_through("", &[])?;
+
+    assert!(!server_output.failed(), "{}", server_output.stderr());
+    assert!(!client_output.failed(), "{}", client_output.stderr());
+    assert!(server_output.stdout().is_empty());
+    Ok(())
+}
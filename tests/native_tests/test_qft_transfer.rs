@@ -29,7 +29,7 @@ pub fn test_file_transfer_no_compression_simple() -> TestResult {
         ClientHandle(client_thread?),
     )?;
 
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -71,7 +71,7 @@ pub fn test_stdout_transfer_no_compression_mmap() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -114,7 +114,7 @@ pub fn test_stdin_stdout_transfer_no_compression() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -158,7 +158,7 @@ pub fn test_file_transfer_no_compression_with_no_prealloc() -> TestResult {
         client_out.display_diagnostics();
     }
 
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -221,7 +221,7 @@ pub fn test_file_transfer_bzip2_default_with_no_prealloc() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -283,7 +283,7 @@ pub fn test_file_transfer_gzip_default_with_no_prealloc() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -345,7 +345,7 @@ pub fn test_file_transfer_lz4_default_with_no_prealloc() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -407,7 +407,7 @@ pub fn test_file_transfer_xz_default_with_no_prealloc() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -461,7 +461,7 @@ pub fn test_file_transfer_bzip2_compr_level_1() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -518,7 +518,7 @@ pub fn test_file_transfer_gzip_compr_level_1() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -576,7 +576,7 @@ pub fn test_file_transfer_xz_compr_level_1() -> TestResult {
         server_out.display_diagnostics();
         client_out.display_diagnostics();
     }
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(server_out.stderr())?;
         assert_no_errors_or_warn(client_out.stderr())?;
     } else {
@@ -644,7 +644,7 @@ pub fn test_file_transfer_output_dir_single_file() -> TestResult {
         stderr: server_stderr,
     } = join_thread_and_get_output_if_success(server_thread)?;
 
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(&server_stderr)?;
         assert_no_errors_or_warn(&client_stderr)?;
     } else {
@@ -734,7 +734,7 @@ pub fn test_file_transfer_output_dir_multiple_files() -> TestResult {
     eprintln!("=== COMMAND STDOUT ===\n{_client_stdout}\n^^^COMMAND STDOUT^^^\n");
     eprintln!("=== COMMAND STDERR ===\n{client_stderr}\n^^^COMMAND STDERR^^^\n");
 
-    if cfg!(linux) {
+    if cfg!(target_os = "linux") {
         assert_no_errors_or_warn(&server_stderr)?;
         assert_no_errors_or_warn(&client_stderr)?;
     } else {
@@ -753,3 +753,622 @@ pub fn test_file_transfer_output_dir_multiple_files() -> TestResult {
 
     Ok(())
 }
+
+/// File names close to the file system limit of 255 bytes used to overflow the single byte command header
+#[test]
+pub fn test_file_transfer_output_dir_long_file_name() -> TestResult {
+    let fname = "f".repeat(250) + ".txt";
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child(&fname);
+    let subdir = dir.join("tmp_subdir");
+    fs::create_dir(&subdir)?;
+    let subdir_path = subdir.as_path().to_string_lossy().into_owned().leak();
+    let file_to_receive = subdir.join(&fname);
+
+    const TRANSFERED_CONTENTS: &str = "contents";
+    fs::write(&file_to_transfer, TRANSFERED_CONTENTS)?;
+
+    let port = get_free_port(IP).unwrap();
+
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip",
+            IP,
+            "--port",
+            port.as_str(),
+            "-vv",
+            "--output-dir",
+            subdir_path,
+        ],
+    )?;
+
+    let mut cmd = Command::cargo_bin(BIN_NAME).unwrap();
+    cmd.args([
+        "send",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+        "-vv",
+        "--file",
+        file_to_transfer.path().to_str().unwrap(),
+    ]);
+    let StdoutStderr {
+        stdout: _client_stdout,
+        stderr: client_stderr,
+    } = process_output_to_stdio_if_success(cmd.output()?)?;
+
+    let StdoutStderr {
+        stdout: _server_stdout,
+        stderr: server_stderr,
+    } = join_thread_and_get_output_if_success(server_thread)?;
+
+    if cfg!(target_os = "linux") {
+        assert_no_errors_or_warn(&server_stderr)?;
+        assert_no_errors_or_warn(&client_stderr)?;
+    } else {
+        let ignore_retrying_warn = r"retrying in";
+        assert_no_errors_or_warn_with_ignore(&server_stderr, ignore_retrying_warn)?;
+        assert_no_errors_or_warn_with_ignore(&client_stderr, ignore_retrying_warn)?;
+    }
+
+    pretty_assert_str_eq!(TRANSFERED_CONTENTS, fs::read_to_string(file_to_receive)?);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_file_transfer_lz4_mmap_checksum_verified() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child("f1.txt");
+    let file_to_receive = dir.child("f2.txt");
+
+    const TRANSFERED_CONTENTS: &str = LOREM_IPSUM_0x80000_BYTES;
+    fs::write(&file_to_transfer, TRANSFERED_CONTENTS)?;
+
+    let port = get_free_port(IP).unwrap();
+    let mut cmd_client = Command::cargo_bin(BIN_NAME)?;
+    cmd_client.args([
+        "send",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+        "-vv",
+        "--file",
+        file_to_transfer.path().to_str().unwrap(),
+        "--mmap",
+        "lz4",
+    ]);
+    let client_thread = spawn_cmd_thread(
+        "Client thread",
+        cmd_client,
+        Some(Duration::from_millis(200)),
+    );
+    let server_thread = spawn_server_thread(
+        Some(file_to_receive.path()),
+        ["--ip", IP, "--port", port.as_str(), "-vv"],
+    );
+
+    let (server_out, client_out) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread?),
+        ClientHandle(client_thread?),
+    )?;
+    if server_out.failed() || client_out.failed() {
+        server_out.display_diagnostics();
+        client_out.display_diagnostics();
+    }
+    if cfg!(target_os = "linux") {
+        assert_no_errors_or_warn(server_out.stderr())?;
+        assert_no_errors_or_warn(client_out.stderr())?;
+    } else {
+        let ignore_retrying_warn = r"retrying in";
+        assert_no_errors_or_warn_with_ignore(server_out.stderr(), ignore_retrying_warn)?;
+        assert_no_errors_or_warn_with_ignore(client_out.stderr(), ignore_retrying_warn)?;
+    }
+    match_count(false, client_out.stderr(), "verified by server", 1)?;
+    pretty_assert_str_eq!(TRANSFERED_CONTENTS, fs::read_to_string(file_to_receive)?);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_file_transfer_output_dir_recursive() -> TestResult {
+    let dir = TempDir::new()?;
+    // A directory tree with nested and empty directories
+    let src_dir = dir.child("src_dir");
+    fs::create_dir_all(src_dir.join("nested/deeper"))?;
+    fs::create_dir(src_dir.join("empty"))?;
+    fs::write(src_dir.join("top.txt"), "top")?;
+    fs::write(
+        src_dir.join("nested/deeper/bottom.txt"),
+        LOREM_IPSUM_0x80000_BYTES,
+    )?;
+    // A file next to the directory
+    let single_file = dir.child("single.txt");
+    fs::write(&single_file, "single")?;
+
+    let output_dir = dir.child("output_dir");
+    let output_dir_str = output_dir.to_string_lossy().into_owned();
+
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "-vv".to_owned(),
+            "--output-dir".to_owned(),
+            output_dir_str,
+        ],
+    )?;
+
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args([
+        "send",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+        "-vv",
+        "--recursive",
+        "--file",
+        src_dir.path().to_str().unwrap(),
+        "--file",
+        single_file.path().to_str().unwrap(),
+    ]);
+    let StdoutStderr {
+        stdout: _,
+        stderr: client_stderr,
+    } = process_output_to_stdio_if_success(cmd.output()?)?;
+    let StdoutStderr {
+        stdout: _,
+        stderr: server_stderr,
+    } = join_thread_and_get_output_if_success(server_thread)?;
+
+    if cfg!(target_os = "linux") {
+        assert_no_errors_or_warn(&server_stderr)?;
+        assert_no_errors_or_warn(&client_stderr)?;
+    } else {
+        let ignore_retrying_warn = r"retrying in";
+        assert_no_errors_or_warn_with_ignore(&server_stderr, ignore_retrying_warn)?;
+        assert_no_errors_or_warn_with_ignore(&client_stderr, ignore_retrying_warn)?;
+    }
+
+    let received_dir = output_dir.join("src_dir");
+    pretty_assert_str_eq!("top", fs::read_to_string(received_dir.join("top.txt"))?);
+    pretty_assert_str_eq!(
+        LOREM_IPSUM_0x80000_BYTES,
+        fs::read_to_string(received_dir.join("nested/deeper/bottom.txt"))?
+    );
+    assert!(received_dir.join("empty").is_dir());
+    pretty_assert_str_eq!("single", fs::read_to_string(output_dir.join("single.txt"))?);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_send_directory_without_recursive_fails() -> TestResult {
+    let dir = TempDir::new()?;
+    let port = get_free_port(IP).unwrap();
+
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args([
+        "send",
+        "--one-shot",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+        "--file",
+        dir.path().to_str().unwrap(),
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("requires --recursive"));
+
+    Ok(())
+}
+
+#[test]
+pub fn test_file_transfer_preserve_permissions_and_times() -> TestResult {
+    let dir = TempDir::new()?;
+    let file_to_transfer = dir.child("f1.sh");
+    let file_to_receive = dir.child("f2.sh");
+
+    const TRANSFERED_CONTENTS: &str = "#!/bin/sh\necho hello";
+    fs::write(&file_to_transfer, TRANSFERED_CONTENTS)?;
+    let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+    fs::File::options()
+        .write(true)
+        .open(&file_to_transfer)?
+        .set_times(fs::FileTimes::new().set_modified(mtime))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&file_to_transfer, fs::Permissions::from_mode(0o750))?;
+    }
+
+    let port = get_free_port(IP).unwrap();
+    let client_thread = spawn_client_thread(
+        file_to_transfer.path(),
+        ["ip", IP, "--port", port.as_str(), "-vv", "--preserve"],
+    );
+    let server_thread = spawn_server_thread(
+        Some(file_to_receive.path()),
+        ["--ip", IP, "--port", port.as_str(), "-vv"],
+    );
+
+    let (server_out, client_out) = join_server_and_client_get_outputs(
+        ServerHandle(server_thread?),
+        ClientHandle(client_thread?),
+    )?;
+    if server_out.failed() || client_out.failed() {
+        server_out.display_diagnostics();
+        client_out.display_diagnostics();
+    }
+    if cfg!(target_os = "linux") {
+        assert_no_errors_or_warn(server_out.stderr())?;
+        assert_no_errors_or_warn(client_out.stderr())?;
+    } else {
+        let ignore_retrying_warn = r"retrying in";
+        assert_no_errors_or_warn_with_ignore(server_out.stderr(), ignore_retrying_warn)?;
+        assert_no_errors_or_warn_with_ignore(client_out.stderr(), ignore_retrying_warn)?;
+    }
+    pretty_assert_str_eq!(TRANSFERED_CONTENTS, fs::read_to_string(&file_to_receive)?);
+    let received_md = fs::metadata(&file_to_receive)?;
+    pretty_assert_eq!(received_md.modified()?, mtime);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        pretty_assert_eq!(received_md.permissions().mode() & 0o7777, 0o750);
+    }
+
+    Ok(())
+}
+
+/// A directory that is a symlink out of the output directory on the receiving end must not be written through
+#[cfg(unix)]
+#[test]
+pub fn test_recursive_transfer_through_symlink_out_of_output_dir_is_rejected() -> TestResult {
+    let dir = TempDir::new()?;
+    let src_dir = dir.child("tree");
+    fs::create_dir(&src_dir)?;
+    fs::write(src_dir.join("f.txt"), "content")?;
+
+    let outside_dir = dir.child("outside");
+    fs::create_dir(&outside_dir)?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+    std::os::unix::fs::symlink(outside_dir.path(), output_dir.join("tree"))?;
+    let output_dir_str = output_dir.to_string_lossy().into_owned();
+
+    let port = get_free_port(IP).unwrap();
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "-vv".to_owned(),
+            "--output-dir".to_owned(),
+            output_dir_str,
+        ],
+    )?;
+
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args([
+        "send",
+        "ip",
+        IP,
+        "--port",
+        port.as_str(),
+        "-vv",
+        "--recursive",
+        "--file",
+        src_dir.path().to_str().unwrap(),
+    ]);
+    let ProcessOutput {
+        status,
+        stdout: _,
+        stderr: client_stderr,
+    } = process_output(cmd.output()?)?;
+    let ProcessOutput {
+        status: _,
+        stdout: _,
+        stderr: server_stderr,
+    } = join_thread_and_get_output(server_thread)?;
+    eprintln!("{server_stderr}");
+
+    assert!(!status.success(), "{client_stderr}");
+    match_count(
+        false,
+        &client_stderr,
+        "Rejected received name .* outside of the output directory",
+        1,
+    )?;
+    assert_eq!(fs::read_dir(&outside_dir)?.count(), 0);
+    Ok(())
+}
+
+#[test]
+pub fn test_file_transfer_output_dir_parallel_jobs() -> TestResult {
+    let dir = TempDir::new()?;
+    let output_dir = dir.child("output_dir");
+    fs::create_dir(&output_dir)?;
+    let files: Vec<PathBuf> = (0..12)
+        .map(|i| {
+            let file = dir.child(format!("f{i:02}.txt"));
+            fs::write(&file, LOREM_IPSUM_0x80000_BYTES.repeat(i % 3 + 1))?;
+            Ok(file.to_path_buf())
+        })
+        .collect::<io::Result<_>>()?;
+    let port = get_free_port(IP).unwrap();
+
+    let server_thread = spawn_server_thread(
+        None,
+        [
+            "--ip".to_owned(),
+            IP.to_owned(),
+            "--port".to_owned(),
+            port.as_str().to_owned(),
+            "-vv".to_owned(),
+            "--output-dir".to_owned(),
+            output_dir.to_string_lossy().into_owned(),
+        ],
+    )?;
+    let mut cmd = Command::cargo_bin(BIN_NAME)?;
+    cmd.args(["send", "ip", IP, "--port", port.as_str(), "-vv", "-j", "4"]);
+    for file in &files {
+        cmd.arg("--file").arg(file);
+    }
+    let StdoutStderr {
+        stderr: client_stderr,
+        ..
+    } = process_output_to_stdio_if_success(cmd.output()?)?;
+    let StdoutStderr {
+        stderr: server_stderr,
+        ..
+    } = join_thread_and_get_output_if_success(server_thread)?;
+
+    assert_no_errors_or_warn(&server_stderr)?;
+    assert_no_errors_or_warn(&client_stderr)?;
+    match_count(false, &client_stderr, "Sending with 4 job", 1)?;
+    // The outcomes are logged in the order of the files, regardless of which job sent them
+    let sent_at: Vec<usize> = files
+        .iter()
+        .map(|file| {
+            client_stderr
+                .find(&format!("Sent {}", file.display()))
+                .expect("Sent file isn't logged")
+        })
+        .collect();
+    assert!(sent_at.windows(2).all(|w| w[0] < w[1]), "{client_stderr}");
+    for (i, file) in files.iter().enumerate() {
+        pretty_assert_str_eq!(
+            fs::read_to_string(output_dir.join(file.file_name().unwrap()))?,
+            LOREM_IPSUM_0x80000_BYTES.repeat(i % 3 + 1)
+        );
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_file_transfer_split_into_parallel_streams() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    // Over 4 MiB, split into 4 ranges
+    let content = LOREM_IPSUM_0x80000_BYTES.repeat(42);
+    fs::write(&file, &content)?;
+
+    for (i, compression) in [None, Some("lz4"), Some("gzip")].into_iter().enumerate() {
+        let output_dir = dir.child(format!("output_{i}"));
+        fs::create_dir(&output_dir)?;
+        let port = get_free_port(IP).unwrap();
+        let server_thread = spawn_server_thread(
+            None,
+            [
+                "--ip".to_owned(),
+                IP.to_owned(),
+                "--port".to_owned(),
+                port.as_str().to_owned(),
+                "-vv".to_owned(),
+                "--output-dir".to_owned(),
+                output_dir.to_string_lossy().into_owned(),
+            ],
+        )?;
+        let mut cmd = Command::cargo_bin(BIN_NAME)?;
+        cmd.args(["send", "ip", IP, "--port", port.as_str(), "-vv"])
+            .args(["--streams", "4", "--file"])
+            .arg(file.path())
+            .args(compression);
+        let StdoutStderr {
+            stderr: client_stderr,
+            ..
+        } = process_output_to_stdio_if_success(cmd.output()?)?;
+        let StdoutStderr {
+            stderr: server_stderr,
+            ..
+        } = join_thread_and_get_output_if_success(server_thread)?;
+
+        assert_no_errors_or_warn(&server_stderr)?;
+        assert_no_errors_or_warn(&client_stderr)?;
+        match_count(false, &client_stderr, "in 4 ranges", 1)?;
+        match_count(false, &server_stderr, "in ranges", 2)?;
+        pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, content);
+        assert_eq!(
+            fs::read_dir(&output_dir)?.count(),
+            1,
+            "No temporary file left"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_file_transfer_delta_of_updated_file() -> TestResult {
+    let dir = TempDir::new()?;
+    let file = dir.child("f.txt");
+    let old_content = LOREM_IPSUM_0x80000_BYTES.repeat(10);
+    let mut content = old_content.clone();
+    content.replace_range(300_000..300_010, "0123456789");
+    content.insert_str(600_000, "inserted");
+    fs::write(&file, &content)?;
+
+    for (i, compression) in [None, Some("lz4")].into_iter().enumerate() {
+        let output_dir = dir.child(format!("output_{i}"));
+        fs::create_dir(&output_dir)?;
+        fs::write(output_dir.join("f.txt"), &old_content)?;
+        // Without an existing file all of it is sent
+        fs::write(dir.child("new.txt"), &content)?;
+        let port = get_free_port(IP).unwrap();
+        let server_thread = spawn_server_thread(
+            None,
+            [
+                "--ip".to_owned(),
+                IP.to_owned(),
+                "--port".to_owned(),
+                port.as_str().to_owned(),
+                "-vv".to_owned(),
+                "--output-dir".to_owned(),
+                output_dir.to_string_lossy().into_owned(),
+            ],
+        )?;
+        let mut cmd = Command::cargo_bin(BIN_NAME)?;
+        cmd.args(["send", "ip", IP, "--port", port.as_str(), "-vv", "--delta"])
+            .arg("--file")
+            .arg(file.path())
+            .arg("--file")
+            .arg(dir.child("new.txt").path())
+            .args(compression);
+        let StdoutStderr {
+            stderr: client_stderr,
+            ..
+        } = process_output_to_stdio_if_success(cmd.output()?)?;
+        let StdoutStderr {
+            stderr: server_stderr,
+            ..
+        } = join_thread_and_get_output_if_success(server_thread)?;
+
+        assert_no_errors_or_warn(&server_stderr)?;
+        assert_no_errors_or_warn(&client_stderr)?;
+        match_count(false, &client_stderr, "f.txt as a delta", 1)?;
+        match_count(false, &client_stderr, "no existing new.txt", 1)?;
+        match_count(false, &server_stderr, "Rebuilt", 1)?;
+        pretty_assert_str_eq!(fs::read_to_string(output_dir.join("f.txt"))?, content);
+        pretty_assert_str_eq!(fs::read_to_string(output_dir.join("new.txt"))?, content);
+        assert_eq!(
+            fs::read_dir(&output_dir)?.count(),
+            2,
+            "No temporary file left"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_sync_sends_only_missing_or_stale_files() -> TestResult {
+    let dir = TempDir::new()?;
+    let src_dir = dir.child("src_dir");
+    fs::create_dir_all(src_dir.join("nested"))?;
+    fs::write(src_dir.join("unchanged.txt"), LOREM_IPSUM_0x80000_BYTES)?;
+    fs::write(src_dir.join("nested/changed.txt"), "old")?;
+    let output_dir = dir.child("output_dir");
+
+    let sync = |extra_args: &[&str]| -> TestResult<(String, String)> {
+        let port = get_free_port(IP).unwrap();
+        let server_thread = spawn_server_thread(
+            None,
+            [
+                "--ip".to_owned(),
+                IP.to_owned(),
+                "--port".to_owned(),
+                port.as_str().to_owned(),
+                "-vv".to_owned(),
+                "--output-dir".to_owned(),
+                output_dir.to_string_lossy().into_owned(),
+            ],
+        )?;
+        let mut cmd = Command::cargo_bin(BIN_NAME)?;
+        cmd.args([
+            "send",
+            "ip",
+            IP,
+            "--port",
+            port.as_str(),
+            "-vv",
+            "--recursive",
+            "--sync",
+        ])
+        .args(extra_args)
+        .arg("--file")
+        .arg(src_dir.path());
+        let StdoutStderr {
+            stderr: client_stderr,
+            ..
+        } = process_output_to_stdio_if_success(cmd.output()?)?;
+        let StdoutStderr {
+            stderr: server_stderr,
+            ..
+        } = join_thread_and_get_output_if_success(server_thread)?;
+        assert_no_errors_or_warn(&server_stderr)?;
+        assert_no_errors_or_warn(&client_stderr)?;
+        Ok((client_stderr, server_stderr))
+    };
+
+    // Nothing to sync with yet
+    let (client_stderr, server_stderr) = sync(&[])?;
+    match_count(false, &client_stderr, r"0 of 2 file\(s\) up to date", 1)?;
+    match_count(
+        false,
+        &server_stderr,
+        r"2 of 2 file\(s\) are missing or stale",
+        1,
+    )?;
+
+    fs::write(src_dir.join("nested/changed.txt"), "changed")?;
+    let received_dir = output_dir.join("src_dir");
+    fs::write(received_dir.join("nested/deleted.txt"), "deleted")?;
+    fs::create_dir(received_dir.join("deleted_dir"))?;
+    let (client_stderr, server_stderr) = sync(&["--delete"])?;
+    match_count(false, &client_stderr, r"1 of 2 file\(s\) up to date", 1)?;
+    match_count(false, &client_stderr, r"Sending 1 file\(s\)", 1)?;
+    match_count(
+        false,
+        &client_stderr,
+        "Deleted src_dir/deleted_dir on the server",
+        1,
+    )?;
+    match_count(
+        false,
+        &client_stderr,
+        "Deleted src_dir/nested/deleted.txt on the server",
+        1,
+    )?;
+    match_count(
+        false,
+        &server_stderr,
+        r"1 of 2 file\(s\) are missing or stale",
+        1,
+    )?;
+
+    pretty_assert_str_eq!(
+        LOREM_IPSUM_0x80000_BYTES,
+        fs::read_to_string(received_dir.join("unchanged.txt"))?
+    );
+    pretty_assert_str_eq!(
+        "changed",
+        fs::read_to_string(received_dir.join("nested/changed.txt"))?
+    );
+    assert!(!received_dir.join("nested/deleted.txt").exists());
+    assert!(!received_dir.join("deleted_dir").exists());
+
+    // Everything is up to date, also when comparing checksums
+    let (client_stderr, _) = sync(&["--checksum"])?;
+    match_count(false, &client_stderr, r"2 of 2 file\(s\) up to date", 1)?;
+    Ok(())
+}